@@ -1,163 +1,4088 @@
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+#[cfg(all(test, not(feature = "std")))]
+use alloc::{borrow::ToOwned, format, string::ToString};
+use alloc::collections::BTreeMap;
 use core::fmt;
+use core::ops::Range;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, Read};
 
-use thiserror;
-
-#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+#[derive(Clone, Debug, Copy, PartialEq, Eq, Hash)]
 pub enum BfIR {
-    AddVal(u8),     // +
-    SubVal(u8),     // -
+    AddVal(u32),    // +
+    SubVal(u32),    // -
     AddPtr(u32),    // >
     SubPtr(u32),    // <
     GetByte,        // ,
     PutByte,        // .
     Jz,             // [
-    Jnz             // ]
+    Jnz,            // ]
+    SetVal(u32),    // [-] or [+]
+    MulVal { offset: i32, factor: u32 }, // [->+<]
+    AddValAt { offset: i32, val: u32 },  // >+<
+    ScanRight,      // [>]
+    ScanLeft,       // [<]
+    ClearRange { len: u32 },    // [-]>[-]>[-]
+    PutByteN { count: u32 },    // .....
+    DumpTape,       // #, see `Dialect::dump_tape`
+    AddPtrThenAddVal { ptr_delta: i32, val: u32 },  // >+ or <-
+    ReadLine,       // ;, see `Dialect::read_line`
+    Nop,            // left by a pass in place of a deleted instruction, see `strip_nops`
+}
+
+/// size of a tape cell, cell arithmetic wraps at this width
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CellWidth {
+    #[default]
+    W8,
+    W16,
+    W32,
+}
+
+impl CellWidth {
+    /// size of a cell in bytes
+    pub fn bytes(self) -> usize {
+        match self {
+            CellWidth::W8 => 1,
+            CellWidth::W16 => 2,
+            CellWidth::W32 => 4,
+        }
+    }
+
+    /// values of a cell are taken modulo this
+    fn modulus(self) -> i64 {
+        1 << (8 * self.bytes())
+    }
+
+    /// the largest value a cell holds
+    pub(crate) fn max(self) -> u32 {
+        (self.modulus() - 1) as u32
+    }
+
+    /// read the cell stored in native byte order at the start of `mem`
+    pub(crate) fn load(self, mem: &[u8]) -> u32 {
+        match self {
+            CellWidth::W8 => mem[0] as u32,
+            CellWidth::W16 => u16::from_ne_bytes([mem[0], mem[1]]) as u32,
+            CellWidth::W32 => u32::from_ne_bytes([mem[0], mem[1], mem[2], mem[3]]),
+        }
+    }
+
+    /// store `val`, truncated to the cell width, at the start of `mem`
+    pub(crate) fn store(self, mem: &mut [u8], val: u32) {
+        let n = self.bytes();
+        match self {
+            CellWidth::W8 => mem[0] = val as u8,
+            CellWidth::W16 => mem[..n].copy_from_slice(&(val as u16).to_ne_bytes()),
+            CellWidth::W32 => mem[..n].copy_from_slice(&val.to_ne_bytes()),
+        }
+    }
+}
+
+/// what `AddVal` and `SubVal` do once a cell would go past either end
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum ArithMode {
+    /// wrap around, 255 + 1 is 0 in an 8-bit cell
+    #[default]
+    Wrapping,
+    /// stay at the end, 255 + 1 is 255 and 0 - 1 is 0; `MulVal`, `AddValAt`
+    /// and `AddPtrThenAddVal` still wrap, the optimizer leaves them out in
+    /// this mode
+    Saturating,
+}
+
+/// `(line, col)` of the source character an instruction stems from
+pub type SourcePos = (u32, u32);
+
+/// names of the instruction kinds, indexed by `BfIR::kind`
+pub const KIND_NAMES: [&str; 19] = [
+    "addval", "subval", "addptr", "subptr", "getbyte", "putbyte",
+    "jz", "jnz", "setval", "mulval", "addvalat", "scanright", "scanleft",
+    "clearrange", "putbyten", "dumptape", "addptrthenaddval", "readline", "nop",
+];
+
+impl BfIR {
+    /// index of the instruction kind into `KIND_NAMES`
+    pub fn kind(&self) -> usize {
+        use BfIR::*;
+        match self {
+            AddVal(_) => 0,
+            SubVal(_) => 1,
+            AddPtr(_) => 2,
+            SubPtr(_) => 3,
+            GetByte => 4,
+            PutByte => 5,
+            Jz => 6,
+            Jnz => 7,
+            SetVal(_) => 8,
+            MulVal { .. } => 9,
+            AddValAt { .. } => 10,
+            ScanRight => 11,
+            ScanLeft => 12,
+            ClearRange { .. } => 13,
+            PutByteN { .. } => 14,
+            DumpTape => 15,
+            AddPtrThenAddVal { .. } => 16,
+            ReadLine => 17,
+            Nop => 18,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        KIND_NAMES[self.kind()]
+    }
+
+    /// an `AddPtrThenAddVal` as its pointer move and the value to add after
+    /// it, which is how the backends run it, other instructions as they are
+    pub(crate) fn split_move(self) -> (BfIR, Option<u32>) {
+        match self {
+            BfIR::AddPtrThenAddVal { ptr_delta, val } if ptr_delta < 0 => (BfIR::SubPtr(ptr_delta.unsigned_abs()), Some(val)),
+            BfIR::AddPtrThenAddVal { ptr_delta, val } => (BfIR::AddPtr(ptr_delta as u32), Some(val)),
+            op => (op, None),
+        }
+    }
+}
+
+/// write `c` `n` times
+fn write_repeated(f: &mut impl fmt::Write, c: char, n: u32) -> fmt::Result {
+    (0..n).try_for_each(|_| f.write_char(c))
+}
+
+/// write `x` as `+`s, or as `-`s past `i32::MAX`, which is the same modulo
+/// every cell width
+fn write_add(f: &mut impl fmt::Write, x: u32) -> fmt::Result {
+    if x > i32::MAX as u32 {
+        write_repeated(f, '-', x.wrapping_neg())
+    }
+    else {
+        write_repeated(f, '+', x)
+    }
+}
+
+/// add `x` to the cell at `offset` and come back
+fn write_add_at(f: &mut impl fmt::Write, offset: i32, x: u32) -> fmt::Result {
+    let (there, back) = if offset < 0 { ('<', '>') } else { ('>', '<') };
+    write_repeated(f, there, offset.unsigned_abs())?;
+    write_add(f, x)?;
+    write_repeated(f, back, offset.unsigned_abs())
+}
+
+/// the commands an instruction stands for, e.g. `+++` for `AddVal(3)`; a
+/// `MulVal` shows as a multiply loop, which also clears the cell, see
+/// `to_source` for a whole program
+impl fmt::Display for BfIR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use BfIR::*;
+        match *self {
+            AddVal(x) => write_add(f, x),
+            SubVal(x) => write_add(f, x.wrapping_neg()),
+            AddPtr(x) => write_repeated(f, '>', x),
+            SubPtr(x) => write_repeated(f, '<', x),
+            GetByte => f.write_str(","),
+            PutByte => f.write_str("."),
+            Jz => f.write_str("["),
+            Jnz => f.write_str("]"),
+            SetVal(x) => {
+                f.write_str("[-]")?;
+                write_add(f, x)
+            }
+            MulVal { offset, factor } => {
+                f.write_str("[-")?;
+                write_add_at(f, offset, factor)?;
+                f.write_str("]")
+            }
+            AddValAt { offset, val } => write_add_at(f, offset, val),
+            ScanRight => f.write_str("[>]"),
+            ScanLeft => f.write_str("[<]"),
+            ClearRange { len } => {
+                f.write_str("[-]")?;
+                (1..len).try_for_each(|_| f.write_str(">[-]"))
+            }
+            PutByteN { count } => write_repeated(f, '.', count),
+            DumpTape => f.write_str("#"),
+            ReadLine => f.write_str(";"),
+            Nop => Ok(()),
+            AddPtrThenAddVal { ptr_delta, val } => {
+                let mv = if ptr_delta < 0 { '<' } else { '>' };
+                write_repeated(f, mv, ptr_delta.unsigned_abs())?;
+                write_add(f, val)
+            }
+        }
+    }
+}
+
+/// an ir program along with a content hash computed once, so it compares
+/// and hashes cheaply as a cache key
+#[derive(Clone, Debug)]
+pub struct Program {
+    ir: Vec<BfIR>,
+    hash: u64,
+}
+
+impl Program {
+    pub fn new(ir: Vec<BfIR>) -> Self {
+        // fnv-1a over the kind and operands of every instruction, which
+        // stays the same across builds and platforms
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut feed = |word: u32| {
+            for byte in word.to_le_bytes() {
+                hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        };
+        for op in &ir {
+            use BfIR::*;
+            feed(op.kind() as u32);
+            match *op {
+                AddVal(x) | SubVal(x) | AddPtr(x) | SubPtr(x) | SetVal(x) => feed(x),
+                ClearRange { len: x } | PutByteN { count: x } => feed(x),
+                MulVal { offset, factor: x } | AddValAt { offset, val: x } | AddPtrThenAddVal { ptr_delta: offset, val: x } => {
+                    feed(offset as u32);
+                    feed(x);
+                }
+                GetByte | PutByte | Jz | Jnz | ScanRight | ScanLeft | DumpTape | ReadLine | Nop => {}
+            }
+        }
+        Self { ir, hash }
+    }
+
+    pub fn ir(&self) -> &[BfIR] {
+        &self.ir
+    }
+
+    pub fn into_ir(self) -> Vec<BfIR> {
+        self.ir
+    }
+
+    /// the hash computed by `new`, equal programs have equal ones
+    pub fn content_hash(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl From<Vec<BfIR>> for Program {
+    fn from(ir: Vec<BfIR>) -> Self {
+        Self::new(ir)
+    }
 }
 
-#[derive(Debug, thiserror::Error)]
+impl PartialEq for Program {
+    fn eq(&self, other: &Self) -> bool {
+        self.hash == other.hash && self.ir == other.ir
+    }
+}
+
+impl Eq for Program {}
+
+impl core::hash::Hash for Program {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+/// render `ir` as text, one instruction per line
+///
+/// each line is the name of the instruction followed by its operands:
+/// `addval n`, `subval n`, `addptr n`, `subptr n`, `setval n`, `clearrange len`, `putbyten count`,
+/// `mulval offset factor`, `addvalat offset val` and plain
+/// `getbyte`, `putbyte`, `jz`, `jnz`, `scanright`, `scanleft`, `dumptape`.
+/// loop bodies are indented by two spaces per level.
+pub fn disassemble(ir: &[BfIR]) -> String {
+    use core::fmt::Write;
+    use BfIR::*;
+
+    let mut out = String::new();
+    let mut depth: usize = 0;
+    for op in ir {
+        if let Jnz = op {
+            depth = depth.saturating_sub(1);
+        }
+        out.push_str(&"  ".repeat(depth));
+        out.push_str(op.name());
+        match *op {
+            AddVal(x) | SubVal(x) | SetVal(x) => write!(out, " {}", x).unwrap(),
+            AddPtr(x) | SubPtr(x) | ClearRange { len: x } | PutByteN { count: x } => write!(out, " {}", x).unwrap(),
+            MulVal { offset, factor } => write!(out, " {} {}", offset, factor).unwrap(),
+            AddValAt { offset, val } => write!(out, " {} {}", offset, val).unwrap(),
+            AddPtrThenAddVal { ptr_delta, val } => write!(out, " {} {}", ptr_delta, val).unwrap(),
+            GetByte | PutByte | Jnz | ScanRight | ScanLeft | DumpTape | ReadLine | Nop => {}
+            Jz => depth += 1,
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// brainfuck source doing what `ir` does, each instruction as it shows with
+/// `Display`, except that the `MulVal`s before a `SetVal(0)`, as made by the
+/// optimizer, go into one multiply loop; `#` needs `Dialect::dump_tape` to
+/// compile back into `DumpTape` and `;` needs `Dialect::read_line`
+pub fn to_source(ir: &[BfIR]) -> String {
+    use core::fmt::Write;
+
+    let mut out = String::new();
+    let mut i = 0;
+    while i < ir.len() {
+        let muls = ir[i..].iter().take_while(|op| matches!(op, BfIR::MulVal { .. })).count();
+        if muls > 0 && ir.get(i + muls) == Some(&BfIR::SetVal(0)) {
+            out.push_str("[-");
+            for op in &ir[i..i + muls] {
+                if let BfIR::MulVal { offset, factor } = *op {
+                    write_add_at(&mut out, offset, factor).unwrap();
+                }
+            }
+            out.push(']');
+            i += muls + 1;
+            continue;
+        }
+        write!(out, "{}", ir[i]).unwrap();
+        i += 1;
+    }
+    out
+}
+
+/// how many instructions of each kind `ir` contains, absent kinds are left out
+#[cfg(feature = "std")]
+pub fn count_ops(ir: &[BfIR]) -> HashMap<&'static str, u64> {
+    let mut counts = [0; KIND_NAMES.len()];
+    for op in ir {
+        counts[op.kind()] += 1;
+    }
+    named_counts(&counts)
+}
+
+/// an upper bound on the bytes of machine code the JIT generates for `ir`,
+/// on either architecture and with every option that adds code turned on,
+/// so a caller can turn down a program before compiling it
+pub fn estimated_code_size(ir: &[BfIR]) -> usize {
+    use BfIR::*;
+    // the entry, exit and error paths
+    const FIXED: usize = 256;
+    ir.iter().map(|op| match op {
+        Nop => 24,
+        SetVal(_) => 40,
+        GetByte | PutByte | DumpTape | ReadLine => 48,
+        PutByteN { .. } => 56,
+        AddVal(_) | SubVal(_) => 64,
+        ScanRight | ScanLeft | Jz | Jnz => 72,
+        AddPtr(_) | SubPtr(_) | ClearRange { .. } => 80,
+        AddPtrThenAddVal { .. } => 96,
+        AddValAt { .. } => 144,
+        MulVal { .. } => 160,
+    }).sum::<usize>() + FIXED
+}
+
+/// where in the profiling counters the instruction `op` at `i` is counted:
+/// `regions` holds the block of `KIND_NAMES.len()` counters of every
+/// instruction, 0 for any past its end
+pub(crate) fn counter_index(regions: &[usize], i: usize, op: BfIR) -> usize {
+    regions.get(i).copied().unwrap_or(0) * KIND_NAMES.len() + op.kind()
+}
+
+/// pair per-kind counters with their names, leaving out zeros
+#[cfg(feature = "std")]
+pub(crate) fn named_counts(counts: &[u64]) -> HashMap<&'static str, u64> {
+    KIND_NAMES
+        .iter()
+        .zip(counts)
+        .filter(|(_, &n)| n != 0)
+        .map(|(&name, &n)| (name, n))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
 pub enum CompileErrorKind {
     #[error("Unclosed left bracket")]
     UnclosedLeftBracket,
     #[error("Unexpected right bracket")]
     UnexpectedRightBracket,
+    /// reading the source failed, see `compile_reader`
+    #[cfg(feature = "std")]
+    #[error("Failed to read source: {0}")]
+    Io(io::ErrorKind),
+    /// this bracket opens a loop nested more than `depth` loops deep
+    #[error("Loops nested more than {depth} deep")]
+    NestingTooDeep { depth: usize },
 }
 
-#[derive(Debug)]
+/// a quick fix for a bracket mismatch, see `CompileError::suggestion`;
+/// it is advisory, the program may still be wrong after applying it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suggestion {
+    /// insert `ch` so it ends up at `line`:`col`, `offset` bytes into the source
+    Insert { ch: char, line: u32, col: u32, offset: usize },
+    /// remove the `ch` at `line`:`col`, `offset` bytes into the source
+    Remove { ch: char, line: u32, col: u32, offset: usize },
+}
+
+impl fmt::Display for Suggestion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Suggestion::Insert { ch, line, col, .. } => write!(f, "insert `{}` at line {}:{}", ch, line, col),
+            Suggestion::Remove { ch, line, col, .. } => write!(f, "remove `{}` at line {}:{}", ch, line, col),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CompileError {
     line: u32,
     col: u32,
-    kind: CompileErrorKind
+    offset: usize,
+    kind: CompileErrorKind,
+    suggestion: Option<Suggestion>
+}
+
+impl CompileError {
+    /// 1-based line of the offending bracket, or where reading stopped
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    /// 1-based column of the offending bracket, counted in chars
+    pub fn col(&self) -> u32 {
+        self.col
+    }
+
+    /// byte offset of the offending bracket into the source,
+    /// unlike `col` this counts bytes, not chars
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn kind(&self) -> CompileErrorKind {
+        self.kind
+    }
+
+    /// how to fix a bracket mismatch, `None` for the other errors
+    pub fn suggestion(&self) -> Option<Suggestion> {
+        self.suggestion
+    }
 }
 
 impl fmt::Display for CompileError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{} at line {}:{}", self.kind, self.line, self.col)
     }
 }
 
-impl std::error::Error for CompileError {}
+impl core::error::Error for CompileError {}
+
+/// why `deserialize` rejected its input
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DecodeError {
+    #[error("Not a bfir file")]
+    BadMagic,
+    #[error("Unsupported bfir version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Unexpected end of input")]
+    UnexpectedEof,
+    #[error("Unknown instruction kind {0}")]
+    UnknownKind(u8),
+    #[error("Operand out of range")]
+    OperandOutOfRange,
+    #[error("Unbalanced brackets")]
+    UnbalancedBrackets,
+    #[error("Trailing bytes after the last instruction")]
+    TrailingBytes,
+}
+
+/// why `from_json` rejected its input
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{kind} at byte {offset}")]
+pub struct JsonError {
+    /// where in the input, the start of the instruction for the errors
+    /// about one
+    pub offset: usize,
+    pub kind: JsonErrorKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum JsonErrorKind {
+    #[error("Malformed json")]
+    Syntax,
+    #[error("Unknown op")]
+    UnknownOp,
+    #[error("Missing or unexpected field")]
+    BadField,
+    #[error("Operand out of range")]
+    OperandOutOfRange,
+    #[error("Unbalanced brackets")]
+    UnbalancedBrackets,
+}
+
+/// why `verify` rejected an ir
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{kind} at instruction {index}")]
+pub struct VerifyError {
+    /// the offending instruction
+    pub index: usize,
+    pub kind: VerifyErrorKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum VerifyErrorKind {
+    #[error("Jnz without a Jz")]
+    UnmatchedJnz,
+    #[error("Jz without a Jnz")]
+    UnclosedJz,
+    /// an offset no tape is large enough for
+    #[error("Offset out of range")]
+    OffsetOutOfRange,
+    /// a `MulVal` adding the current cell to itself, which no loop folds to
+    #[error("MulVal onto its own cell")]
+    SelfMultiply,
+    /// a `ClearRange` or `PutByteN` of nothing
+    #[error("Zero length")]
+    ZeroLength,
+}
+
+/// which optimization passes to run
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptLevel {
+    /// leave the ir untouched
+    None,
+    /// fold runs of the same instruction
+    Basic,
+    /// additionally cancel mixed runs and rewrite common loop idioms
+    Full,
+}
 
+/// optimize for 8-bit cells at `OptLevel::Full`, the passes run until none
+/// changes anything, so optimizing the result again leaves it as it is unless
+/// it took more rounds than `Optimizer::new` allows
 pub fn optimize(ir: &mut Vec<BfIR>) {
-    let len = ir.len();
-    let mut i = 0;
-    let mut pc = 0;
+    optimize_with(ir, OptLevel::Full)
+}
 
-    macro_rules! _fold_ir {
-        ($variant:ident, $x:ident) => {{
-            let mut j = i + 1;
-            while j < len {
-                if let $variant(d) = ir[j] {
-                    $x = $x.wrapping_add(d);
-                }
-                else {
-                    break
-                }
-                j += 1;
-            }
-            i = j;
-            ir[pc] = $variant($x);
-            pc += 1;
-        }};
+/// `optimize`, returning the optimized ir along with what the passes did
+pub fn optimize_with_stats(mut ir: Vec<BfIR>) -> (Vec<BfIR>, OptStats) {
+    let mut positions = vec![(0, 0); ir.len()];
+    let stats = Optimizer::for_level(OptLevel::Full, CellWidth::W8).run_with_stats(&mut ir, &mut positions);
+    ir.shrink_to_fit();
+    (ir, stats)
+}
+
+/// optimize for 8-bit cells
+pub fn optimize_with(ir: &mut Vec<BfIR>, level: OptLevel) {
+    let mut positions = vec![(0, 0); ir.len()];
+    optimize_with_positions(ir, &mut positions, level, CellWidth::W8);
+}
+
+/// like `optimize_with` for cells of `width`, keeping `positions` aligned with `ir`,
+/// an instruction folded from several keeps the position of the first
+pub fn optimize_with_positions(
+    ir: &mut Vec<BfIR>,
+    positions: &mut Vec<SourcePos>,
+    level: OptLevel,
+    width: CellWidth
+) {
+    optimize_with_arith(ir, positions, level, width, ArithMode::Wrapping)
+}
+
+/// like `optimize_with_positions` for cells doing `arith`, see `Optimizer::for_arith`
+pub fn optimize_with_arith(
+    ir: &mut Vec<BfIR>,
+    positions: &mut Vec<SourcePos>,
+    level: OptLevel,
+    width: CellWidth,
+    arith: ArithMode
+) {
+    assert_eq!(ir.len(), positions.len());
+    if level == OptLevel::None {
+        return;
     }
+    Optimizer::for_arith(level, width, arith).run_with_positions(ir, positions);
+    ir.shrink_to_fit();
+    positions.shrink_to_fit();
+}
 
-    macro_rules! _normal_ir {
-        () => {{
-            ir[pc] = ir[i];
-            i += 1;
-            pc += 1;
-        }};
+/// like `optimize_with_positions`, keeping `map`, the source byte range of
+/// every instruction, e.g. from `tokenize`, aligned with `ir`; an instruction
+/// folded from several spans all of them, along with any optimized away
+/// right after it
+pub fn optimize_with_map(
+    ir: &mut Vec<BfIR>,
+    map: &mut Vec<Range<usize>>,
+    level: OptLevel,
+    width: CellWidth
+) {
+    assert_eq!(ir.len(), map.len());
+    if level == OptLevel::None {
+        return;
     }
+    let mut origins: Vec<usize> = (0..ir.len()).collect();
+    Optimizer::for_level(level, width).run_with_origins(ir, &mut origins);
+    ir.shrink_to_fit();
+    // where the instructions kept from the original ir start, in source order
+    let mut kept: Vec<usize> = origins.iter().copied().filter(|&o| o != usize::MAX).collect();
+    kept.sort_unstable();
+    kept.dedup();
+    let spans = origins.iter().map(|&origin| {
+        if origin == usize::MAX {
+            return 0..0;
+        }
+        // it took in everything up to the next instruction kept from elsewhere
+        let end = kept.get(kept.partition_point(|&o| o <= origin)).copied().unwrap_or(map.len());
+        map[origin].start..map[end - 1].end
+    }).collect();
+    *map = spans;
+}
 
-    use BfIR::*;
-    while i < len {
-        match ir[i] {
-            AddPtr(mut x) => _fold_ir!(AddPtr, x),
-            SubPtr(mut x) => _fold_ir!(SubPtr, x),
-            AddVal(mut x) => _fold_ir!(AddVal, x),
-            SubVal(mut x) => _fold_ir!(SubVal, x),
-            _ => _normal_ir!()
+/// what `Optimizer::run_with_stats` did to the ir
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OptStats {
+    pub instructions_before: usize,
+    pub instructions_after: usize,
+    pub rounds: usize,
+    /// instructions `FoldRuns` or `SaturatingRuns` folded away
+    pub folds_applied: usize,
+    /// loops turned into an instruction by `ClearLoops`
+    pub clear_loops: usize,
+    /// loops turned into an instruction by `ScanLoops`
+    pub scan_loops: usize,
+    /// loops turned into `MulVal`s by `MulLoops`
+    pub multiply_loops: usize,
+}
+
+/// an optimization pass, see `Optimizer`
+pub trait IrPass {
+    /// rewrite `ir`, returning whether it changed; a pass may leave `Nop`s
+    /// in place of what it deleted, the `Optimizer` strips them after it
+    fn run(&self, ir: &mut Vec<BfIR>) -> bool;
+
+    /// like `run`, keeping `positions` aligned with `ir`, by default all
+    /// positions are lost once the pass changes the length of `ir`
+    fn run_with_positions(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) -> bool {
+        let changed = self.run(ir);
+        if positions.len() != ir.len() {
+            *positions = vec![(0, 0); ir.len()];
         }
+        changed
     }
 
-    ir.truncate(pc);
-    ir.shrink_to_fit();
-}
+    /// like `run_with_positions`, adding what it did to `stats`, which is
+    /// left as it is by default
+    fn run_with_stats(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>, _stats: &mut OptStats) -> bool {
+        self.run_with_positions(ir, positions)
+    }
 
-pub fn compile(src: &str) -> Result<Vec<BfIR>, CompileError> {
-    let mut ir: Vec<BfIR> = vec![];
+    /// like `run_with_positions` for `origins`, the index every instruction
+    /// had before optimizing, see `Optimizer::run_with_origins`; by default
+    /// all origins are lost, `usize::MAX`, once the length of `ir` changes
+    fn run_with_origins(&self, ir: &mut Vec<BfIR>, origins: &mut Vec<usize>) -> bool {
+        let changed = self.run(ir);
+        if origins.len() != ir.len() {
+            *origins = vec![usize::MAX; ir.len()];
+        }
+        changed
+    }
+}
 
-    // (bra pos, line, col)
-    let mut stk: Vec<(u32, u32, u32)> = vec![];
+/// the number of loops in `ir`
+fn loops(ir: &[BfIR]) -> usize {
+    ir.iter().filter(|&&op| op == BfIR::Jz).count()
+}
 
-    let mut line: u32 = 1;
-    let mut col: u32 = 0;
+/// implement `IrPass` for a built-in pass from its position keeping version,
+/// whose `body` returns whether it changed `ir`; with a `field`
+/// of `OptStats` and a `measure` of the ir it adds how much the pass took
+/// off the measure to the field
+macro_rules! builtin_pass {
+    ($pass:ty, |$self:ident, $ir:ident, $pos:ident| $body:expr $(, $field:ident by $measure:expr)?) => {
+        impl IrPass for $pass {
+            fn run(&self, ir: &mut Vec<BfIR>) -> bool {
+                let mut positions = vec![(0, 0); ir.len()];
+                self.run_with_positions(ir, &mut positions)
+            }
 
-    for ch in src.chars() {
-        col += 1;
-        match ch {
-            '\n' => {
-                line += 1;
-                col = 0;
-            },
-            '+' => ir.push(BfIR::AddVal(1)),
-            '-' => ir.push(BfIR::SubVal(1)),
-            '>' => ir.push(BfIR::AddPtr(1)),
-            '<' => ir.push(BfIR::SubPtr(1)),
-            ',' => ir.push(BfIR::GetByte),
-            '.' => ir.push(BfIR::PutByte),
-            '[' => {
-                let pos = ir.len() as u32;
-                stk.push((pos, line, col));
-                ir.push(BfIR::Jz);
+            fn run_with_positions(&$self, $ir: &mut Vec<BfIR>, $pos: &mut Vec<SourcePos>) -> bool {
+                $body
             }
-            ']' => {
-                stk.pop().ok_or(CompileError {
-                    line,
-                    col,
-                    kind: CompileErrorKind::UnexpectedRightBracket
-                })?;
 
-                ir.push(BfIR::Jnz);
+            fn run_with_origins(&$self, $ir: &mut Vec<BfIR>, $pos: &mut Vec<usize>) -> bool {
+                $body
             }
-            _ => {}
+
+            $(
+                fn run_with_stats(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>, stats: &mut OptStats) -> bool {
+                    let before = $measure(&ir[..]);
+                    let changed = self.run_with_positions(ir, positions);
+                    stats.$field += before - $measure(&ir[..]);
+                    changed
+                }
+            )?
+        }
+    };
+}
+
+/// fold runs of value ops and pointer ops, `cancel` also folds opposite ops like `+-`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FoldRuns {
+    pub cancel: bool,
+    pub width: CellWidth,
+}
+
+builtin_pass!(FoldRuns, |self, ir, pos| {
+    let modulus = self.width.modulus();
+    if self.cancel {
+        // cancelling a run may bring two runs of the same kind together
+        let mut changed = false;
+        while fold_runs(ir, pos, true, modulus, false) {
+            changed = true;
         }
+        changed
+    }
+    else {
+        fold_runs(ir, pos, false, modulus, false)
     }
+}, folds_applied by <[BfIR]>::len);
 
-    if let Some((_, line, col)) = stk.pop() {
-        return Err(CompileError {
-            line,
-            col,
-            kind: CompileErrorKind::UnclosedLeftBracket
-        });
+/// fold runs of the same value op and of pointer ops for saturating cells,
+/// where `+-` does not cancel and a run goes no further than the largest value
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SaturatingRuns {
+    pub width: CellWidth,
+}
+
+builtin_pass!(SaturatingRuns, |self, ir, pos| fold_runs(ir, pos, false, self.width.modulus(), true), folds_applied by <[BfIR]>::len);
+
+/// `[-]` and `[+]` to `SetVal(0)`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClearLoops;
+
+builtin_pass!(ClearLoops, |self, ir, pos| fold_clear_loops(ir, pos), clear_loops by loops);
+
+/// `[>]` and `[<]` to `ScanRight` and `ScanLeft`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanLoops;
+
+builtin_pass!(ScanLoops, |self, ir, pos| fold_scan_loops(ir, pos), scan_loops by loops);
+
+/// multiply loops to `MulVal`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MulLoops;
+
+builtin_pass!(MulLoops, |self, ir, pos| fold_mul_loops(ir, pos), multiply_loops by loops);
+
+/// value ops into a preceding `SetVal`, overwritten `SetVal`s away
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SetVals {
+    pub width: CellWidth,
+}
+
+builtin_pass!(SetVals, |self, ir, pos| fold_set_vals(ir, pos, self.width.modulus()));
+
+/// runs of clears on consecutive cells to `ClearRange`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClearRanges;
+
+builtin_pass!(ClearRanges, |self, ir, pos| fold_clear_ranges(ir, pos));
+
+/// a pointer move followed by a value op to `AddPtrThenAddVal`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FusePtrVals {
+    pub width: CellWidth,
+}
+
+builtin_pass!(FusePtrVals, |self, ir, pos| fuse_ptr_vals(ir, pos, self.width.modulus()));
+
+/// windows of pointer and value ops to `AddValAt`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Offsets {
+    pub width: CellWidth,
+}
+
+builtin_pass!(Offsets, |self, ir, pos| fold_offsets(ir, pos, self.width.modulus()));
+
+/// runs of `PutByte` to `PutByteN`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PutRuns;
+
+builtin_pass!(PutRuns, |self, ir, pos| fold_put_runs(ir, pos));
+
+/// loops reached on a zeroed tape away, see `remove_dead_loops`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeadLoops;
+
+builtin_pass!(DeadLoops, |self, ir, pos| remove_dead_loops(ir, pos));
+
+/// known cell values into the ir, see `propagate_constants`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConstProp {
+    pub width: CellWidth,
+    pub arith: ArithMode,
+}
+
+builtin_pass!(ConstProp, |self, ir, pos| propagate_constants(ir, pos, self.width, self.arith));
+
+/// store a `SetVal` of a loop only on its first iteration, see `hoist_set_vals`;
+/// this is not part of `optimize` as it doubles the size of such loops
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HoistSetVals;
+
+impl IrPass for HoistSetVals {
+    fn run(&self, ir: &mut Vec<BfIR>) -> bool {
+        let mut positions = vec![(0, 0); ir.len()];
+        self.run_with_positions(ir, &mut positions)
     }
 
-    Ok(ir)
+    fn run_with_positions(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) -> bool {
+        hoist_set_vals(ir, positions)
+    }
+
+    fn run_with_origins(&self, ir: &mut Vec<BfIR>, origins: &mut Vec<usize>) -> bool {
+        hoist_set_vals(ir, origins)
+    }
 }
 
-#[test]
-fn test_compile() {
-    assert_eq!(
-        compile("+[,.]").unwrap(),
-        vec![
-            BfIR::AddVal(1),
-            BfIR::Jz,
-            BfIR::GetByte,
-            BfIR::PutByte,
-            BfIR::Jnz,
-        ]
-    );
+/// the names `Optimizer::from_names` knows the built-in passes by, in the
+/// order `for_level` runs them, then those `optimize` leaves out
+pub const PASS_NAMES: [&str; 12] = [
+    "fold", "clear-loop", "scan", "multiply", "set-val", "clear-range", "offset", "fuse", "put-run",
+    "dce", "const-prop", "hoist",
+];
 
-    match compile("[").unwrap_err().kind {
-        CompileErrorKind::UnclosedLeftBracket => {}
-        _ => panic!(),
-    };
+/// why `Optimizer::from_names` rejected a name
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("Unknown pass {name:?}, expected one of {}", PASS_NAMES.join(", "))]
+pub struct UnknownPass {
+    pub name: String,
+}
 
-    match compile("]").unwrap_err().kind {
-        CompileErrorKind::UnexpectedRightBracket => {}
-        _ => panic!(),
-    };
+/// the built-in pass called `name` in `PASS_NAMES`, for cells of `width`
+/// doing `arith`
+fn pass_by_name(name: &str, width: CellWidth, arith: ArithMode) -> Option<Box<dyn IrPass>> {
+    Some(match name {
+        "fold" if arith == ArithMode::Saturating => Box::new(SaturatingRuns { width }),
+        "fold" => Box::new(FoldRuns { cancel: true, width }),
+        "clear-loop" => Box::new(ClearLoops),
+        "scan" => Box::new(ScanLoops),
+        "multiply" => Box::new(MulLoops),
+        "set-val" => Box::new(SetVals { width }),
+        "clear-range" => Box::new(ClearRanges),
+        "offset" => Box::new(Offsets { width }),
+        "fuse" => Box::new(FusePtrVals { width }),
+        "put-run" => Box::new(PutRuns),
+        "dce" => Box::new(DeadLoops),
+        "const-prop" => Box::new(ConstProp { width, arith }),
+        "hoist" => Box::new(HoistSetVals),
+        _ => return None,
+    })
+}
 
-    let mut code = compile("[+++++]").unwrap();
-    optimize(&mut code);
-    assert_eq!(code, vec![BfIR::Jz, BfIR::AddVal(5), BfIR::Jnz]);
-}
\ No newline at end of file
+/// runs its passes in order, again and again while any of them changes the ir
+pub struct Optimizer {
+    passes: Vec<Box<dyn IrPass>>,
+    max_rounds: usize,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Optimizer {
+    /// no passes, at most 16 rounds
+    pub fn new() -> Self {
+        Self { passes: vec![], max_rounds: 16 }
+    }
+
+    /// the built-in passes `optimize` runs at `level`
+    pub fn for_level(level: OptLevel, width: CellWidth) -> Self {
+        match level {
+            OptLevel::None => Self::new(),
+            OptLevel::Basic => Self::new().with_pass(FoldRuns { cancel: false, width }),
+            OptLevel::Full => Self::new()
+                .with_pass(FoldRuns { cancel: true, width })
+                .with_pass(ClearLoops)
+                .with_pass(ScanLoops)
+                .with_pass(MulLoops)
+                .with_pass(SetVals { width })
+                .with_pass(ClearRanges)
+                .with_pass(Offsets { width })
+                .with_pass(FusePtrVals { width })
+                .with_pass(PutRuns),
+        }
+    }
+
+    /// the passes `optimize` runs at `level` for cells doing `arith`, which are
+    /// those of `for_level` when wrapping; saturating cells only get runs
+    /// folded along with scans and output, as the loop idioms rely on wrapping
+    pub fn for_arith(level: OptLevel, width: CellWidth, arith: ArithMode) -> Self {
+        match (arith, level) {
+            (ArithMode::Wrapping, _) | (_, OptLevel::None) => Self::for_level(level, width),
+            (ArithMode::Saturating, OptLevel::Basic) => Self::new().with_pass(SaturatingRuns { width }),
+            (ArithMode::Saturating, OptLevel::Full) => Self::new()
+                .with_pass(SaturatingRuns { width })
+                .with_pass(ScanLoops)
+                .with_pass(PutRuns),
+        }
+    }
+
+    /// the passes of `for_arith` for a tape wrapping around as a ring, which
+    /// leave out those making offset and scan nodes, as these run off the
+    /// ends instead of wrapping
+    pub fn for_ring(level: OptLevel, width: CellWidth, arith: ArithMode) -> Self {
+        match (arith, level) {
+            (ArithMode::Wrapping, OptLevel::Full) => Self::new()
+                .with_pass(FoldRuns { cancel: true, width })
+                .with_pass(ClearLoops)
+                .with_pass(SetVals { width })
+                .with_pass(PutRuns),
+            (ArithMode::Saturating, OptLevel::Full) => Self::new()
+                .with_pass(SaturatingRuns { width })
+                .with_pass(PutRuns),
+            _ => Self::for_arith(level, width, arith),
+        }
+    }
+
+    /// the built-in passes called `names`, see `PASS_NAMES`, in that order
+    /// for cells of `width` doing `arith`; `fold` does saturating runs then,
+    /// the loop idioms `for_arith` leaves out are still up to the caller
+    pub fn from_names(names: &[&str], width: CellWidth, arith: ArithMode) -> Result<Self, UnknownPass> {
+        let mut optimizer = Self::new();
+        for &name in names {
+            let pass = pass_by_name(name, width, arith).ok_or_else(|| UnknownPass { name: name.into() })?;
+            optimizer.passes.push(pass);
+        }
+        Ok(optimizer)
+    }
+
+    /// append `pass` to the pipeline
+    pub fn with_pass(mut self, pass: impl IrPass + 'static) -> Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// stop after `max_rounds` rounds even if the ir still changes
+    pub fn with_max_rounds(mut self, max_rounds: usize) -> Self {
+        self.max_rounds = max_rounds;
+        self
+    }
+
+    /// optimize `ir`, returns the number of rounds run
+    pub fn run(&self, ir: &mut Vec<BfIR>) -> usize {
+        let mut positions = vec![(0, 0); ir.len()];
+        self.run_with_positions(ir, &mut positions)
+    }
+
+    /// like `run`, keeping `positions` aligned with `ir`
+    pub fn run_with_positions(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) -> usize {
+        self.run_with_stats(ir, positions).rounds
+    }
+
+    /// like `run_with_positions`, returns what every pass did
+    pub fn run_with_stats(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) -> OptStats {
+        let mut stats = OptStats { instructions_before: ir.len(), ..OptStats::default() };
+        stats.rounds = self.run_rounds(ir, positions, |pass, ir, positions| pass.run_with_stats(ir, positions, &mut stats));
+        stats.instructions_after = ir.len();
+        stats
+    }
+
+    /// like `run`, keeping `origins` aligned with `ir`, which start out as the
+    /// index of every instruction, e.g. `0..ir.len()`, and stay with what
+    /// took its place; an instruction folded from several keeps the origin of
+    /// the first
+    pub fn run_with_origins(&self, ir: &mut Vec<BfIR>, origins: &mut Vec<usize>) -> usize {
+        self.run_rounds(ir, origins, |pass, ir, origins| pass.run_with_origins(ir, origins))
+    }
+
+    /// run rounds of `run_pass` with every pass until none changes `ir` or
+    /// `max_rounds` is reached, returns the number of rounds run
+    fn run_rounds<P: Copy>(
+        &self,
+        ir: &mut Vec<BfIR>,
+        side: &mut Vec<P>,
+        mut run_pass: impl FnMut(&dyn IrPass, &mut Vec<BfIR>, &mut Vec<P>) -> bool
+    ) -> usize {
+        assert_eq!(ir.len(), side.len());
+        let mut rounds = 0;
+        while rounds < self.max_rounds {
+            rounds += 1;
+            let mut changed = false;
+            for pass in &self.passes {
+                if run_pass(pass.as_ref(), ir, side) {
+                    strip_nops(ir, side);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        rounds
+    }
+}
+
+/// drop every `Nop`, leaving the rest in order
+pub fn strip_nops<P: Copy>(ir: &mut Vec<BfIR>, positions: &mut Vec<P>) {
+    assert_eq!(ir.len(), positions.len());
+    let len = ir.len();
+    let mut pc = 0;
+
+    for i in 0..len {
+        if ir[i] != BfIR::Nop {
+            ir[pc] = ir[i];
+            positions[pc] = positions[i];
+            pc += 1;
+        }
+    }
+
+    ir.truncate(pc);
+    positions.truncate(pc);
+}
+
+/// drop the loops reached while the tape is still all zero, their guard
+/// cell is zero so they never run; only pointer moves, output and clears
+/// may come before them.
+/// this is not part of `optimize` since it assumes a zeroed tape,
+/// while a `BfVM` run again without `reset` starts on the last run's tape.
+/// returns whether any loop went away
+pub fn remove_dead_loops<P: Copy>(ir: &mut Vec<BfIR>, positions: &mut Vec<P>) -> bool {
+    assert_eq!(ir.len(), positions.len());
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut zero = true;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        if zero {
+            match ir[i] {
+                Jz => match loop_body(ir, i) {
+                    Some(body) => {
+                        i += body.len() + 2;
+                        changed = true;
+                        continue;
+                    }
+                    // unbalanced, keep the rest as it is
+                    None => zero = false
+                },
+                AddPtr(_) | SubPtr(_) | PutByte | PutByteN { .. } | DumpTape | Nop | SetVal(0) | ClearRange { .. } => {}
+                _ => zero = false
+            }
+        }
+        ir[pc] = ir[i];
+        positions[pc] = positions[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    positions.truncate(pc);
+    changed
+}
+
+/// fold the cell values known from the start of the program, on a zeroed tape
+/// of `width` cells, into `ir`: value ops on a known cell become `SetVal`, and
+/// loops, scans and multiplies on a cell known to be zero go away.
+/// a cell read by `,` is unknown from then on, and tracking stops for good at
+/// the first loop or scan that may run, as what it leaves behind is unknown.
+/// `AddVal` and `SubVal` fold as `arith` has them, the other ops always wrap.
+/// returns whether the ir changed; like `remove_dead_loops` this is not part
+/// of `optimize`
+pub fn propagate_constants<P: Copy>(ir: &mut Vec<BfIR>, positions: &mut Vec<P>, width: CellWidth, arith: ArithMode) -> bool {
+    assert_eq!(ir.len(), positions.len());
+    // a fused add may become a `SetVal`, which needs the move on its own
+    let fused = ir.iter().any(|op| matches!(op, BfIR::AddPtrThenAddVal { .. }));
+    // splitting and fusing again may give back the same ir
+    let original = fused.then(|| ir.clone());
+    if fused {
+        split_ptr_vals(ir, positions);
+    }
+    let modulus = width.modulus() as u64;
+    let add = |v: u32, d: u64| ((v as u64).wrapping_add(d) % modulus) as u32;
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+
+    // cells by their offset from the first one, missing ones are still zero,
+    // `None` once unknown
+    let mut cells: BTreeMap<i64, Option<u32>> = BTreeMap::new();
+    let mut ptr: i64 = 0;
+    let mut tracking = true;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        let mut op = ir[i];
+        if tracking {
+            let cur = cells.get(&ptr).copied().unwrap_or(Some(0));
+            match op {
+                AddVal(x) | SubVal(x) => {
+                    let d = if let AddVal(_) = op { x as u64 } else { (x as u64).wrapping_neg() };
+                    let v = cur.map(|v| match (arith, op) {
+                        (ArithMode::Saturating, AddVal(_)) => (v as u64 + x as u64).min(modulus - 1) as u32,
+                        (ArithMode::Saturating, _) => v.saturating_sub(x),
+                        (ArithMode::Wrapping, _) => add(v, d)
+                    });
+                    cells.insert(ptr, v);
+                    if let Some(v) = v {
+                        op = SetVal(v);
+                    }
+                }
+                SetVal(x) => {
+                    cells.insert(ptr, Some(add(x, 0)));
+                }
+                AddPtr(x) => ptr += x as i64,
+                SubPtr(x) => ptr -= x as i64,
+                AddValAt { offset, val } => {
+                    let cell = cells.entry(ptr + offset as i64).or_insert(Some(0));
+                    *cell = cell.map(|v| add(v, val as u64));
+                }
+                AddPtrThenAddVal { ptr_delta, val } => {
+                    ptr += ptr_delta as i64;
+                    let cell = cells.entry(ptr).or_insert(Some(0));
+                    *cell = cell.map(|v| add(v, val as u64));
+                }
+                MulVal { .. } | ScanRight | ScanLeft if cur == Some(0) => {
+                    i += 1;
+                    changed = true;
+                    continue;
+                }
+                MulVal { offset, factor } => {
+                    let cell = cells.entry(ptr + offset as i64).or_insert(Some(0));
+                    *cell = match (*cell, cur) {
+                        (Some(v), Some(c)) => Some(add(v, (c as u64).wrapping_mul(factor as u64))),
+                        _ => None
+                    };
+                }
+                ClearRange { len } => {
+                    let end = ptr + len as i64;
+                    cells.retain(|&cell, _| cell < ptr || cell >= end);
+                    ptr = end - 1;
+                }
+                GetByte => {
+                    cells.insert(ptr, None);
+                }
+                PutByte | PutByteN { .. } | DumpTape | Nop => {}
+                Jz if cur == Some(0) => {
+                    i += loop_body(ir, i).map_or(len - i, |body| body.len() + 2);
+                    changed = true;
+                    continue;
+                }
+                Jz | Jnz | ScanRight | ScanLeft | ReadLine => tracking = false,
+            }
+        }
+        changed |= op != ir[i];
+        ir[pc] = op;
+        positions[pc] = positions[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    positions.truncate(pc);
+    match original {
+        Some(original) => {
+            fuse_ptr_vals(ir, positions, modulus as i64);
+            *ir != original
+        }
+        None => changed
+    }
+}
+
+/// undo `fuse_ptr_vals`, both halves keep the position of the fused node
+fn split_ptr_vals<P: Copy>(ir: &mut Vec<BfIR>, positions: &mut Vec<P>) {
+    let mut split = Vec::with_capacity(ir.len());
+    let mut split_positions = Vec::with_capacity(ir.len());
+    for (&op, &pos) in ir.iter().zip(positions.iter()) {
+        match op.split_move() {
+            (op, Some(val)) => {
+                split.extend([op, BfIR::AddVal(val)]);
+                split_positions.extend([pos, pos]);
+            }
+            (op, None) => {
+                split.push(op);
+                split_positions.push(pos);
+            }
+        }
+    }
+    *ir = split;
+    *positions = split_positions;
+}
+
+/// fold runs of value ops and pointer ops into single instructions,
+/// `cancel` lets opposite ops like `+-` fold into each other,
+/// values wrap at `modulus` or, with `saturate`, stop short of it,
+/// returns whether the ir changed
+fn fold_runs<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>, cancel: bool, modulus: i64, saturate: bool) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    // sum up a run of `$add`/`$sub` as a signed accumulator
+    macro_rules! _fold_ir {
+        ($add:ident, $sub:ident) => {{
+            let first = core::mem::discriminant(&ir[i]);
+            let mut acc: i64 = 0;
+            while i < len && (cancel || core::mem::discriminant(&ir[i]) == first) {
+                match ir[i] {
+                    $add(d) => acc += d as i64,
+                    $sub(d) => acc -= d as i64,
+                    _ => break
+                }
+                i += 1;
+            }
+            acc
+        }};
+    }
+
+    macro_rules! _normal_ir {
+        () => {{
+            ir[pc] = ir[i];
+            pos[pc] = pos[i];
+            i += 1;
+            pc += 1;
+        }};
+    }
+
+    use BfIR::*;
+    while i < len {
+        let start = pos[i];
+        let (first, run_start, out_start) = (ir[i], i, pc);
+        match ir[i] {
+            AddVal(_) | SubVal(_) => {
+                // cell arithmetic wraps, so only the remainder matters,
+                // while a saturating run is done once it got to the end
+                let acc = _fold_ir!(AddVal, SubVal);
+                let acc = if saturate { acc.clamp(1 - modulus, modulus - 1) } else { acc % modulus };
+                if acc != 0 {
+                    ir[pc] = if acc > 0 { AddVal(acc as u32) } else { SubVal(-acc as u32) };
+                    pos[pc] = start;
+                    pc += 1;
+                }
+            }
+            AddPtr(_) | SubPtr(_) => {
+                // every instruction of the run moves at most u32::MAX,
+                // so splitting never emits more than the run consumed
+                let mut acc = _fold_ir!(AddPtr, SubPtr);
+                while acc != 0 {
+                    let d = acc.unsigned_abs().min(u32::MAX as u64) as u32;
+                    ir[pc] = if acc > 0 { AddPtr(d) } else { SubPtr(d) };
+                    pos[pc] = start;
+                    acc -= acc.signum() * d as i64;
+                    pc += 1;
+                }
+            }
+            _ => _normal_ir!()
+        }
+        // a run folds to itself only if it is a single instruction already in range
+        changed |= i - run_start != pc - out_start || (pc - out_start == 1 && ir[out_start] != first);
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// replace `[-]` and `[+]` with `SetVal(0)`
+fn fold_clear_loops<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        if i + 2 < len {
+            if let (Jz, AddVal(1) | SubVal(1), Jnz) = (ir[i], ir[i + 1], ir[i + 2]) {
+                ir[pc] = SetVal(0);
+                pos[pc] = pos[i];
+                i += 3;
+                pc += 1;
+                changed = true;
+                continue;
+            }
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// fold value ops into a `SetVal` right before them and drop a `SetVal`
+/// overwritten by the next one, values wrap at `modulus`
+fn fold_set_vals<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>, modulus: i64) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        if pc > 0 {
+            let folded = match (ir[pc - 1], ir[i]) {
+                (SetVal(x), AddVal(y)) => Some((x as i64 + y as i64) % modulus),
+                (SetVal(x), SubVal(y)) => Some((x as i64 - y as i64).rem_euclid(modulus)),
+                (SetVal(_), SetVal(y)) => Some(y as i64),
+                _ => None
+            };
+            if let Some(x) = folded {
+                ir[pc - 1] = SetVal(x as u32);
+                i += 1;
+                changed = true;
+                continue;
+            }
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// replace `SetVal(0)` on two or more cells in a row, `[-]>[-]`,
+/// with a `ClearRange` leaving the pointer on the last of them
+fn fold_clear_ranges<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        if let SetVal(0) = ir[i] {
+            let mut end = i + 1;
+            while end + 1 < len && ir[end] == AddPtr(1) && ir[end + 1] == SetVal(0) {
+                end += 2;
+            }
+            if end > i + 1 {
+                ir[pc] = ClearRange { len: ((end - i) / 2 + 1) as u32 };
+                pos[pc] = pos[i];
+                i = end;
+                pc += 1;
+                changed = true;
+                continue;
+            }
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// replace runs of `PutByte`, which all write the same cell, with `PutByteN`
+fn fold_put_runs<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        let count = |op| match op {
+            PutByte => Some(1),
+            PutByteN { count } => Some(count),
+            _ => None
+        };
+        if let Some(mut total) = count(ir[i]) {
+            let start = i;
+            i += 1;
+            while let Some(n) = ir.get(i).copied().and_then(count) {
+                let Some(sum) = total.checked_add(n) else { break };
+                total = sum;
+                i += 1;
+            }
+            ir[pc] = if i - start == 1 { ir[start] } else { PutByteN { count: total } };
+            pos[pc] = pos[start];
+            pc += 1;
+            changed |= i - start > 1;
+            continue;
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// replace `[>]` and `[<]` with `ScanRight` and `ScanLeft`
+fn fold_scan_loops<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    use BfIR::*;
+    while i < len {
+        if i + 2 < len {
+            let scan = match (ir[i], ir[i + 1], ir[i + 2]) {
+                (Jz, AddPtr(1), Jnz) => Some(ScanRight),
+                (Jz, SubPtr(1), Jnz) => Some(ScanLeft),
+                _ => None
+            };
+            if let Some(scan) = scan {
+                ir[pc] = scan;
+                pos[pc] = pos[i];
+                i += 3;
+                pc += 1;
+                changed = true;
+                continue;
+            }
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// replace multiply loops like `[->+<]` and `[->++>+++<<]`
+/// with `MulVal` nodes followed by `SetVal(0)`
+fn fold_mul_loops<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    while i < len {
+        if let Some((muls, loop_len)) = match_mul_loop(&ir[i..]) {
+            let start = pos[i];
+            for (offset, factor) in muls {
+                ir[pc] = BfIR::MulVal { offset, factor };
+                pos[pc] = start;
+                pc += 1;
+            }
+            ir[pc] = BfIR::SetVal(0);
+            pos[pc] = start;
+            i += loop_len;
+            pc += 1;
+            changed = true;
+            continue;
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// peel the first iteration off innermost loops with a `SetVal` on a cell
+/// nothing else in the loop reads or writes, `[>SetVal(c)<-]` becomes
+/// `[>SetVal(c)<-[><-]]`, so the store is done once instead of every time
+/// around; the loops must leave the pointer where they found it, returns
+/// whether any loop was peeled
+fn hoist_set_vals<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>) -> bool {
+    use BfIR::*;
+    let mut out = Vec::with_capacity(ir.len());
+    let mut out_pos = Vec::with_capacity(pos.len());
+    // start of the output copied since the last `Jz`, `None` after a `Jnz`
+    let mut open = None;
+    let mut changed = false;
+    for (i, &op) in ir.iter().enumerate() {
+        out.push(op);
+        out_pos.push(pos[i]);
+        match op {
+            Jz => open = Some(i),
+            Jnz => {
+                let hoisted = open.take().and_then(|jz| Some((jz, invariant_set_vals(&ir[jz + 1..i])?)));
+                if let Some((jz, hoisted)) = hoisted {
+                    // finish the first iteration, then loop over the rest
+                    out.pop();
+                    out_pos.pop();
+                    out.push(Jz);
+                    out_pos.push(pos[jz]);
+                    for j in jz + 1..i {
+                        if !hoisted.contains(&(j - jz - 1)) {
+                            out.push(ir[j]);
+                            out_pos.push(pos[j]);
+                        }
+                    }
+                    out.extend([Jnz, Jnz]);
+                    out_pos.extend([pos[i], pos[i]]);
+                    changed = true;
+                }
+            }
+            _ => {}
+        }
+    }
+    *ir = out;
+    *pos = out_pos;
+    changed
+}
+
+/// indices of the `SetVal`s in a loop `body` on cells the rest of it never
+/// touches, `None` if there are none or the body is not simple enough to tell
+fn invariant_set_vals(body: &[BfIR]) -> Option<Vec<usize>> {
+    use BfIR::*;
+    // how often each cell, relative to the guard, is read and written
+    let mut reads = BTreeMap::from([(0, 1)]);
+    let mut writes: BTreeMap<i64, usize> = BTreeMap::new();
+    let mut sets = vec![];
+    let mut ptr: i64 = 0;
+    let touch = |cells: &mut BTreeMap<i64, usize>, cell| *cells.entry(cell).or_insert(0) += 1;
+    for (j, &op) in body.iter().enumerate() {
+        match op {
+            AddPtr(x) => ptr += x as i64,
+            SubPtr(x) => ptr -= x as i64,
+            AddVal(_) | SubVal(_) => {
+                touch(&mut reads, ptr);
+                touch(&mut writes, ptr);
+            }
+            SetVal(_) => {
+                sets.push((j, ptr));
+                touch(&mut writes, ptr);
+            }
+            MulVal { offset, .. } => {
+                touch(&mut reads, ptr);
+                touch(&mut reads, ptr + offset as i64);
+                touch(&mut writes, ptr + offset as i64);
+            }
+            AddValAt { offset, .. } => {
+                touch(&mut reads, ptr + offset as i64);
+                touch(&mut writes, ptr + offset as i64);
+            }
+            AddPtrThenAddVal { ptr_delta, .. } => {
+                ptr += ptr_delta as i64;
+                touch(&mut reads, ptr);
+                touch(&mut writes, ptr);
+            }
+            ClearRange { len } => {
+                for _ in 1..len {
+                    touch(&mut writes, ptr);
+                    ptr += 1;
+                }
+                touch(&mut writes, ptr);
+            }
+            GetByte => touch(&mut writes, ptr),
+            PutByte | PutByteN { .. } => touch(&mut reads, ptr),
+            Nop => {}
+            Jz | Jnz | ScanRight | ScanLeft | DumpTape | ReadLine => return None
+        }
+    }
+    let hoisted: Vec<usize> = sets
+        .into_iter()
+        .filter(|&(_, cell)| writes[&cell] == 1 && !reads.contains_key(&cell))
+        .map(|(j, _)| j)
+        .collect();
+    (ptr == 0 && !hoisted.is_empty()).then_some(hoisted)
+}
+
+/// match a multiply loop at the start of `ir`: a body of only moves and
+/// adds, in any order, that returns to the origin cell and takes exactly one
+/// from it, like `[->>>+<<<]` or `[>++<-]`; returns the `(offset, factor)`
+/// pairs by offset and the length of the loop
+fn match_mul_loop(ir: &[BfIR]) -> Option<(Vec<(i32, u32)>, usize)> {
+    use BfIR::*;
+    if ir.first() != Some(&Jz) {
+        return None;
+    }
+
+    let end = 1 + ir[1..].iter().position(|op| matches!(op, Jz | Jnz))?;
+    if ir[end] != Jnz || loop_pointer_delta(&ir[1..end]) != Some(0) {
+        return None;
+    }
+
+    let mut ptr: i64 = 0;
+    // what the body adds to every cell it touches, relative to the origin,
+    // wrapping at 32 bits which is right for every narrower cell too
+    let mut deltas: BTreeMap<i64, u32> = BTreeMap::new();
+    let mut add = |cell, x: u32| {
+        let delta = deltas.entry(cell).or_insert(0);
+        *delta = delta.wrapping_add(x);
+    };
+    for &op in &ir[1..end] {
+        match op {
+            AddPtr(x) => ptr += x as i64,
+            SubPtr(x) => ptr -= x as i64,
+            AddVal(x) => add(ptr, x),
+            SubVal(x) => add(ptr, x.wrapping_neg()),
+            AddValAt { offset, val } => add(ptr + offset as i64, val),
+            // the other nodes are not linear
+            _ => return None
+        }
+    }
+    if deltas.remove(&0) != Some(u32::MAX) {
+        return None;
+    }
+    let muls = deltas
+        .into_iter()
+        .filter(|&(_, delta)| delta != 0)
+        .map(|(offset, delta)| Some((i32::try_from(offset).ok()?, delta)))
+        .collect::<Option<_>>()?;
+    Some((muls, end + 1))
+}
+
+/// replace a pointer move followed by a value op, like `>+` or `<<---`,
+/// with `AddPtrThenAddVal`, values wrapping at `modulus`
+fn fuse_ptr_vals<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>, modulus: i64) -> bool {
+    use BfIR::*;
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    while i < len {
+        let ptr_delta = match ir[i] {
+            AddPtr(x) => i32::try_from(x).ok(),
+            SubPtr(x) => i32::try_from(x).ok().map(|x| -x),
+            _ => None
+        };
+        let val = match ir.get(i + 1) {
+            Some(&AddVal(x)) => Some(x as i64),
+            Some(&SubVal(x)) => Some(-(x as i64)),
+            _ => None
+        };
+        if let (Some(ptr_delta), Some(val)) = (ptr_delta, val) {
+            ir[pc] = AddPtrThenAddVal { ptr_delta, val: val.rem_euclid(modulus) as u32 };
+            pos[pc] = pos[i];
+            i += 2;
+            pc += 1;
+            changed = true;
+            continue;
+        }
+        ir[pc] = ir[i];
+        pos[pc] = pos[i];
+        i += 1;
+        pc += 1;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// rewrite windows where the pointer bounces around, like `>+++>++<<`,
+/// into `AddValAt` nodes followed by a single pointer move
+fn fold_offsets<P: Copy>(ir: &mut Vec<BfIR>, pos: &mut Vec<P>, modulus: i64) -> bool {
+    use BfIR::*;
+    let len = ir.len();
+    let mut i = 0;
+    let mut pc = 0;
+    let mut changed = false;
+
+    while i < len {
+        let end = i + ir[i..]
+            .iter()
+            .take_while(|op| matches!(op, AddVal(_) | SubVal(_) | AddPtr(_) | SubPtr(_)))
+            .count();
+
+        if end == i {
+            ir[pc] = ir[i];
+            pos[pc] = pos[i];
+            i += 1;
+            pc += 1;
+            continue;
+        }
+
+        // the folded window is never longer than the original one
+        let folded = fold_window(&ir[i..end], modulus);
+        match folded {
+            Some(ops) => {
+                ir[pc..pc + ops.len()].copy_from_slice(&ops);
+                let start = pos[i];
+                pos[pc..pc + ops.len()].fill(start);
+                pc += ops.len();
+                // it has fewer moves than the window, so is never the same
+                changed = true;
+            }
+            None => {
+                ir.copy_within(i..end, pc);
+                pos.copy_within(i..end, pc);
+                pc += end - i;
+            }
+        }
+        i = end;
+    }
+
+    ir.truncate(pc);
+    pos.truncate(pc);
+    changed
+}
+
+/// fold a window of pure pointer/value ops with values wrapping at `modulus`,
+/// returns `None` if it isn't worth it or offsets don't fit
+fn fold_window(window: &[BfIR], modulus: i64) -> Option<Vec<BfIR>> {
+    use BfIR::*;
+    let moves = window.iter().filter(|op| matches!(op, AddPtr(_) | SubPtr(_))).count();
+    if moves < 2 {
+        return None;
+    }
+
+    let mut offset: i64 = 0;
+    // (offset, delta)
+    let mut deltas: Vec<(i64, i64)> = vec![];
+    for &op in window {
+        let d = match op {
+            AddPtr(x) => { offset += x as i64; continue }
+            SubPtr(x) => { offset -= x as i64; continue }
+            AddVal(x) => x as i64,
+            SubVal(x) => -(x as i64),
+            _ => unreachable!()
+        };
+        match deltas.iter_mut().find(|(o, _)| *o == offset) {
+            Some((_, acc)) => *acc += d,
+            None => deltas.push((offset, d))
+        }
+    }
+    deltas.sort_by_key(|&(o, _)| o);
+
+    let mut ops = vec![];
+    for (o, d) in deltas {
+        let val = d.rem_euclid(modulus) as u32;
+        if val != 0 {
+            ops.push(AddValAt { offset: i32::try_from(o).ok()?, val });
+        }
+    }
+    if offset > 0 {
+        ops.push(AddPtr(u32::try_from(offset).ok()?));
+    }
+    else if offset < 0 {
+        ops.push(SubPtr(u32::try_from(-offset).ok()?));
+    }
+    Some(ops)
+}
+
+/// the kind and offset of an instruction making windows with its neighbours
+/// of the same kind, the `AddValAt`s or `MulVal`s between two moves
+fn window_offset(op: BfIR) -> Option<(usize, i32)> {
+    match op {
+        BfIR::AddValAt { offset, .. } | BfIR::MulVal { offset, .. } => Some((op.kind(), offset)),
+        _ => None
+    }
+}
+
+/// if `ir[i]` starts a window of `AddValAt` or of `MulVal` nodes,
+/// returns the lowest and highest offset the window touches
+pub(crate) fn window_extent(ir: &[BfIR], i: usize) -> Option<(i32, i32)> {
+    let (kind, _) = window_offset(ir[i])?;
+    if i > 0 && window_offset(ir[i - 1]).is_some_and(|(k, _)| k == kind) {
+        return None;
+    }
+    ir[i..]
+        .iter()
+        .map_while(|&op| window_offset(op).filter(|&(k, _)| k == kind).map(|(_, offset)| offset))
+        .fold(None, |ext, o| match ext {
+            None => Some((o, o)),
+            Some((lo, hi)) => Some((o.min(lo), o.max(hi)))
+        })
+}
+
+/// for every `Jz`, whether every iteration of its loop leaves the pointer
+/// where it was, false for everything else
+fn balanced_loops(ir: &[BfIR]) -> Vec<bool> {
+    use BfIR::*;
+    let mut balanced = vec![false; ir.len()];
+    // (index of the `Jz`, pointer moved so far, `None` if unknown)
+    let mut stk: Vec<(usize, Option<i64>)> = vec![];
+    for (i, &op) in ir.iter().enumerate() {
+        let moved = match op {
+            AddPtr(x) => Some(x as i64),
+            SubPtr(x) => Some(-(x as i64)),
+            ClearRange { len } => Some(len as i64 - 1),
+            AddPtrThenAddVal { ptr_delta, .. } => Some(ptr_delta as i64),
+            ScanRight | ScanLeft => None,
+            Jz => {
+                stk.push((i, Some(0)));
+                continue;
+            }
+            Jnz => match stk.pop() {
+                Some((jz, delta)) => {
+                    balanced[jz] = delta == Some(0);
+                    if delta == Some(0) { Some(0) } else { None }
+                }
+                None => break
+            },
+            _ => Some(0)
+        };
+        if let Some((_, delta)) = stk.last_mut() {
+            *delta = delta.zip(moved).map(|(d, m)| d + m);
+        }
+    }
+    balanced
+}
+
+/// the highest cell `ir` can touch with the pointer starting at cell 0, `None`
+/// if a scan, a loop that moves the pointer on balance or a `;`, which reads
+/// as far as the tape goes, leaves it unbounded.
+/// a balanced loop touches the same cells on every iteration, so one pass
+/// over its body covers them all
+pub fn max_reach(ir: &[BfIR]) -> Option<usize> {
+    use BfIR::*;
+    let balanced = balanced_loops(ir);
+    let mut ptr: i64 = 0;
+    let mut reach: i64 = 0;
+    for (i, &op) in ir.iter().enumerate() {
+        let touched = match op {
+            AddPtr(x) => {
+                ptr += x as i64;
+                ptr
+            }
+            SubPtr(x) => {
+                ptr -= x as i64;
+                ptr
+            }
+            AddPtrThenAddVal { ptr_delta, .. } => {
+                ptr += ptr_delta as i64;
+                ptr
+            }
+            ClearRange { len } => {
+                ptr += len as i64 - 1;
+                ptr
+            }
+            MulVal { offset, .. } | AddValAt { offset, .. } => ptr + offset as i64,
+            ScanRight | ScanLeft | ReadLine => return None,
+            Jz if !balanced[i] => return None,
+            AddVal(_) | SubVal(_) | SetVal(_) | GetByte | PutByte | PutByteN { .. } | DumpTape | Nop | Jz | Jnz => ptr
+        };
+        reach = reach.max(touched);
+    }
+    Some(reach as usize)
+}
+
+/// for every instruction, whether its bounds check provably passes on a tape
+/// of at least `cells` cells with the pointer starting at cell `start`.
+/// the pointer is known up to the first scan or loop that moves it on balance,
+/// loops with the pointer back where it was after every iteration keep it known.
+/// for an `AddValAt` or `MulVal` window only its first node counts
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+pub(crate) fn in_bounds(ir: &[BfIR], start: usize, cells: usize) -> Vec<bool> {
+    use BfIR::*;
+    let balanced = balanced_loops(ir);
+    let cells = cells as i64;
+    let on_tape = |cell: i64| (0..cells).contains(&cell);
+    let mut safe = vec![false; ir.len()];
+    let mut ptr = start as i64;
+    for (i, &op) in ir.iter().enumerate() {
+        match op {
+            AddPtr(x) => {
+                ptr += x as i64;
+                safe[i] = ptr < cells;
+            }
+            SubPtr(x) => {
+                ptr -= x as i64;
+                safe[i] = ptr >= 0;
+            }
+            AddPtrThenAddVal { ptr_delta, .. } => {
+                ptr += ptr_delta as i64;
+                safe[i] = on_tape(ptr);
+            }
+            MulVal { .. } | AddValAt { .. } => {
+                safe[i] = window_extent(ir, i).is_none_or(|(lo, hi)| on_tape(ptr + lo as i64) && on_tape(ptr + hi as i64));
+            }
+            ClearRange { len } => {
+                ptr += len as i64 - 1;
+                safe[i] = ptr < cells;
+            }
+            ScanRight | ScanLeft => break,
+            Jz if !balanced[i] => break,
+            // `ReadLine` stops short of the end of the tape by itself
+            AddVal(_) | SubVal(_) | SetVal(_) | GetByte | PutByte | PutByteN { .. } | DumpTape | ReadLine | Nop | Jz | Jnz => safe[i] = true,
+        }
+    }
+    safe
+}
+
+/// index of the first `Jnz` without a `Jz` before it or, failing that, of
+/// the last `Jz` left open, `None` if the brackets of `ir` are balanced
+pub fn unbalanced_bracket(ir: &[BfIR]) -> Option<usize> {
+    let mut stk = vec![];
+    for (i, &op) in ir.iter().enumerate() {
+        match op {
+            BfIR::Jz => stk.push(i),
+            BfIR::Jnz if stk.pop().is_none() => return Some(i),
+            _ => {}
+        }
+    }
+    stk.pop()
+}
+
+/// check that `ir` is something the optimizer could have produced: balanced
+/// brackets, offsets that can reach a cell of some tape and operands that
+/// make sense; reports unbalanced brackets like `unbalanced_bracket` and
+/// otherwise the first bad instruction
+pub fn verify(ir: &[BfIR]) -> Result<(), VerifyError> {
+    use BfIR::*;
+    let fail = |index, kind| Err(VerifyError { index, kind });
+    let far = |offset: i32| offset.unsigned_abs() as usize >= crate::interp::MAX_MEM_SIZE;
+    let mut stk = vec![];
+    for (i, &op) in ir.iter().enumerate() {
+        match op {
+            Jz => stk.push(i),
+            Jnz if stk.pop().is_none() => return fail(i, VerifyErrorKind::UnmatchedJnz),
+            MulVal { offset: 0, .. } => return fail(i, VerifyErrorKind::SelfMultiply),
+            MulVal { offset, .. } | AddValAt { offset, .. } | AddPtrThenAddVal { ptr_delta: offset, .. } if far(offset) => {
+                return fail(i, VerifyErrorKind::OffsetOutOfRange);
+            }
+            ClearRange { len: 0 } | PutByteN { count: 0 } => return fail(i, VerifyErrorKind::ZeroLength),
+            _ => {}
+        }
+    }
+    match stk.pop() {
+        Some(i) => fail(i, VerifyErrorKind::UnclosedJz),
+        None => Ok(())
+    }
+}
+
+/// something `analyze` found in a program
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum Warning {
+    /// the loop starting at this index never ends once entered
+    #[error("Infinite loop at instruction {0}")]
+    InfiniteLoop(usize),
+    /// a command on its own inside prose, likely meant as punctuation
+    #[error("Suspicious '{ch}' in a comment at line {}, col {}", pos.0, pos.1)]
+    SuspiciousCommandInComment { pos: SourcePos, ch: char },
+}
+
+/// look for loops that provably never end, assuming the program starts on a
+/// zeroed tape; this is conservative, a loop not warned about may still hang
+pub fn analyze(ir: &[BfIR]) -> Vec<Warning> {
+    use BfIR::*;
+    let mut warnings = vec![];
+
+    // lowest byte of every cell written so far while the pointer is known,
+    // until the first loop or input
+    let mut tape: Option<(BTreeMap<i64, u8>, i64)> = Some((BTreeMap::new(), 0));
+    // lowest byte of the current cell, which is all a loop like `[++]` needs
+    let mut cur: Option<u8> = Some(0);
+
+    for (i, &op) in ir.iter().enumerate() {
+        match op {
+            AddVal(x) | SubVal(x) => {
+                let x = if let AddVal(_) = op { x as u8 } else { (x as u8).wrapping_neg() };
+                cur = cur.map(|v| v.wrapping_add(x));
+            }
+            SetVal(x) => cur = Some(x as u8),
+            AddPtr(x) | SubPtr(x) => {
+                cur = None;
+                if let Some((cells, ptr)) = &mut tape {
+                    *ptr += if let AddPtr(_) = op { x as i64 } else { -(x as i64) };
+                    cur = Some(cells.get(ptr).copied().unwrap_or(0));
+                }
+            }
+            AddValAt { offset, val } => {
+                if let Some((cells, ptr)) = &mut tape {
+                    let cell = cells.entry(*ptr + offset as i64).or_insert(0);
+                    *cell = cell.wrapping_add(val as u8);
+                }
+            }
+            AddPtrThenAddVal { ptr_delta, val } => {
+                cur = None;
+                if let Some((cells, ptr)) = &mut tape {
+                    *ptr += ptr_delta as i64;
+                    cur = Some(cells.get(ptr).copied().unwrap_or(0).wrapping_add(val as u8));
+                }
+            }
+            PutByte | PutByteN { .. } | DumpTape | Nop => {}
+            // the target cell depends on the current one, which stays as is
+            MulVal { .. } => tape = None,
+            GetByte | ReadLine => {
+                cur = None;
+                tape = None;
+            }
+            Jz => {
+                if let Some(body) = loop_body(ir, i) {
+                    if loops_forever(body, cur) {
+                        warnings.push(Warning::InfiniteLoop(i));
+                    }
+                }
+                cur = None;
+                tape = None;
+            }
+            // a loop or scan only ends on a zero cell, a clear ends on one
+            Jnz | ScanRight | ScanLeft | ClearRange { .. } => {
+                cur = Some(0);
+                tape = None;
+            }
+        }
+        // keep the current cell in the tape up to date
+        if let (Some((cells, ptr)), Some(v)) = (&mut tape, cur) {
+            cells.insert(*ptr, v);
+        }
+    }
+    warnings
+}
+
+/// the instructions between the `Jz` at `i` and its `Jnz`
+fn loop_body(ir: &[BfIR], i: usize) -> Option<&[BfIR]> {
+    let mut depth = 0;
+    for (j, op) in ir.iter().enumerate().skip(i) {
+        match op {
+            BfIR::Jz => depth += 1,
+            BfIR::Jnz => depth -= 1,
+            _ => {}
+        }
+        if depth == 0 {
+            return Some(&ir[i + 1..j]);
+        }
+    }
+    None
+}
+
+/// how far one run through a loop `body` moves the pointer, `None` unless the
+/// body is straight code without io, nested loops or scans
+pub fn loop_pointer_delta(body: &[BfIR]) -> Option<i64> {
+    use BfIR::*;
+    let mut delta: i64 = 0;
+    for &op in body {
+        match op {
+            AddPtr(x) => delta += x as i64,
+            SubPtr(x) => delta -= x as i64,
+            // a range clear ends on its last cell
+            ClearRange { len } => delta += (len as i64 - 1).max(0),
+            AddPtrThenAddVal { ptr_delta, .. } => delta += ptr_delta as i64,
+            AddVal(_) | SubVal(_) | SetVal(_) | MulVal { .. } | AddValAt { .. } | Nop => {}
+            GetByte | PutByte | PutByteN { .. } | DumpTape | ReadLine | Jz | Jnz | ScanRight | ScanLeft => return None,
+        }
+    }
+    Some(delta)
+}
+
+/// whether a loop with `body` never ends once entered on a cell whose lowest
+/// byte is `guard`, if known
+fn loops_forever(body: &[BfIR], guard: Option<u8>) -> bool {
+    use BfIR::*;
+    // what an iteration adds to the guard, the pointer must stay put
+    let mut step: u32 = 0;
+    for op in body {
+        match *op {
+            AddVal(x) => step = step.wrapping_add(x),
+            SubVal(x) => step = step.wrapping_sub(x),
+            PutByte | PutByteN { .. } | DumpTape | Nop => {}
+            AddValAt { offset, .. } | MulVal { offset, .. } if offset != 0 => {}
+            _ => return false
+        }
+    }
+    if step == 0 {
+        return true;
+    }
+    // adding `step` over and over only reaches zero from a guard that is a
+    // multiple of the largest power of two dividing `step`, taken at 8 bits
+    // since wider cells only make that power larger
+    let power = 1_u32 << step.trailing_zeros().min(8);
+    guard.is_some_and(|guard| !(guard as u32).is_multiple_of(power))
+}
+
+/// how many letters a stretch of comment needs before `lint_comments`
+/// takes it for prose
+const MIN_PROSE_LETTERS: usize = 12;
+
+/// look for commands `compile` would run that likely belong to a comment: a
+/// command with no other command next to it, following a word in a line
+/// stretch of at least `MIN_PROSE_LETTERS` letters without adjacent commands;
+/// this is opt-in, `compile` never warns
+pub fn lint_comments(src: &str) -> Vec<Warning> {
+    let is_command = |c: char| "+-<>,.[]".contains(c);
+    let mut warnings = vec![];
+    for (line, text) in src.split('\n').enumerate() {
+        let chars: Vec<char> = text.chars().collect();
+        // a command next to another one is code, which bounds a stretch
+        let code = |j: usize| is_command(chars[j])
+            && (j > 0 && is_command(chars[j - 1]) || chars.get(j + 1).is_some_and(|&c| is_command(c)));
+        let letters = |range: Range<usize>| chars[range].iter().filter(|c| c.is_alphabetic()).count();
+        for (i, &ch) in chars.iter().enumerate() {
+            if !is_command(ch) || code(i) {
+                continue;
+            }
+            let start = (0..i).rev().find(|&j| code(j)).map_or(0, |j| j + 1);
+            let end = (i + 1..chars.len()).find(|&j| code(j)).unwrap_or(chars.len());
+            if letters(start..i) > 0 && letters(start..end) >= MIN_PROSE_LETTERS {
+                let pos = (line as u32 + 1, i as u32 + 1);
+                warnings.push(Warning::SuspiciousCommandInComment { pos, ch });
+            }
+        }
+    }
+    warnings
+}
+
+/// how deep `compile` lets loops nest
+pub const DEFAULT_MAX_LOOP_DEPTH: usize = 1 << 16;
+
+/// how `compile_with_dialect` reads a source beyond the eight commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dialect {
+    /// fail with `CompileErrorKind::NestingTooDeep` on loops nested deeper,
+    /// `DEFAULT_MAX_LOOP_DEPTH` by default
+    pub max_loop_depth: usize,
+    /// compile `#` into `BfIR::DumpTape` instead of skipping it, off by default
+    pub dump_tape: bool,
+    /// compile `;` into `BfIR::ReadLine` instead of skipping it, off by default
+    pub read_line: bool,
+    /// read `#name{` up to the matching `}` as a region named `name`, see
+    /// `compile_with_regions`, instead of as comments, off by default
+    pub regions: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self { max_loop_depth: DEFAULT_MAX_LOOP_DEPTH, dump_tape: false, read_line: false, regions: false }
+    }
+}
+
+/// a named part of the source, from `#name{` to its `}`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub name: String,
+    /// where the `#` is
+    pub start: SourcePos,
+    /// where the `}` is, or just past the source if it is missing
+    pub end: SourcePos,
+}
+
+impl Region {
+    /// whether an instruction at `pos` is inside
+    pub fn contains(&self, pos: SourcePos) -> bool {
+        self.start < pos && pos < self.end
+    }
+}
+
+/// the instructions, the source position of every one and the regions,
+/// see `compile_with_regions`
+pub type CompiledSource = (Vec<BfIR>, Vec<SourcePos>, Vec<Region>);
+
+pub fn compile(src: &str) -> Result<Vec<BfIR>, CompileError> {
+    compile_with_positions(src).map(|(ir, _)| ir)
+}
+
+/// like `compile`, also returning the source position of every instruction
+pub fn compile_with_positions(src: &str) -> Result<(Vec<BfIR>, Vec<SourcePos>), CompileError> {
+    compile_with_dialect(src, Dialect::default())
+}
+
+/// like `compile`, failing with `CompileErrorKind::NestingTooDeep` once
+/// loops nest more than `max_loop_depth` deep instead of the default
+pub fn compile_with_max_loop_depth(src: &str, max_loop_depth: usize) -> Result<Vec<BfIR>, CompileError> {
+    compile_with_dialect(src, Dialect { max_loop_depth, ..Dialect::default() }).map(|(ir, _)| ir)
+}
+
+/// like `compile_with_positions`, reading `src` as `dialect`
+pub fn compile_with_dialect(src: &str, dialect: Dialect) -> Result<(Vec<BfIR>, Vec<SourcePos>), CompileError> {
+    compile_with_regions(src, dialect).map(|(ir, positions, _)| (ir, positions))
+}
+
+/// like `compile_with_dialect`, also returning the regions of the source in
+/// the order they start, none unless `dialect.regions` is set; a name is
+/// ascii letters, digits and `_`, regions nest and a stray `}` is skipped
+pub fn compile_with_regions(src: &str, dialect: Dialect) -> Result<CompiledSource, CompileError> {
+    compile_bytes(src.bytes().map(Ok), dialect)
+}
+
+/// like `compile`, streaming the source from `reader` instead of
+/// holding it whole; non-command bytes are skipped, valid utf-8 or not
+#[cfg(feature = "std")]
+pub fn compile_reader(reader: impl Read) -> Result<Vec<BfIR>, CompileError> {
+    compile_reader_with_positions(reader, Dialect::default()).map(|(ir, _)| ir)
+}
+
+/// like `compile_reader`, also returning the source position of every instruction
+#[cfg(feature = "std")]
+pub(crate) fn compile_reader_with_positions(
+    reader: impl Read,
+    dialect: Dialect
+) -> Result<(Vec<BfIR>, Vec<SourcePos>), CompileError> {
+    compile_reader_with_regions(reader, dialect).map(|(ir, positions, _)| (ir, positions))
+}
+
+/// like `compile_reader_with_positions`, also returning the regions like
+/// `compile_with_regions`
+#[cfg(feature = "std")]
+pub(crate) fn compile_reader_with_regions(
+    reader: impl Read,
+    dialect: Dialect
+) -> Result<CompiledSource, CompileError> {
+    compile_bytes(BufReader::new(reader).bytes().map(read_error), dialect)
+}
+
+/// like `compile_reader_with_regions`, but stop at the first `!` and leave
+/// `reader` right after it, also returning whether there was one
+#[cfg(feature = "std")]
+pub(crate) fn compile_until_bang(
+    reader: &mut impl BufRead,
+    dialect: Dialect
+) -> Result<(CompiledSource, bool), CompileError> {
+    let mut bang = false;
+    let bytes = reader.bytes().take_while(|byte| {
+        bang = matches!(byte, Ok(b'!'));
+        !bang
+    });
+    compile_bytes(bytes.map(read_error), dialect).map(|compiled| (compiled, bang))
+}
+
+#[cfg(feature = "std")]
+fn read_error(byte: io::Result<u8>) -> Result<u8, CompileErrorKind> {
+    byte.map_err(|e| CompileErrorKind::Io(e.kind()))
+}
+
+fn compile_bytes(
+    bytes: impl Iterator<Item = Result<u8, CompileErrorKind>>,
+    dialect: Dialect
+) -> Result<CompiledSource, CompileError> {
+    let mut ir: Vec<BfIR> = vec![];
+    let mut positions: Vec<SourcePos> = vec![];
+
+    // (bra pos, line, col, offset)
+    let mut stk: Vec<(u32, u32, u32, usize)> = vec![];
+
+    let mut regions: Vec<Region> = vec![];
+    // (name, pos of `#`) of the regions not closed yet
+    let mut open: Vec<(String, SourcePos)> = vec![];
+    // the `#` and the name read so far, while it may still start a region
+    let mut tag: Option<(SourcePos, String)> = None;
+
+    let mut line: u32 = 1;
+    let mut col: u32 = 0;
+    // offset just past the last byte
+    let mut end = 0;
+
+    for (offset, byte) in bytes.enumerate() {
+        let byte = byte.map_err(|kind| CompileError { line, col, offset, kind, suggestion: None })?;
+        end = offset + 1;
+        // columns count chars, the continuation bytes of one don't start another
+        if byte & 0xc0 != 0x80 {
+            col += 1;
+        }
+        if dialect.regions {
+            if let Some((pos, mut name)) = tag.take() {
+                if byte.is_ascii_alphanumeric() || byte == b'_' {
+                    name.push(byte as char);
+                    tag = Some((pos, name));
+                    continue;
+                }
+                if byte == b'{' && !name.is_empty() {
+                    open.push((name, pos));
+                    continue;
+                }
+                // no region, the `#` stands alone
+                if dialect.dump_tape {
+                    ir.push(BfIR::DumpTape);
+                    positions.push(pos);
+                }
+            }
+            match byte {
+                b'#' => {
+                    tag = Some(((line, col), String::new()));
+                    continue;
+                }
+                b'}' => {
+                    if let Some((name, start)) = open.pop() {
+                        regions.push(Region { name, start, end: (line, col) });
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        match byte {
+            b'\n' => {
+                line += 1;
+                col = 0;
+            },
+            b'+' => ir.push(BfIR::AddVal(1)),
+            b'-' => ir.push(BfIR::SubVal(1)),
+            b'>' => ir.push(BfIR::AddPtr(1)),
+            b'<' => ir.push(BfIR::SubPtr(1)),
+            b',' => ir.push(BfIR::GetByte),
+            b'.' => ir.push(BfIR::PutByte),
+            b'#' if dialect.dump_tape => ir.push(BfIR::DumpTape),
+            b';' if dialect.read_line => ir.push(BfIR::ReadLine),
+            b'[' => {
+                if stk.len() == dialect.max_loop_depth {
+                    return Err(CompileError {
+                        line,
+                        col,
+                        offset,
+                        kind: CompileErrorKind::NestingTooDeep { depth: dialect.max_loop_depth },
+                        suggestion: None
+                    });
+                }
+                let pos = ir.len() as u32;
+                stk.push((pos, line, col, offset));
+                ir.push(BfIR::Jz);
+            }
+            b']' => {
+                stk.pop().ok_or(CompileError {
+                    line,
+                    col,
+                    offset,
+                    kind: CompileErrorKind::UnexpectedRightBracket,
+                    suggestion: Some(Suggestion::Remove { ch: ']', line, col, offset })
+                })?;
+
+                ir.push(BfIR::Jnz);
+            }
+            _ => {}
+        }
+        if positions.len() < ir.len() {
+            positions.push((line, col));
+        }
+    }
+
+    if let Some((_, bra_line, bra_col, bra_offset)) = stk.pop() {
+        return Err(CompileError {
+            line: bra_line,
+            col: bra_col,
+            offset: bra_offset,
+            kind: CompileErrorKind::UnclosedLeftBracket,
+            suggestion: Some(Suggestion::Insert { ch: ']', line, col: col + 1, offset: end })
+        });
+    }
+
+    if let Some((pos, _)) = tag {
+        if dialect.dump_tape {
+            ir.push(BfIR::DumpTape);
+            positions.push(pos);
+        }
+    }
+    regions.extend(open.into_iter().map(|(name, start)| Region { name, start, end: (line, col + 1) }));
+    regions.sort_by_key(|region| region.start);
+
+    Ok((ir, positions, regions))
+}
+
+/// like `compile`, but keeps scanning after an error and reports all of them,
+/// stray `]` as they appear followed by every `[` left open
+pub fn compile_all(src: &str) -> Result<Vec<BfIR>, Vec<CompileError>> {
+    compile(src).map_err(|e| match e.kind {
+        // not a mismatch, there is no telling what else is wrong
+        CompileErrorKind::NestingTooDeep { .. } => vec![e],
+        _ => bracket_errors(src)
+    })
+}
+
+/// every bracket mismatch in `src`, in the order `compile_all` reports them
+fn bracket_errors(src: &str) -> Vec<CompileError> {
+    let mut errors = vec![];
+    // (line, col, offset)
+    let mut stk: Vec<(u32, u32, usize)> = vec![];
+
+    let mut line: u32 = 1;
+    let mut col: u32 = 0;
+
+    for (offset, ch) in src.char_indices() {
+        col += 1;
+        match ch {
+            '\n' => {
+                line += 1;
+                col = 0;
+            },
+            '[' => stk.push((line, col, offset)),
+            ']' => match stk.pop() {
+                Some(_) => {}
+                None => errors.push(CompileError {
+                    line,
+                    col,
+                    offset,
+                    kind: CompileErrorKind::UnexpectedRightBracket,
+                    suggestion: Some(Suggestion::Remove { ch: ']', line, col, offset })
+                })
+            }
+            _ => {}
+        }
+    }
+
+    // every unclosed `[` is closed at the end of the source
+    let eof = Suggestion::Insert { ch: ']', line, col: col + 1, offset: src.len() };
+    errors.extend(stk.into_iter().map(|(line, col, offset)| CompileError {
+        line,
+        col,
+        offset,
+        kind: CompileErrorKind::UnclosedLeftBracket,
+        suggestion: Some(eof)
+    }));
+    errors
+}
+
+/// a token of `tokenize`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Token {
+    /// a command, as `compile` turns it into an instruction
+    Op(BfIR),
+    /// a run of `len` bytes that are no commands, only with `Lexer::with_comments`
+    Comment { len: usize },
+}
+
+/// the tokens of a source with the `line`, `col` and byte offset they start at,
+/// counted like `compile` does, see `tokenize`
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    offset: usize,
+    line: u32,
+    col: u32,
+    comments: bool
+}
+
+/// lex `src` token by token, without matching brackets or building the ir,
+/// everything but commands is skipped
+pub fn tokenize(src: &str) -> Lexer<'_> {
+    Lexer { src: src.as_bytes(), offset: 0, line: 1, col: 0, comments: false }
+}
+
+impl Lexer<'_> {
+    /// yield runs of non-command characters as `Token::Comment` instead of skipping them
+    pub fn with_comments(mut self) -> Self {
+        self.comments = true;
+        self
+    }
+
+    /// move past the byte at `offset`, returning its position
+    fn advance(&mut self) -> (u32, u32) {
+        let byte = self.src[self.offset];
+        // columns count chars, the continuation bytes of one don't start another
+        if byte & 0xc0 != 0x80 {
+            self.col += 1;
+        }
+        let pos = (self.line, self.col);
+        if byte == b'\n' {
+            self.line += 1;
+            self.col = 0;
+        }
+        self.offset += 1;
+        pos
+    }
+}
+
+/// the instruction a command compiles to
+fn command(byte: u8) -> Option<BfIR> {
+    match byte {
+        b'+' => Some(BfIR::AddVal(1)),
+        b'-' => Some(BfIR::SubVal(1)),
+        b'>' => Some(BfIR::AddPtr(1)),
+        b'<' => Some(BfIR::SubPtr(1)),
+        b',' => Some(BfIR::GetByte),
+        b'.' => Some(BfIR::PutByte),
+        b'[' => Some(BfIR::Jz),
+        b']' => Some(BfIR::Jnz),
+        _ => None
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    /// `(token, line, col, offset)`
+    type Item = (Token, u32, u32, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let start = self.offset;
+            let &byte = self.src.get(start)?;
+            let (line, col) = self.advance();
+            if let Some(op) = command(byte) {
+                return Some((Token::Op(op), line, col, start));
+            }
+            if self.comments {
+                while self.src.get(self.offset).is_some_and(|&b| command(b).is_none()) {
+                    self.advance();
+                }
+                return Some((Token::Comment { len: self.offset - start }, line, col, start));
+            }
+        }
+    }
+}
+
+const MAGIC: &[u8; 4] = b"BFIR";
+const VERSION: u8 = 1;
+
+/// encode `ir` as bytecode that `deserialize` reads back
+///
+/// the format is `MAGIC`, a version byte and the number of instructions,
+/// followed by the `kind` of every instruction and its operands.
+/// numbers are LEB128 encoded, offsets zigzag encoded first.
+pub fn serialize(ir: &[BfIR]) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    write_varint(&mut out, ir.len() as u64);
+
+    use BfIR::*;
+    for &op in ir {
+        out.push(op.kind() as u8);
+        match op {
+            AddVal(x) | SubVal(x) | SetVal(x) => write_varint(&mut out, x as u64),
+            AddPtr(x) | SubPtr(x) | ClearRange { len: x } | PutByteN { count: x } => write_varint(&mut out, x as u64),
+            MulVal { offset, factor: x } | AddValAt { offset, val: x } | AddPtrThenAddVal { ptr_delta: offset, val: x } => {
+                write_varint(&mut out, zigzag(offset));
+                write_varint(&mut out, x as u64);
+            }
+            GetByte | PutByte | Jz | Jnz | ScanRight | ScanLeft | DumpTape | ReadLine | Nop => {}
+        }
+    }
+    out
+}
+
+/// decode bytecode written by `serialize`, checking that brackets are balanced
+pub fn deserialize(bytes: &[u8]) -> Result<Vec<BfIR>, DecodeError> {
+    let rest = bytes.strip_prefix(MAGIC).ok_or(DecodeError::BadMagic)?;
+    let (&version, mut rest) = rest.split_first().ok_or(DecodeError::BadMagic)?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let len = read_varint(&mut rest)?;
+    // every instruction takes at least a byte, don't trust `len` any further
+    let mut ir = Vec::with_capacity(len.min(rest.len() as u64) as usize);
+    let mut depth: usize = 0;
+
+    use BfIR::*;
+    for _ in 0..len {
+        let (&kind, tail) = rest.split_first().ok_or(DecodeError::UnexpectedEof)?;
+        rest = tail;
+        let op = match kind {
+            0 => AddVal(read_u32(&mut rest)?),
+            1 => SubVal(read_u32(&mut rest)?),
+            2 => AddPtr(read_u32(&mut rest)?),
+            3 => SubPtr(read_u32(&mut rest)?),
+            4 => GetByte,
+            5 => PutByte,
+            6 => Jz,
+            7 => Jnz,
+            8 => SetVal(read_u32(&mut rest)?),
+            9 => MulVal { offset: read_offset(&mut rest)?, factor: read_u32(&mut rest)? },
+            10 => AddValAt { offset: read_offset(&mut rest)?, val: read_u32(&mut rest)? },
+            11 => ScanRight,
+            12 => ScanLeft,
+            13 => ClearRange { len: read_u32(&mut rest)? },
+            14 => PutByteN { count: read_u32(&mut rest)? },
+            15 => DumpTape,
+            16 => AddPtrThenAddVal { ptr_delta: read_offset(&mut rest)?, val: read_u32(&mut rest)? },
+            17 => ReadLine,
+            18 => Nop,
+            _ => return Err(DecodeError::UnknownKind(kind))
+        };
+        match op {
+            Jz => depth += 1,
+            Jnz => depth = depth.checked_sub(1).ok_or(DecodeError::UnbalancedBrackets)?,
+            _ => {}
+        }
+        ir.push(op);
+    }
+
+    if depth != 0 {
+        return Err(DecodeError::UnbalancedBrackets);
+    }
+    if !rest.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok(ir)
+}
+
+/// the operand fields of an instruction in json, by kind, each with its value
+fn json_fields(op: BfIR) -> ([&'static str; 2], [i64; 2], usize) {
+    use BfIR::*;
+    match op {
+        AddVal(x) | SubVal(x) | AddPtr(x) | SubPtr(x) | SetVal(x) => (["n", ""], [x as i64, 0], 1),
+        ClearRange { len } => (["len", ""], [len as i64, 0], 1),
+        PutByteN { count } => (["count", ""], [count as i64, 0], 1),
+        MulVal { offset, factor } => (["offset", "factor"], [offset as i64, factor as i64], 2),
+        AddValAt { offset, val } => (["offset", "val"], [offset as i64, val as i64], 2),
+        AddPtrThenAddVal { ptr_delta, val } => (["ptr_delta", "val"], [ptr_delta as i64, val as i64], 2),
+        GetByte | PutByte | Jz | Jnz | ScanRight | ScanLeft | DumpTape | ReadLine | Nop => (["", ""], [0, 0], 0),
+    }
+}
+
+/// encode `ir` as a json array that `from_json` reads back, one object per
+/// instruction with its name from `KIND_NAMES` as `op` and its operands named
+/// like the fields of `BfIR`, the single one of the others as `n`, e.g.
+/// `[{"op":"addval","n":3},{"op":"mulval","offset":-1,"factor":2}]`
+pub fn to_json(ir: &[BfIR]) -> String {
+    use core::fmt::Write;
+
+    let mut out = String::from("[");
+    for (i, &op) in ir.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write!(out, "{{\"op\":\"{}\"", op.name()).unwrap();
+        let (names, values, n) = json_fields(op);
+        for (name, value) in names.iter().zip(values).take(n) {
+            write!(out, ",\"{}\":{}", name, value).unwrap();
+        }
+        out.push('}');
+    }
+    out.push(']');
+    out
+}
+
+/// a value in the json `from_json` reads
+enum JsonValue<'a> {
+    Str(&'a str),
+    Int(i64),
+}
+
+/// a reader for the subset of json `to_json` writes: arrays of objects with
+/// string and integer values, strings without escapes
+struct JsonReader<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    fn error(&self, kind: JsonErrorKind) -> JsonError {
+        JsonError { offset: self.pos, kind }
+    }
+
+    fn skip_ws(&mut self) {
+        while self.src.get(self.pos).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// skip whitespace, then take `byte` if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_ws();
+        let found = self.src.get(self.pos) == Some(&byte);
+        self.pos += found as usize;
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), JsonError> {
+        if self.eat(byte) { Ok(()) } else { Err(self.error(JsonErrorKind::Syntax)) }
+    }
+
+    fn string(&mut self) -> Result<&'a str, JsonError> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        while let Some(&b) = self.src.get(self.pos) {
+            match b {
+                b'"' => {
+                    self.pos += 1;
+                    // the quotes are ascii, so this splits at char boundaries
+                    return core::str::from_utf8(&self.src[start..self.pos - 1]).map_err(|_| self.error(JsonErrorKind::Syntax));
+                }
+                b'\\' | ..=0x1f => break,
+                _ => self.pos += 1
+            }
+        }
+        Err(self.error(JsonErrorKind::Syntax))
+    }
+
+    fn value(&mut self) -> Result<JsonValue<'a>, JsonError> {
+        self.skip_ws();
+        if self.src.get(self.pos) == Some(&b'"') {
+            return self.string().map(JsonValue::Str);
+        }
+        let start = self.pos;
+        self.pos += (self.src.get(self.pos) == Some(&b'-')) as usize;
+        let digits = self.src[self.pos..].iter().take_while(|b| b.is_ascii_digit()).count();
+        self.pos += digits;
+        if digits == 0 || matches!(self.src.get(self.pos), Some(b'.' | b'e' | b'E')) {
+            return Err(self.error(JsonErrorKind::Syntax));
+        }
+        // all ascii, and too many digits only overflow
+        core::str::from_utf8(&self.src[start..self.pos])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(JsonValue::Int)
+            .ok_or(JsonError { offset: start, kind: JsonErrorKind::OperandOutOfRange })
+    }
+
+    /// an object describing one instruction
+    fn op(&mut self) -> Result<BfIR, JsonError> {
+        self.skip_ws();
+        let start = self.pos;
+        let fail = |kind| JsonError { offset: start, kind };
+        self.expect(b'{')?;
+        let mut fields = vec![];
+        if !self.eat(b'}') {
+            loop {
+                let key = self.string()?;
+                self.expect(b':')?;
+                fields.push((key, self.value()?));
+                if self.eat(b'}') {
+                    break;
+                }
+                self.expect(b',')?;
+            }
+        }
+
+        let name = match fields.iter().position(|(key, _)| *key == "op") {
+            Some(i) => match fields.swap_remove(i).1 {
+                JsonValue::Str(name) => name,
+                JsonValue::Int(_) => return Err(fail(JsonErrorKind::BadField)),
+            },
+            None => return Err(fail(JsonErrorKind::BadField)),
+        };
+        let kind = KIND_NAMES.iter().position(|&k| k == name).ok_or(fail(JsonErrorKind::UnknownOp))?;
+        // an instruction of the kind with placeholder operands tells which fields it takes
+        use BfIR::*;
+        let template = [
+            AddVal(0), SubVal(0), AddPtr(0), SubPtr(0), GetByte, PutByte, Jz, Jnz, SetVal(0),
+            MulVal { offset: 0, factor: 0 }, AddValAt { offset: 0, val: 0 }, ScanRight, ScanLeft,
+            ClearRange { len: 0 }, PutByteN { count: 0 }, DumpTape, AddPtrThenAddVal { ptr_delta: 0, val: 0 },
+            ReadLine, Nop,
+        ][kind];
+        let (names, _, n) = json_fields(template);
+        if fields.len() != n {
+            return Err(fail(JsonErrorKind::BadField));
+        }
+        let mut values = [0; 2];
+        for (name, value) in names.iter().zip(&mut values).take(n) {
+            *value = match fields.iter().find(|(key, _)| key == name) {
+                Some((_, JsonValue::Int(x))) => *x,
+                _ => return Err(fail(JsonErrorKind::BadField)),
+            };
+        }
+        let uint = |x: i64| u32::try_from(x).map_err(|_| fail(JsonErrorKind::OperandOutOfRange));
+        let int = |x: i64| i32::try_from(x).map_err(|_| fail(JsonErrorKind::OperandOutOfRange));
+        Ok(match template {
+            AddVal(_) => AddVal(uint(values[0])?),
+            SubVal(_) => SubVal(uint(values[0])?),
+            AddPtr(_) => AddPtr(uint(values[0])?),
+            SubPtr(_) => SubPtr(uint(values[0])?),
+            SetVal(_) => SetVal(uint(values[0])?),
+            ClearRange { .. } => ClearRange { len: uint(values[0])? },
+            PutByteN { .. } => PutByteN { count: uint(values[0])? },
+            MulVal { .. } => MulVal { offset: int(values[0])?, factor: uint(values[1])? },
+            AddValAt { .. } => AddValAt { offset: int(values[0])?, val: uint(values[1])? },
+            AddPtrThenAddVal { .. } => AddPtrThenAddVal { ptr_delta: int(values[0])?, val: uint(values[1])? },
+            op => op,
+        })
+    }
+}
+
+/// decode json written by `to_json`, checking that brackets are balanced;
+/// fields may come in any order with any whitespace between
+pub fn from_json(json: &str) -> Result<Vec<BfIR>, JsonError> {
+    let mut reader = JsonReader { src: json.as_bytes(), pos: 0 };
+    let mut ir = vec![];
+    let mut depth: usize = 0;
+    reader.expect(b'[')?;
+    if !reader.eat(b']') {
+        loop {
+            reader.skip_ws();
+            let start = reader.pos;
+            let op = reader.op()?;
+            match op {
+                BfIR::Jz => depth += 1,
+                BfIR::Jnz => {
+                    depth = depth.checked_sub(1).ok_or(JsonError { offset: start, kind: JsonErrorKind::UnbalancedBrackets })?
+                }
+                _ => {}
+            }
+            ir.push(op);
+            if reader.eat(b']') {
+                break;
+            }
+            reader.expect(b',')?;
+        }
+    }
+    reader.skip_ws();
+    if reader.pos != json.len() {
+        return Err(reader.error(JsonErrorKind::Syntax));
+    }
+    if depth != 0 {
+        return Err(reader.error(JsonErrorKind::UnbalancedBrackets));
+    }
+    Ok(ir)
+}
+
+fn zigzag(x: i32) -> u64 {
+    ((x << 1) ^ (x >> 31)) as u32 as u64
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+    let mut x: u64 = 0;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = bytes.split_first().ok_or(DecodeError::UnexpectedEof)?;
+        *bytes = rest;
+        x |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(x);
+        }
+    }
+    Err(DecodeError::OperandOutOfRange)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32, DecodeError> {
+    u32::try_from(read_varint(bytes)?).map_err(|_| DecodeError::OperandOutOfRange)
+}
+
+fn read_offset(bytes: &mut &[u8]) -> Result<i32, DecodeError> {
+    let x = read_u32(bytes)?;
+    Ok((x >> 1) as i32 ^ -((x & 1) as i32))
+}
+
+#[test]
+fn test_compile() {
+    assert_eq!(
+        compile("+[,.]").unwrap(),
+        vec![
+            BfIR::AddVal(1),
+            BfIR::Jz,
+            BfIR::GetByte,
+            BfIR::PutByte,
+            BfIR::Jnz,
+        ]
+    );
+
+    match compile("[").unwrap_err().kind {
+        CompileErrorKind::UnclosedLeftBracket => {}
+        _ => panic!(),
+    };
+
+    match compile("]").unwrap_err().kind {
+        CompileErrorKind::UnexpectedRightBracket => {}
+        _ => panic!(),
+    };
+
+    let mut code = compile("[+++++]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::Jz, BfIR::AddVal(5), BfIR::Jnz]);
+}
+
+#[test]
+fn test_optimize_clear_loop() {
+    let mut code = compile("[-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SetVal(0)]);
+
+    let mut code = compile("+[+]>").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddVal(1), BfIR::SetVal(0), BfIR::AddPtr(1)]);
+
+    // only a single `+`/`-` in the body counts as a clear loop
+    let mut code = compile("[-.][--][>-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::Jz,
+            BfIR::SubVal(1),
+            BfIR::PutByte,
+            BfIR::Jnz,
+            BfIR::Jz,
+            BfIR::SubVal(2),
+            BfIR::Jnz,
+            BfIR::Jz,
+            BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 255 },
+            BfIR::Jnz,
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_set_val() {
+    let mut code = compile("[-]+++").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SetVal(3)]);
+
+    let mut code = compile("[-][-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SetVal(0)]);
+
+    let mut code = compile(&format!("[-]--[+]{}>", "+".repeat(300))).unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SetVal(44), BfIR::AddPtr(1)]);
+
+    // moves and io are barriers
+    let mut code = compile("[-]>+<[-].+,+").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::SetVal(0),
+            BfIR::AddValAt { offset: 1, val: 1 },
+            BfIR::SetVal(0),
+            BfIR::PutByte,
+            BfIR::AddVal(1),
+            BfIR::GetByte,
+            BfIR::AddVal(1),
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_clear_range() {
+    let mut code = compile("[-]>[-]>[-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::ClearRange { len: 3 }]);
+
+    let mut code = compile("+>[-]>[+]>>[-]<[-]>[-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::AddVal(1),
+            BfIR::AddPtr(1),
+            BfIR::ClearRange { len: 2 },
+            BfIR::AddPtr(2),
+            BfIR::SetVal(0),
+            BfIR::SubPtr(1),
+            BfIR::ClearRange { len: 2 },
+        ]
+    );
+}
+
+#[test]
+fn test_optimize_put_runs() {
+    let mut code = compile(".....").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::PutByteN { count: 5 }]);
+
+    let mut code = compile(".+..>.<..").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::PutByte,
+            BfIR::AddVal(1),
+            BfIR::PutByteN { count: 2 },
+            BfIR::AddPtr(1),
+            BfIR::PutByte,
+            BfIR::SubPtr(1),
+            BfIR::PutByteN { count: 2 },
+        ]
+    );
+
+    // counts don't overflow, the run splits instead
+    let mut code = vec![BfIR::PutByteN { count: u32::MAX }, BfIR::PutByte, BfIR::PutByte];
+    let mut positions = vec![(1, 1), (1, 2), (1, 3)];
+    PutRuns.run_with_positions(&mut code, &mut positions);
+    assert_eq!(code, vec![BfIR::PutByteN { count: u32::MAX }, BfIR::PutByteN { count: 2 }]);
+    assert_eq!(positions, vec![(1, 1), (1, 2)]);
+}
+
+#[test]
+fn test_optimize_mul_loop() {
+    let mut code = compile("[->+<]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::MulVal { offset: 1, factor: 1 }, BfIR::SetVal(0)]);
+
+    let mut code = compile("[->++>+++<<]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::MulVal { offset: 1, factor: 2 },
+            BfIR::MulVal { offset: 2, factor: 3 },
+            BfIR::SetVal(0),
+        ]
+    );
+
+    let mut code = compile("[-<<+>>]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::MulVal { offset: -2, factor: 1 }, BfIR::SetVal(0)]);
+
+    // the pointer must return to the origin cell
+    let mut code = compile("[->+]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![BfIR::Jz, BfIR::SubVal(1), BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 1 }, BfIR::Jnz]
+    );
+
+    // io inside the body is a side effect
+    let mut code = compile("[->+<.]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code[0], BfIR::Jz);
+
+    // wide offsets and moves in any order
+    let mut code = compile("[->>>+<<<]").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::MulVal { offset: 3, factor: 1 }, BfIR::SetVal(0)]);
+    let mut code = compile("[>>+++<<<<+>>>-<-]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::MulVal { offset: -2, factor: 1 },
+            BfIR::MulVal { offset: 1, factor: u32::MAX },
+            BfIR::MulVal { offset: 2, factor: 3 },
+            BfIR::SetVal(0),
+        ]
+    );
+    // the origin must go down by exactly one
+    for src in ["[-->+<]", "[>+<]", "[->+<[-]]", "[-,>+<]"] {
+        let mut code = compile(src).unwrap();
+        optimize(&mut code);
+        assert!(!code.iter().any(|op| matches!(op, BfIR::MulVal { .. })), "{}", src);
+    }
+}
+
+#[test]
+fn test_optimize_cancel() {
+    let mut code = compile("+-><").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![]);
+
+    let mut code = compile("+++-----").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SubVal(2)]);
+
+    let mut code = compile("-----+++").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SubVal(2)]);
+
+    let mut code = compile(">>><<").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddPtr(1)]);
+
+    let mut code = compile(">><<<<").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::SubPtr(2)]);
+
+    // runs brought together by a cancelled run fold as well
+    let mut code = compile("+><+").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddVal(2)]);
+
+    // wraps like the unfolded u8 arithmetic
+    let mut code = compile(&"+".repeat(300)).unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddVal(44)]);
+
+    // io is a barrier
+    let mut code = compile("++,-->.<").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::AddVal(2),
+            BfIR::GetByte,
+            BfIR::SubVal(2),
+            BfIR::AddPtr(1),
+            BfIR::PutByte,
+            BfIR::SubPtr(1),
+        ]
+    );
+}
+
+#[test]
+fn test_fuse_ptr_vals() {
+    let mut code = compile(">+").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 1 }]);
+
+    let mut code = compile(",<<---.").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::GetByte, BfIR::AddPtrThenAddVal { ptr_delta: -2, val: 253 }, BfIR::PutByte]);
+
+    let mut code = compile(",<<---.").unwrap();
+    let mut pos = vec![(0, 0); code.len()];
+    optimize_with_positions(&mut code, &mut pos, OptLevel::Full, CellWidth::W16);
+    assert_eq!(code[1], BfIR::AddPtrThenAddVal { ptr_delta: -2, val: 65533 });
+
+    // a value op alone or before the move stays as it is
+    let mut code = compile(",+>.").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::GetByte, BfIR::AddVal(1), BfIR::AddPtr(1), BfIR::PutByte]);
+
+    let code = vec![BfIR::AddPtrThenAddVal { ptr_delta: -3, val: 7 }];
+    assert_eq!(deserialize(&serialize(&code)).unwrap(), code);
+    assert_eq!(from_json(&to_json(&code)).unwrap(), code);
+}
+
+#[test]
+fn test_optimize_offsets() {
+    let mut code = compile(">+++>++<<").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![BfIR::AddValAt { offset: 1, val: 3 }, BfIR::AddValAt { offset: 2, val: 2 }]
+    );
+
+    let mut code = compile(">+>-<<-<").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::AddValAt { offset: 0, val: 255 },
+            BfIR::AddValAt { offset: 1, val: 1 },
+            BfIR::AddValAt { offset: 2, val: 255 },
+            BfIR::SubPtr(1),
+        ]
+    );
+
+    // a single move is left to `FusePtrVals`
+    let mut code = compile(">+").unwrap();
+    optimize(&mut code);
+    assert_eq!(code, vec![BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 1 }]);
+
+    // io and loops break the window
+    let mut code = compile(">+.>+<<[>+<<+>]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        code,
+        vec![
+            BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 1 },
+            BfIR::PutByte,
+            BfIR::AddValAt { offset: 1, val: 1 },
+            BfIR::SubPtr(1),
+            BfIR::Jz,
+            BfIR::AddValAt { offset: -1, val: 1 },
+            BfIR::AddValAt { offset: 1, val: 1 },
+            BfIR::Jnz,
+        ]
+    );
+}
+
+#[test]
+fn test_opt_level() {
+    let samples = [
+        include_str!("../examples/mendelbrot.bf"),
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.",
+        "+-+[-]>>+<-<[->+<]",
+    ];
+
+    for src in samples {
+        let ir = compile(src).unwrap();
+
+        let mut none = ir.clone();
+        optimize_with(&mut none, OptLevel::None);
+        assert_eq!(none, ir);
+
+        let mut basic = ir.clone();
+        optimize_with(&mut basic, OptLevel::Basic);
+        let mut full = ir.clone();
+        optimize_with(&mut full, OptLevel::Full);
+
+        assert!(basic.len() <= none.len());
+        assert!(full.len() <= basic.len());
+    }
+
+    let mut code = compile("+-[-]").unwrap();
+    optimize_with(&mut code, OptLevel::Basic);
+    assert_eq!(
+        code,
+        vec![BfIR::AddVal(1), BfIR::SubVal(1), BfIR::Jz, BfIR::SubVal(1), BfIR::Jnz]
+    );
+}
+
+#[test]
+fn test_hoist_set_vals() {
+    use crate::interp::{FnIo, Interpreter};
+    use BfIR::*;
+
+    let run = |ir: Vec<BfIR>| {
+        let mut output = vec![];
+        let mut interp = Interpreter::with_io(
+            ir,
+            vec![0; 4].into_boxed_slice(),
+            FnIo { read: || None, write: |byte| output.push(byte) }
+        );
+        interp.run().unwrap();
+        let memory = interp.memory.clone();
+        drop(interp);
+        (memory, output)
+    };
+
+    // counts down the first cell, setting the third one and echoing the second on the way
+    let mut ir = compile("+++>+<").unwrap();
+    ir.extend([Jz, SubVal(1), AddPtr(1), PutByte, AddPtr(1), SetVal(7), SubPtr(2), Jnz]);
+    let mut hoisted = ir.clone();
+    assert!(HoistSetVals.run(&mut hoisted));
+    assert_eq!(hoisted[6..], [Jz, SubVal(1), AddPtr(1), PutByte, AddPtr(1), SetVal(7), SubPtr(2),
+        Jz, SubVal(1), AddPtr(1), PutByte, AddPtr(1), SubPtr(2), Jnz, Jnz]);
+    assert_eq!(run(hoisted.clone()), run(ir));
+    assert_eq!(run(hoisted.clone()).0[..], [0, 1, 7, 0]);
+    // the peeled loop has nothing left to hoist
+    assert!(!HoistSetVals.run(&mut hoisted.clone()));
+
+    // once the loop reads the cell as well, or writes it elsewhere, it stays
+    for extra in [PutByte, AddVal(1), GetByte] {
+        let ir = vec![AddVal(1), Jz, SubVal(1), AddPtr(1), SetVal(7), extra, SubPtr(1), Jnz];
+        assert!(!HoistSetVals.run(&mut ir.clone()));
+    }
+    // as does the set on the guard, and any in a loop moving the pointer
+    for src in ["+[>+<[-]]", "+[[-]>+<-]", "+[>[-]-]"] {
+        let mut ir = compile(src).unwrap();
+        ClearLoops.run(&mut ir);
+        assert!(!HoistSetVals.run(&mut ir.clone()), "{}", src);
+    }
+    // the positions go along
+    let (mut ir, mut positions) = compile_with_positions("+[->[-]<]").unwrap();
+    fold_clear_loops(&mut ir, &mut positions);
+    HoistSetVals.run_with_positions(&mut ir, &mut positions);
+    assert_eq!(ir.len(), positions.len());
+    assert_eq!(positions[6..], [(1, 2), (1, 3), (1, 4), (1, 8), (1, 9), (1, 9)]);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_count_ops() {
+    let counts = count_ops(&compile("+[,.]").unwrap());
+    assert_eq!(counts.len(), 5);
+    for name in ["addval", "jz", "getbyte", "putbyte", "jnz"] {
+        assert_eq!(counts[name], 1);
+    }
+
+    let mut code = compile("+++[->++<]").unwrap();
+    optimize(&mut code);
+    assert_eq!(
+        count_ops(&code),
+        HashMap::from([("addval", 1), ("mulval", 1), ("setval", 1)])
+    );
+}
+
+#[test]
+fn test_disassemble() {
+    let mut code = compile("[+++++]").unwrap();
+    optimize(&mut code);
+    assert_eq!(disassemble(&code), "jz\n  addval 5\njnz\n");
+
+    let mut code = compile("+[>[-<++>]<<.,]>>+>+<").unwrap();
+    optimize(&mut code);
+    let lines = [
+        "addval 1",
+        "jz",
+        "  addptr 1",
+        "  mulval -1 2",
+        "  setval 0",
+        "  subptr 2",
+        "  putbyte",
+        "  getbyte",
+        "jnz",
+        "addvalat 2 1",
+        "addvalat 3 1",
+        "addptr 2",
+    ];
+    assert_eq!(disassemble(&code), lines.map(|l| l.to_owned() + "\n").concat());
+}
+#[test]
+fn test_positions() {
+    let (mut ir, mut positions) = compile_with_positions("+ +\n[-]>>.").unwrap();
+    assert_eq!(positions, [(1, 1), (1, 3), (2, 1), (2, 2), (2, 3), (2, 4), (2, 5), (2, 6)]);
+
+    optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, CellWidth::W8);
+    assert_eq!(ir, [BfIR::AddVal(2), BfIR::SetVal(0), BfIR::AddPtr(2), BfIR::PutByte]);
+    assert_eq!(positions, [(1, 1), (2, 1), (2, 4), (2, 6)]);
+}
+
+#[test]
+fn test_optimize_with_map() {
+    let map = |src| {
+        let mut ir = compile(src).unwrap();
+        let mut map = tokenize(src).map(|(_, _, _, offset)| offset..offset + 1).collect();
+        optimize_with_map(&mut ir, &mut map, OptLevel::Full, CellWidth::W8);
+        (ir, map)
+    };
+    // the folded `+++` spans all three of them
+    let (ir, spans) = map("+++");
+    assert_eq!((ir, spans.len(), spans[0].clone()), (vec![BfIR::AddVal(3)], 1, 0..3));
+    assert_eq!(map("a +++ b [-]."), (vec![BfIR::AddVal(3), BfIR::SetVal(0), BfIR::PutByte], vec![2..5, 8..11, 11..12]));
+    // everything a multiply loop folds into stems from all of it
+    assert_eq!(map("+[->+<]").1, [0..1, 1..7, 1..7]);
+    assert_eq!(map(">+-").1.pop(), Some(0..3));
+
+    // origins follow instructions a pass moved out of order
+    let mut ir = vec![BfIR::Jz, BfIR::AddPtr(1), BfIR::SetVal(5), BfIR::SubPtr(1), BfIR::SubVal(1), BfIR::Jnz];
+    let mut origins: Vec<usize> = (0..ir.len()).collect();
+    Optimizer::new().with_pass(HoistSetVals).with_max_rounds(1).run_with_origins(&mut ir, &mut origins);
+    assert_eq!(origins, [0, 1, 2, 3, 4, 0, 1, 3, 4, 5, 5]);
+}
+
+#[test]
+fn test_unbalanced_bracket() {
+    use BfIR::*;
+    assert_eq!(unbalanced_bracket(&compile("+[[-]>]").unwrap()), None);
+    assert_eq!(unbalanced_bracket(&[Jnz]), Some(0));
+    assert_eq!(unbalanced_bracket(&[Jz, Jnz, AddVal(1), Jnz, Jz]), Some(3));
+    assert_eq!(unbalanced_bracket(&[Jz, Jz, Jnz]), Some(0));
+}
+
+#[test]
+fn test_verify() {
+    use BfIR::*;
+    let mut ir = compile("+[->+<]>[[-]>.,<]>>>.").unwrap();
+    assert_eq!(verify(&ir), Ok(()));
+    optimize(&mut ir);
+    assert_eq!(verify(&ir), Ok(()));
+    assert_eq!(verify(&[]), Ok(()));
+
+    let err = |index, kind| Err(VerifyError { index, kind });
+    assert_eq!(verify(&[AddVal(1), Jz, Jnz, Jnz, Jz]), err(3, VerifyErrorKind::UnmatchedJnz));
+    assert_eq!(verify(&[Jz, AddVal(1), Jz, SubVal(1), Jnz]), err(0, VerifyErrorKind::UnclosedJz));
+    assert_eq!(verify(&[AddPtr(1), MulVal { offset: 0, factor: 2 }]), err(1, VerifyErrorKind::SelfMultiply));
+    assert_eq!(verify(&[AddValAt { offset: i32::MIN, val: 1 }]), err(0, VerifyErrorKind::OffsetOutOfRange));
+    assert_eq!(verify(&[AddPtrThenAddVal { ptr_delta: i32::MAX, val: 1 }]), err(0, VerifyErrorKind::OffsetOutOfRange));
+    assert_eq!(verify(&[Jz, ClearRange { len: 0 }, Jnz]), err(1, VerifyErrorKind::ZeroLength));
+    assert_eq!(verify(&[PutByteN { count: 0 }]), err(0, VerifyErrorKind::ZeroLength));
+    assert_eq!(err(3, VerifyErrorKind::UnmatchedJnz).unwrap_err().to_string(), "Jnz without a Jz at instruction 3");
+}
+
+#[test]
+fn test_compile_all() {
+    assert_eq!(compile_all("+[,.]").unwrap(), compile("+[,.]").unwrap());
+
+    let errors = compile_all("+]\n[-] ]\n[[-]").unwrap_err();
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, [
+        "Unexpected right bracket at line 1:2",
+        "Unexpected right bracket at line 2:5",
+        "Unclosed left bracket at line 3:1",
+    ]);
+}
+
+#[test]
+fn test_compile_error_accessors() {
+    let err = compile("+\n ]").unwrap_err();
+    assert_eq!((err.line(), err.col(), err.kind()), (2, 2, CompileErrorKind::UnexpectedRightBracket));
+
+    let err = compile("[[]").unwrap_err();
+    assert_eq!((err.line(), err.col(), err.kind()), (1, 1, CompileErrorKind::UnclosedLeftBracket));
+    assert_eq!(err.to_string(), "Unclosed left bracket at line 1:1");
+}
+
+#[test]
+fn test_compile_error_suggestion() {
+    let err = compile("+[[-]\n>").unwrap_err();
+    assert_eq!(err.suggestion(), Some(Suggestion::Insert { ch: ']', line: 2, col: 2, offset: 7 }));
+    assert_eq!(err.suggestion().unwrap().to_string(), "insert `]` at line 2:2");
+
+    let err = compile("+\n ]").unwrap_err();
+    assert_eq!(err.suggestion(), Some(Suggestion::Remove { ch: ']', line: 2, col: 2, offset: 3 }));
+    assert_eq!(err.suggestion().unwrap().to_string(), "remove `]` at line 2:2");
+
+    let errors = compile_all("]\n[[").unwrap_err();
+    let suggestions: Vec<String> = errors.iter().map(|e| e.suggestion().unwrap().to_string()).collect();
+    assert_eq!(suggestions, ["remove `]` at line 1:1", "insert `]` at line 2:3", "insert `]` at line 2:3"]);
+
+    let err = compile_with_max_loop_depth("[[]]", 1).unwrap_err();
+    assert_eq!(err.suggestion(), None);
+}
+
+#[test]
+fn test_compile_error_offset() {
+    // "ü" takes two bytes but one column
+    let src = "+ grün\n[";
+    let err = compile(src).unwrap_err();
+    assert_eq!((err.line(), err.col()), (2, 1));
+    assert_eq!(err.offset(), 8);
+    assert_eq!(&src[err.offset()..], "[");
+
+    let errors = compile_all("ö]").unwrap_err();
+    assert_eq!(errors[0].offset(), 2);
+    assert_eq!(errors[0].col(), 2);
+}
+
+#[test]
+fn test_optimize_cell_width() {
+    let (mut ir, mut positions) = compile_with_positions(&"+".repeat(300)).unwrap();
+    optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, CellWidth::W16);
+    assert_eq!(ir, [BfIR::AddVal(300)]);
+
+    let (mut ir, mut positions) = compile_with_positions(&"-".repeat(65537)).unwrap();
+    optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, CellWidth::W16);
+    assert_eq!(ir, [BfIR::SubVal(1)]);
+
+    let (mut ir, mut positions) = compile_with_positions(">->+<<").unwrap();
+    optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, CellWidth::W32);
+    assert_eq!(ir, [BfIR::AddValAt { offset: 1, val: u32::MAX }, BfIR::AddValAt { offset: 2, val: 1 }]);
+}
+
+#[test]
+fn test_serialize() {
+    let mut ir = compile(include_str!("../examples/mendelbrot.bf")).unwrap();
+    optimize(&mut ir);
+    let bytes = serialize(&ir);
+    assert_eq!(deserialize(&bytes).unwrap(), ir);
+
+    let ir = vec![
+        BfIR::AddVal(u32::MAX),
+        BfIR::Jz,
+        BfIR::MulVal { offset: i32::MIN, factor: 3 },
+        BfIR::AddValAt { offset: i32::MAX, val: 0 },
+        BfIR::AddValAt { offset: -1, val: 200 },
+        BfIR::ScanLeft,
+        BfIR::Jnz,
+        BfIR::ScanRight,
+        BfIR::ClearRange { len: u32::MAX },
+        BfIR::PutByteN { count: 5 },
+    ];
+    let bytes = serialize(&ir);
+    assert_eq!(deserialize(&bytes).unwrap(), ir);
+    assert_eq!(deserialize(&serialize(&[])).unwrap(), []);
+
+    // every truncation fails cleanly
+    for len in 0..bytes.len() {
+        assert!(deserialize(&bytes[..len]).is_err(), "{}", len);
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert_eq!(deserialize(&trailing), Err(DecodeError::TrailingBytes));
+    assert_eq!(deserialize(b"BFJT\x01\x00"), Err(DecodeError::BadMagic));
+    assert_eq!(deserialize(b"BFIR\x02\x00"), Err(DecodeError::UnsupportedVersion(2)));
+    assert_eq!(deserialize(b"BFIR\x01\x01\x63"), Err(DecodeError::UnknownKind(0x63)));
+    assert_eq!(deserialize(b"BFIR\x01\x01\x00\xff\xff\xff\xff\x7f"), Err(DecodeError::OperandOutOfRange));
+    assert_eq!(deserialize(b"BFIR\x01\x01\x07"), Err(DecodeError::UnbalancedBrackets));
+    assert_eq!(deserialize(b"BFIR\x01\x01\x06"), Err(DecodeError::UnbalancedBrackets));
+    // a huge count doesn't allocate up front
+    assert_eq!(deserialize(b"BFIR\x01\xff\xff\xff\xff\x0f"), Err(DecodeError::UnexpectedEof));
+}
+
+#[test]
+fn test_remove_dead_loops() {
+    let remove = |src| {
+        let mut ir = compile(src).unwrap();
+        let mut positions = vec![(0, 0); ir.len()];
+        remove_dead_loops(&mut ir, &mut positions);
+        assert_eq!(ir.len(), positions.len());
+        ir
+    };
+    assert_eq!(remove("[junk]+."), compile("+.").unwrap());
+    assert_eq!(remove("[[-]>[+]]>.[<]<[-]+[-]"), compile(">.<+[-]").unwrap());
+    // the input may set the guard cell
+    assert_eq!(remove(",[-]+."), compile(",[-]+.").unwrap());
+    assert_eq!(remove("+[-]"), compile("+[-]").unwrap());
+
+    let (mut ir, mut positions) = compile_with_positions("[.]\n+").unwrap();
+    remove_dead_loops(&mut ir, &mut positions);
+    assert_eq!((ir, positions), (vec![BfIR::AddVal(1)], vec![(2, 1)]));
+
+    // an unclosed loop is kept, not run past
+    let mut ir = vec![BfIR::Jz, BfIR::Jz, BfIR::Jnz, BfIR::AddVal(1)];
+    let mut positions = vec![(0, 0); 4];
+    remove_dead_loops(&mut ir, &mut positions);
+    assert_eq!(ir, [BfIR::Jz, BfIR::Jz, BfIR::Jnz, BfIR::AddVal(1)]);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_propagate_constants() {
+    let propagate = |src| {
+        let (mut ir, mut positions) = compile_with_positions(src).unwrap();
+        optimize(&mut ir);
+        positions.resize(ir.len(), (0, 0));
+        propagate_constants(&mut ir, &mut positions, CellWidth::W8, ArithMode::Wrapping);
+        ir
+    };
+    assert_eq!(propagate("+++"), [BfIR::SetVal(3)]);
+    assert_eq!(propagate("---"), [BfIR::SetVal(253)]);
+
+    // saturating cells stop at either end
+    let mut ir = vec![BfIR::SubVal(3), BfIR::AddVal(250), BfIR::AddVal(10), BfIR::SubVal(1)];
+    let mut positions = vec![(0, 0); ir.len()];
+    propagate_constants(&mut ir, &mut positions, CellWidth::W8, ArithMode::Saturating);
+    assert_eq!(ir, [BfIR::SetVal(0), BfIR::SetVal(250), BfIR::SetVal(255), BfIR::SetVal(254)]);
+    // a read cell is unknown, the others are not
+    assert_eq!(propagate(",+++"), [BfIR::GetByte, BfIR::AddVal(3)]);
+    assert_eq!(
+        propagate(">,<.+>.+"),
+        [
+            BfIR::AddPtr(1),
+            BfIR::GetByte,
+            BfIR::SubPtr(1),
+            BfIR::PutByte,
+            BfIR::SetVal(1),
+            BfIR::AddPtr(1),
+            BfIR::PutByte,
+            BfIR::AddVal(1),
+        ]
+    );
+    // loops on a zero cell are dead, one that may run ends tracking
+    assert_eq!(
+        propagate("[.]>[>]+[-<+>]."),
+        [BfIR::AddPtr(1), BfIR::SetVal(1), BfIR::MulVal { offset: -1, factor: 1 }, BfIR::SetVal(0), BfIR::PutByte]
+    );
+    assert_eq!(
+        propagate("+[->+<]>-"),
+        [BfIR::SetVal(1), BfIR::MulVal { offset: 1, factor: 1 }, BfIR::SetVal(0), BfIR::AddPtr(1), BfIR::SetVal(0)]
+    );
+    assert_eq!(propagate("+[[-]>]+"), [BfIR::SetVal(1), BfIR::Jz, BfIR::SetVal(0), BfIR::AddPtr(1), BfIR::Jnz, BfIR::AddVal(1)]);
+    assert_eq!(propagate(">>+[-]>[-]<<[>]"), [BfIR::AddPtr(2), BfIR::SetVal(1), BfIR::ClearRange { len: 2 }, BfIR::SubPtr(2)]);
+
+    // the same output as without it
+    let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let mut ir = compile(src).unwrap();
+    let mut positions = vec![(0, 0); ir.len()];
+    Optimizer::for_level(OptLevel::Full, CellWidth::W8)
+        .with_pass(ConstProp { width: CellWidth::W8, arith: ArithMode::Wrapping })
+        .run_with_positions(&mut ir, &mut positions);
+    let output = crate::bfjit::SharedBuf::default();
+    crate::interp::Interpreter::new(ir, vec![0; 16].into_boxed_slice(), Box::new(std::io::empty()), Box::new(output.clone()))
+        .run()
+        .unwrap();
+    assert_eq!(output.take(), b"Hello World!\n");
+}
+
+#[test]
+fn test_optimize_with_stats() {
+    let (ir, stats) = optimize_with_stats(compile("[-]>+++++").unwrap());
+    assert_eq!(ir, [BfIR::SetVal(0), BfIR::AddPtrThenAddVal { ptr_delta: 1, val: 5 }]);
+    assert_eq!(
+        stats,
+        OptStats {
+            instructions_before: 9,
+            instructions_after: 2,
+            rounds: 2,
+            folds_applied: 4,
+            clear_loops: 1,
+            scan_loops: 0,
+            multiply_loops: 0
+        }
+    );
+
+    let (_, stats) = optimize_with_stats(compile("+[->++<]>[-]<[>]+-").unwrap());
+    assert_eq!((stats.clear_loops, stats.scan_loops, stats.multiply_loops), (1, 1, 1));
+    assert_eq!(stats.folds_applied, 3);
+    // passes without a field of their own count nothing
+    let mut ir = compile("[-]").unwrap();
+    let mut positions = vec![(0, 0); ir.len()];
+    let stats = Optimizer::new().with_pass(DeadLoops).run_with_stats(&mut ir, &mut positions);
+    assert_eq!(stats, OptStats { instructions_before: 3, rounds: 2, ..OptStats::default() });
+}
+
+#[test]
+fn test_optimizer() {
+    struct Noop;
+    impl IrPass for Noop {
+        fn run(&self, _: &mut Vec<BfIR>) -> bool {
+            false
+        }
+    }
+
+    let samples = [
+        include_str!("../examples/mendelbrot.bf"),
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.",
+        "+-+[-]>>+<-<[->+<][-]+++[>]",
+    ];
+    for src in samples {
+        let ir = compile(src).unwrap();
+        let mut expected = ir.clone();
+        optimize(&mut expected);
+
+        // the fixed sequence `optimize` used to run
+        let mut once = ir.clone();
+        let mut pos = vec![(0, 0); once.len()];
+        let modulus = CellWidth::W8.modulus();
+        while fold_runs(&mut once, &mut pos, true, modulus, false) {}
+        fold_clear_loops(&mut once, &mut pos);
+        fold_scan_loops(&mut once, &mut pos);
+        fold_mul_loops(&mut once, &mut pos);
+        fold_set_vals(&mut once, &mut pos, modulus);
+        fold_clear_ranges(&mut once, &mut pos);
+        fold_offsets(&mut once, &mut pos, modulus);
+        fuse_ptr_vals(&mut once, &mut pos, modulus);
+        fold_put_runs(&mut once, &mut pos);
+        assert_eq!(once, expected);
+
+        let mut code = ir.clone();
+        let rounds = Optimizer::new()
+            .with_pass(Noop)
+            .with_pass(FoldRuns { cancel: true, width: CellWidth::W8 })
+            .run(&mut code);
+        assert_eq!(rounds, 2);
+        let mut folded = ir.clone();
+        let mut pos = vec![(0, 0); folded.len()];
+        while fold_runs(&mut folded, &mut pos, true, modulus, false) {}
+        assert_eq!(code, folded);
+
+        let mut code = ir.clone();
+        assert!(Optimizer::for_level(OptLevel::Full, CellWidth::W8).with_pass(Noop).run(&mut code) < 16);
+        assert_eq!(code, expected);
+    }
+
+    // a pass that never settles stops at the cap
+    struct Always;
+    impl IrPass for Always {
+        fn run(&self, ir: &mut Vec<BfIR>) -> bool {
+            ir.pop();
+            true
+        }
+    }
+    let (mut ir, mut positions) = compile_with_positions("+>+>+>+").unwrap();
+    assert_eq!(Optimizer::new().with_pass(Always).with_max_rounds(3).run_with_positions(&mut ir, &mut positions), 3);
+    assert_eq!(ir.len(), 4);
+    assert_eq!(positions, [(0, 0); 4]);
+
+    // a rewrite keeping the length counts as a change, so the passes before it run again
+    let mut ir = vec![BfIR::AddVal(3), BfIR::AddVal(2)];
+    let rounds = Optimizer::new()
+        .with_pass(SetVals { width: CellWidth::W8 })
+        .with_pass(ConstProp { width: CellWidth::W8, arith: ArithMode::Wrapping })
+        .run(&mut ir);
+    assert_eq!((ir, rounds), (vec![BfIR::SetVal(5)], 3));
+}
+
+#[test]
+fn test_optimizer_from_names() {
+    let run = |names: &[&str], src| {
+        let mut ir = compile(src).unwrap();
+        Optimizer::from_names(names, CellWidth::W8, ArithMode::Wrapping).unwrap().run(&mut ir);
+        ir
+    };
+    use BfIR::*;
+    assert_eq!(run(&["fold"], "+++[-]>>"), [AddVal(3), Jz, SubVal(1), Jnz, AddPtr(2)]);
+    assert_eq!(run(&["fold", "clear-loop"], "+++[-]>>"), [AddVal(3), SetVal(0), AddPtr(2)]);
+    assert_eq!(run(&[], "+-"), [AddVal(1), SubVal(1)]);
+
+    // every name stands for a pass, and all of those `optimize` runs
+    // together make the same pipeline
+    let src = include_str!("../examples/mendelbrot.bf");
+    let mut expected = compile(src).unwrap();
+    optimize(&mut expected);
+    assert_eq!(run(&PASS_NAMES[..9], src), expected);
+    assert!(Optimizer::from_names(&PASS_NAMES, CellWidth::W8, ArithMode::Wrapping).is_ok());
+
+    // the passes work on cells of the given width and arithmetic
+    let run_with = |names: &[&str], src: &str, width, arith| {
+        let mut ir = compile(src).unwrap();
+        Optimizer::from_names(names, width, arith).unwrap().run(&mut ir);
+        ir
+    };
+    let src = "+".repeat(256);
+    assert_eq!(run_with(&["fold"], &src, CellWidth::W8, ArithMode::Wrapping), []);
+    assert_eq!(run_with(&["fold"], &src, CellWidth::W16, ArithMode::Wrapping), [AddVal(256)]);
+    assert_eq!(run_with(&["fold"], "+-", CellWidth::W8, ArithMode::Saturating), [AddVal(1), SubVal(1)]);
+    assert_eq!(run_with(&["const-prop"], "-.", CellWidth::W8, ArithMode::Wrapping), [SetVal(255), PutByte]);
+    assert_eq!(run_with(&["const-prop"], "-.", CellWidth::W8, ArithMode::Saturating), [SetVal(0), PutByte]);
+
+    let err = Optimizer::from_names(&["fold", "unroll"], CellWidth::W8, ArithMode::Wrapping).err().unwrap();
+    assert_eq!(err, UnknownPass { name: "unroll".into() });
+    assert!(err.to_string().starts_with("Unknown pass \"unroll\", expected one of fold, clear-loop, "));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_strip_nops() {
+    use crate::bfjit::{BfVM, SharedBuf};
+
+    /// turns `+-` pairs into two `Nop`s
+    struct NopPairs;
+    impl IrPass for NopPairs {
+        fn run(&self, ir: &mut Vec<BfIR>) -> bool {
+            let mut changed = false;
+            for i in 1..ir.len() {
+                if let (BfIR::AddVal(x), BfIR::SubVal(y)) = (ir[i - 1], ir[i]) {
+                    if x == y {
+                        ir[i - 1] = BfIR::Nop;
+                        ir[i] = BfIR::Nop;
+                        changed = true;
+                    }
+                }
+            }
+            changed
+        }
+
+        fn run_with_positions(&self, ir: &mut Vec<BfIR>, _: &mut Vec<SourcePos>) -> bool {
+            self.run(ir)
+        }
+    }
+
+    /// deletes `+-` pairs right away
+    struct DeletePairs;
+    impl IrPass for DeletePairs {
+        fn run(&self, ir: &mut Vec<BfIR>) -> bool {
+            let mut positions = vec![(0, 0); ir.len()];
+            self.run_with_positions(ir, &mut positions)
+        }
+
+        fn run_with_positions(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) -> bool {
+            let len = ir.len();
+            let mut i = 0;
+            let mut pc = 0;
+            while i < len {
+                if let (BfIR::AddVal(x), Some(&BfIR::SubVal(y))) = (ir[i], ir.get(i + 1)) {
+                    if x == y {
+                        i += 2;
+                        continue;
+                    }
+                }
+                ir[pc] = ir[i];
+                positions[pc] = positions[i];
+                i += 1;
+                pc += 1;
+            }
+            ir.truncate(pc);
+            positions.truncate(pc);
+            pc < len
+        }
+    }
+
+    let (ir, positions) = compile_with_positions("+>+-.<+-+-[-+>+-<]+.").unwrap();
+    let (mut nops, mut nop_positions) = (ir.clone(), positions.clone());
+    assert!(NopPairs.run(&mut nops));
+    assert_eq!(nops.len(), ir.len());
+    strip_nops(&mut nops, &mut nop_positions);
+    let (mut deleted, mut deleted_positions) = (ir.clone(), positions.clone());
+    assert!(DeletePairs.run_with_positions(&mut deleted, &mut deleted_positions));
+    assert_eq!((&nops, &nop_positions), (&deleted, &deleted_positions));
+
+    // the optimizer strips them after the pass, so later passes never see one
+    let (mut nops, mut nop_positions) = (ir.clone(), positions.clone());
+    let rounds = Optimizer::new().with_pass(NopPairs).run_with_positions(&mut nops, &mut nop_positions);
+    let (mut deleted, mut deleted_positions) = (ir, positions);
+    assert_eq!(Optimizer::new().with_pass(DeletePairs).run_with_positions(&mut deleted, &mut deleted_positions), rounds);
+    assert_eq!((&nops, &nop_positions), (&deleted, &deleted_positions));
+    assert!(!nops.contains(&BfIR::Nop));
+
+    // the JIT emits nothing for one
+    let output = SharedBuf::default();
+    let ir = vec![BfIR::Nop, BfIR::AddVal(65), BfIR::Nop, BfIR::PutByte, BfIR::Nop];
+    BfVM::from_ir(ir, Box::new(std::io::empty()), Box::new(output.clone())).unwrap().run().unwrap();
+    assert_eq!(output.take(), b"A");
+}
+
+#[test]
+fn test_analyze() {
+    let warnings = |src| analyze(&compile(src).unwrap());
+    assert_eq!(warnings("+[]"), [Warning::InfiniteLoop(1)]);
+    assert_eq!(warnings(",[.+-]"), [Warning::InfiniteLoop(1)]);
+    // only ever odd
+    assert_eq!(warnings("+[++]"), [Warning::InfiniteLoop(1)]);
+    assert_eq!(warnings(">+++<+>-<[--]"), [Warning::InfiniteLoop(9)]);
+    assert_eq!(warnings(">+++<+>-[--]"), []);
+    // a zero guard is reached eventually, with wrapping if need be
+    assert_eq!(warnings("+[-]"), []);
+    assert_eq!(warnings("+[+]"), []);
+    assert_eq!(warnings("++[++]"), []);
+    // the guard isn't known
+    assert_eq!(warnings(",[++]"), []);
+    assert_eq!(warnings(",[>]"), []);
+
+    let mut ir = compile("++[>+<-]>[++]").unwrap();
+    optimize(&mut ir);
+    assert_eq!(analyze(&ir), []);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_compile_reader() {
+    let src = include_str!("../examples/mendelbrot.bf");
+    assert_eq!(compile_reader(src.as_bytes()).unwrap(), compile(src).unwrap());
+    assert_eq!(compile_reader_with_positions(src.as_bytes(), Dialect::default()).unwrap(), compile_with_positions(src).unwrap());
+
+    // positions count chars and bytes like `compile` does, even in invalid utf-8
+    let err = compile_reader(&b"\xff\xfe\n\xc3\xa9+]"[..]).unwrap_err();
+    assert_eq!((err.line(), err.col(), err.offset(), err.kind()), (2, 3, 6, CompileErrorKind::UnexpectedRightBracket));
+
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::PermissionDenied.into())
+        }
+    }
+    let err = compile_reader(Failing).unwrap_err();
+    assert_eq!(err.kind(), CompileErrorKind::Io(io::ErrorKind::PermissionDenied));
+}
+
+#[test]
+fn test_in_bounds() {
+    use BfIR::*;
+    let ir = compile(">>+[>+<-]>>").unwrap();
+    // the loop brings the pointer back, so only the last move can leave a 4 cell tape
+    let expected: Vec<bool> = ir.iter().enumerate().map(|(i, _)| i != ir.len() - 1).collect();
+    assert_eq!(in_bounds(&ir, 0, 4), expected);
+    assert_eq!(in_bounds(&ir, 0, 5), vec![true; ir.len()]);
+    assert!(!in_bounds(&ir, 1, 5)[ir.len() - 1]);
+
+    // nothing is known past a loop that moves the pointer or a scan
+    let ir = compile("<>+[>]>").unwrap();
+    assert_eq!(in_bounds(&ir, 0, 8), [false, true, true, false, false, false, false]);
+    assert_eq!(in_bounds(&[SetVal(1), ScanRight, AddPtr(1)], 0, 8), [true, false, false]);
+
+    // a window counts at its first node
+    let ir = [AddValAt { offset: -1, val: 1 }, AddValAt { offset: 2, val: 1 }];
+    assert_eq!(in_bounds(&ir, 1, 4), [true, true]);
+    assert_eq!(in_bounds(&ir, 1, 3), [false, true]);
+    assert_eq!(in_bounds(&[ClearRange { len: 3 }, MulVal { offset: -3, factor: 2 }], 0, 3), [true, false]);
+}
+
+#[test]
+fn test_max_reach() {
+    let reach = |src| {
+        let mut ir = compile(src).unwrap();
+        let unoptimized = max_reach(&ir);
+        // folded moves may skip cells the pointer passed on the way
+        optimize(&mut ir);
+        assert!(max_reach(&ir) <= unoptimized, "{}", src);
+        unoptimized
+    };
+    assert_eq!(reach(">>>"), Some(3));
+    assert_eq!(reach("[>]"), None);
+    assert_eq!(reach(""), Some(0));
+    assert_eq!(reach(">>><<+"), Some(3));
+    assert_eq!(reach("+[->>>+<<<]>"), Some(3));
+    assert_eq!(reach("+[->[->>+<<]<]"), Some(3));
+    assert_eq!(reach(">>[-]>>[-]<"), Some(4));
+    assert_eq!(reach("+[>+<-]>[>+>]"), None);
+    assert_eq!(max_reach(&[BfIR::ReadLine]), None);
+}
+
+#[test]
+fn test_max_loop_depth() {
+    let src = "+[>[\n<[-]]]";
+    assert!(compile_with_max_loop_depth(src, 3).is_ok());
+    let err = compile_with_max_loop_depth(src, 2).unwrap_err();
+    assert_eq!((err.line(), err.col(), err.offset()), (2, 2, 6));
+    assert_eq!(err.kind(), CompileErrorKind::NestingTooDeep { depth: 2 });
+    assert_eq!(err.to_string(), "Loops nested more than 2 deep at line 2:2");
+
+    let deep = format!("{}{}", "[".repeat(DEFAULT_MAX_LOOP_DEPTH + 1), "]".repeat(DEFAULT_MAX_LOOP_DEPTH + 1));
+    let err = compile(&deep).unwrap_err();
+    assert_eq!((err.offset(), err.kind()), (DEFAULT_MAX_LOOP_DEPTH, CompileErrorKind::NestingTooDeep { depth: DEFAULT_MAX_LOOP_DEPTH }));
+    let errors = compile_all(&deep).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert!(compile(&deep[1..deep.len() - 1]).is_ok());
+}
+
+#[test]
+fn test_tokenize() {
+    use BfIR::*;
+    let tokens: Vec<_> = tokenize("+>[-]").collect();
+    assert_eq!(tokens, [
+        (Token::Op(AddVal(1)), 1, 1, 0),
+        (Token::Op(AddPtr(1)), 1, 2, 1),
+        (Token::Op(Jz), 1, 3, 2),
+        (Token::Op(SubVal(1)), 1, 4, 3),
+        (Token::Op(Jnz), 1, 5, 4),
+    ]);
+
+    // positions agree with `compile`, brackets need not match
+    let src = "ä+\n ]x.";
+    let tokens: Vec<_> = tokenize(src).collect();
+    assert_eq!(tokens, [(Token::Op(AddVal(1)), 1, 2, 2), (Token::Op(Jnz), 2, 2, 5), (Token::Op(PutByte), 2, 4, 7)]);
+    let err = compile(src).unwrap_err();
+    assert_eq!((err.line(), err.col(), err.offset()), (2, 2, 5));
+
+    let tokens: Vec<_> = tokenize(src).with_comments().collect();
+    assert_eq!(tokens, [
+        (Token::Comment { len: 2 }, 1, 1, 0),
+        (Token::Op(AddVal(1)), 1, 2, 2),
+        (Token::Comment { len: 2 }, 1, 3, 3),
+        (Token::Op(Jnz), 2, 2, 5),
+        (Token::Comment { len: 1 }, 2, 3, 6),
+        (Token::Op(PutByte), 2, 4, 7),
+    ]);
+}
+
+#[test]
+fn test_lint_comments() {
+    let src = "+++ this adds one + to the cell\n[-]>>\n> move right\na+b";
+    assert_eq!(lint_comments(src), [Warning::SuspiciousCommandInComment { pos: (1, 19), ch: '+' }]);
+    // the warning is opt-in, the `+` still compiles
+    assert_eq!(compile(src).unwrap().len(), 11);
+    assert_eq!(
+        lint_comments("[\n  -]  Print it. Then move on"),
+        [Warning::SuspiciousCommandInComment { pos: (2, 15), ch: '.' }]
+    );
+    assert_eq!(lint_comments("+[->+<]>. short, ok"), []);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_program() {
+    use std::collections::HashSet;
+
+    let program = |src| {
+        let mut ir = compile(src).unwrap();
+        optimize(&mut ir);
+        Program::new(ir)
+    };
+    // the same program after optimizing, spelled differently
+    let a = program("++[->+<]>.");
+    let b = program("+ + [ - > + < ] > . comment");
+    let c = program("+++[->+<]>.");
+    assert_eq!(a, b);
+    assert_eq!(a.content_hash(), b.content_hash());
+    assert_ne!(a, c);
+    assert_ne!(a.content_hash(), c.content_hash());
+    // operands count, not just the kinds
+    assert_ne!(Program::new(vec![BfIR::AddValAt { offset: 1, val: 2 }]), Program::new(vec![BfIR::AddValAt { offset: 2, val: 1 }]));
+
+    let set: HashSet<Program> = [a.clone(), b, c].into_iter().collect();
+    assert_eq!(set.len(), 2);
+    assert!(set.contains(&a));
+    assert_eq!(Program::from(a.clone().into_ir()), a);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_to_source() {
+    assert_eq!(to_source(&compile("+[,.]").unwrap()), "+[,.]");
+    assert_eq!(BfIR::AddPtr(5).to_string(), ">>>>>");
+    assert_eq!(BfIR::SubVal(2).to_string(), "--");
+    assert_eq!(BfIR::AddValAt { offset: -2, val: u32::MAX }.to_string(), "<<->>");
+    assert_eq!(BfIR::ClearRange { len: 3 }.to_string(), "[-]>[-]>[-]");
+    assert_eq!(to_source(&[BfIR::MulVal { offset: 1, factor: 2 }, BfIR::MulVal { offset: -1, factor: 1 }, BfIR::SetVal(0)]), "[->++<<+>]");
+
+    // optimized ir goes back to source doing the same
+    let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let mut ir = compile(src).unwrap();
+    optimize(&mut ir);
+    let output = crate::bfjit::SharedBuf::default();
+    crate::interp::Interpreter::new(
+        compile(&to_source(&ir)).unwrap(),
+        vec![0; 16].into_boxed_slice(),
+        Box::new(std::io::empty()),
+        Box::new(output.clone())
+    )
+    .run()
+    .unwrap();
+    assert_eq!(output.take(), b"Hello World!\n");
+}
+
+#[test]
+fn test_json() {
+    let mut ir = compile(include_str!("../examples/mendelbrot.bf")).unwrap();
+    optimize(&mut ir);
+    assert_eq!(from_json(&to_json(&ir)).unwrap(), ir);
+
+    let ir = vec![
+        BfIR::AddVal(3),
+        BfIR::Jz,
+        BfIR::MulVal { offset: i32::MIN, factor: u32::MAX },
+        BfIR::AddValAt { offset: -1, val: 200 },
+        BfIR::Jnz,
+        BfIR::ClearRange { len: 2 },
+        BfIR::PutByteN { count: 5 },
+        BfIR::DumpTape,
+        BfIR::ReadLine,
+        BfIR::Nop,
+    ];
+    let json = to_json(&ir);
+    assert!(json.starts_with(r#"[{"op":"addval","n":3},{"op":"jz"},{"op":"mulval","offset":-2147483648,"factor":4294967295}"#));
+    assert_eq!(from_json(&json).unwrap(), ir);
+    assert_eq!(from_json(" [ ] ").unwrap(), []);
+    // any order and whitespace
+    assert_eq!(
+        from_json("[ {\"val\": 2, \"op\": \"addvalat\", \"offset\": 1}\n]").unwrap(),
+        [BfIR::AddValAt { offset: 1, val: 2 }]
+    );
+
+    let error = |json| from_json(json).unwrap_err();
+    assert_eq!(error(r#"[{"op":"addval","n":3}"#), JsonError { offset: 22, kind: JsonErrorKind::Syntax });
+    assert_eq!(error(r#"[{"op":"addval" "n":3}]"#).kind, JsonErrorKind::Syntax);
+    assert_eq!(error(r#"[{"op":"addval","n":1.5}]"#).kind, JsonErrorKind::Syntax);
+    assert_eq!(error(r#"[{"op":"addval","n":3}] x"#).kind, JsonErrorKind::Syntax);
+    assert_eq!(error(r#"[{"op":"add"}]"#), JsonError { offset: 1, kind: JsonErrorKind::UnknownOp });
+    assert_eq!(error(r#"[{"op":"addval"}]"#).kind, JsonErrorKind::BadField);
+    assert_eq!(error(r#"[{"op":"jz","n":1}]"#).kind, JsonErrorKind::BadField);
+    assert_eq!(error(r#"[{"op":"mulval","offset":1,"val":1}]"#).kind, JsonErrorKind::BadField);
+    assert_eq!(error(r#"[{"op":"addval","n":-1}]"#).kind, JsonErrorKind::OperandOutOfRange);
+    assert_eq!(error(r#"[{"op":"addval","n":99999999999999999999}]"#).kind, JsonErrorKind::OperandOutOfRange);
+    assert_eq!(error(r#"[{"op":"jnz"}]"#).kind, JsonErrorKind::UnbalancedBrackets);
+    assert_eq!(error(r#"[{"op":"jz"}]"#).kind, JsonErrorKind::UnbalancedBrackets);
+}
+
+#[test]
+fn test_loop_pointer_delta() {
+    let delta = |src| loop_pointer_delta(&compile(src).unwrap());
+    assert_eq!(delta("->+<"), Some(0));
+    assert_eq!(delta("->+"), Some(1));
+    assert_eq!(delta("<<-"), Some(-2));
+    assert_eq!(delta(""), Some(0));
+    assert_eq!(delta("->[-]<"), None);
+    assert_eq!(delta("->+<."), None);
+    assert_eq!(loop_pointer_delta(&[BfIR::ClearRange { len: 3 }, BfIR::MulVal { offset: 4, factor: 1 }]), Some(2));
+    assert_eq!(loop_pointer_delta(&[BfIR::ScanRight]), None);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_optimize_idempotent() {
+    use crate::bfjit::{SeededInput, SharedBuf};
+    use crate::error::RuntimeError;
+    use std::io::Read;
+
+    /// a random program with balanced brackets, starting on the middle of
+    /// the tape so it moves left as often as right
+    fn random_program(seed: u64) -> String {
+        let mut rng = SeededInput::new(seed);
+        let mut byte = || {
+            let mut buf = [0];
+            rng.read_exact(&mut buf).unwrap();
+            buf[0]
+        };
+        let mut src = ">".repeat(32);
+        let mut depth = 0;
+        for _ in 0..1 + byte() % 48 {
+            match b"++--<>.,[]"[byte() as usize % 10] {
+                b'[' if depth == 3 => {}
+                b'[' => {
+                    src.push('[');
+                    depth += 1;
+                }
+                b']' if depth == 0 => {}
+                b']' => {
+                    src.push(']');
+                    depth -= 1;
+                }
+                c => src.push(c as char),
+            }
+        }
+        src.push_str(&"]".repeat(depth));
+        src
+    }
+
+    /// how the run ended, its output and the tape it left
+    fn run(ir: Vec<BfIR>) -> (Result<(), RuntimeError>, Vec<u8>, Box<[u8]>) {
+        let output = SharedBuf::default();
+        let mut interp = crate::interp::Interpreter::new(
+            ir,
+            vec![0; 64].into_boxed_slice(),
+            Box::new(SeededInput::new(1).take(8)),
+            Box::new(output.clone())
+        ).with_max_steps(10_000);
+        let ret = interp.run();
+        (ret, output.take(), interp.memory)
+    }
+
+    let mut finished = 0;
+    for seed in 0..500 {
+        let src = random_program(seed);
+        let ir = compile(&src).unwrap();
+        let mut once = ir.clone();
+        optimize(&mut once);
+        let mut twice = once.clone();
+        optimize(&mut twice);
+        assert_eq!(twice, once, "{}", src);
+
+        match (run(ir), run(once)) {
+            ((Err(RuntimeError::StepLimitExceeded { .. }), ..), _) => {}
+            ((Ok(()), out, tape), (ret, opt_out, opt_tape)) => {
+                assert!(ret.is_ok(), "{}: {:?}", src, ret);
+                assert_eq!((out, tape), (opt_out, opt_tape), "{}", src);
+                finished += 1;
+            }
+            ((Err(e), ..), (ret, ..)) => assert!(ret.is_err(), "{}: {} but not optimized", src, e),
+        }
+    }
+    // most programs are worth comparing
+    assert!(finished > 250, "{}", finished);
+}
+
+#[test]
+fn test_compile_with_regions() {
+    let dialect = Dialect { regions: true, dump_tape: true, ..Dialect::default() };
+    let (ir, positions, regions) = compile_with_regions("+#a{>#b{+}}}#\n#c{", dialect).unwrap();
+    // the `#` that starts no region still dumps, the stray `}` is skipped
+    assert_eq!(ir, [BfIR::AddVal(1), BfIR::AddPtr(1), BfIR::AddVal(1), BfIR::DumpTape]);
+    assert_eq!(positions, [(1, 1), (1, 5), (1, 9), (1, 13)]);
+    let names: Vec<_> = regions.iter().map(|r| (r.name.as_str(), r.start, r.end)).collect();
+    assert_eq!(names, [("a", (1, 2), (1, 11)), ("b", (1, 6), (1, 10)), ("c", (2, 1), (2, 4))]);
+    assert!(regions[0].contains(positions[2]) && !regions[1].contains(positions[1]));
+
+    // off, the tags are comments
+    let (ir, _, regions) = compile_with_regions("#a{+}", Dialect::default()).unwrap();
+    assert_eq!((ir, regions), (vec![BfIR::AddVal(1)], vec![]));
+}
@@ -1,206 +1,3522 @@
-use crate::bfir::{self, BfIR};
-use crate::error::{Result, RuntimeError, VMError};
+use crate::bfir::{self, ArithMode, BfIR, CellWidth, OptLevel, CompiledSource, Program, Region, SourcePos, VerifyErrorKind};
+use crate::error::{Direction, IoError, Result, RuntimeError, VMError};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use crate::interp::{dump_line, grown_len, read_line, Utf8Check};
+pub use crate::interp::{EofPolicy, TapeMode, MAX_MEM_SIZE};
 
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 use std::ptr;
 
-use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+use crate::interp::{Interpreter, StdIo};
 
-const MAX_MEM_SIZE: usize = 4 * 1024 * 1024;
+#[cfg(target_arch = "x86_64")]
+mod x64;
+#[cfg(target_arch = "x86_64")]
+mod object;
+#[cfg(any(target_arch = "aarch64", test))]
+mod aarch64;
+mod tape;
 
+use tape::Tape;
+
+/// tape size used unless configured otherwise
+pub const DEFAULT_MEM_SIZE: usize = 4 * 1024 * 1024;
+
+/// where `,` reads from, `Send` so the vm can move to another thread
+pub type Input = Box<dyn Read + Send>;
+
+/// where `.` writes to, `Send` like `Input`
+pub type Output = Box<dyn Write + Send>;
+
+/// `input` behind a `BufReader` if `buffered`
+fn buffer_input(input: Input, buffered: bool) -> Input {
+    if buffered { Box::new(std::io::BufReader::new(input)) } else { input }
+}
+
+/// how `.` hands its byte to the output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// through a buffer unless turned off with `buffered`, flushed at the
+    /// end of every run
+    #[default]
+    Buffered,
+    /// flush the output after every write, so each `.` shows up right away,
+    /// e.g. for a program prompting for input
+    Flush,
+}
+
+/// what the bytes `.` writes are meant to be
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// anything goes
+    #[default]
+    Raw,
+    /// UTF-8: a byte that makes it invalid is not written and fails the run
+    /// with `RuntimeError::InvalidUtf8`, as does a run ending in the middle
+    /// of a character
+    Utf8Validated,
+}
+
+/// a writer flushing `0` after every write
+struct FlushEach(Output);
+
+impl Write for FlushEach {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// `output` behind a `BufWriter` if `buffered`, which `BfVM::run` flushes,
+/// or flushing every write with `OutputMode::Flush`
+fn buffer_output(output: Output, buffered: bool, mode: OutputMode) -> Output {
+    match mode {
+        OutputMode::Flush => Box::new(FlushEach(output)),
+        OutputMode::Buffered if buffered => Box::new(std::io::BufWriter::new(output)),
+        OutputMode::Buffered => output
+    }
+}
+
+/// a cloneable writer collecting everything written into one buffer
+#[derive(Clone, Default)]
+pub(crate) struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    /// takes the bytes written so far
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// input arriving over a channel, a read waits for the next byte and takes
+/// what else came along, eof once the sender is gone
+struct ChannelInput(Receiver<u8>);
+
+impl Read for ChannelInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some((first, rest)) = buf.split_first_mut() else {
+            return Ok(0);
+        };
+        match self.0.recv() {
+            Ok(byte) => *first = byte,
+            Err(_) => return Ok(0)
+        }
+        let mut n = 1;
+        for (slot, byte) in rest.iter_mut().zip(self.0.try_iter()) {
+            *slot = byte;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// output sent over a channel byte by byte, a closed pipe once the receiver
+/// is gone; a byte a bounded channel has no room for within the timeout
+/// times the write out
+enum ChannelOutput {
+    Unbounded(Sender<u8>),
+    Bounded(SyncSender<u8>, Duration)
+}
+
+impl ChannelOutput {
+    fn send(&self, byte: u8) -> std::io::Result<()> {
+        let closed = |_| std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        let (sender, timeout) = match self {
+            ChannelOutput::Unbounded(sender) => return sender.send(byte).map_err(closed),
+            ChannelOutput::Bounded(sender, timeout) => (sender, *timeout)
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match sender.try_send(byte) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(_)) => return Err(std::io::ErrorKind::BrokenPipe.into()),
+                Err(TrySendError::Full(_)) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(std::io::ErrorKind::TimedOut.into());
+                    }
+                    std::thread::sleep(left.min(Duration::from_millis(1)));
+                }
+            }
+        }
+    }
+}
+
+impl Write for ChannelOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        for (sent, &byte) in buf.iter().enumerate() {
+            match self.send(byte) {
+                Ok(()) => {}
+                // the bytes that went out count, the error comes with the next write
+                Err(_) if sent > 0 => return Ok(sent),
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// the worker thread of `BfVM::spawn`, giving back the vm and how its run went
+pub type SpawnedRun = JoinHandle<(BfVM, Result<()>)>;
+
+/// an endless stream of pseudo-random bytes, the same for the same seed,
+/// e.g. as the input of a property test
+#[derive(Clone, Debug)]
+pub struct SeededInput {
+    state: u64,
+    /// bytes of the last number not read yet, lowest first
+    pending: u64,
+    left: u32,
+}
+
+impl SeededInput {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed, pending: 0, left: 0 }
+    }
+
+    /// splitmix64, which is fine with any seed including 0
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Read for SeededInput {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        for byte in buf.iter_mut() {
+            if self.left == 0 {
+                self.pending = self.next_u64();
+                self.left = 8;
+            }
+            *byte = self.pending as u8;
+            self.pending >>= 8;
+            self.left -= 1;
+        }
+        Ok(buf.len())
+    }
+}
+
+/// compile and run `src` with the default settings on `input`, returns what
+/// it wrote
+pub fn run_bytes(src: &str, input: &[u8]) -> Result<Vec<u8>> {
+    BfVM::builder().source_str(src).input_bytes(input).build()?.run_capture()
+}
+
+/// `run_bytes` with the input and output as text, output that isn't valid
+/// utf-8 is converted lossily
+pub fn run_str(src: &str, input: &str) -> Result<String> {
+    run_bytes(src, input.as_bytes()).map(|out| String::from_utf8_lossy(&out).into_owned())
+}
+
+/// settings the code generators need besides the ir
+#[derive(Clone, Copy, Default)]
+pub(crate) struct JitOptions<'a> {
+    /// per-kind execution counters, indexed by `BfIR::kind`
+    pub(crate) counters: Option<*mut u64>,
+    /// per-loop iteration counters bumped at every `Jnz`, indexed by the
+    /// order of the loop's `Jz`
+    pub(crate) loop_counters: Option<*mut u64>,
+    pub(crate) cell_width: CellWidth,
+    pub(crate) arith: ArithMode,
+    /// slot holding the remaining loop iterations, loaded on entry
+    /// and stored back on exit
+    pub(crate) steps: Option<*mut u64>,
+    pub(crate) tape_mode: TapeMode,
+    /// cells past where `tape_mode` starts the pointer it starts at instead
+    pub(crate) start_offset: usize,
+    /// instructions whose bounds checks can't fail, indexed like the ir,
+    /// see `bfir::in_bounds`, any past its end are checked
+    pub(crate) in_bounds: &'a [bool],
+    /// the block of `counters` of every instruction, see `bfir::counter_index`
+    pub(crate) regions: &'a [usize],
+}
+
+impl JitOptions<'_> {
+    /// whether the instruction at `i` needs its bounds checks
+    pub(crate) fn checked(&self, i: usize) -> bool {
+        !self.in_bounds.get(i).copied().unwrap_or(false)
+    }
+}
+
+/// `bfir::verify` with unbalanced brackets reported as `VMError::UnbalancedIr`
+fn check_ir(ir: &[BfIR]) -> Result<()> {
+    bfir::verify(ir).map_err(|e| match e.kind {
+        VerifyErrorKind::UnmatchedJnz | VerifyErrorKind::UnclosedJz => VMError::UnbalancedIr { index: e.index },
+        _ => VMError::InvalidIr(e)
+    })
+}
+
+/// the names of `regions` without repeats, and the block of the profiling
+/// counters of every instruction: that of the innermost region around its
+/// position, 1 and on in the order of the names, or 0 outside of all
+fn region_slots(positions: &[SourcePos], regions: &[Region]) -> (Vec<String>, Vec<usize>) {
+    let mut names: Vec<String> = vec![];
+    let slots: Vec<usize> = regions
+        .iter()
+        .map(|region| match names.iter().position(|name| *name == region.name) {
+            Some(i) => i + 1,
+            None => {
+                names.push(region.name.clone());
+                names.len()
+            }
+        })
+        .collect();
+    let blocks = positions
+        .iter()
+        .map(|&pos| {
+            // sorted by start, so the last one around `pos` is the innermost
+            regions.iter().zip(&slots).rev().find(|(region, _)| region.contains(pos)).map_or(0, |(_, &slot)| slot)
+        })
+        .collect();
+    (names, blocks)
+}
+
+/// the positions of the `Jz` and `Jnz` of every loop in `ir`, in the order
+/// of their `Jz`
+fn loop_spans(ir: &[BfIR], positions: &[SourcePos]) -> Vec<(SourcePos, SourcePos)> {
+    let mut spans = vec![];
+    let mut stk = vec![];
+    for (&op, &pos) in ir.iter().zip(positions) {
+        match op {
+            BfIR::Jz => {
+                stk.push(spans.len());
+                spans.push((pos, pos));
+            }
+            BfIR::Jnz => {
+                // balanced, `build_ir` checked
+                let id = stk.pop().unwrap();
+                spans[id].1 = pos;
+            }
+            _ => {}
+        }
+    }
+    spans
+}
+
+/// write `byte` `count` times, from one buffer unless that would be huge;
+/// unlike `write_all` the error tells how many of the bytes went out
+pub(crate) fn write_repeated(output: &mut dyn Write, byte: u8, count: u32) -> std::result::Result<(), IoError> {
+    const CHUNK: usize = 64 * 1024;
+    let total = count as usize;
+    let buf = vec![byte; total.min(CHUNK)];
+    let mut written = 0;
+    while written < total {
+        let n = (total - written).min(CHUNK);
+        match output.write(&buf[..n]) {
+            Ok(0) => return Err(IoError::after_writing(std::io::ErrorKind::WriteZero.into(), written, total)),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(IoError::after_writing(e, written, total))
+        }
+    }
+    Ok(())
+}
+
+/// everything the generated code depends on besides per-vm addresses
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(PartialEq, Eq, Hash)]
+struct JitKey {
+    program: Program,
+    cell_width: CellWidth,
+    arith: ArithMode,
+    tape_mode: TapeMode,
+    start_offset: usize,
+    in_bounds: Vec<bool>,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Clone)]
+struct JitCode {
+    code: Arc<dynasmrt::ExecutableBuffer>,
+    start: dynasmrt::AssemblyOffset,
+    #[cfg(target_arch = "x86_64")]
+    relocs: Vec<object::Reloc>,
+}
+
+/// timings and sizes of a vm, see `BfVmBuilder::metrics`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// parsing, optimizing and generating the code in `build`
+    pub compile_time: Duration,
+    /// the last `run`, zero before the first one
+    pub run_time: Duration,
+    /// bytes of generated code, 0 on targets without a JIT backend
+    pub code_size: usize,
+    /// instructions in the optimized ir
+    pub ir_len: usize,
+}
+
+/// how often the body of one loop ran, see `BfVM::loop_profile`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopProfile {
+    /// positions of the loop's `[` and `]`, `(0, 0)` without a source
+    pub source_span: (SourcePos, SourcePos),
+    pub iterations: u64,
+}
+
+/// generated code shared between vms built from the same optimized program
+/// with the same options, see `BfVmBuilder::jit_cache`; clones share the
+/// entries, which are never evicted
+#[derive(Clone, Default)]
+pub struct JitCache {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    entries: Arc<Mutex<HashMap<JitKey, JitCode>>>,
+}
+
+impl JitCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// number of programs compiled into the cache, always 0 on targets
+    /// without a JIT backend
+    pub fn len(&self) -> usize {
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        return self.entries.lock().unwrap().len();
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// `BfVM` is `Send`: the generated code only embeds addresses of heap
+/// allocations the vm owns (`counters`, `loop_counters`, `steps`), which
+/// stay put when the vm moves, and gets the vm itself passed in as `this`,
+/// along with the helper table holding its interrupt flag, on every `run`,
+/// so nothing in it refers to the thread that built it
 pub struct BfVM {
-    code: dynasmrt::ExecutableBuffer,
+    /// shared with other vms through a `JitCache`
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    code: Arc<dynasmrt::ExecutableBuffer>,
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
     start: dynasmrt::AssemblyOffset,
-    memory: Box<[u8]>,
-    input: Box<dyn Read>,
-    output: Box<dyn Write>
+    /// absolute addresses in `code`, for `emit_object`
+    #[cfg(target_arch = "x86_64")]
+    relocs: Vec<object::Reloc>,
+    /// the optimized program, which `recompile` compares against and the
+    /// interpreter runs on targets without a JIT backend
+    ir: Vec<BfIR>,
+    settings: CompileSettings,
+    /// source position of every ir instruction, to locate runtime errors
+    positions: Vec<SourcePos>,
+    /// the cells in native byte order
+    memory: Tape,
+    input: Input,
+    output: Output,
+    /// where `#` dumps the tape to
+    dump: Output,
+    eof_policy: EofPolicy,
+    cell_width: CellWidth,
+    #[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
+    arith: ArithMode,
+    #[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
+    tape_mode: TapeMode,
+    #[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
+    start_offset: usize,
+    stop_on_broken_pipe: bool,
+    /// write every byte `,` reads to the output too
+    echo_input: bool,
+    /// whether `set_input` and `set_output` put a buffer in front
+    buffered: bool,
+    output_mode: OutputMode,
+    /// checks the output with `OutputEncoding::Utf8Validated`
+    utf8: Option<Utf8Check>,
+    /// per-kind execution counters when profiling, one block of
+    /// `bfir::KIND_NAMES.len()` outside of all regions followed by one per
+    /// entry of `region_names`
+    counters: Option<Box<[u64]>>,
+    region_names: Vec<String>,
+    /// the block of `counters` of every instruction, see `bfir::counter_index`
+    regions: Vec<usize>,
+    /// per-loop iteration counters when profiling loops, and the `[` and `]`
+    /// of every loop, both in the order of their `Jz`
+    loop_counters: Option<Box<[u64]>>,
+    loop_spans: Vec<(SourcePos, SourcePos)>,
+    max_steps: Option<u64>,
+    /// what is left of `max_steps` while running
+    #[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64")), allow(dead_code))]
+    steps: Option<Box<u64>>,
+    /// polled at every `]`, the one given to `cancel_flag` or else one of
+    /// the vm's own, `run_with_timeout` stops the run through it
+    interrupt: Arc<AtomicBool>,
+    metrics: Option<Metrics>
+}
+
+/// the options the builder compiled the program with, for `BfVM::recompile`
+/// to compile another one the same way
+struct CompileSettings {
+    opt_level: OptLevel,
+    cell_width: CellWidth,
+    arith: ArithMode,
+    tape_mode: TapeMode,
+    start_offset: usize,
+    elide_bounds_checks: bool,
+    dialect: bfir::Dialect,
+    split_on_bang: bool,
+    profile: bool,
+    profile_loops: bool,
+    max_steps: Option<u64>,
+    cancel: Option<Arc<AtomicBool>>,
+    jit_cache: Option<JitCache>
+}
+
+impl CompileSettings {
+    /// a builder with these options, everything else left at the default
+    fn builder(&self) -> BfVmBuilder {
+        BfVmBuilder {
+            opt_level: self.opt_level,
+            cell_width: self.cell_width,
+            arith: self.arith,
+            tape_mode: self.tape_mode,
+            start_offset: self.start_offset,
+            elide_bounds_checks: self.elide_bounds_checks,
+            dialect: self.dialect,
+            split_on_bang: self.split_on_bang,
+            profile: self.profile,
+            profile_loops: self.profile_loops,
+            max_steps: self.max_steps,
+            cancel: self.cancel.clone(),
+            jit_cache: self.jit_cache.clone(),
+            ..BfVmBuilder::default()
+        }
+    }
+}
+
+/// what `BfVmBuilder::generate` makes of a program
+struct Generated {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    code: JitCode,
+    ir: Vec<BfIR>,
+    positions: Vec<SourcePos>,
+    counters: Option<Box<[u64]>>,
+    region_names: Vec<String>,
+    regions: Vec<usize>,
+    loop_counters: Option<Box<[u64]>>,
+    loop_spans: Vec<(SourcePos, SourcePos)>,
+    steps: Option<Box<u64>>
 }
 
 /// move possible error to the heap, returns a pointer to it
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 #[inline(always)]
 fn vm_error(re: RuntimeError) -> *mut VMError {
     let e = Box::new(VMError::from(re));
     Box::into_raw(e)
 }
 
+/// returned by a helper to end the run early without an error,
+/// never the address of a real error
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn stopped() -> *mut VMError {
+    ptr::NonNull::dangling().as_ptr()
+}
+
+/// declare a function with the calling convention the generated code uses,
+/// SysV on x64 and AAPCS64 on aarch64
+macro_rules! jit_fn {
+    ($(unsafe fn $name:ident $args:tt -> $ret:ty $body:block)*) => {$(
+        #[cfg(target_arch = "x86_64")]
+        unsafe extern "sysv64" fn $name $args -> $ret $body
+
+        #[cfg(not(target_arch = "x86_64"))]
+        unsafe extern "C" fn $name $args -> $ret $body
+    )*};
+}
+
+#[cfg(target_arch = "x86_64")]
+type RawFn = unsafe extern "sysv64" fn(
+    this: *mut BfVM,
+    memory_start: *mut u8,
+    memory_end: *const u8,
+    helpers: *const usize
+) -> *mut VMError;
+
+#[cfg(target_arch = "aarch64")]
+type RawFn = unsafe extern "C" fn(
+    this: *mut BfVM,
+    memory_start: *mut u8,
+    memory_end: *const u8,
+    helpers: *const usize
+) -> *mut VMError;
+
+/// the runtime functions generated code calls and the interrupt flag of the
+/// vm it polls, it is passed a table of their addresses in this order and
+/// holds no absolute address of its own for them
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Helper {
+    GetByte,
+    PutByte,
+    PutByteN,
+    OverflowLeftError,
+    OverflowRightError,
+    StepLimitError,
+    CancelledError,
+    Grow,
+    DumpTape,
+    ReadLine,
+    Interrupt,
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+impl Helper {
+    pub(crate) const ALL: [Helper; 11] = [
+        Helper::GetByte,
+        Helper::PutByte,
+        Helper::PutByteN,
+        Helper::OverflowLeftError,
+        Helper::OverflowRightError,
+        Helper::StepLimitError,
+        Helper::CancelledError,
+        Helper::Grow,
+        Helper::DumpTape,
+        Helper::ReadLine,
+        Helper::Interrupt,
+    ];
+
+    /// byte offset of its entry in the table
+    pub(crate) fn offset(self) -> i32 {
+        self as i32 * 8
+    }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
 impl BfVM {
-    unsafe extern "sysv64" fn getbyte(this: *mut Self, ptr: *mut u8) -> *mut VMError {
-        let mut buf = [0_u8];
-        let this = &mut *this;
-        match this.input.read(&mut buf) {
-            Ok(0) => {}
-            Ok(1) => *ptr = buf[0],
-            Err(e) => return vm_error(RuntimeError::IO(e)),
-            _ => unreachable!()
+    jit_fn! {
+        unsafe fn getbyte(this: *mut Self, ptr: *mut u8) -> *mut VMError {
+            let mut buf = [0_u8];
+            let this = &mut *this;
+            let width = this.cell_width;
+            let cell = std::slice::from_raw_parts_mut(ptr, width.bytes());
+            match this.input.read(&mut buf) {
+                Ok(0) => {
+                    let mut val = width.load(cell);
+                    this.eof_policy.apply(&mut val);
+                    width.store(cell, val);
+                }
+                Ok(1) => {
+                    width.store(cell, buf[0] as u32);
+                    if this.echo_input {
+                        if let Err(e) = this.output.write_all(&buf) {
+                            return this.output_error(e.into());
+                        }
+                    }
+                }
+                Err(e) => return vm_error(e.into()),
+                _ => unreachable!()
+            }
+            ptr::null_mut()
         }
-        ptr::null_mut()
-    }
 
-    unsafe extern "sysv64" fn putbyte(this: *mut Self, ptr: *mut u8) -> *mut VMError {
-        let buf = std::slice::from_ref(&*ptr);
-        let this = &mut *this;
-        match this.output.write_all(buf) {
-            Ok(()) => ptr::null_mut(),
-            Err(e) => return vm_error(RuntimeError::IO(e))
+        unsafe fn putbyte(this: *mut Self, ptr: *mut u8) -> *mut VMError {
+            let this = &mut *this;
+            // only the low byte of a wider cell is written
+            let width = this.cell_width;
+            let buf = [width.load(std::slice::from_raw_parts(ptr, width.bytes())) as u8];
+            if let Some(Err(e)) = this.utf8.as_mut().map(|check| check.write(buf[0], 1)) {
+                return vm_error(e);
+            }
+            match this.output.write_all(&buf) {
+                Ok(()) => ptr::null_mut(),
+                Err(e) => this.output_error(e.into())
+            }
         }
-    }
 
-    unsafe extern "sysv64" fn overflow_error() -> *mut VMError {
-        vm_error(RuntimeError::PointerOverflow)
-    }
+        unsafe fn putbyte_n(this: *mut Self, ptr: *mut u8, count: u32) -> *mut VMError {
+            let this = &mut *this;
+            let width = this.cell_width;
+            let byte = width.load(std::slice::from_raw_parts(ptr, width.bytes())) as u8;
+            if let Some(Err(e)) = this.utf8.as_mut().map(|check| check.write(byte, count)) {
+                return vm_error(e);
+            }
+            match write_repeated(&mut this.output, byte, count) {
+                Ok(()) => ptr::null_mut(),
+                Err(e) => this.output_error(e)
+            }
+        }
 
-    fn compile(code: &[BfIR]) -> Result<(dynasmrt::ExecutableBuffer, dynasmrt::AssemblyOffset)> {
-        let mut ops = dynasmrt::x64::Assembler::new()?;
-        let start = ops.offset();
-
-        let mut loop_stack: Vec<(dynasmrt::DynamicLabel, dynasmrt::DynamicLabel)> = vec![];
-
-        // this:         rdi r12
-        // memory_start: rsi r13
-        // memory_end:   rdx r14
-        // ptr:              r15
-
-        dynasm!(ops
-            ; push rax
-            ; push r12
-            ; push r13
-            ; push r14
-            ; push r15
-            ; mov r12, rdi   // save this
-            ; mov r13, rsi   // save memory_start
-            ; mov r14, rdx   // save memory_end
-            ; mov r15, rsi   // ptr = memory_start
-        );
-
-        use BfIR::*;
-        for &ir in code {
-            match ir {
-                AddPtr(x) => dynasm!(ops
-                    ; add r15, x as i32 // ptr += x
-                    ; jc ->overflow
-                    ; cmp r15, r14      // ptr - memory_end
-                    ; jnb ->overflow
-                ),
-                SubPtr(x) => dynasm!(ops
-                    ; sub r15, x as i32 // ptr += x
-                    ; jc ->overflow
-                    ; cmp r15, r13      // ptr - memory_start
-                    ; jb ->overflow
-                ),
-                AddVal(x) => dynasm!(ops
-                    ; add BYTE [r15], x as i8   // *ptr += x
-                ),
-                SubVal(x) => dynasm!(ops
-                    ; sub BYTE [r15], x as i8   // *ptr -= x
-                ),
-                GetByte => dynasm!(ops
-                    ; mov rdi, r12      // load this
-                    ; mov rsi, r15      // load ptr
-                    ; mov rax, QWORD BfVM::getbyte as _ // (this, ptr)
-                    ; call rax
-                    ; test rax, rax
-                    ; jnz ->io_error
-                ),
-                PutByte => dynasm!(ops
-                    ; mov rdi, r12      // load this
-                    ; mov rsi, r15      // load ptr
-                    ; mov rax, QWORD BfVM::putbyte as _ // (this, ptr)
-                    ; call rax
-                    ; test rax, rax
-                    ; jnz ->io_error
-                ),
-                Jz => {
-                    let left = ops.new_dynamic_label();
-                    let right = ops.new_dynamic_label();
-                    loop_stack.push((left, right));
-
-                    dynasm!(ops
-                        ; cmp BYTE [r15], 0
-                        ; jz => right       // jmp if *ptr == 0
-                        ; => left
-                    )
-                }
-                Jnz => {
-                    let (left, right) = loop_stack.pop().unwrap();
-                    
-                    dynasm!(ops
-                        ; cmp BYTE [r15], 0
-                        ; jnz => left       // jmp if *ptr != 0
-                        ; => right
-                    )
+        unsafe fn overflow_left_error(this: *mut Self, index: usize, offset: usize) -> *mut VMError {
+            (*this).overflow_error(Direction::Left, index, offset)
+        }
+
+        unsafe fn overflow_right_error(this: *mut Self, index: usize, offset: usize) -> *mut VMError {
+            (*this).overflow_error(Direction::Right, index, offset)
+        }
+
+        unsafe fn cancelled_error() -> *mut VMError {
+            vm_error(RuntimeError::Cancelled)
+        }
+
+        unsafe fn step_limit_error(this: *mut Self, index: usize) -> *mut VMError {
+            let steps = (*this).max_steps.unwrap_or(0);
+            let e = Box::new((*this).locate(RuntimeError::StepLimitExceeded { steps }, index));
+            Box::into_raw(e)
+        }
+
+        // grow the tape after the instruction at `index` ran off its right
+        // end, storing the new `memory_start` and `memory_end` to `bounds`
+        unsafe fn grow(this: *mut Self, index: usize, offset: usize, bounds: *mut [*mut u8; 2]) -> *mut VMError {
+            let this = &mut *this;
+            let Some(len) = grown_len(this.memory.len()) else {
+                return this.overflow_error(Direction::Right, index, offset);
+            };
+            this.memory.grow(len);
+            let start = this.memory.as_mut_ptr();
+            *bounds = [start, start.add(len)];
+            ptr::null_mut()
+        }
+
+        unsafe fn dump_tape(this: *mut Self, ptr: *mut u8) -> *mut VMError {
+            let this = &mut *this;
+            let cell = (ptr as usize - this.memory.as_ptr() as usize) / this.cell_width.bytes();
+            let line = dump_line(&this.memory, this.cell_width, cell);
+            match writeln!(this.dump, "{line}") {
+                Ok(()) => ptr::null_mut(),
+                Err(e) => vm_error(e.into())
+            }
+        }
+
+        unsafe fn read_line(this: *mut Self, ptr: *mut u8) -> *mut VMError {
+            let this = &mut *this;
+            let cell = (ptr as usize - this.memory.as_ptr() as usize) / this.cell_width.bytes();
+            let input = &mut this.input;
+            let read_byte = || {
+                let mut buf = [0_u8];
+                match input.read(&mut buf)? {
+                    0 => Ok(None),
+                    _ => Ok(Some(buf[0]))
                 }
+            };
+            match read_line(&mut this.memory, this.cell_width, cell, read_byte) {
+                Ok(()) => ptr::null_mut(),
+                Err(e) => vm_error(e)
             }
         }
+    }
 
-        dynasm!(ops
-            ; xor rax, rax
-            ; jmp >exit
-            ; -> overflow:
-            ; mov rax, QWORD BfVM::overflow_error as _
-            ; call rax
-            ; jmp >exit
-            ; -> io_error:
-            ; exit:
-            ; pop r15
-            ; pop r14
-            ; pop r13
-            ; pop r12
-            ; pop rdx
-            ; ret
-        );
-
-        let code = ops.finalize().unwrap();
-        Ok((code, start))
-    }
-
-    pub fn new(
-        file_path: &Path,
-        input: Box<dyn Read>,
-        output: Box<dyn Write>,
-        optimize: bool
-    ) -> Result<Self> {
-        let src = std::fs::read_to_string(file_path)?;
-        let mut ir = bfir::compile(&src)?;
-        drop(src);
-
-        if optimize {
-            bfir::optimize(&mut ir);
-        }
-        let (code, start) = Self::compile(&ir)?;
-        drop(ir);
-        
-        let memory = vec![0; MAX_MEM_SIZE].into_boxed_slice();
-        Ok(Self {
-            code,
-            start,
-            memory,
-            input,
-            output
+    /// a failed write, which just stops the run on a closed pipe if asked to
+    fn output_error(&self, e: IoError) -> *mut VMError {
+        if self.stop_on_broken_pipe && e.kind() == std::io::ErrorKind::BrokenPipe {
+            stopped()
+        }
+        else {
+            vm_error(RuntimeError::from_output(e))
+        }
+    }
+
+    /// `offset` is the byte offset of the pointer from `memory_start` before
+    /// the failing instruction
+    fn overflow_error(&self, direction: Direction, index: usize, offset: usize) -> *mut VMError {
+        let at = offset / self.cell_width.bytes();
+        let e = Box::new(self.locate(RuntimeError::PointerOverflow { at, direction }, index));
+        Box::into_raw(e)
+    }
+
+    /// the table of `Helper` addresses passed to the generated code
+    fn helper_table(&self) -> [usize; Helper::ALL.len()] {
+        Helper::ALL.map(|helper| match helper {
+            Helper::GetByte => BfVM::getbyte as *const () as usize,
+            Helper::PutByte => BfVM::putbyte as *const () as usize,
+            Helper::PutByteN => BfVM::putbyte_n as *const () as usize,
+            Helper::OverflowLeftError => BfVM::overflow_left_error as *const () as usize,
+            Helper::OverflowRightError => BfVM::overflow_right_error as *const () as usize,
+            Helper::StepLimitError => BfVM::step_limit_error as *const () as usize,
+            Helper::CancelledError => BfVM::cancelled_error as *const () as usize,
+            Helper::Grow => BfVM::grow as *const () as usize,
+            Helper::DumpTape => BfVM::dump_tape as *const () as usize,
+            Helper::ReadLine => BfVM::read_line as *const () as usize,
+            Helper::Interrupt => Arc::as_ptr(&self.interrupt) as usize,
         })
     }
+}
 
-    pub fn run(&mut self) -> Result<()> {
-        type RawFn = unsafe extern "sysv64" fn(
-            this: *mut BfVM,
-            memory_start: *mut u8,
-            memory_end: *const u8
-        ) -> *mut VMError;
+/// where the program comes from
+enum Source {
+    Path(PathBuf),
+    Str(String),
+    Ir(Vec<BfIR>),
+}
 
-        let raw_fn: RawFn = unsafe { std::mem::transmute(self.code.ptr(self.start)) };
+/// configures and builds a `BfVM`
+pub struct BfVmBuilder {
+    source: Option<Source>,
+    input: Input,
+    output: Output,
+    dump: Output,
+    mem_size: usize,
+    opt_level: OptLevel,
+    eof_policy: EofPolicy,
+    cell_width: CellWidth,
+    arith: ArithMode,
+    tape_mode: TapeMode,
+    start_offset: usize,
+    elide_bounds_checks: bool,
+    dialect: bfir::Dialect,
+    split_on_bang: bool,
+    stop_on_broken_pipe: bool,
+    echo_input: bool,
+    buffered: bool,
+    output_mode: OutputMode,
+    output_encoding: OutputEncoding,
+    profile: bool,
+    profile_loops: bool,
+    max_steps: Option<u64>,
+    cancel: Option<Arc<AtomicBool>>,
+    jit_cache: Option<JitCache>,
+    /// code generated up front for the ir by `BfVM::from_ir_many`
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    precompiled: Option<JitCode>,
+    lazy_tape: bool,
+    fit_tape: bool,
+    metrics: bool
+}
 
-        let this: *mut Self = self;
-        let memory_start = self.memory.as_mut_ptr();
-        let memory_end = unsafe { memory_start.add(MAX_MEM_SIZE) };
+impl Default for BfVmBuilder {
+    fn default() -> Self {
+        Self {
+            source: None,
+            input: Box::new(std::io::stdin()),
+            output: Box::new(std::io::stdout()),
+            dump: Box::new(std::io::stderr()),
+            mem_size: DEFAULT_MEM_SIZE,
+            opt_level: OptLevel::Full,
+            eof_policy: EofPolicy::Unchanged,
+            cell_width: CellWidth::W8,
+            arith: ArithMode::Wrapping,
+            tape_mode: TapeMode::Fixed,
+            start_offset: 0,
+            elide_bounds_checks: true,
+            dialect: bfir::Dialect::default(),
+            split_on_bang: false,
+            stop_on_broken_pipe: false,
+            echo_input: false,
+            buffered: true,
+            output_mode: OutputMode::Buffered,
+            output_encoding: OutputEncoding::Raw,
+            profile: false,
+            profile_loops: false,
+            max_steps: None,
+            cancel: None,
+            jit_cache: None,
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            precompiled: None,
+            lazy_tape: false,
+            fit_tape: false,
+            metrics: false
+        }
+    }
+}
+
+impl BfVmBuilder {
+    /// read the program from a file
+    pub fn source_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.source = Some(Source::Path(path.as_ref().to_owned()));
+        self
+    }
 
-        let ret = unsafe { raw_fn(this, memory_start, memory_end) };
+    /// take the program from a string
+    pub fn source_str(mut self, src: &str) -> Self {
+        self.source = Some(Source::Str(src.to_owned()));
+        self
+    }
 
-        if ret.is_null() {
-            Ok(())
+    /// run already compiled ir, e.g. from `bfir::deserialize`, which must have
+    /// balanced brackets, runtime errors can't point into the source then
+    pub fn source_ir(mut self, ir: Vec<BfIR>) -> Self {
+        self.source = Some(Source::Ir(ir));
+        self
+    }
+
+    /// defaults to stdin
+    pub fn input(mut self, input: Input) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// read a copy of `bytes`, then eof
+    pub fn input_bytes(self, bytes: &[u8]) -> Self {
+        self.input(Box::new(std::io::Cursor::new(bytes.to_vec())))
+    }
+
+    /// defaults to stdout
+    pub fn output(mut self, output: Output) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// where `#` dumps the tape to with `dump_tape`, defaults to stderr
+    pub fn dump_output(mut self, dump: Output) -> Self {
+        self.dump = dump;
+        self
+    }
+
+    /// number of cells, defaults to `DEFAULT_MEM_SIZE`
+    pub fn mem_size(mut self, mem_size: usize) -> Self {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// defaults to `OptLevel::Full`
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// defaults to `EofPolicy::Unchanged`
+    pub fn eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.eof_policy = eof_policy;
+        self
+    }
+
+    /// defaults to `CellWidth::W8`, the tape of `mem_size` cells
+    /// must not take more than `MAX_MEM_SIZE` bytes
+    pub fn cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    /// defaults to `ArithMode::Wrapping`
+    pub fn arith_mode(mut self, arith: ArithMode) -> Self {
+        self.arith = arith;
+        self
+    }
+
+    /// defaults to `TapeMode::Fixed`
+    pub fn tape_mode(mut self, tape_mode: TapeMode) -> Self {
+        self.tape_mode = tape_mode;
+        self
+    }
+
+    /// start the pointer `offset` cells right of where the tape mode starts
+    /// it, e.g. to leave scratch cells left of it, defaults to 0; building
+    /// fails with `VMError::InvalidStartOffset` if that is off the tape
+    pub fn start_offset(mut self, offset: usize) -> Self {
+        self.start_offset = offset;
+        self
+    }
+
+    /// leave out the bounds checks of pointer moves and accesses that are
+    /// proven to stay on the tape, on by default
+    pub fn elide_bounds_checks(mut self, elide: bool) -> Self {
+        self.elide_bounds_checks = elide;
+        self
+    }
+
+    /// fail to build with `CompileErrorKind::NestingTooDeep` on loops nested
+    /// more than `depth` deep, defaults to `bfir::DEFAULT_MAX_LOOP_DEPTH`
+    pub fn max_loop_depth(mut self, depth: usize) -> Self {
+        self.dialect.max_loop_depth = depth;
+        self
+    }
+
+    /// make `#` print the cells around the pointer, see `dump_output`,
+    /// instead of treating it as a comment, off by default
+    pub fn dump_tape(mut self, dump: bool) -> Self {
+        self.dialect.dump_tape = dump;
+        self
+    }
+
+    /// make `;` read a line into the cells from the pointer on, followed by
+    /// how many bytes it read, instead of treating it as a comment, off by
+    /// default
+    pub fn read_line(mut self, read_line: bool) -> Self {
+        self.dialect.read_line = read_line;
+        self
+    }
+
+    /// read `#name{ ... }` as a region named `name` whose instructions
+    /// `BfVM::region_ops` counts apart when profiling, instead of treating
+    /// it as comments, off by default
+    pub fn regions(mut self, regions: bool) -> Self {
+        self.dialect.regions = regions;
+        self
+    }
+
+    /// end the program at the first `!` of the source and read the bytes
+    /// after it before the configured input, off by default
+    pub fn split_on_bang(mut self, split: bool) -> Self {
+        self.split_on_bang = split;
+        self
+    }
+
+    /// end the run like the program did once the output is a closed pipe,
+    /// e.g. when piped into `head`, instead of failing with `BrokenPipe`
+    pub fn stop_on_broken_pipe(mut self, stop: bool) -> Self {
+        self.stop_on_broken_pipe = stop;
+        self
+    }
+
+    /// write every byte `,` reads to the output as well, as a terminal
+    /// echoes what is typed, off by default
+    pub fn echo_input(mut self, echo: bool) -> Self {
+        self.echo_input = echo;
+        self
+    }
+
+    /// read the input and write the output through buffers, flushed at
+    /// the end of every run, on by default; turn it off for programs
+    /// prompting for input, whose prompt would only show up after the run
+    pub fn buffered(mut self, buffered: bool) -> Self {
+        self.buffered = buffered;
+        self
+    }
+
+    /// with `OutputMode::Flush` every `.` is flushed to the output right
+    /// away, `OutputMode::Buffered` by default
+    pub fn output_mode(mut self, output_mode: OutputMode) -> Self {
+        self.output_mode = output_mode;
+        self
+    }
+
+    /// with `OutputEncoding::Utf8Validated` every run has to write UTF-8,
+    /// `OutputEncoding::Raw` by default
+    pub fn output_encoding(mut self, encoding: OutputEncoding) -> Self {
+        self.output_encoding = encoding;
+        self
+    }
+
+    /// count executed instructions per kind, see `BfVM::executed_ops`,
+    /// off by default as it slows down every instruction
+    pub fn profile(mut self, profile: bool) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// count how often the body of every loop runs, see `BfVM::loop_profile`,
+    /// off by default as it slows down every `]`
+    pub fn profile_loops(mut self, profile_loops: bool) -> Self {
+        self.profile_loops = profile_loops;
+        self
+    }
+
+    /// stop with `RuntimeError::StepLimitExceeded` once `]` ran more than
+    /// `max_steps` times in one run, at the `]` that would have gone over,
+    /// unlimited by default
+    pub fn max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// stop with `RuntimeError::Cancelled` at the next `]` once `cancel` is set,
+    /// e.g. from another thread; `BfVM::run_with_timeout` sets it to stop a
+    /// run too, and clears it again, the vm leaves it alone otherwise
+    pub fn cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// put the tape in anonymous memory the os zeroes page by page as the
+    /// program first touches it instead of zeroing it all up front, which
+    /// saves time and memory for a large tape of which a program only uses a
+    /// bit; falls back to the heap where that can't be mapped, off by default
+    pub fn lazy_tape(mut self, lazy_tape: bool) -> Self {
+        self.lazy_tape = lazy_tape;
+        self
+    }
+
+    /// allocate just the cells the optimized program can reach on a fixed
+    /// tape when `bfir::max_reach` bounds them below `mem_size`, so `memory`
+    /// and the `#` dumps show no more than those; off by default
+    pub fn fit_tape(mut self, fit_tape: bool) -> Self {
+        self.fit_tape = fit_tape;
+        self
+    }
+
+    /// take the generated code from `cache` if a vm with the same optimized
+    /// program and options was built through it before, and put it there
+    /// otherwise; vms built with `profile` or `max_steps` compile their own
+    /// code, which refers to the counters they own
+    pub fn jit_cache(mut self, cache: &JitCache) -> Self {
+        self.jit_cache = Some(cache.clone());
+        self
+    }
+
+    /// record how long building and running took, see `BfVM::metrics`,
+    /// off by default
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn build(mut self) -> Result<BfVM> {
+        let started = self.metrics.then(Instant::now);
+        if self.mem_size == 0 || self.mem_size > MAX_MEM_SIZE / self.cell_width.bytes() {
+            return Err(VMError::InvalidMemSize(self.mem_size));
+        }
+        if self.tape_mode.start_cell(self.mem_size).checked_add(self.start_offset).is_none_or(|start| start >= self.mem_size) {
+            return Err(VMError::InvalidStartOffset(self.start_offset));
+        }
+
+        let (ir, positions, regions) = match self.source.take() {
+            // stream the file instead of holding all of it
+            Some(Source::Path(path)) => self.compile_reader(std::fs::File::open(path)?)?,
+            Some(Source::Str(src)) => self.compile_reader(std::io::Cursor::new(src.into_bytes()))?,
+            Some(Source::Ir(ir)) => {
+                let positions = vec![(0, 0); ir.len()];
+                (ir, positions, vec![])
+            }
+            None => return Err(VMError::MissingSource)
+        };
+        let mut vm = self.build_ir(ir, positions, &regions)?;
+        if let Some(started) = started {
+            vm.metrics = Some(Metrics {
+                compile_time: started.elapsed(),
+                run_time: Duration::ZERO,
+                #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+                code_size: vm.code.len(),
+                #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+                code_size: 0,
+                ir_len: vm.positions.len(),
+            });
+        }
+        Ok(vm)
+    }
+
+    /// compile the source from `reader`, with `split_on_bang` the data after
+    /// the first `!` goes in front of the input
+    fn compile_reader(&mut self, reader: impl Read + Send + 'static) -> Result<CompiledSource> {
+        if !self.split_on_bang {
+            return Ok(bfir::compile_reader_with_regions(reader, self.dialect)?);
+        }
+        let mut reader = std::io::BufReader::new(reader);
+        let (compiled, bang) = bfir::compile_until_bang(&mut reader, self.dialect)?;
+        if bang {
+            let input = std::mem::replace(&mut self.input, Box::new(std::io::empty()));
+            self.input = Box::new(reader.chain(input));
+        }
+        Ok(compiled)
+    }
+
+    fn build_ir(self, mut ir: Vec<BfIR>, mut positions: Vec<SourcePos>, regions: &[Region]) -> Result<BfVM> {
+        check_ir(&ir)?;
+        self.optimize(&mut ir, &mut positions);
+        self.build_optimized(ir, positions, regions)
+    }
+
+    fn optimize(&self, ir: &mut Vec<BfIR>, positions: &mut Vec<SourcePos>) {
+        if self.tape_mode == TapeMode::Wrapping {
+            bfir::Optimizer::for_ring(self.opt_level, self.cell_width, self.arith).run_with_positions(ir, positions);
         }
         else {
-            Err(*unsafe { Box::from_raw(ret) })
+            bfir::optimize_with_arith(ir, positions, self.opt_level, self.cell_width, self.arith);
         }
     }
-}
\ No newline at end of file
+
+    /// everything of the vm for the optimized `ir` but its tape and io, with
+    /// the code checking the bounds of a tape of `mem_size` cells
+    fn generate(&mut self, ir: Vec<BfIR>, positions: Vec<SourcePos>, regions: &[Region], mem_size: usize) -> Result<Generated> {
+        let (region_names, regions) = if self.profile { region_slots(&positions, regions) } else { (vec![], vec![]) };
+        // the boxed counters stay put, so the generated code can refer to them directly
+        let mut counters = self.profile.then(|| vec![0; bfir::KIND_NAMES.len() * (1 + region_names.len())].into_boxed_slice());
+        let loop_spans = if self.profile_loops { loop_spans(&ir, &positions) } else { vec![] };
+        let mut loop_counters = self.profile_loops.then(|| vec![0; loop_spans.len()].into_boxed_slice());
+        let mut steps = self.max_steps.map(Box::new);
+        // a wrapping pointer is not where `in_bounds` expects it
+        let in_bounds = if self.elide_bounds_checks && self.tape_mode != TapeMode::Wrapping {
+            bfir::in_bounds(&ir, self.tape_mode.start_cell(mem_size) + self.start_offset, mem_size)
+        }
+        else {
+            vec![]
+        };
+        let opts = JitOptions {
+            counters: counters.as_mut().map(|c| c.as_mut_ptr()),
+            loop_counters: loop_counters.as_mut().map(|c| c.as_mut_ptr()),
+            cell_width: self.cell_width,
+            arith: self.arith,
+            steps: steps.as_deref_mut().map(|s| s as *mut u64),
+            tape_mode: self.tape_mode,
+            start_offset: self.start_offset,
+            in_bounds: &in_bounds,
+            regions: &regions,
+        };
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        let code = match &self.jit_cache {
+            _ if self.precompiled.is_some() => self.precompiled.take().unwrap(),
+            Some(cache) if counters.is_none() && loop_counters.is_none() && steps.is_none() => {
+                let key = JitKey {
+                    program: Program::new(ir.clone()),
+                    cell_width: self.cell_width,
+                    arith: self.arith,
+                    tape_mode: self.tape_mode,
+                    start_offset: self.start_offset,
+                    in_bounds: in_bounds.clone(),
+                };
+                let mut entries = cache.entries.lock().unwrap();
+                if let Some(cached) = entries.get(&key) {
+                    cached.clone()
+                }
+                else {
+                    let compiled = BfVM::compile(key.program.ir(), opts)?;
+                    entries.insert(key, compiled.clone());
+                    compiled
+                }
+            }
+            _ => BfVM::compile(&ir, opts)?
+        };
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        let _ = opts;
+
+        Ok(Generated {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            code,
+            ir,
+            positions,
+            counters,
+            region_names,
+            regions,
+            loop_counters,
+            loop_spans,
+            steps
+        })
+    }
+
+    fn build_optimized(mut self, ir: Vec<BfIR>, positions: Vec<SourcePos>, regions: &[Region]) -> Result<BfVM> {
+        let mem_size = match bfir::max_reach(&ir) {
+            Some(reach) if self.fit_tape && self.tape_mode == TapeMode::Fixed => self.mem_size.min(self.start_offset + reach + 1),
+            _ => self.mem_size
+        };
+        let Generated {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            code: JitCode {
+                code,
+                start,
+                #[cfg(target_arch = "x86_64")]
+                relocs
+            },
+            ir,
+            positions,
+            counters,
+            region_names,
+            regions,
+            loop_counters,
+            loop_spans,
+            steps
+        } = self.generate(ir, positions, regions, mem_size)?;
+
+        let len = mem_size * self.cell_width.bytes();
+        let memory = if self.lazy_tape { Tape::lazy(len) } else { Tape::heap(len) };
+        Ok(BfVM {
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            code,
+            #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+            start,
+            #[cfg(target_arch = "x86_64")]
+            relocs,
+            ir,
+            positions,
+            memory,
+            input: buffer_input(self.input, self.buffered),
+            output: buffer_output(self.output, self.buffered, self.output_mode),
+            dump: self.dump,
+            eof_policy: self.eof_policy,
+            cell_width: self.cell_width,
+            arith: self.arith,
+            tape_mode: self.tape_mode,
+            start_offset: self.start_offset,
+            stop_on_broken_pipe: self.stop_on_broken_pipe,
+            echo_input: self.echo_input,
+            buffered: self.buffered,
+            output_mode: self.output_mode,
+            utf8: (self.output_encoding == OutputEncoding::Utf8Validated).then(Utf8Check::default),
+            settings: CompileSettings {
+                opt_level: self.opt_level,
+                cell_width: self.cell_width,
+                arith: self.arith,
+                tape_mode: self.tape_mode,
+                start_offset: self.start_offset,
+                elide_bounds_checks: self.elide_bounds_checks,
+                dialect: self.dialect,
+                split_on_bang: self.split_on_bang,
+                profile: self.profile,
+                profile_loops: self.profile_loops,
+                max_steps: self.max_steps,
+                cancel: self.cancel.clone(),
+                jit_cache: self.jit_cache
+            },
+            counters,
+            region_names,
+            regions,
+            loop_counters,
+            loop_spans,
+            max_steps: self.max_steps,
+            steps,
+            interrupt: self.cancel.unwrap_or_default(),
+            metrics: None
+        })
+    }
+}
+
+impl BfVM {
+    /// generate the code for the host
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn compile(ir: &[BfIR], opts: JitOptions) -> Result<JitCode> {
+        #[cfg(target_arch = "x86_64")]
+        let (code, start, relocs) = BfVM::compile_x64(ir, opts)?;
+        #[cfg(target_arch = "aarch64")]
+        let (code, start) = BfVM::compile_aarch64(ir, opts)?;
+        Ok(JitCode {
+            code: Arc::new(code),
+            start,
+            #[cfg(target_arch = "x86_64")]
+            relocs
+        })
+    }
+
+    /// compile every program in `programs` as is into one buffer, so they
+    /// share one allocation, each with the default options and all of its
+    /// bounds checks; returns the buffer and where each program is entered
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn compile_many(programs: &[&[BfIR]]) -> Result<(dynasmrt::ExecutableBuffer, Vec<dynasmrt::AssemblyOffset>)> {
+        #[cfg(target_arch = "x86_64")]
+        let mut ops = dynasmrt::x64::Assembler::new()?;
+        #[cfg(target_arch = "aarch64")]
+        let mut ops = dynasmrt::aarch64::Assembler::new()?;
+        let mut entries = Vec::with_capacity(programs.len());
+        for ir in programs {
+            check_ir(ir)?;
+            // nothing to relocate without counters or steps
+            #[cfg(target_arch = "x86_64")]
+            entries.push(BfVM::emit_x64(&mut ops, ir, JitOptions::default(), &mut vec![])?);
+            #[cfg(target_arch = "aarch64")]
+            entries.push(BfVM::emit_aarch64(&mut ops, ir, JitOptions::default())?);
+        }
+        ops.commit().map_err(|e| VMError::Assemble(e.to_string()))?;
+        let code = ops.finalize().map_err(|_| VMError::Assemble("code buffer in use".into()))?;
+        Ok((code, entries))
+    }
+
+    /// like `from_ir` for every program in `programs`, with the code of all of
+    /// them generated by `compile_many` and shared between the vms; they read
+    /// from stdin and write to stdout until given their own io with
+    /// `set_input` and `set_output`
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn from_ir_many(programs: &[&[BfIR]]) -> Result<Vec<Self>> {
+        let (code, entries) = BfVM::compile_many(programs)?;
+        let code = Arc::new(code);
+        programs
+            .iter()
+            .zip(entries)
+            .map(|(ir, start)| {
+                let mut builder = Self::builder().source_ir(ir.to_vec()).opt_level(OptLevel::None);
+                builder.precompiled = Some(JitCode {
+                    code: code.clone(),
+                    start,
+                    #[cfg(target_arch = "x86_64")]
+                    relocs: vec![]
+                });
+                builder.build()
+            })
+            .collect()
+    }
+
+    pub fn builder() -> BfVmBuilder {
+        BfVmBuilder::default()
+    }
+
+    /// the program at `file_path`, fully optimized if `optimize`, with the
+    /// defaults of `builder` for everything else
+    pub fn new(file_path: &Path, input: Input, output: Output, optimize: bool) -> Result<Self> {
+        Self::builder()
+            .source_path(file_path)
+            .input(input)
+            .output(output)
+            .opt_level(if optimize { OptLevel::Full } else { OptLevel::None })
+            .build()
+    }
+
+    /// like `new`, but takes the program itself instead of a file
+    pub fn from_source(
+        src: &str,
+        input: Input,
+        output: Output,
+        opt_level: OptLevel
+    ) -> Result<Self> {
+        Self::builder()
+            .source_str(src)
+            .input(input)
+            .output(output)
+            .opt_level(opt_level)
+            .build()
+    }
+
+    /// attach the ir index and source position of the failing instruction
+    fn locate(&self, error: RuntimeError, index: usize) -> VMError {
+        let (line, col) = self.positions[index];
+        VMError::RuntimeAt { error, index, line, col }
+    }
+
+    /// run `ir` as is, without parsing or optimizing it again
+    pub fn from_ir(ir: Vec<BfIR>, input: Input, output: Output) -> Result<Self> {
+        Self::builder()
+            .source_ir(ir)
+            .input(input)
+            .output(output)
+            .opt_level(OptLevel::None)
+            .build()
+    }
+
+    /// write the generated code as an ELF relocatable object, exporting it as
+    /// `bf_main(this, memory_start, memory_end, helpers)` with the SysV calling
+    /// convention, along with `bf_helpers`, the table to pass as `helpers`
+    ///
+    /// the helpers the table points to and the profiling and limit slots the
+    /// code uses become undefined symbols like `bf_getbyte`, which the program
+    /// linking the object has to provide, `bf_interrupt` among them is a byte
+    /// the code polls at every `]` and stops once it is set; the addresses of
+    /// the slots are absolute, so an object using them links best into a
+    /// non-PIE executable
+    #[cfg(target_arch = "x86_64")]
+    pub fn emit_object(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, object::write_elf(&self.code, self.start.0, &self.relocs))?;
+        Ok(())
+    }
+
+    /// the tape as left behind by the last run, wider cells take
+    /// several bytes each in native byte order, see `cell`
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// the value of cell `index`, `None` if out of range
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        let bytes = self.cell_width.bytes();
+        let start = index.checked_mul(bytes)?;
+        Some(self.cell_width.load(self.memory.get(start..start.checked_add(bytes)?)?))
+    }
+
+    /// `len` bytes of the tape starting at `start`, `None` if out of range
+    pub fn memory_range(&self, start: usize, len: usize) -> Option<&[u8]> {
+        self.memory.get(start..start.checked_add(len)?)
+    }
+
+    /// zero the tape so the program can run again from a clean state
+    pub fn reset(&mut self) {
+        self.memory.clear();
+        if let Some(counters) = &mut self.counters {
+            counters.fill(0);
+        }
+        if let Some(loop_counters) = &mut self.loop_counters {
+            loop_counters.fill(0);
+        }
+    }
+
+    /// the generated machine code, e.g. to feed a disassembler
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn code_bytes(&self) -> &[u8] {
+        &self.code
+    }
+
+    /// where in `code_bytes` the code is entered
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn entry_offset(&self) -> usize {
+        self.start.0
+    }
+
+    /// `None` unless built with `metrics`
+    pub fn metrics(&self) -> Option<Metrics> {
+        self.metrics
+    }
+
+    /// how many instructions of each kind ran so far, `None` unless built with `profile`
+    pub fn executed_ops(&self) -> Option<HashMap<&'static str, u64>> {
+        let counters = self.counters.as_deref()?;
+        let mut total = [0; bfir::KIND_NAMES.len()];
+        for block in counters.chunks(total.len()) {
+            total.iter_mut().zip(block).for_each(|(t, n)| *t += n);
+        }
+        Some(bfir::named_counts(&total))
+    }
+
+    /// `executed_ops` of every region, see `BfVmBuilder::regions`, counting
+    /// the instructions of nested regions in the innermost alone and those
+    /// of regions with the same name together, `None` unless built with
+    /// `profile`
+    pub fn region_ops(&self) -> Option<HashMap<String, HashMap<&'static str, u64>>> {
+        let counters = self.counters.as_deref()?;
+        let blocks = counters.chunks(bfir::KIND_NAMES.len()).skip(1);
+        Some(self.region_names.iter().cloned().zip(blocks.map(bfir::named_counts)).collect())
+    }
+
+    /// how often the body of every loop left after optimizing ran so far,
+    /// the hottest first, `None` unless built with `profile_loops`
+    pub fn loop_profile(&self) -> Option<Vec<LoopProfile>> {
+        let loop_counters = self.loop_counters.as_deref()?;
+        let mut profile: Vec<LoopProfile> = self.loop_spans
+            .iter()
+            .zip(loop_counters)
+            .map(|(&source_span, &iterations)| LoopProfile { source_span, iterations })
+            .collect();
+        // stable, so equally hot loops stay in source order
+        profile.sort_by_key(|l| std::cmp::Reverse(l.iterations));
+        Some(profile)
+    }
+
+    /// compile `src` in place of the program with the options the builder
+    /// compiled it with, keeping the tape as it is along with the io; with
+    /// `split_on_bang` the source ends at its first `!` and the rest is
+    /// dropped. when the optimized program comes out the same, e.g. after an
+    /// edit to a comment, the generated code is kept as well and only the
+    /// source positions change. returns whether new code was generated, which
+    /// starts the profiling counters over
+    pub fn recompile(&mut self, src: &str) -> Result<bool> {
+        let mut builder = self.settings.builder();
+        let (mut ir, mut positions, regions) = if builder.split_on_bang {
+            bfir::compile_until_bang(&mut src.as_bytes(), builder.dialect)?.0
+        }
+        else {
+            bfir::compile_with_regions(src, builder.dialect)?
+        };
+        check_ir(&ir)?;
+        builder.optimize(&mut ir, &mut positions);
+        // moving instructions in or out of a region changes what they count towards
+        let same_regions = !builder.profile || {
+            let (names, slots) = region_slots(&positions, &regions);
+            names == self.region_names && slots == self.regions
+        };
+        if ir == self.ir && same_regions {
+            if self.loop_counters.is_some() {
+                self.loop_spans = loop_spans(&ir, &positions);
+            }
+            self.positions = positions;
+            return Ok(false);
+        }
+
+        // only the code is generated again, for the tape there is
+        let mem_size = self.memory.len() / self.cell_width.bytes();
+        let generated = builder.generate(ir, positions, &regions, mem_size)?;
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            self.code = generated.code.code;
+            self.start = generated.code.start;
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.relocs = generated.code.relocs;
+        }
+        self.ir = generated.ir;
+        self.positions = generated.positions;
+        self.counters = generated.counters;
+        self.region_names = generated.region_names;
+        self.regions = generated.regions;
+        self.loop_counters = generated.loop_counters;
+        self.loop_spans = generated.loop_spans;
+        self.steps = generated.steps;
+        Ok(true)
+    }
+
+    /// replace the input, e.g. before running again
+    pub fn set_input(&mut self, input: Input) {
+        self.input = buffer_input(input, self.buffered);
+    }
+
+    /// replace the output, e.g. before running again
+    pub fn set_output(&mut self, output: Output) {
+        self.output = buffer_output(output, self.buffered, self.output_mode);
+    }
+
+    /// run with the output collected into a buffer instead of the configured sink,
+    /// which is put back afterwards
+    pub fn run_capture(&mut self) -> Result<Vec<u8>> {
+        let buf = SharedBuf::default();
+        let output = std::mem::replace(&mut self.output, Box::new(buf.clone()));
+        let ret = self.run();
+        self.output = output;
+        ret.map(|()| buf.take())
+    }
+
+    /// run once for every `(input, output)` pair on a freshly reset tape,
+    /// collecting the results; the configured input and output are put back
+    /// afterwards
+    pub fn run_n(&mut self, runs: impl IntoIterator<Item = (Input, Output)>) -> Vec<Result<()>> {
+        let input = std::mem::replace(&mut self.input, Box::new(std::io::empty()));
+        let output = std::mem::replace(&mut self.output, Box::new(std::io::sink()));
+        let results = runs
+            .into_iter()
+            .map(|(input, output)| {
+                self.reset();
+                self.set_input(input);
+                self.set_output(output);
+                self.run()
+            })
+            .collect();
+        self.input = input;
+        self.output = output;
+        results
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        let started = self.metrics.is_some().then(Instant::now);
+        let ret = self.run_code();
+        // flush what was written before an error as well
+        let flushed = self.output.flush();
+        let checked = self.utf8.as_mut().map_or(Ok(()), Utf8Check::finish);
+        if let (Some(metrics), Some(started)) = (&mut self.metrics, started) {
+            metrics.run_time = started.elapsed();
+        }
+        ret?;
+        checked?;
+        match flushed {
+            Err(e) if !(self.stop_on_broken_pipe && e.kind() == std::io::ErrorKind::BrokenPipe) => Err(RuntimeError::from(e).into()),
+            _ => Ok(())
+        }
+    }
+
+    /// like `run`, but stop with `RuntimeError::Timeout` once it took longer
+    /// than `timeout`; the run is stopped through the flag it polls, the cancel
+    /// flag if there is one, so it only stops at the next `]`, a long stretch
+    /// without loops or waiting for input runs past the deadline
+    pub fn run_with_timeout(&mut self, timeout: Duration) -> Result<()> {
+        let (done, finished) = std::sync::mpsc::channel::<()>();
+        let timer = {
+            let interrupt = self.interrupt.clone();
+            std::thread::spawn(move || {
+                // the sender is dropped once the run is over
+                let timed_out = finished.recv_timeout(timeout) == Err(RecvTimeoutError::Timeout);
+                if timed_out {
+                    interrupt.store(true, Ordering::Relaxed);
+                }
+                timed_out
+            })
+        };
+        let ret = self.run();
+        drop(done);
+        if !timer.join().unwrap() {
+            return ret;
+        }
+        self.interrupt.store(false, Ordering::Relaxed);
+        match ret {
+            Err(VMError::Runtime(RuntimeError::Cancelled)) => Err(RuntimeError::Timeout.into()),
+            ret => ret
+        }
+    }
+
+    /// run on a worker thread, reading `,` from what is sent to the returned
+    /// sender, eof once it is dropped, and passing every byte `.` writes to
+    /// the returned receiver right away, which ends with the run; joining
+    /// the handle gives back the vm, with its input and output emptied, and
+    /// how the run went
+    pub fn spawn(self) -> (Sender<u8>, Receiver<u8>, SpawnedRun) {
+        let (to_host, output) = std::sync::mpsc::channel();
+        self.spawn_with(ChannelOutput::Unbounded(to_host), output)
+    }
+
+    /// like `spawn`, but the returned receiver holds at most `capacity` bytes
+    /// not taken yet, a `.` finding it full waits for room and fails the run
+    /// with `RuntimeError::OutputTimeout` if none is made within `timeout`
+    pub fn spawn_bounded(self, capacity: usize, timeout: Duration) -> (Sender<u8>, Receiver<u8>, SpawnedRun) {
+        let (to_host, output) = std::sync::mpsc::sync_channel(capacity);
+        self.spawn_with(ChannelOutput::Bounded(to_host, timeout), output)
+    }
+
+    fn spawn_with(mut self, to_host: ChannelOutput, output: Receiver<u8>) -> (Sender<u8>, Receiver<u8>, SpawnedRun) {
+        let (input, from_host) = std::sync::mpsc::channel();
+        self.input = Box::new(ChannelInput(from_host));
+        self.output = Box::new(to_host);
+        let handle = std::thread::spawn(move || {
+            let ret = self.run();
+            // hang up on the host
+            self.input = Box::new(std::io::empty());
+            self.output = Box::new(std::io::sink());
+            (self, ret)
+        });
+        (input, output, handle)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn run_code(&mut self) -> Result<()> {
+        // lend the tape and io to an interpreter for the duration of the run
+        let io = StdIo {
+            input: std::mem::replace(&mut self.input, Box::new(std::io::empty())),
+            output: std::mem::replace(&mut self.output, Box::new(std::io::sink())),
+            dump: std::mem::replace(&mut self.dump, Box::new(std::io::sink())),
+            echo_input: self.echo_input,
+            utf8: self.utf8.take()
+        };
+        let mut interp = Interpreter::with_io(self.ir.clone(), std::mem::take(&mut self.memory).into_boxed(), io)
+            .with_eof_policy(self.eof_policy).with_cell_width(self.cell_width).with_tape_mode(self.tape_mode)
+            .with_start_offset(self.start_offset)
+            .with_arith_mode(self.arith);
+        if let Some(max_steps) = self.max_steps {
+            interp = interp.with_max_steps(max_steps);
+        }
+        interp = interp.with_cancel_flag(self.interrupt.clone());
+        interp.counters = self.counters.take();
+        interp.regions = self.regions.clone();
+        if let Some(loop_counters) = self.loop_counters.take() {
+            interp = interp.with_loop_profiling();
+            interp.loop_counters = Some(loop_counters);
+        }
+        let ret = interp.run();
+        let pc = interp.pc();
+        let memory;
+        Interpreter {
+            memory,
+            io: StdIo { input: self.input, output: self.output, dump: self.dump, utf8: self.utf8, .. },
+            counters: self.counters,
+            loop_counters: self.loop_counters,
+            ..
+        } = interp;
+        self.memory = Tape::Heap(memory);
+        match ret {
+            Err(e @ (RuntimeError::PointerOverflow { .. } | RuntimeError::StepLimitExceeded { .. })) => Err(self.locate(e, pc)),
+            Err(RuntimeError::IO(e)) if self.stop_on_broken_pipe && e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            ret => Ok(ret?)
+        }
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn run_code(&mut self) -> Result<()> {
+        let raw_fn: RawFn = unsafe { std::mem::transmute(self.code.ptr(self.start)) };
+        if let (Some(steps), Some(max_steps)) = (&mut self.steps, self.max_steps) {
+            **steps = max_steps;
+        }
+
+        let this: *mut Self = self;
+        let memory_start = self.memory.as_mut_ptr();
+        let memory_end = unsafe { memory_start.add(self.memory.len()) };
+
+        let helpers = self.helper_table();
+
+        let ret = unsafe { raw_fn(this, memory_start, memory_end, helpers.as_ptr()) };
+
+        if ret.is_null() || ret == stopped() {
+            Ok(())
+        }
+        else {
+            Err(*unsafe { Box::from_raw(ret) })
+        }
+    }
+}
+
+/// programs the other backends are checked against the JIT with, as
+/// `(source, input, mem_size)`
+#[cfg(test)]
+pub(crate) const BACKEND_SAMPLES: [(&str, &str, usize); 13] = [
+    ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", "", 16),
+    (",[.,]", "echo", 16),
+    ("+,.,.", "a", 16),
+    ("+++++.....[->+>+>+<<<]>>>.", "", 4),
+    ("+>+>+<<[-]>[-]>[-]+.", "", 4),
+    (">>[-]>[-]", "", 4),
+    (">>>>", "", 4),
+    ("<", "", 4),
+    ("+[>+]", "", 4),
+    ("+>+>+>+[<]<", "", 4),
+    (">>>+[->+<]", "", 4),
+    (">+<<+", "", 4),
+    (MANDELBROT, "", 30000),
+];
+
+/// the largest of `BACKEND_SAMPLES`, prints the mandelbrot set
+#[cfg(test)]
+pub(crate) const MANDELBROT: &str = include_str!("../examples/mendelbrot.bf");
+
+/// one of `BACKEND_SAMPLES` optimized for a cell width, with how the JIT
+/// ran it
+#[cfg(test)]
+pub(crate) struct BackendSample {
+    pub(crate) src: &'static str,
+    pub(crate) input: &'static [u8],
+    pub(crate) mem_size: usize,
+    pub(crate) width: CellWidth,
+    pub(crate) ir: Vec<BfIR>,
+    /// the runtime error the run stopped with, if any, and the index of
+    /// the instruction raising it
+    pub(crate) error: Option<(RuntimeError, usize)>,
+    pub(crate) output: Vec<u8>,
+    pub(crate) memory: Vec<u8>
+}
+
+/// every one of `BACKEND_SAMPLES` at every cell width, run on the JIT with
+/// `EofPolicy::Zero`
+#[cfg(test)]
+pub(crate) fn backend_samples() -> impl Iterator<Item = BackendSample> {
+    [CellWidth::W8, CellWidth::W16, CellWidth::W32].into_iter().flat_map(|width| {
+        BACKEND_SAMPLES.into_iter().map(move |(src, input, mem_size)| {
+            let mut ir = bfir::compile(src).unwrap();
+            let mut positions = vec![(0, 0); ir.len()];
+            bfir::optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, width);
+            let output = SharedBuf::default();
+            let mut vm = BfVM::builder()
+                .source_ir(ir.clone())
+                .mem_size(mem_size)
+                .cell_width(width)
+                .eof_policy(EofPolicy::Zero)
+                .input_bytes(input.as_bytes())
+                .output(Box::new(output.clone()))
+                .build()
+                .unwrap();
+            let error = match vm.run() {
+                Ok(()) => None,
+                Err(VMError::RuntimeAt { error, index, .. }) => Some((error, index)),
+                Err(e) => panic!("{}: {}", src, e)
+            };
+            BackendSample {
+                src,
+                input: input.as_bytes(),
+                mem_size,
+                width,
+                ir,
+                error,
+                output: output.take(),
+                memory: vm.memory().to_vec()
+            }
+        })
+    })
+}
+
+#[test]
+fn test_run() {
+    let samples: [(&str, &[u8], &[u8]); 4] = [
+        (",[.[-],]", b"hello", b"hello"),
+        ("++++++++[->++++++++<]>+.[-]", b"", b"A"),
+        ("++++[->+>++<<]>>>+++>++<<[-<+++++++++++++++>]<.", b"", b"|"),
+        (
+            "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.",
+            b"",
+            b"Hello World!\n"
+        ),
+    ];
+
+    for (i, (src, input, expected)) in samples.into_iter().enumerate() {
+        let path = std::env::temp_dir().join(format!("bfjit_test_run_{}_{}.bf", std::process::id(), i));
+        std::fs::write(&path, src).unwrap();
+
+        for opt_level in [OptLevel::None, OptLevel::Basic, OptLevel::Full] {
+            let output = SharedBuf::default();
+            let mut vm = BfVM::builder()
+                .source_path(&path)
+                .input_bytes(input)
+                .output(Box::new(output.clone()))
+                .opt_level(opt_level)
+                .build()
+                .unwrap();
+            vm.run().unwrap();
+            assert_eq!(output.take(), expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn test_mem_size() {
+    let path = std::env::temp_dir().join(format!("bfjit_test_mem_size_{}.bf", std::process::id()));
+
+    let run = |src: &str, mem_size| {
+        std::fs::write(&path, src).unwrap();
+        BfVM::builder()
+            .source_path(&path)
+            .input(Box::new(std::io::empty()))
+            .output(Box::new(std::io::sink()))
+            .opt_level(OptLevel::None)
+            .mem_size(mem_size)
+            .build()
+            .and_then(|mut vm| vm.run())
+    };
+
+    run(">>>+", 4).unwrap();
+    assert!(matches!(run(">>>>", 4), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, .. })));
+    run("+", 1).unwrap();
+    assert!(matches!(run(">", 1), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, .. })));
+
+    assert!(matches!(run("+", 0), Err(VMError::InvalidMemSize(0))));
+    assert!(matches!(
+        run("+", MAX_MEM_SIZE + 1),
+        Err(VMError::InvalidMemSize(size)) if size == MAX_MEM_SIZE + 1
+    ));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_eof_policy() {
+    let path = std::env::temp_dir().join(format!("bfjit_test_eof_policy_{}.bf", std::process::id()));
+    std::fs::write(&path, "+++,.").unwrap();
+
+    for (policy, expected) in [
+        (EofPolicy::Unchanged, 3),
+        (EofPolicy::Zero, 0),
+        (EofPolicy::NegativeOne, 255),
+    ] {
+        let output = SharedBuf::default();
+        let mut vm = BfVM::builder()
+            .source_path(&path)
+            .input(Box::new(std::io::empty()))
+            .output(Box::new(output.clone()))
+            .eof_policy(policy)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!(output.take(), [expected]);
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_builder() {
+    let output = SharedBuf::default();
+    let mut vm = BfVM::builder()
+        .source_str("++++++++[->++++++++<]>+.+.")
+        .input(Box::new(std::io::empty()))
+        .output(Box::new(output.clone()))
+        .mem_size(2)
+        .build()
+        .unwrap();
+    vm.run().unwrap();
+    assert_eq!(output.take(), *b"AB");
+
+    let err = BfVM::builder().build().err().unwrap();
+    assert!(matches!(err, VMError::MissingSource));
+    assert_eq!(err.to_string(), "No source given, set one with `source_path` or `source_str`");
+
+    match BfVM::builder().source_str("[[[]]]").max_loop_depth(2).build() {
+        Err(VMError::Compile(e)) => assert_eq!((e.col(), e.kind()), (3, bfir::CompileErrorKind::NestingTooDeep { depth: 2 })),
+        _ => panic!()
+    }
+}
+
+#[test]
+fn test_split_on_bang() {
+    let run = |src: &str, split| {
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .split_on_bang(split)
+            .input_bytes(b"?")
+            .eof_policy(EofPolicy::Zero)
+            .build()
+            .unwrap();
+        vm.run_capture().unwrap()
+    };
+    assert_eq!(run("/code/,[.,]!INPUT", true), b"INPUT?");
+    // `,` and `.` past the `!` are data, not commands
+    assert_eq!(run(",[.,]!,.", true), b",.?");
+    assert_eq!(run(",[.,]!INPUT", false), b"?");
+    assert_eq!(run(",[.,]", true), b"?");
+
+    let err = BfVM::builder().source_str("[!]").split_on_bang(true).build().err().unwrap();
+    assert!(matches!(err, VMError::Compile(e) if e.kind() == bfir::CompileErrorKind::UnclosedLeftBracket));
+}
+
+#[test]
+fn test_from_source() {
+    let output = SharedBuf::default();
+    let mut vm = BfVM::from_source(
+        "++.",
+        Box::new(std::io::empty()),
+        Box::new(output.clone()),
+        OptLevel::Full
+    ).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output.take(), [2]);
+
+    assert!(matches!(
+        BfVM::from_source("[", Box::new(std::io::empty()), Box::new(std::io::sink()), OptLevel::Full),
+        Err(VMError::Compile(_))
+    ));
+}
+
+#[test]
+fn test_run_capture() {
+    let output = SharedBuf::default();
+    let mut vm = BfVM::builder()
+        .source_str(">[-]<[-]++++++++[->+++++++++<]>.<+++++[->++++++<]>-.+++++++..+++.")
+        .output(Box::new(output.clone()))
+        .build()
+        .unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"Hello");
+    assert!(output.take().is_empty());
+
+    // the configured sink is back in place
+    vm.run().unwrap();
+    assert_eq!(output.take(), b"Hello");
+
+    let mut vm = BfVM::builder().source_str(".<").build().unwrap();
+    assert!(matches!(vm.run_capture(), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, .. })));
+}
+
+#[test]
+fn test_reset() {
+    // sums the input bytes into cell 1 and prints it
+    let mut vm = BfVM::builder()
+        .source_str(",[[->+<],]>.")
+        .input(Box::new(&b"\x01\x02"[..]))
+        .eof_policy(EofPolicy::Zero)
+        .build()
+        .unwrap();
+    assert_eq!(vm.run_capture().unwrap(), [3]);
+
+    vm.reset();
+    let output = SharedBuf::default();
+    vm.set_input(Box::new(&b"\x04"[..]));
+    vm.set_output(Box::new(output.clone()));
+    vm.run().unwrap();
+    assert_eq!(output.take(), [4]);
+}
+
+#[test]
+fn test_memory() {
+    let mut vm = BfVM::builder().source_str("+++>>-").mem_size(16).build().unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.memory()[0], 3);
+    assert_eq!(vm.memory().len(), 16);
+    assert_eq!(vm.memory_range(0, 3), Some(&[3, 0, 255][..]));
+    assert_eq!(vm.memory_range(15, 1), Some(&[0][..]));
+    assert_eq!(vm.memory_range(15, 2), None);
+    assert_eq!(vm.memory_range(usize::MAX, 2), None);
+}
+
+#[test]
+fn test_executed_ops() {
+    let mut vm = BfVM::builder()
+        .source_str("+++[>++<-]")
+        .opt_level(OptLevel::None)
+        .profile(true)
+        .build()
+        .unwrap();
+    assert_eq!(vm.executed_ops(), Some(HashMap::new()));
+
+    vm.run().unwrap();
+    let counts = vm.executed_ops().unwrap();
+    assert_eq!(
+        counts,
+        HashMap::from([
+            ("addval", 3 + 3 * 2),
+            ("jz", 1),
+            ("addptr", 3),
+            ("subptr", 3),
+            ("subval", 3),
+            ("jnz", 3),
+        ])
+    );
+
+    vm.reset();
+    assert_eq!(vm.executed_ops(), Some(HashMap::new()));
+
+    let mut vm = BfVM::builder().source_str("+").build().unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.executed_ops(), None);
+}
+
+#[test]
+fn test_region_ops() {
+    let build = |src: &str, regions| {
+        BfVM::builder().source_str(src).opt_level(OptLevel::None).profile(true).regions(regions).build().unwrap()
+    };
+    let mut vm = build("+#hot{++[-]}+", true);
+    vm.run().unwrap();
+    let hot = HashMap::from([("addval", 2), ("jz", 1), ("subval", 3), ("jnz", 3)]);
+    assert_eq!(vm.region_ops().unwrap(), HashMap::from([("hot".to_string(), hot)]));
+    assert_eq!(vm.executed_ops().unwrap()["addval"], 4);
+
+    // nested regions count towards the innermost, repeated names together
+    let mut vm = build("#a{+#b{+}+}\n#a{+}", true);
+    vm.run().unwrap();
+    let ops = vm.region_ops().unwrap();
+    assert_eq!((ops["a"]["addval"], ops["b"]["addval"]), (3, 1));
+
+    // renaming a region leaves the program the same but not what it counts
+    assert!(vm.recompile("#a{+#c{+}+}\n#a{+}").unwrap());
+    vm.run().unwrap();
+    assert_eq!(vm.region_ops().unwrap()["c"]["addval"], 1);
+
+    // off, the tags are comments
+    let mut vm = build("+#hot{++}", false);
+    assert_eq!(vm.run_capture().unwrap(), []);
+    assert_eq!(vm.region_ops(), Some(HashMap::new()));
+    assert_eq!(vm.cell(0), Some(3));
+}
+
+#[test]
+fn test_error_position() {
+    // unoptimized, the third `<` fails, otherwise the folded run does
+    let src = "+++[->+<]>>.\n  <<<<";
+    for (opt_level, expected) in [(OptLevel::None, 5), (OptLevel::Basic, 3), (OptLevel::Full, 3)] {
+        let mut vm = BfVM::builder().source_str(src).opt_level(opt_level).build().unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(
+            err,
+            VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, line: 2, col, .. } if col == expected
+        ), "{:?}: {}", opt_level, err);
+    }
+
+    let mut vm = BfVM::builder().source_str(">\n<<").opt_level(OptLevel::None).build().unwrap();
+    let err = vm.run().unwrap_err();
+    assert!(matches!(err, VMError::RuntimeAt { index: 2, line: 2, col: 2, .. }));
+    assert_eq!(err.to_string(), "Runtime: Pointer overflow to the left of cell 0 at line 2:2");
+
+    // a bounds check on a folded window points at its start
+    let mut vm = BfVM::builder().source_str("+.\n>>+<+<<+>").mem_size(3).build().unwrap();
+    assert!(matches!(vm.run(), Err(VMError::RuntimeAt { line: 2, col: 1, .. })));
+}
+
+#[test]
+fn test_helper_table() {
+    let mut vm = BfVM::builder().source_str(",.").input_bytes(b"a").build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"a");
+
+    let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let mut vm = BfVM::builder().source_str(hello).build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"Hello World!\n");
+
+    // the code doesn't embed the address of any helper as an immediate
+    #[cfg(target_arch = "x86_64")]
+    for addr in vm.helper_table() {
+        assert!(!vm.code.windows(8).any(|w| w == addr.to_le_bytes()));
+    }
+}
+
+#[test]
+fn test_input_bytes() {
+    let mut vm = BfVM::builder().source_str(",.").input_bytes(b"xyz").build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"x");
+
+    let mut vm = BfVM::builder().source_str(",[.,]").input_bytes(b"xyz").eof_policy(EofPolicy::Zero).build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"xyz");
+}
+
+#[test]
+fn test_send() {
+    let output = SharedBuf::default();
+    let mut vm = BfVM::builder()
+        .source_str(",[.,]")
+        .input(Box::new(std::io::Cursor::new(b"thread".to_vec())))
+        .output(Box::new(output.clone()))
+        .eof_policy(EofPolicy::Zero)
+        .profile(true)
+        .max_steps(100)
+        .build()
+        .unwrap();
+    let handle = std::thread::spawn(move || {
+        vm.run().unwrap();
+        vm
+    });
+    let mut vm = handle.join().unwrap();
+    assert_eq!(output.take(), b"thread");
+    assert_eq!(vm.executed_ops().unwrap()["putbyte"], 6);
+
+    // and back, running again on this thread
+    vm.set_input(Box::new(&b"back"[..]));
+    vm.run().unwrap();
+    assert_eq!(output.take(), b"back");
+}
+
+#[test]
+fn test_clear_range() {
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        let src = "+>+>+>+>+<<<<[-]>[-]>[-]>+";
+        let mut vm = BfVM::builder().source_str(src).cell_width(width).mem_size(5).build().unwrap();
+        vm.run().unwrap();
+        let tape: Vec<u32> = (0..5).map(|i| vm.cell(i).unwrap()).collect();
+        assert_eq!(tape, [0, 0, 0, 2, 1], "{:?}", width);
+
+        // the last cell is past the end of the tape
+        let mut vm = BfVM::builder().source_str(">>[-]>[-]>[-]").cell_width(width).mem_size(4).build().unwrap();
+        assert!(matches!(
+            vm.run(),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, line: 1, col: 3, .. })
+        ));
+    }
+}
+
+#[test]
+fn test_scan() {
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        // the first zero right of the start is at cell 3
+        let mut vm = BfVM::builder().source_str("+>+>+<<[>]++").cell_width(width).mem_size(8).build().unwrap();
+        vm.run().unwrap();
+        let tape: Vec<u32> = (0..5).map(|i| vm.cell(i).unwrap()).collect();
+        assert_eq!(tape, [1, 1, 1, 2, 0], "{:?}", width);
+
+        let mut vm = BfVM::builder().source_str(">>>>+<+<+[<]++").cell_width(width).mem_size(8).build().unwrap();
+        vm.run().unwrap();
+        let tape: Vec<u32> = (0..5).map(|i| vm.cell(i).unwrap()).collect();
+        assert_eq!(tape, [0, 2, 1, 1, 1], "{:?}", width);
+
+        // no zero left in range
+        for (src, col) in [("+>+>+[>]", 6), (">+<+[<]", 5)] {
+            let mut vm = BfVM::builder().source_str(src).cell_width(width).mem_size(3).build().unwrap();
+            match vm.run() {
+                Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, line: 1, col: c, .. }) => assert_eq!(c, col),
+                ret => panic!("{:?} {:?}", src, ret.err())
+            }
+        }
+    }
+
+    let mut ir = bfir::compile("[>][<][>>]").unwrap();
+    bfir::optimize(&mut ir);
+    assert_eq!(ir[..2], [BfIR::ScanRight, BfIR::ScanLeft]);
+}
+
+#[test]
+fn test_cell_width() {
+    // 256 increments wrap back to 0 in an 8-bit cell only
+    let src = format!("{}[[-]>+<]>.", "+".repeat(256));
+    for opt_level in [OptLevel::None, OptLevel::Basic, OptLevel::Full] {
+        for (width, expected) in [(CellWidth::W8, 0), (CellWidth::W16, 1), (CellWidth::W32, 1)] {
+            let mut vm = BfVM::builder()
+                .source_str(&src)
+                .opt_level(opt_level)
+                .cell_width(width)
+                .build()
+                .unwrap();
+            assert_eq!(vm.run_capture().unwrap(), [expected], "{:?} {:?}", opt_level, width);
+        }
+    }
+
+    // wrapping and multiply loops at the full width, `.` writes the low byte
+    let src = format!("{}.[-]->+++[->>++++<<]>>.", "+".repeat(300));
+    for (width, cells) in [
+        (CellWidth::W8, [255, 0, 0, 12]),
+        (CellWidth::W16, [65535, 0, 0, 12]),
+        (CellWidth::W32, [u32::MAX, 0, 0, 12]),
+    ] {
+        for opt_level in [OptLevel::None, OptLevel::Full] {
+            let mut vm = BfVM::builder()
+                .source_str(&src)
+                .opt_level(opt_level)
+                .cell_width(width)
+                .mem_size(4)
+                .build()
+                .unwrap();
+            assert_eq!(vm.run_capture().unwrap(), [44, 12]);
+            let tape: Vec<u32> = (0..4).map(|i| vm.cell(i).unwrap()).collect();
+            assert_eq!(tape, cells, "{:?} {:?}", opt_level, width);
+            assert_eq!(vm.memory().len(), 4 * width.bytes());
+            assert_eq!(vm.cell(4), None);
+        }
+    }
+
+    // folded offsets wrap at the full width as well
+    let mut vm = BfVM::builder().source_str(">->+<<->").cell_width(CellWidth::W16).mem_size(3).build().unwrap();
+    vm.run().unwrap();
+    assert_eq!((vm.cell(0), vm.cell(1), vm.cell(2)), (Some(0xffff), Some(0xffff), Some(1)));
+
+    // `,` stores all bits on eof, and the tape still ends where it should
+    let mut vm = BfVM::builder()
+        .source_str(",>,")
+        .input(Box::new(&b"\x07"[..]))
+        .eof_policy(EofPolicy::NegativeOne)
+        .cell_width(CellWidth::W16)
+        .mem_size(2)
+        .build()
+        .unwrap();
+    vm.run().unwrap();
+    assert_eq!((vm.cell(0), vm.cell(1)), (Some(7), Some(0xffff)));
+    let mut vm = BfVM::builder().source_str(">>").cell_width(CellWidth::W32).mem_size(2).build().unwrap();
+    assert!(matches!(vm.run(), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, .. })));
+
+    assert!(matches!(
+        BfVM::builder().source_str("+").cell_width(CellWidth::W32).mem_size(MAX_MEM_SIZE).build(),
+        Err(VMError::InvalidMemSize(MAX_MEM_SIZE))
+    ));
+}
+
+#[test]
+fn test_max_steps() {
+    for opt_level in [OptLevel::None, OptLevel::Full] {
+        let mut vm = BfVM::builder().source_str("+[]").opt_level(opt_level).max_steps(1000).build().unwrap();
+        let err = vm.run().unwrap_err();
+        assert!(matches!(err, VMError::RuntimeAt { error: RuntimeError::StepLimitExceeded { steps: 1000 }, line: 1, col: 3, .. }));
+        assert_eq!(err.to_string(), "Runtime: Step limit exceeded after 1000 steps at line 1:3");
+    }
+
+    // the loop runs its `]` 10 times, the budget is per run
+    let mut vm = BfVM::builder().source_str("++++++++++[-]").opt_level(OptLevel::None).max_steps(10).build().unwrap();
+    vm.run().unwrap();
+    vm.run().unwrap();
+    let mut vm = BfVM::builder().source_str("++++++++++[-]").opt_level(OptLevel::None).max_steps(9).build().unwrap();
+    assert!(matches!(vm.run(), Err(VMError::RuntimeAt { error: RuntimeError::StepLimitExceeded { steps: 9 }, .. })));
+
+    // the error tells the budget and the `]` that ran out of it, with the
+    // cell showing how far the loop got
+    let src = "+++++\n[->++\n<]";
+    let mut vm = BfVM::builder().source_str(src).opt_level(OptLevel::None).max_steps(3).build().unwrap();
+    let err = vm.run().unwrap_err();
+    assert!(matches!(err, VMError::RuntimeAt { error: RuntimeError::StepLimitExceeded { steps: 3 }, index: 11, line: 3, col: 2 }), "{}", err);
+    assert_eq!((vm.cell(0), vm.cell(1)), (Some(1), Some(8)));
+}
+
+#[test]
+fn test_cancel_flag() {
+    use std::sync::atomic::Ordering;
+
+    for opt_level in [OptLevel::None, OptLevel::Full] {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let handle = std::thread::spawn(move || {
+            BfVM::builder()
+                .source_str("+[]")
+                .opt_level(opt_level)
+                .cancel_flag(flag)
+                .build()
+                .unwrap()
+                .run()
+        });
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        cancel.store(true, Ordering::Relaxed);
+        let err = handle.join().unwrap().unwrap_err();
+        assert!(matches!(err, VMError::Runtime(RuntimeError::Cancelled)), "{}", err);
+    }
+
+    // a set flag stops before the first iteration repeats
+    let output = SharedBuf::default();
+    let mut vm = BfVM::builder()
+        .source_str("+[.]")
+        .output(Box::new(output.clone()))
+        .cancel_flag(Arc::new(AtomicBool::new(true)))
+        .build()
+        .unwrap();
+    assert!(matches!(vm.run(), Err(VMError::Runtime(RuntimeError::Cancelled))));
+    assert_eq!(output.take(), [1]);
+}
+
+#[test]
+fn test_from_ir() {
+    let mut ir = bfir::compile("++++++++[->++++++++<]>+.+.").unwrap();
+    bfir::optimize(&mut ir);
+    let ir = bfir::deserialize(&bfir::serialize(&ir)).unwrap();
+
+    let output = SharedBuf::default();
+    let mut vm = BfVM::from_ir(ir, Box::new(std::io::empty()), Box::new(output.clone())).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output.take(), *b"AB");
+
+    // ir put together by hand
+    let input = Box::new(std::io::Cursor::new(b"x".to_vec()));
+    let mut vm = BfVM::from_ir(vec![BfIR::GetByte, BfIR::PutByte], input, Box::new(output.clone())).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output.take(), *b"x");
+
+    // the builder optimizes ir as well
+    let mut vm = BfVM::builder()
+        .source_ir(vec![BfIR::AddVal(1), BfIR::AddVal(1), BfIR::PutByte])
+        .profile(true)
+        .build()
+        .unwrap();
+    assert_eq!(vm.run_capture().unwrap(), [2]);
+    assert_eq!(vm.executed_ops().unwrap()["addval"], 1);
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn test_emit_object() {
+    let vm = BfVM::builder().source_str(",[.,]").build().unwrap();
+    let path = std::env::temp_dir().join(format!("bfjit_test_object_{}.o", std::process::id()));
+    vm.emit_object(&path).unwrap();
+    let elf = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let u16_at = |at: usize| u16::from_le_bytes(elf[at..at + 2].try_into().unwrap()) as usize;
+    let u32_at = |at: usize| u32::from_le_bytes(elf[at..at + 4].try_into().unwrap()) as usize;
+    let u64_at = |at: usize| u64::from_le_bytes(elf[at..at + 8].try_into().unwrap()) as usize;
+    let c_str = |at: usize| {
+        let end = at + elf[at..].iter().position(|&b| b == 0).unwrap();
+        std::str::from_utf8(&elf[at..end]).unwrap()
+    };
+
+    assert_eq!(elf[..4], *b"\x7fELF");
+    assert_eq!((u16_at(16), u16_at(18)), (1, 62));
+
+    // (name, offset, size) of every section
+    let shoff = u64_at(40);
+    let shstrtab = shoff + 64 * u16_at(62);
+    let sections: Vec<(&str, usize, usize)> = (0..u16_at(60))
+        .map(|i| {
+            let sh = shoff + 64 * i;
+            (c_str(u64_at(shstrtab + 24) + u32_at(sh)), u64_at(sh + 24), u64_at(sh + 32))
+        })
+        .collect();
+    let section = |name| *sections.iter().find(|s| s.0 == name).unwrap();
+
+    let text = section(".text");
+    assert_eq!(text.2, vm.code.len());
+
+    let (_, symtab, symtab_size) = section(".symtab");
+    let (_, strtab, _) = section(".strtab");
+    let symbols: Vec<&str> = (0..symtab_size / 24).map(|i| c_str(strtab + u32_at(symtab + 24 * i))).collect();
+    assert_eq!(
+        symbols,
+        [
+            "",
+            "bf_main",
+            "bf_helpers",
+            "bf_getbyte",
+            "bf_putbyte",
+            "bf_putbyte_n",
+            "bf_overflow_left_error",
+            "bf_overflow_right_error",
+            "bf_step_limit_error",
+            "bf_cancelled_error",
+            "bf_grow",
+            "bf_dump_tape",
+            "bf_read_line",
+            "bf_interrupt"
+        ]
+    );
+    // bf_main points at the entry
+    assert_eq!(u64_at(symtab + 24 + 8), vm.start.0);
+    assert_eq!(section(".rela.text").2, 24 * vm.relocs.len());
+    // the helpers are only reached through the table
+    assert!(vm.relocs.is_empty());
+    assert_eq!(section(".data.rel.ro").2, 8 * Helper::ALL.len());
+    assert_eq!(section(".rela.data.rel.ro").2, 24 * Helper::ALL.len());
+}
+
+#[test]
+fn test_overflow_direction() {
+    use crate::interp::Interpreter;
+
+    // 4 cells, the pointer position an overflow is reported at and its direction
+    let samples = [
+        (">>>>", OptLevel::None, 3, Direction::Right),
+        (">>>>", OptLevel::Full, 0, Direction::Right),
+        ("><<", OptLevel::None, 0, Direction::Left),
+        (">>>+[<<<<]", OptLevel::Full, 3, Direction::Left),
+        ("+>+>+[<]", OptLevel::Full, 2, Direction::Left),
+        ("+>+>+>+<[>]", OptLevel::Full, 2, Direction::Right),
+        (">+[-<<+>>]", OptLevel::Full, 1, Direction::Left),
+        (">>>+[->+<]", OptLevel::Full, 3, Direction::Right),
+        (">>+>>+", OptLevel::Full, 0, Direction::Right),
+        (">+<<+", OptLevel::Full, 0, Direction::Left),
+        (">>>[-]>[-]", OptLevel::Full, 3, Direction::Right),
+    ];
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        for (src, opt_level, at, direction) in samples {
+            let mut vm = BfVM::builder().source_str(src).opt_level(opt_level).cell_width(width).mem_size(4).build().unwrap();
+            match vm.run() {
+                Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: a, direction: d }, .. }) => {
+                    assert_eq!((a, d), (at, direction), "{} {:?}", src, width)
+                }
+                ret => panic!("{} {:?}: {:?}", src, width, ret)
+            }
+
+            let mut ir = bfir::compile(src).unwrap();
+            let mut positions = vec![(0, 0); ir.len()];
+            bfir::optimize_with_positions(&mut ir, &mut positions, opt_level, width);
+            let mut interp = Interpreter::new(
+                ir,
+                vec![0; 4 * width.bytes()].into_boxed_slice(),
+                Box::new(std::io::empty()),
+                Box::new(std::io::sink())
+            ).with_cell_width(width);
+            assert!(matches!(
+                interp.run(),
+                Err(RuntimeError::PointerOverflow { at: a, direction: d }) if (a, d) == (at, direction)
+            ), "{} {:?}", src, width);
+        }
+    }
+}
+
+#[test]
+fn test_growable() {
+    use crate::interp::Interpreter;
+
+    // each walks past the 4 cells of the tape in another way
+    let samples = [
+        ">>>>>>>>>>+.",
+        ">>>+[-]>[-]>[-]>[-]>[-]+.",
+        "+[->>>>>>>>>>+<<<<<<<<<<]>>>>>>>>>>.",
+        "+>+>+>+>>>+<<<<<<[>]+.",
+        "+>>>>+<<<<[>>>>>>>+<<<<<<<-]>>>>>>>.",
+    ];
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        for src in samples {
+            let build = |tape_mode| BfVM::builder()
+                .source_str(src)
+                .cell_width(width)
+                .mem_size(4)
+                .tape_mode(tape_mode)
+                .build()
+                .unwrap();
+            assert!(matches!(
+                build(TapeMode::Fixed).run_capture(),
+                Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { direction: Direction::Right, .. }, .. })
+            ), "{} {:?}", src, width);
+            let mut vm = build(TapeMode::Growable);
+            assert_eq!(vm.run_capture().unwrap(), [1], "{} {:?}", src, width);
+            assert!(vm.memory.len() > 4 * width.bytes());
+
+            let mut ir = bfir::compile(src).unwrap();
+            let mut positions = vec![(0, 0); ir.len()];
+            bfir::optimize_with_positions(&mut ir, &mut positions, OptLevel::Full, width);
+            let output = SharedBuf::default();
+            Interpreter::new(
+                ir,
+                vec![0; 4 * width.bytes()].into_boxed_slice(),
+                Box::new(std::io::empty()),
+                Box::new(output.clone())
+            ).with_cell_width(width).with_tape_mode(TapeMode::Growable).run().unwrap();
+            assert_eq!(output.take(), [1], "{} {:?}", src, width);
+        }
+    }
+
+    // the left end stays fixed
+    let mut vm = BfVM::builder().source_str("><<").tape_mode(TapeMode::Growable).mem_size(4).build().unwrap();
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 0, direction: Direction::Left }, .. })
+    ));
+}
+
+#[test]
+fn test_bidirectional() {
+    use crate::interp::Interpreter;
+
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        let build = |src, tape_mode| BfVM::builder()
+            .source_str(src)
+            .cell_width(width)
+            .mem_size(5)
+            .tape_mode(tape_mode)
+            .build()
+            .unwrap();
+        assert!(matches!(
+            build("<+", TapeMode::Fixed).run(),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 0, direction: Direction::Left }, .. })
+        ));
+        let mut vm = build("<+<+++>>>>++", TapeMode::Bidirectional);
+        vm.run().unwrap();
+        let cells: Vec<u32> = vm.memory.chunks(width.bytes()).map(|cell| width.load(cell)).collect();
+        assert_eq!(cells, [3, 1, 0, 0, 2]);
+        // still bounded on both ends, counting cells from the first one
+        assert!(matches!(
+            build("<<<", TapeMode::Bidirectional).run(),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 2, direction: Direction::Left }, .. })
+        ));
+        assert!(matches!(
+            build(">>>", TapeMode::Bidirectional).run(),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 2, direction: Direction::Right }, .. })
+        ));
+
+        let mut interp = Interpreter::new(
+            bfir::compile("<+<+++>>>>++").unwrap(),
+            vec![0; 5 * width.bytes()].into_boxed_slice(),
+            Box::new(std::io::empty()),
+            Box::new(std::io::sink())
+        ).with_cell_width(width).with_tape_mode(TapeMode::Bidirectional);
+        interp.run().unwrap();
+        assert_eq!((0..5).map(|i| interp.cell(i).unwrap()).collect::<Vec<_>>(), [3, 1, 0, 0, 2]);
+    }
+}
+
+#[test]
+fn test_put_runs() {
+    /// records every single write
+    #[derive(Clone, Default)]
+    struct Writes(Arc<Mutex<Vec<Vec<u8>>>>);
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let src = format!("{}.....", "+".repeat(65));
+    for (opt_level, expected) in [(OptLevel::Full, vec![b"AAAAA".to_vec()]), (OptLevel::None, vec![b"A".to_vec(); 5])] {
+        let writes = Writes::default();
+        let mut vm = BfVM::builder().source_str(&src).opt_level(opt_level).output(Box::new(writes.clone())).buffered(false).build().unwrap();
+        vm.run().unwrap();
+        assert_eq!(*writes.0.lock().unwrap(), expected);
+    }
+
+    // just the low byte of wider cells
+    let mut vm = BfVM::builder().source_str(&format!("{}...", "+".repeat(256 + 66))).cell_width(CellWidth::W16).build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), b"BBB");
+}
+
+#[test]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn test_code_bytes() {
+    let vm = BfVM::builder().source_str("+[-]").build().unwrap();
+    let code = vm.code_bytes();
+    assert!(vm.entry_offset() < code.len());
+    let entry = &code[vm.entry_offset()..];
+    // push rbx
+    #[cfg(target_arch = "x86_64")]
+    assert_eq!(entry[0], 0x53);
+    // stp x29, x30, [sp, #-64]!
+    #[cfg(target_arch = "aarch64")]
+    assert_eq!(entry[..4], [0xfd, 0x7b, 0xbc, 0xa9]);
+}
+
+#[test]
+fn test_elide_bounds_checks() {
+    let samples = [
+        "+++[>+++<-]>>>.",
+        ">>>>",
+        "+[>+]",
+        "+>+>+>+[<]",
+        ">>[-]<<+[->>>+<<<]>>>.",
+        ">>+>+<<<-",
+    ];
+    for src in samples {
+        let mut results = vec![];
+        for elide in [true, false] {
+            let mut vm = BfVM::builder().source_str(src).mem_size(4).elide_bounds_checks(elide).build().unwrap();
+            results.push(format!("{:?}", vm.run_capture()));
+        }
+        assert_eq!(results[0], results[1], "{}", src);
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let size = |elide| BfVM::builder().source_str("+++[>+++<-]>.").mem_size(4).elide_bounds_checks(elide).build().unwrap().code_bytes().len();
+        assert!(size(true) < size(false));
+    }
+}
+
+#[test]
+fn test_run_str() {
+    assert_eq!(crate::run_str(",+.", "A").unwrap(), "B");
+    // a lone 0xff is no utf-8
+    assert_eq!(run_str("-.,.", "é").unwrap(), "\u{fffd}\u{fffd}");
+    assert_eq!(run_bytes(",[.,]", b"\xff\x01\0").unwrap(), b"\xff\x01");
+    assert!(matches!(run_str("[", ""), Err(VMError::Compile(_))));
+}
+
+#[test]
+fn test_add_val_operands() {
+    let cell = |ir: Vec<BfIR>, width| {
+        let mut vm = BfVM::builder().source_ir(ir).opt_level(OptLevel::None).cell_width(width).mem_size(2).build().unwrap();
+        vm.run().unwrap();
+        vm.cell(0).unwrap()
+    };
+    // operands past the signed range of the cell still add modulo its size
+    for x in [127, 128, 200, 255] {
+        assert_eq!(cell(vec![BfIR::AddVal(x)], CellWidth::W8), x);
+        assert_eq!(cell(vec![BfIR::SetVal(1), BfIR::AddVal(x)], CellWidth::W8), (x + 1) % 256);
+        assert_eq!(cell(vec![BfIR::SubVal(x)], CellWidth::W8), 256 - x);
+        assert_eq!(cell(vec![BfIR::AddPtr(1), BfIR::AddValAt { offset: -1, val: x }], CellWidth::W8), x);
+    }
+    assert_eq!(cell(vec![BfIR::AddVal(300)], CellWidth::W8), 44);
+    assert_eq!(cell(vec![BfIR::AddVal(65535)], CellWidth::W16), 65535);
+    assert_eq!(cell(vec![BfIR::AddVal(u32::MAX), BfIR::AddVal(2)], CellWidth::W32), 1);
+
+    // a folded run wraps as the unfolded one does
+    let src = "+".repeat(300);
+    let mut vm = BfVM::builder().source_str(&src).build().unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cell(0), Some(44));
+    let mut vm = BfVM::builder().source_str(&src).cell_width(CellWidth::W16).build().unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cell(0), Some(300));
+}
+
+#[test]
+fn test_spawn() {
+    let vm = BfVM::builder().source_str(",[.,]").eof_policy(EofPolicy::Zero).build().unwrap();
+    let (input, output, handle) = vm.spawn();
+    // each byte comes back before the next one is sent
+    for &byte in b"hello" {
+        input.send(byte).unwrap();
+        assert_eq!(output.recv().unwrap(), byte);
+    }
+    input.send(b'!').unwrap();
+    drop(input);
+    assert_eq!(output.iter().collect::<Vec<_>>(), b"!");
+    let (mut vm, ret) = handle.join().unwrap();
+    ret.unwrap();
+    // the vm runs on as usual
+    vm.set_input(Box::new(&b"ab"[..]));
+    assert_eq!(vm.run_capture().unwrap(), b"ab");
+}
+
+#[test]
+fn test_spawn_bounded() {
+    // nobody ever takes a byte
+    let vm = BfVM::builder().source_str("+[.]").build().unwrap();
+    let (_input, output, handle) = vm.spawn_bounded(4, Duration::from_millis(20));
+    let (_, ret) = handle.join().unwrap();
+    assert!(matches!(ret, Err(VMError::Runtime(RuntimeError::OutputTimeout))));
+    assert_eq!(output.try_iter().count(), 4);
+
+    // a slow reader still gets everything
+    let vm = BfVM::builder().source_str("++++++++[.-]").build().unwrap();
+    let (_input, output, handle) = vm.spawn_bounded(1, Duration::from_secs(10));
+    let mut got = vec![];
+    while let Ok(byte) = output.recv() {
+        std::thread::sleep(Duration::from_millis(2));
+        got.push(byte);
+    }
+    assert_eq!(got, [8, 7, 6, 5, 4, 3, 2, 1]);
+    handle.join().unwrap().1.unwrap();
+}
+
+#[test]
+fn test_estimated_code_size() {
+    let samples = [
+        include_str!("../examples/mendelbrot.bf"),
+        "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.",
+        "+[->>+<<]>[-]>>>[<]<<,.",
+        "",
+    ];
+    for src in samples {
+        for opt_level in [OptLevel::None, OptLevel::Full] {
+            for cell_width in [CellWidth::W8, CellWidth::W32] {
+                let vm = BfVM::builder()
+                    .source_str(src)
+                    .opt_level(opt_level)
+                    .cell_width(cell_width)
+                    .elide_bounds_checks(false)
+                    .profile(true)
+                    .profile_loops(true)
+                    .max_steps(1)
+                    .cancel_flag(Arc::default())
+                    .build()
+                    .unwrap();
+                let mut ir = bfir::compile(src).unwrap();
+                let mut positions = vec![(0, 0); ir.len()];
+                bfir::optimize_with_positions(&mut ir, &mut positions, opt_level, cell_width);
+                assert!(bfir::estimated_code_size(&ir) >= vm.code_bytes().len(), "{}", src);
+                #[cfg(target_arch = "x86_64")]
+                {
+                    let opts = JitOptions { cell_width, ..JitOptions::default() };
+                    assert!(bfir::estimated_code_size(&ir) >= BfVM::compile_aarch64(&ir, opts).unwrap().0.len());
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_coalesce_bounds_checks() {
+    // the output, or the end an overflow ran off
+    let run = |src: &str, opt_level, mem_size| {
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .opt_level(opt_level)
+            .mem_size(mem_size)
+            .elide_bounds_checks(false)
+            .build()
+            .unwrap();
+        match vm.run_capture() {
+            Ok(out) => Ok(out),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { direction, .. }, .. }) => Err(direction),
+            Err(e) => panic!("{}: {}", src, e)
+        }
+    };
+    let samples = [
+        ("+++[->+>++>+++<<<]>.>.>.", 4, Ok(vec![3, 6, 9])),
+        ("+++[->+>++>+++<<<]", 3, Err(Direction::Right)),
+        (">>++[-<<+>+>>+<]<<.>.>>.", 4, Ok(vec![2, 2, 2])),
+        ("+[-<+>>+<]", 2, Err(Direction::Left)),
+        // no check while the loop does not run
+        (">>>[->+>+<<]+.", 4, Ok(vec![1])),
+    ];
+    for (src, mem_size, expected) in samples {
+        // unoptimized every access is checked on its own
+        assert_eq!(run(src, OptLevel::None, mem_size), expected, "{}", src);
+        assert_eq!(run(src, OptLevel::Full, mem_size), expected, "{}", src);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    {
+        // cmp rcx, r13 and cmp rcx, r14
+        let cmps = |src| {
+            let vm = BfVM::builder().source_str(src).elide_bounds_checks(false).build().unwrap();
+            vm.code_bytes().windows(3).filter(|w| *w == [0x49, 0x3b, 0xcd] || *w == [0x49, 0x3b, 0xce]).count()
+        };
+        assert_eq!(cmps("+[->+<]"), 2);
+        assert_eq!(cmps("+[->+>++>+++<<<]"), 2);
+        assert_eq!(cmps("+>+>+<<"), 2);
+    }
+}
+
+#[test]
+fn test_error_source() {
+    use std::error::Error;
+
+    struct Failing;
+    impl Write for Failing {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::PermissionDenied.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// the innermost `IoError` in the chain of `e`
+    fn io_kind(e: &(dyn Error + 'static)) -> Option<std::io::ErrorKind> {
+        let mut source = Some(e);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<crate::error::IoError>() {
+                return Some(e.kind());
+            }
+            source = e.source();
+        }
+        None
+    }
+
+    let mut vm = BfVM::builder().source_str("+.").output(Box::new(Failing)).build().unwrap();
+    let err = vm.run().unwrap_err();
+    assert!(err.source().unwrap().is::<RuntimeError>());
+    assert_eq!(io_kind(&err), Some(std::io::ErrorKind::PermissionDenied));
+
+    let err = VMError::RuntimeAt {
+        error: std::io::Error::from(std::io::ErrorKind::BrokenPipe).into(),
+        index: 0,
+        line: 1,
+        col: 1
+    };
+    assert_eq!(io_kind(&err), Some(std::io::ErrorKind::BrokenPipe));
+    assert!(VMError::from(RuntimeError::Cancelled).source().unwrap().source().is_none());
+}
+
+#[test]
+fn test_echo_input() {
+    let run = |echo| {
+        let output = SharedBuf::default();
+        let mut vm = BfVM::builder()
+            .source_str(",+.,,")
+            .input_bytes(b"ab")
+            .output(Box::new(output.clone()))
+            .echo_input(echo)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        output.take()
+    };
+    // nothing is echoed at eof
+    assert_eq!(run(true), b"abb");
+    assert_eq!(run(false), b"b");
+}
+
+#[test]
+fn test_stop_on_broken_pipe() {
+    /// takes one write, then the reader is gone
+    #[derive(Clone, Default)]
+    struct Closing(Arc<Mutex<Vec<u8>>>);
+    impl Write for Closing {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut written = self.0.lock().unwrap();
+            if !written.is_empty() {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            written.push(buf[0]);
+            Ok(1)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    for src in ["+.+.+.", "+.+..."] {
+        let output = Closing::default();
+        let mut vm = BfVM::builder().source_str(src).output(Box::new(output.clone())).stop_on_broken_pipe(true).build().unwrap();
+        vm.run().unwrap();
+        assert_eq!(*output.0.lock().unwrap(), [1]);
+
+        let mut vm = BfVM::builder().source_str(src).output(Box::new(Closing::default())).build().unwrap();
+        assert!(matches!(vm.run(), Err(VMError::Runtime(RuntimeError::IO(e))) if e.kind() == std::io::ErrorKind::BrokenPipe));
+    }
+}
+
+#[test]
+fn test_partial_write() {
+    /// takes `room` bytes in all, then the disk is full
+    #[derive(Clone)]
+    struct Full(Arc<Mutex<usize>>);
+    impl Write for Full {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut room = self.0.lock().unwrap();
+            if *room == 0 {
+                return Err(std::io::Error::other("disk full"));
+            }
+            let n = buf.len().min(*room);
+            *room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let src = format!("{}{}", "+".repeat(65), ".".repeat(10));
+    let ir = crate::bfir::compile(&src).map(|mut ir| {
+        crate::bfir::optimize(&mut ir);
+        ir
+    }).unwrap();
+    assert_eq!(ir[1], BfIR::PutByteN { count: 10 });
+    for (room, partial) in [(3, Some((3, 10))), (0, None)] {
+        let output = Box::new(Full(Arc::new(Mutex::new(room))));
+        // a buffer in between would only fail at the flush
+        let mut vm = BfVM::builder().source_ir(ir.clone()).opt_level(OptLevel::None).output(output.clone()).buffered(false).build().unwrap();
+        let Err(VMError::Runtime(RuntimeError::IO(e))) = vm.run() else { panic!() };
+        assert_eq!(e.partial_write(), partial);
+
+        *output.0.lock().unwrap() = room;
+        let ret = crate::interp::Interpreter::new(ir.clone(), vec![0; 1].into_boxed_slice(), Box::new(std::io::empty()), output).run();
+        assert_eq!(ret, Err(RuntimeError::IO(e.clone())));
+        if room > 0 {
+            assert_eq!(e.to_string(), "disk full after writing 3 of 10 bytes");
+        }
+    }
+}
+
+#[test]
+fn test_dump_tape() {
+    let run = |src: &str, dump_tape, mem_size| {
+        let dump = SharedBuf::default();
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .mem_size(mem_size)
+            .dump_tape(dump_tape)
+            .dump_output(Box::new(dump.clone()))
+            .build()
+            .unwrap();
+        assert!(vm.run_capture().unwrap().is_empty());
+        String::from_utf8(dump.take()).unwrap()
+    };
+    assert_eq!(run("+++#", true, 16), "*0:3 1:0 2:0 3:0 4:0 5:0 6:0 7:0\n");
+    assert_eq!(run("+++#", false, 16), "");
+    // the window stays on the tape around the pointer
+    assert_eq!(run(">>>>>>+#<<<#", true, 10), "2:0 3:0 4:0 5:0 *6:1 7:0 8:0 9:0\n0:0 1:0 2:0 *3:0 4:0 5:0 6:1 7:0\n");
+    assert_eq!(run("+>++#", true, 2), "0:1 *1:2\n");
+}
+
+#[test]
+fn test_read_line() {
+    let run = |src: &str, input: &[u8], mem_size| {
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .mem_size(mem_size)
+            .read_line(true)
+            .input_bytes(input)
+            .build()
+            .unwrap();
+        let out = vm.run_capture().unwrap();
+        (vm.memory().to_vec(), out)
+    };
+    assert_eq!(run(";", b"abc\n", 6).0, b"abc\x03\0\0");
+    // the pointer stays, the next line goes on after the newline
+    assert_eq!(run(";.>>>>;", b"abc\nde", 8), (b"abc\x03de\x02\0".to_vec(), b"a".to_vec()));
+    // a line longer than the tape is cut short, the rest stays unread
+    assert_eq!(run(">;,", b"abcdef\n", 4).0, b"\0cb\x02");
+    assert_eq!(run(">>>;", b"abc\n", 4).0, b"\0\0\0\0");
+    // a comment without the dialect
+    let mut vm = BfVM::builder().source_str(";,").input_bytes(b"a").build().unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cell(0), Some(b'a' as u32));
+}
+
+#[test]
+fn test_buffered() {
+    /// counts the writes and flushes reaching it
+    #[derive(Clone, Default)]
+    struct Counting(Arc<Mutex<(Vec<u8>, usize, usize)>>);
+    impl Write for Counting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut inner = self.0.lock().unwrap();
+            inner.0.extend_from_slice(buf);
+            inner.1 += 1;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.0.lock().unwrap().2 += 1;
+            Ok(())
+        }
+    }
+
+    let src = ",[.,]";
+    let input = b"hello world".repeat(100);
+    for buffered in [true, false] {
+        let output = Counting::default();
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .input_bytes(&input)
+            .output(Box::new(output.clone()))
+            .eof_policy(EofPolicy::Zero)
+            .buffered(buffered)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        // all of it is through once `run` returns, with the vm still around
+        let (written, writes, flushes) = output.0.lock().unwrap().clone();
+        assert_eq!(written, input);
+        assert_eq!((writes, flushes), if buffered { (1, 1) } else { (input.len(), 1) });
+
+        // a new output gets buffered the same
+        let output = Counting::default();
+        vm.set_input(Box::new(std::io::Cursor::new(b"abc".to_vec())));
+        vm.set_output(Box::new(output.clone()));
+        vm.run().unwrap();
+        assert_eq!(output.0.lock().unwrap().clone(), (b"abc".to_vec(), if buffered { 1 } else { 3 }, 1));
+    }
+
+    // output before an error is flushed too
+    let output = SharedBuf::default();
+    let mut vm = BfVM::builder().source_str("+.<").output(Box::new(output.clone())).build().unwrap();
+    assert!(vm.run().is_err());
+    assert_eq!(output.take(), [1]);
+}
+
+#[test]
+fn test_run_with_timeout() {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let mut vm = BfVM::builder().source_str("+[]").cancel_flag(cancel.clone()).build().unwrap();
+    let start = std::time::Instant::now();
+    assert!(matches!(vm.run_with_timeout(Duration::from_millis(50)), Err(VMError::Runtime(RuntimeError::Timeout))));
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    assert!(!cancel.load(Ordering::Relaxed));
+
+    // a run done in time is not held up by the timer
+    let mut vm = BfVM::builder().source_str("+++[-]+.").cancel_flag(cancel).build().unwrap();
+    let start = std::time::Instant::now();
+    vm.run_with_timeout(Duration::from_secs(10)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2));
+
+    // no cancel flag is needed, and the vm runs again after a timeout
+    let mut vm = BfVM::builder().source_str("+[]").build().unwrap();
+    assert!(matches!(vm.run_with_timeout(Duration::from_millis(50)), Err(VMError::Runtime(RuntimeError::Timeout))));
+    assert!(matches!(vm.run_with_timeout(Duration::from_millis(50)), Err(VMError::Runtime(RuntimeError::Timeout))));
+}
+
+#[test]
+fn test_unbalanced_ir() {
+    for (ir, index) in [(vec![BfIR::Jnz], 0), (vec![BfIR::Jz, BfIR::AddVal(1)], 0), (vec![BfIR::Jz, BfIR::Jnz, BfIR::Jnz], 2)] {
+        let ret = BfVM::from_ir(ir, Box::new(std::io::empty()), Box::new(std::io::sink()));
+        assert!(matches!(ret, Err(VMError::UnbalancedIr { index: i }) if i == index));
+    }
+    let ret = BfVM::from_ir(vec![BfIR::AddVal(1), BfIR::ClearRange { len: 0 }], Box::new(std::io::empty()), Box::new(std::io::sink()));
+    assert!(matches!(ret, Err(VMError::InvalidIr(bfir::VerifyError { index: 1, kind: VerifyErrorKind::ZeroLength }))));
+    // the code generators do not trust the builder to check
+    #[cfg(target_arch = "x86_64")]
+    assert!(matches!(BfVM::compile_x64(&[BfIR::Jnz], JitOptions::default()), Err(VMError::UnbalancedIr { index: 0 })));
+    assert!(matches!(BfVM::compile_aarch64(&[BfIR::Jnz], JitOptions::default()), Err(VMError::UnbalancedIr { index: 0 })));
+    // a loop left open jumps to a label never defined
+    #[cfg(target_arch = "x86_64")]
+    assert!(matches!(BfVM::compile_x64(&[BfIR::Jz], JitOptions::default()), Err(VMError::Assemble(_))));
+    assert!(matches!(BfVM::compile_aarch64(&[BfIR::Jz], JitOptions::default()), Err(VMError::Assemble(_))));
+}
+
+#[test]
+fn test_start_offset() {
+    use crate::interp::Interpreter;
+
+    let build = |src: &str, offset, tape_mode| BfVM::builder()
+        .source_str(src)
+        .mem_size(16)
+        .tape_mode(tape_mode)
+        .start_offset(offset)
+        .build();
+
+    // the program's cell 0 is the tape's cell 10
+    let mut vm = build("+++", 10, TapeMode::Fixed).unwrap();
+    vm.run().unwrap();
+    assert_eq!((vm.cell(0), vm.cell(10)), (Some(0), Some(3)));
+    let mut vm = build(&format!("{}+", "<".repeat(10)), 10, TapeMode::Fixed).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cell(0), Some(1));
+    assert!(matches!(
+        build(&"<".repeat(11), 10, TapeMode::Fixed).unwrap().run(),
+        Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 10, direction: Direction::Left }, .. })
+    ));
+    // it adds to where the tape mode starts
+    let mut vm = build("+", 2, TapeMode::Bidirectional).unwrap();
+    vm.run().unwrap();
+    assert_eq!(vm.cell(10), Some(1));
+
+    assert!(matches!(build("+", 16, TapeMode::Fixed), Err(VMError::InvalidStartOffset(16))));
+    assert!(matches!(build("+", 8, TapeMode::Bidirectional), Err(VMError::InvalidStartOffset(8))));
+    assert!(matches!(build("+", usize::MAX, TapeMode::Bidirectional), Err(VMError::InvalidStartOffset(usize::MAX))));
+
+    let mut interp = Interpreter::new(
+        bfir::compile("+++").unwrap(),
+        vec![0; 16].into_boxed_slice(),
+        Box::new(std::io::empty()),
+        Box::new(std::io::sink())
+    ).with_start_offset(10);
+    interp.run().unwrap();
+    assert_eq!(interp.cell(10), Some(3));
+    let mut interp = Interpreter::new(vec![], vec![0; 16].into_boxed_slice(), Box::new(std::io::empty()), Box::new(std::io::sink()))
+        .with_start_offset(16);
+    assert!(matches!(interp.run(), Err(RuntimeError::PointerOverflow { direction: Direction::Right, .. })));
+}
+
+#[test]
+fn test_arith_mode() {
+    use crate::interp::Interpreter;
+
+    let run = |src: &str, opt_level, width, arith| {
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .opt_level(opt_level)
+            .cell_width(width)
+            .arith_mode(arith)
+            .mem_size(2)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        (vm.cell(0).unwrap(), vm.cell(1).unwrap())
+    };
+    // 255 + 1 wraps to 0 or sticks at 255, a run is split so the folded
+    // add overflows as well
+    let src = format!("{}>-<+", "+".repeat(255));
+    for opt_level in [OptLevel::None, OptLevel::Basic, OptLevel::Full] {
+        assert_eq!(run(&src, opt_level, CellWidth::W8, ArithMode::Wrapping), (0, 255), "{:?}", opt_level);
+        assert_eq!(run(&src, opt_level, CellWidth::W8, ArithMode::Saturating), (255, 0), "{:?}", opt_level);
+        assert_eq!(run(&"+".repeat(300), opt_level, CellWidth::W8, ArithMode::Saturating).0, 255);
+        for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+            // `-+` does not cancel once the `-` saturates at 0
+            assert_eq!(run("-+>-", opt_level, width, ArithMode::Wrapping), (0, width.max()));
+            assert_eq!(run("-+>-", opt_level, width, ArithMode::Saturating), (1, 0), "{:?} {:?}", opt_level, width);
+        }
+    }
+
+    for (arith, expected) in [(ArithMode::Wrapping, [0, 255]), (ArithMode::Saturating, [255, 0])] {
+        let mut interp = Interpreter::new(
+            bfir::compile(&src).unwrap(),
+            vec![0; 2].into_boxed_slice(),
+            Box::new(std::io::empty()),
+            Box::new(std::io::sink())
+        ).with_arith_mode(arith);
+        interp.run().unwrap();
+        assert_eq!(*interp.memory, expected);
+    }
+}
+
+#[test]
+fn test_run_n() {
+    let mut vm = BfVM::builder().source_str(",.>+.").build().unwrap();
+    let outputs: Vec<SharedBuf> = (0..3).map(|_| SharedBuf::default()).collect();
+    let runs = [b"a", b"b", b"c"].into_iter().zip(&outputs).map(|(input, output)| {
+        (Box::new(&input[..]) as Input, Box::new(output.clone()) as Output)
+    });
+    let results = vm.run_n(runs);
+    assert_eq!(results.len(), 3);
+    assert!(results.iter().all(|ret| ret.is_ok()));
+    // the tape is reset, so the second cell is 1 after every run
+    let outputs: Vec<Vec<u8>> = outputs.iter().map(|output| output.take()).collect();
+    assert_eq!(outputs, [b"a\x01", b"b\x01", b"c\x01"]);
+}
+
+#[test]
+fn test_jit_cache() {
+    let cache = JitCache::new();
+    let build = |src: &str| BfVM::builder().source_str(src).jit_cache(&cache).build().unwrap();
+    let mut a = build("++++++++[>++++++++<-]>+.");
+    // comments and folded runs make no difference to the optimized program
+    let mut b = build("+++++ +++ [>++++++++<-] > + . done");
+    let c = build("+.");
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        assert_eq!(a.code_bytes().as_ptr(), b.code_bytes().as_ptr());
+        assert_ne!(a.code_bytes().as_ptr(), c.code_bytes().as_ptr());
+        assert_eq!(cache.len(), 2);
+    }
+    assert_eq!(a.run_capture().unwrap(), b"A");
+    assert_eq!(b.run_capture().unwrap(), b"A");
+
+    // code referring to a vm's own counters stays out of the cache
+    let mut d = BfVM::builder().source_str("+.").jit_cache(&cache).max_steps(10).build().unwrap();
+    assert_eq!(d.run_capture().unwrap(), [1]);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        assert_ne!(c.code_bytes().as_ptr(), d.code_bytes().as_ptr());
+        assert_eq!(cache.len(), 2);
+    }
+
+    // the cancel flag goes through the helper table, so the code is shared
+    let cancel = Arc::new(AtomicBool::new(true));
+    let mut e = BfVM::builder().source_str("+[]").jit_cache(&cache).cancel_flag(cancel.clone()).build().unwrap();
+    let mut f = BfVM::builder().source_str("+[]").jit_cache(&cache).cancel_flag(Arc::default()).build().unwrap();
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    assert_eq!(e.code_bytes().as_ptr(), f.code_bytes().as_ptr());
+    assert!(matches!(e.run(), Err(VMError::Runtime(RuntimeError::Cancelled))));
+    assert!(matches!(f.run_with_timeout(Duration::from_millis(50)), Err(VMError::Runtime(RuntimeError::Timeout))));
+    assert!(cancel.load(Ordering::Relaxed));
+}
+
+#[test]
+fn test_wrapping_tape() {
+    use crate::interp::Interpreter;
+
+    let build = |ir: Vec<BfIR>, opt_level, width| BfVM::builder()
+        .source_ir(ir)
+        .opt_level(opt_level)
+        .cell_width(width)
+        .tape_mode(TapeMode::Wrapping)
+        .mem_size(10)
+        .build()
+        .unwrap();
+    let one_at = |cell| (0..10).map(|i| (i == cell) as u32).collect::<Vec<_>>();
+    let cases = [
+        // past the end comes back in at the start, folded or not
+        (bfir::compile(&format!("{}+", ">".repeat(13))).unwrap(), one_at(3)),
+        // `<` at the start goes to the last cell
+        (bfir::compile("<+").unwrap(), one_at(9)),
+        // moves larger than the tape
+        (vec![BfIR::AddPtr(1_000_003), BfIR::AddVal(1)], one_at(3)),
+        (vec![BfIR::SubPtr(1_000_003), BfIR::AddVal(1)], one_at(7)),
+        (vec![BfIR::AddPtr(9), BfIR::SubPtr(u32::MAX), BfIR::AddVal(1)], one_at(4)),
+        // loops moving across the boundary on every iteration
+        (bfir::compile("+++[<++>-]<[>+<-]>>>+").unwrap(), vec![6, 0, 1, 0, 0, 0, 0, 0, 0, 0]),
+    ];
+    for (ir, expected) in cases {
+        for opt_level in [OptLevel::None, OptLevel::Full] {
+            for width in [CellWidth::W8, CellWidth::W32] {
+                let mut vm = build(ir.clone(), opt_level, width);
+                vm.run().unwrap();
+                let tape: Vec<u32> = (0..10).map(|i| vm.cell(i).unwrap()).collect();
+                assert_eq!(tape, expected, "{:?} {:?} {:?}", ir, opt_level, width);
+            }
+        }
+
+        let mut interp = Interpreter::new(ir.clone(), vec![0; 10].into_boxed_slice(), Box::new(std::io::empty()), Box::new(std::io::sink()))
+            .with_tape_mode(TapeMode::Wrapping);
+        interp.run().unwrap();
+        let tape: Vec<u32> = (0..10).map(|i| interp.cell(i).unwrap()).collect();
+        assert_eq!(tape, expected, "{:?}", ir);
+    }
+}
+
+#[test]
+fn test_fit_tape() {
+    let build = |src: &str| BfVM::builder().source_str(src).fit_tape(true).output(Box::new(std::io::sink())).build().unwrap();
+    let mut vm = build(">>>+[->>+<<]");
+    assert_eq!(vm.memory().len(), 6);
+    vm.run().unwrap();
+    assert_eq!(vm.memory(), [0, 0, 0, 0, 0, 1]);
+    let mut vm = BfVM::builder().source_str(">>+<<[-]").fit_tape(true).start_offset(2).build().unwrap();
+    assert_eq!(vm.memory().len(), 5);
+    vm.run().unwrap();
+    assert_eq!(vm.cell(4), Some(1));
+    // a program reaching past `mem_size` still runs off its end
+    let mut vm = BfVM::builder().source_str(&">".repeat(40)).fit_tape(true).mem_size(16).build().unwrap();
+    assert_eq!(vm.memory().len(), 16);
+    assert!(matches!(vm.run(), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { direction: Direction::Right, .. }, .. })));
+    assert_eq!(build("+[>+]").memory().len(), DEFAULT_MEM_SIZE);
+    assert_eq!(BfVM::builder().source_str(">>>").tape_mode(TapeMode::Wrapping).fit_tape(true).build().unwrap().memory().len(), DEFAULT_MEM_SIZE);
+}
+
+#[test]
+fn test_lazy_tape() {
+    let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    let expected = BfVM::builder().source_str(src).build().unwrap().run_capture().unwrap();
+    let mut vm = BfVM::builder().source_str(src).lazy_tape(true).build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), expected);
+    vm.reset();
+    assert!(vm.memory().iter().all(|&b| b == 0));
+    assert_eq!(vm.run_capture().unwrap(), expected);
+
+    // growing keeps what the program wrote
+    let mut vm = BfVM::builder()
+        .source_str(">+>++[>]+<.")
+        .mem_size(2)
+        .tape_mode(TapeMode::Growable)
+        .lazy_tape(true)
+        .build()
+        .unwrap();
+    assert_eq!(vm.run_capture().unwrap(), [2]);
+    assert_eq!(vm.memory()[..4], [0, 1, 2, 1]);
+
+    // only the touched pages of the tape count to the resident memory
+    #[cfg(target_os = "linux")]
+    {
+        let mut vm = BfVM::builder()
+            .source_ir(vec![BfIR::AddVal(1), BfIR::AddPtr(DEFAULT_MEM_SIZE as u32 - 1), BfIR::AddVal(1)])
+            .lazy_tape(true)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        assert_eq!((vm.cell(0), vm.cell(DEFAULT_MEM_SIZE - 1)), (Some(1), Some(1)));
+        let start = vm.memory().as_ptr() as usize;
+        let smaps = std::fs::read_to_string("/proc/self/smaps").unwrap();
+        let mut lines = smaps.lines().skip_while(|line| {
+            !line.split('-').next().is_some_and(|from| usize::from_str_radix(from, 16) == Ok(start))
+        });
+        assert!(lines.next().is_some(), "tape not mapped on its own");
+        let rss_kb: usize = lines
+            .find_map(|line| line.strip_prefix("Rss:"))
+            .and_then(|rss| rss.trim().strip_suffix("kB")?.trim().parse().ok())
+            .unwrap();
+        assert!(rss_kb <= 64, "{} kB resident", rss_kb);
+    }
+}
+
+#[test]
+fn test_fused_ptr_val() {
+    let src = ">+>>---<+<<-.";
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        let mut fused = BfVM::builder().source_str(src).cell_width(width).build().unwrap();
+        let mut plain = BfVM::builder().source_str(src).cell_width(width).opt_level(OptLevel::None).build().unwrap();
+        assert_eq!(fused.run_capture().unwrap(), plain.run_capture().unwrap());
+        assert_eq!(fused.memory(), plain.memory(), "{width:?}");
+    }
+
+    // the move is checked before the add runs
+    let mut vm = BfVM::builder().source_str("<+").build().unwrap();
+    assert!(matches!(
+        vm.run(),
+        Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 0, direction: Direction::Left }, .. })
+    ));
+    assert_eq!(vm.cell(0), Some(0));
+}
+
+#[test]
+fn test_metrics() {
+    let src = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+    assert_eq!(BfVM::builder().source_str(src).build().unwrap().metrics(), None);
+
+    let mut vm = BfVM::builder().source_str(src).metrics(true).build().unwrap();
+    let metrics = vm.metrics().unwrap();
+    assert!(metrics.compile_time > Duration::ZERO);
+    assert_eq!(metrics.run_time, Duration::ZERO);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    assert_eq!(metrics.code_size, vm.code_bytes().len());
+    assert!(metrics.ir_len > 0 && metrics.ir_len < src.len());
+
+    vm.run_capture().unwrap();
+    let after = vm.metrics().unwrap();
+    assert!(after.run_time > Duration::ZERO);
+    assert_eq!(after.compile_time, metrics.compile_time);
+}
+
+#[test]
+fn test_seeded_input() {
+    let run = |seed| {
+        BfVM::builder()
+            .source_str(",[.,]")
+            .input(Box::new(SeededInput::new(seed).take(1000)))
+            .build()
+            .unwrap()
+            .run_capture()
+            .unwrap()
+    };
+    let a = run(1);
+    assert!(!a.is_empty());
+    assert_eq!(run(1), a);
+    assert_ne!(run(2), a);
+
+    // how the reads are split up does not change the stream
+    let mut whole = [0; 20];
+    SeededInput::new(7).read_exact(&mut whole).unwrap();
+    let mut input = SeededInput::new(7);
+    let mut parts = [0; 20];
+    for part in parts.chunks_mut(3) {
+        input.read_exact(part).unwrap();
+    }
+    assert_eq!(whole, parts);
+}
+
+#[test]
+fn test_output_mode() {
+    /// records how many bytes it got by the time of every flush
+    #[derive(Clone, Default)]
+    struct Flushes(Arc<Mutex<(usize, Vec<usize>)>>);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let mut state = self.0.lock().unwrap();
+            let written = state.0;
+            state.1.push(written);
+            Ok(())
+        }
+    }
+
+    for (mode, expected) in [(OutputMode::Flush, vec![1, 2, 3, 3]), (OutputMode::Buffered, vec![3])] {
+        let flushes = Flushes::default();
+        let mut vm = BfVM::builder()
+            .source_str("+.+.>+.")
+            .output(Box::new(flushes.clone()))
+            .output_mode(mode)
+            .build()
+            .unwrap();
+        vm.run().unwrap();
+        // the last flush is the one at the end of the run
+        assert_eq!(flushes.0.lock().unwrap().1, expected, "{mode:?}");
+    }
+}
+
+#[test]
+fn test_output_encoding() {
+    let run = |ir: Vec<BfIR>, encoding| {
+        let output = SharedBuf::default();
+        let mut vm = BfVM::builder()
+            .source_ir(ir)
+            .output(Box::new(output.clone()))
+            .output_encoding(encoding)
+            .build()
+            .unwrap();
+        let ret = vm.run();
+        (ret, output.take())
+    };
+    let bytes = |bytes: &[u8]| bytes.iter().flat_map(|&b| [BfIR::SetVal(b as u32), BfIR::PutByte]).collect::<Vec<_>>();
+
+    let (ret, out) = run(bytes(b"\xff"), OutputEncoding::Raw);
+    assert!(ret.is_ok());
+    assert_eq!(out, b"\xff");
+    let (ret, out) = run(bytes(b"ok\xff"), OutputEncoding::Utf8Validated);
+    assert!(matches!(ret, Err(VMError::Runtime(RuntimeError::InvalidUtf8 { at: 2 }))));
+    assert_eq!(out, b"ok");
+
+    let (ret, out) = run(bytes("é🦀".as_bytes()), OutputEncoding::Utf8Validated);
+    assert!(ret.is_ok());
+    assert_eq!(out, "é🦀".as_bytes());
+    // a character cut short at the end
+    let (ret, out) = run(bytes(b"a\xc3"), OutputEncoding::Utf8Validated);
+    assert!(matches!(ret, Err(VMError::Runtime(RuntimeError::InvalidUtf8 { at: 1 }))));
+    assert_eq!(out, b"a\xc3");
+
+    let (ret, out) = run(vec![BfIR::SetVal(b'a' as u32), BfIR::PutByteN { count: 9 }], OutputEncoding::Utf8Validated);
+    assert!(ret.is_ok());
+    assert_eq!(out, b"aaaaaaaaa");
+    let (ret, _) = run(vec![BfIR::SetVal(0xc3), BfIR::PutByteN { count: 9 }], OutputEncoding::Utf8Validated);
+    assert!(matches!(ret, Err(VMError::Runtime(RuntimeError::InvalidUtf8 { at: 0 }))));
+
+    let mut check = Utf8Check::default();
+    check.write(b'x', 1000).unwrap();
+    check.write(0xe2, 1).unwrap();
+    assert_eq!(check.write(b'x', 1), Err(RuntimeError::InvalidUtf8 { at: 1000 }));
+    assert_eq!(check.finish(), Err(RuntimeError::InvalidUtf8 { at: 1000 }));
+    // the next output starts over
+    assert_eq!(check.finish(), Ok(()));
+}
+
+#[test]
+fn test_recompile() {
+    let mut vm = BfVM::builder().source_str("++[->+<]>.").max_steps(100).build().unwrap();
+    assert_eq!(vm.run_capture().unwrap(), [2]);
+    let code = vm.code_bytes().as_ptr();
+
+    assert!(!vm.recompile("++[->+<]>.").unwrap());
+    assert_eq!(vm.code_bytes().as_ptr(), code);
+    // a comment moves the loop but leaves the program as it is
+    assert!(!vm.recompile("two ++ [->+<]>.").unwrap());
+    assert_eq!(vm.code_bytes().as_ptr(), code);
+    vm.reset();
+    let err = vm.recompile("+[]").map(|_| vm.run().unwrap_err()).unwrap();
+    assert_ne!(vm.code_bytes().as_ptr(), code);
+    assert!(matches!(err, VMError::RuntimeAt { error: RuntimeError::StepLimitExceeded { steps: 100 }, line: 1, col: 3, .. }));
+
+    // the tape and the options stay
+    let mut vm = BfVM::builder().source_str("+++").cell_width(CellWidth::W16).mem_size(4).profile(true).build().unwrap();
+    vm.run().unwrap();
+    assert!(vm.recompile("[>+<-]>-").unwrap());
+    vm.run().unwrap();
+    assert_eq!((vm.cell(0), vm.cell(1)), (Some(0), Some(2)));
+    assert_eq!(vm.memory().len(), 8);
+    // counted from the new code on
+    let ops = vm.executed_ops().unwrap();
+    assert_eq!((ops.get("addval"), ops.get("mulval")), (None, Some(&1)));
+    // a source that does not compile leaves the program as it was
+    assert!(matches!(vm.recompile("["), Err(VMError::Compile(_))));
+    vm.run().unwrap();
+    assert_eq!(vm.cell(1), Some(1));
+
+    // a fitted tape is kept as it was fitted, the new program is checked against it
+    let mut vm = BfVM::builder().source_str(">+").fit_tape(true).build().unwrap();
+    vm.run().unwrap();
+    assert!(vm.recompile(">+>+").unwrap());
+    assert!(matches!(vm.run(), Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, .. })));
+    assert_eq!(vm.memory().len(), 2);
+
+    // the source still ends at `!`
+    let mut vm = BfVM::builder().source_str("+.!").split_on_bang(true).build().unwrap();
+    assert!(vm.recompile("++.![").unwrap());
+    assert_eq!(vm.run_capture().unwrap(), [2]);
+}
+
+#[test]
+fn test_loop_profile() {
+    assert_eq!(BfVM::builder().source_str("+[-]").build().unwrap().loop_profile(), None);
+
+    // the output keeps the inner loop from folding into a multiply
+    let src = "++++[>+++[>+.<-]<-]+[-]";
+    let mut vm = BfVM::builder().source_str(src).output(Box::new(std::io::sink())).profile_loops(true).build().unwrap();
+    assert_eq!(vm.loop_profile().unwrap().iter().map(|l| l.iterations).sum::<u64>(), 0);
+    vm.run().unwrap();
+    let expected = [
+        LoopProfile { source_span: ((1, 10), (1, 16)), iterations: 12 },
+        LoopProfile { source_span: ((1, 5), (1, 19)), iterations: 4 },
+    ];
+    assert_eq!(vm.loop_profile().unwrap(), expected);
+    vm.run().unwrap();
+    assert_eq!(vm.loop_profile().unwrap()[0].iterations, 24);
+    vm.reset();
+    vm.run().unwrap();
+    assert_eq!(vm.loop_profile().unwrap(), expected);
+
+    let ir = bfir::compile(src).unwrap();
+    let mut interp = crate::interp::Interpreter::new(ir, vec![0; 4].into_boxed_slice(), Box::new(std::io::empty()), Box::new(std::io::sink()))
+        .with_loop_profiling();
+    interp.run().unwrap();
+    assert_eq!(interp.loop_iterations(), Some(&[4, 12, 1][..]));
+}
+
+#[test]
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn test_compile_many() {
+    let letters = bfir::compile("++++++++[>++++++++<-]>+.+.").unwrap();
+    let echo = bfir::compile(",[.,]").unwrap();
+    let (code, entries) = BfVM::compile_many(&[&letters, &echo]).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].0 < entries[1].0 && entries[1].0 < code.len());
+
+    let mut vms = BfVM::from_ir_many(&[&letters, &echo, &[BfIR::SubPtr(1)]]).unwrap();
+    vms[1].set_input(Box::new(std::io::Cursor::new(b"xyz\0".to_vec())));
+    assert_eq!(vms[1].run_capture().unwrap(), b"xyz");
+    assert_eq!(vms[0].run_capture().unwrap(), b"AB");
+    assert_eq!(vms[0].cell(1), Some(b'B' as u32));
+    assert_eq!(vms[1].cell(1), Some(0));
+    assert!(matches!(
+        vms[2].run(),
+        Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { at: 0, direction: Direction::Left }, index: 0, .. })
+    ));
+    assert_eq!(vms[0].code_bytes().as_ptr(), vms[2].code_bytes().as_ptr());
+    assert_eq!((vms[0].entry_offset(), vms[1].entry_offset()), (entries[0].0, entries[1].0));
+
+    assert!(matches!(BfVM::compile_many(&[&letters, &[BfIR::Jnz]]), Err(VMError::UnbalancedIr { index: 0 })));
+}
@@ -0,0 +1,637 @@
+use super::{BfVM, Helper, JitOptions, TapeMode};
+use crate::bfir::{self, ArithMode, BfIR, CellWidth};
+use crate::error::{Direction, Result, VMError};
+
+use dynasm::dynasm;
+use dynasmrt::aarch64::Assembler;
+use dynasmrt::{DynasmApi, DynasmLabelApi};
+
+macro_rules! a64 {
+    ($ops:ident $($t:tt)*) => {
+        dynasm!($ops ; .arch aarch64 $($t)*)
+    };
+}
+
+/// load a 64-bit immediate into `reg` with a movz/movk sequence
+fn load_imm(ops: &mut Assembler, reg: u32, imm: u64) {
+    a64!(ops ; movz X(reg), (imm & 0xffff) as u32);
+    if (imm >> 16) & 0xffff != 0 {
+        a64!(ops ; movk X(reg), ((imm >> 16) & 0xffff) as u32, lsl 16);
+    }
+    if (imm >> 32) & 0xffff != 0 {
+        a64!(ops ; movk X(reg), ((imm >> 32) & 0xffff) as u32, lsl 32);
+    }
+    if (imm >> 48) & 0xffff != 0 {
+        a64!(ops ; movk X(reg), ((imm >> 48) & 0xffff) as u32, lsl 48);
+    }
+}
+
+/// load the cell at `[X(base)]` into `W(reg)`, zero extended
+fn load_cell(ops: &mut Assembler, width: CellWidth, reg: u32, base: u32) {
+    match width {
+        CellWidth::W8 => a64!(ops ; ldrb W(reg), [X(base)]),
+        CellWidth::W16 => a64!(ops ; ldrh W(reg), [X(base)]),
+        CellWidth::W32 => a64!(ops ; ldr W(reg), [X(base)]),
+    }
+}
+
+/// store the low bits of `W(reg)` into the cell at `[X(base)]`
+fn store_cell(ops: &mut Assembler, width: CellWidth, reg: u32, base: u32) {
+    match width {
+        CellWidth::W8 => a64!(ops ; strb W(reg), [X(base)]),
+        CellWidth::W16 => a64!(ops ; strh W(reg), [X(base)]),
+        CellWidth::W32 => a64!(ops ; str W(reg), [X(base)]),
+    }
+}
+
+/// `w9 += imm`, going through x12 if it doesn't fit an add/sub immediate
+fn add_imm(ops: &mut Assembler, imm: u32) {
+    if imm < 4096 {
+        a64!(ops ; add w9, w9, imm);
+    }
+    else if imm.wrapping_neg() < 4096 {
+        a64!(ops ; sub w9, w9, imm.wrapping_neg());
+    }
+    else {
+        load_imm(ops, 12, imm as u64);
+        a64!(ops ; add w9, w9, w12);
+    }
+}
+
+impl BfVM {
+    pub(super) fn compile_aarch64(code: &[BfIR], opts: JitOptions) -> Result<(dynasmrt::ExecutableBuffer, dynasmrt::AssemblyOffset)> {
+        let mut ops = Assembler::new()?;
+        let start = BfVM::emit_aarch64(&mut ops, code, opts)?;
+        ops.commit().map_err(|e| VMError::Assemble(e.to_string()))?;
+        // only fails while the code is borrowed for running, which it never is here
+        let code = ops.finalize().map_err(|_| VMError::Assemble("code buffer in use".into()))?;
+        Ok((code, start))
+    }
+
+    /// append the code for `code` to `ops`, with labels of its own so several
+    /// programs can share one buffer, returns where it is entered
+    pub(super) fn emit_aarch64(ops: &mut Assembler, code: &[BfIR], opts: JitOptions) -> Result<dynasmrt::AssemblyOffset> {
+        let start = ops.offset();
+        let width = opts.cell_width;
+        // the shared exits of this program
+        let cancelled = ops.new_dynamic_label();
+        let budget_exhausted = ops.new_dynamic_label();
+        let overflow_left = ops.new_dynamic_label();
+        let overflow_right = ops.new_dynamic_label();
+        let grow = ops.new_dynamic_label();
+        let io_error = ops.new_dynamic_label();
+        let bytes = width.bytes() as i64;
+
+        // labels of the open loops and their index in the order of their `Jz`
+        let mut loop_stack: Vec<(dynasmrt::DynamicLabel, dynasmrt::DynamicLabel, usize)> = vec![];
+        let mut loops = 0;
+        // out of line stubs passing the ir index of a failed bounds check to
+        // =>overflow_left or =>overflow_right, after taking back `undo` bytes
+        // the instruction already moved ptr by
+        let mut overflow_stubs: Vec<(dynasmrt::DynamicLabel, usize, Direction, i64)> = vec![];
+        let mut stub = |ops: &mut Assembler, i, direction, undo| {
+            let label = ops.new_dynamic_label();
+            overflow_stubs.push((label, i, direction, undo));
+            label
+        };
+        // with a growable tape the stubs of the right end grow it instead and
+        // retry the instruction from its label here
+        let growable = opts.tape_mode == TapeMode::Growable;
+        let mut retry_labels = vec![];
+        // same for exhausting the step budget at a `Jnz`
+        let mut step_stubs: Vec<(dynasmrt::DynamicLabel, usize)> = vec![];
+
+        // this:         x0 x19
+        // memory_start: x1 x20
+        // memory_end:   x2 x21
+        // helpers:      x3 x24
+        // ptr:             x22
+        // steps left:      x23
+        // scratch:         x9 - x13
+
+        a64!(ops
+            ; stp x29, x30, [sp, #-64]!
+            ; mov x29, sp
+            ; stp x19, x20, [sp, #16]
+            ; stp x21, x22, [sp, #32]
+            ; stp x23, x24, [sp, #48]
+            ; mov x19, x0   // save this
+            ; mov x20, x1   // save memory_start
+            ; mov x21, x2   // save memory_end
+            ; mov x24, x3   // save helpers
+            ; mov x22, x1   // ptr = memory_start
+        );
+        if opts.tape_mode == TapeMode::Bidirectional {
+            let shift = width.bytes().trailing_zeros();
+            a64!(ops
+                ; sub x9, x21, x20
+                ; lsr x9, x9, shift + 1
+                ; add x22, x20, x9, lsl shift   // ptr = the middle cell
+            );
+        }
+        if opts.start_offset > 0 {
+            load_imm(ops, 9, (opts.start_offset * width.bytes()) as u64);
+            a64!(ops
+                ; add x22, x22, x9
+            );
+        }
+        if let Some(steps) = opts.steps {
+            load_imm(ops, 9, steps as u64);
+            a64!(ops
+                ; ldr x23, [x9]
+            );
+        }
+
+        use BfIR::*;
+        for (i, &ir) in code.iter().enumerate() {
+            if let Some(counters) = opts.counters {
+                load_imm(ops, 9, counters.wrapping_add(bfir::counter_index(opts.regions, i, ir)) as u64);
+                a64!(ops
+                    ; ldr x10, [x9]
+                    ; add x10, x10, 1
+                    ; str x10, [x9]
+                );
+            }
+            if growable {
+                let retry = ops.new_dynamic_label();
+                retry_labels.push(retry);
+                a64!(ops
+                    ; =>retry
+                );
+            }
+
+            // a fused move and add runs as the move here and the add below
+            let (ir, then_add) = ir.split_move();
+            match ir {
+                AddPtr(x) if opts.tape_mode == TapeMode::Wrapping => {
+                    load_imm(ops, 9, x as u64 * bytes as u64);
+                    a64!(ops
+                        ; sub x10, x22, x20         // offset of ptr
+                        ; add x10, x10, x9
+                        ; sub x11, x21, x20         // size of the tape
+                        ; udiv x12, x10, x11
+                        ; msub x10, x12, x11, x10   // offset % size
+                        ; add x22, x20, x10
+                    )
+                }
+                SubPtr(x) if opts.tape_mode == TapeMode::Wrapping => {
+                    load_imm(ops, 9, x as u64 * bytes as u64);
+                    a64!(ops
+                        ; sub x11, x21, x20         // size of the tape
+                        ; udiv x12, x9, x11
+                        ; msub x9, x12, x11, x9     // x % size
+                        ; sub x10, x22, x20         // offset of ptr
+                        ; subs x10, x10, x9
+                        ; add x12, x10, x11
+                        ; csel x10, x12, x10, lo    // came in at the right end
+                        ; add x22, x20, x10
+                    )
+                }
+                AddPtr(x) => {
+                    load_imm(ops, 9, x as u64 * bytes as u64);
+                    if !opts.checked(i) {
+                        a64!(ops
+                            ; add x22, x22, x9  // ptr += x, proven to stay on the tape
+                        );
+                    }
+                    else {
+                        let overflow = stub(ops, i, Direction::Right, x as i64 * bytes);
+                        a64!(ops
+                            ; adds x22, x22, x9 // ptr += x
+                            ; b.cs =>overflow
+                            ; cmp x22, x21      // ptr - memory_end
+                            ; b.hs =>overflow
+                        )
+                    }
+                }
+                SubPtr(x) => {
+                    load_imm(ops, 9, x as u64 * bytes as u64);
+                    if !opts.checked(i) {
+                        a64!(ops
+                            ; sub x22, x22, x9  // ptr -= x, proven to stay on the tape
+                        );
+                    }
+                    else {
+                        let overflow = stub(ops, i, Direction::Left, -(x as i64 * bytes));
+                        a64!(ops
+                            ; subs x22, x22, x9 // ptr -= x
+                            ; b.lo =>overflow
+                            ; cmp x22, x20      // ptr - memory_start
+                            ; b.lo =>overflow
+                        )
+                    }
+                }
+                AddVal(x) if opts.arith == ArithMode::Saturating => {
+                    load_cell(ops, width, 9, 22);
+                    load_imm(ops, 10, x.min(width.max()) as u64);
+                    load_imm(ops, 11, width.max() as u64);
+                    a64!(ops
+                        ; add x9, x9, x10
+                        ; cmp x9, x11
+                        ; csel x9, x11, x9, hi  // *ptr = min(*ptr + x, max)
+                    );
+                    store_cell(ops, width, 9, 22);
+                }
+                SubVal(x) if opts.arith == ArithMode::Saturating => {
+                    load_cell(ops, width, 9, 22);
+                    load_imm(ops, 10, x.min(width.max()) as u64);
+                    a64!(ops
+                        ; subs w9, w9, w10
+                        ; csel w9, wzr, w9, lo  // *ptr = 0 on a borrow
+                    );
+                    store_cell(ops, width, 9, 22);
+                }
+                AddVal(x) => {
+                    load_cell(ops, width, 9, 22);
+                    add_imm(ops, x);   // *ptr += x
+                    store_cell(ops, width, 9, 22);
+                }
+                SubVal(x) => {
+                    load_cell(ops, width, 9, 22);
+                    add_imm(ops, x.wrapping_neg());    // *ptr -= x
+                    store_cell(ops, width, 9, 22);
+                }
+                SetVal(x) => {
+                    load_imm(ops, 9, x as u64);
+                    store_cell(ops, width, 9, 22);    // *ptr = x
+                }
+                MulVal { offset, factor } => {
+                    load_cell(ops, width, 9, 22);
+                    a64!(ops
+                        ; cbz w9, >skip         // the loop never runs if *ptr == 0
+                    );
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(code, i).filter(|_| opts.checked(i)) {
+                        let left = stub(ops, i, Direction::Left, 0);
+                        let right = stub(ops, i, Direction::Right, 0);
+                        load_imm(ops, 10, (lo as i64 * bytes) as u64);
+                        a64!(ops
+                            ; add x11, x22, x10
+                            ; cmp x11, x20      // ptr + lo - memory_start
+                            ; b.lo =>left
+                        );
+                        load_imm(ops, 10, (hi as i64 * bytes) as u64);
+                        a64!(ops
+                            ; add x11, x22, x10
+                            ; cmp x11, x21      // ptr + hi - memory_end
+                            ; b.hs =>right
+                        );
+                    }
+                    load_imm(ops, 10, (offset as i64 * bytes) as u64);
+                    a64!(ops
+                        ; add x11, x22, x10
+                    );
+                    load_imm(ops, 12, factor as u64);
+                    a64!(ops
+                        ; mul w9, w9, w12
+                    );
+                    load_cell(ops, width, 13, 11);
+                    a64!(ops
+                        ; add w13, w13, w9      // ptr[offset] += *ptr * factor
+                    );
+                    store_cell(ops, width, 13, 11);
+                    a64!(ops
+                        ; skip:
+                    )
+                }
+                AddValAt { offset, val } => {
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(code, i).filter(|_| opts.checked(i)) {
+                        let left = stub(ops, i, Direction::Left, 0);
+                        let right = stub(ops, i, Direction::Right, 0);
+                        load_imm(ops, 10, (lo as i64 * bytes) as u64);
+                        a64!(ops
+                            ; add x11, x22, x10
+                            ; cmp x11, x20      // ptr + lo - memory_start
+                            ; b.lo =>left
+                        );
+                        load_imm(ops, 10, (hi as i64 * bytes) as u64);
+                        a64!(ops
+                            ; add x11, x22, x10
+                            ; cmp x11, x21      // ptr + hi - memory_end
+                            ; b.hs =>right
+                        );
+                    }
+                    load_imm(ops, 10, (offset as i64 * bytes) as u64);
+                    a64!(ops
+                        ; add x11, x22, x10
+                    );
+                    load_cell(ops, width, 9, 11);
+                    add_imm(ops, val);     // ptr[offset] += val
+                    store_cell(ops, width, 9, 11);
+                }
+                ClearRange { len } => {
+                    load_imm(ops, 10, (len as u64 - 1) * bytes as u64);
+                    a64!(ops
+                        ; add x11, x22, x10
+                    );
+                    if opts.checked(i) {
+                        let overflow = stub(ops, i, Direction::Right, 0);
+                        a64!(ops
+                            ; cmp x11, x21              // last - memory_end
+                            ; b.hs =>overflow
+                        );
+                    }
+                    a64!(ops
+                        ; movz w9, 0
+                        ; fill:
+                    );
+                    store_cell(ops, width, 9, 22);
+                    a64!(ops
+                        ; cmp x22, x11
+                        ; b.hs >done
+                        ; add x22, x22, bytes as u32    // ptr += 1
+                        ; b <fill
+                        ; done:
+                    )
+                }
+                // scan with x11, so ptr is still where it started on overflow
+                ScanRight => {
+                    let overflow = stub(ops, i, Direction::Right, 0);
+                    a64!(ops
+                        ; mov x11, x22
+                        ; scan:
+                    );
+                    load_cell(ops, width, 9, 11);
+                    a64!(ops
+                        ; cbz w9, >done
+                        ; add x11, x11, bytes as u32    // cell += 1
+                        ; cmp x11, x21                  // cell - memory_end
+                        ; b.hs =>overflow
+                        ; b <scan
+                        ; done:
+                        ; mov x22, x11
+                    )
+                }
+                ScanLeft => {
+                    let overflow = stub(ops, i, Direction::Left, 0);
+                    a64!(ops
+                        ; mov x11, x22
+                        ; scan:
+                    );
+                    load_cell(ops, width, 9, 11);
+                    a64!(ops
+                        ; cbz w9, >done
+                        ; cmp x11, x20                  // cell - memory_start
+                        ; b.ls =>overflow
+                        ; sub x11, x11, bytes as u32    // cell -= 1
+                        ; b <scan
+                        ; done:
+                        ; mov x22, x11
+                    )
+                }
+                GetByte => {
+                    a64!(ops
+                        ; mov x0, x19       // load this
+                        ; mov x1, x22       // load ptr
+                    );
+                    a64!(ops
+                        ; ldr x9, [x24, Helper::GetByte.offset() as u32]
+                        ; blr x9            // (this, ptr)
+                        ; cbnz x0, =>io_error
+                    )
+                }
+                PutByte => {
+                    a64!(ops
+                        ; mov x0, x19       // load this
+                        ; mov x1, x22       // load ptr
+                    );
+                    a64!(ops
+                        ; ldr x9, [x24, Helper::PutByte.offset() as u32]
+                        ; blr x9            // (this, ptr)
+                        ; cbnz x0, =>io_error
+                    )
+                }
+                PutByteN { count } => {
+                    a64!(ops
+                        ; mov x0, x19       // load this
+                        ; mov x1, x22       // load ptr
+                    );
+                    load_imm(ops, 2, count as u64);
+                    a64!(ops
+                        ; ldr x9, [x24, Helper::PutByteN.offset() as u32]
+                        ; blr x9            // (this, ptr, count)
+                        ; cbnz x0, =>io_error
+                    )
+                }
+                Nop => {}
+                DumpTape => {
+                    a64!(ops
+                        ; mov x0, x19       // load this
+                        ; mov x1, x22       // load ptr
+                        ; ldr x9, [x24, Helper::DumpTape.offset() as u32]
+                        ; blr x9            // (this, ptr)
+                        ; cbnz x0, =>io_error
+                    )
+                }
+                ReadLine => {
+                    a64!(ops
+                        ; mov x0, x19       // load this
+                        ; mov x1, x22       // load ptr
+                        ; ldr x9, [x24, Helper::ReadLine.offset() as u32]
+                        ; blr x9            // (this, ptr)
+                        ; cbnz x0, =>io_error
+                    )
+                }
+                Jz => {
+                    let left = ops.new_dynamic_label();
+                    let right = ops.new_dynamic_label();
+                    loop_stack.push((left, right, loops));
+                    loops += 1;
+
+                    // cbz only reaches +-1MiB, so branch around a plain b
+                    load_cell(ops, width, 9, 22);
+                    a64!(ops
+                        ; cbnz w9, >body
+                        ; b => right        // jmp if *ptr == 0
+                        ; body:
+                        ; => left
+                    )
+                }
+                Jnz => {
+                    let (left, right, id) = loop_stack.pop().ok_or(VMError::UnbalancedIr { index: i })?;
+                    if let Some(loop_counters) = opts.loop_counters {
+                        load_imm(ops, 9, loop_counters.wrapping_add(id) as u64);
+                        a64!(ops
+                            ; ldr x10, [x9]
+                            ; add x10, x10, 1
+                            ; str x10, [x9]
+                        );
+                    }
+                    a64!(ops
+                        ; ldr x9, [x24, Helper::Interrupt.offset() as u32]
+                        ; ldrb w10, [x9]
+                        ; cbnz w10, =>cancelled
+                    );
+                    if opts.steps.is_some() {
+                        let exhausted = ops.new_dynamic_label();
+                        step_stubs.push((exhausted, i));
+                        a64!(ops
+                            ; subs x23, x23, 1
+                            ; b.lo =>exhausted
+                        );
+                    }
+
+                    load_cell(ops, width, 9, 22);
+                    a64!(ops
+                        ; cbz w9, >end
+                        ; b => left         // jmp if *ptr != 0
+                        ; end:
+                        ; => right
+                    )
+                }
+                AddPtrThenAddVal { .. } => unreachable!()
+            }
+            if let Some(val) = then_add {
+                load_cell(ops, width, 9, 22);
+                add_imm(ops, val);     // *ptr += val
+                store_cell(ops, width, 9, 22);
+            }
+        }
+
+        a64!(ops
+            ; movz x0, 0
+            ; b >exit
+        );
+        for (label, i, direction, undo) in overflow_stubs {
+            a64!(ops
+                ; =>label
+            );
+            if undo != 0 {
+                load_imm(ops, 9, undo as u64);
+                a64!(ops
+                    ; sub x22, x22, x9  // ptr as it was before the instruction
+                );
+            }
+            load_imm(ops, 1, i as u64);
+            match direction {
+                Direction::Left => a64!(ops ; b =>overflow_left),
+                Direction::Right if growable => a64!(ops
+                    ; bl =>grow
+                    ; b =>retry_labels[i]
+                ),
+                Direction::Right => a64!(ops ; b =>overflow_right),
+            }
+        }
+        for (label, i) in step_stubs {
+            a64!(ops
+                ; =>label
+            );
+            load_imm(ops, 1, i as u64);
+            a64!(ops
+                ; b =>budget_exhausted
+            );
+        }
+        a64!(ops
+            ; =>cancelled
+            ; ldr x9, [x24, Helper::CancelledError.offset() as u32]
+            ; blr x9
+            ; b >exit
+            ; =>budget_exhausted
+            ; mov x0, x19       // load this
+            ; ldr x9, [x24, Helper::StepLimitError.offset() as u32]
+            ; blr x9            // (this, index)
+            ; b >exit
+            ; =>overflow_left
+            ; mov x0, x19       // load this
+            ; sub x2, x22, x20  // ptr - memory_start
+            ; ldr x9, [x24, Helper::OverflowLeftError.offset() as u32]
+            ; blr x9            // (this, index, offset)
+            ; b >exit
+            ; =>overflow_right
+            ; mov x0, x19       // load this
+            ; sub x2, x22, x20  // ptr - memory_start
+            ; ldr x9, [x24, Helper::OverflowRightError.offset() as u32]
+            ; blr x9            // (this, index, offset)
+            ; b >exit
+        );
+        if growable {
+            a64!(ops
+                ; =>grow
+                ; sub sp, sp, 32    // the new bounds and the return address
+                ; str x30, [sp, #16]
+                ; mov x0, x19       // load this
+                ; sub x2, x22, x20  // ptr - memory_start
+                ; mov x3, sp
+                ; ldr x9, [x24, Helper::Grow.offset() as u32]
+                ; blr x9            // (this, index, offset, bounds)
+                ; cbnz x0, >failed
+                ; sub x22, x22, x20
+                ; ldp x20, x21, [sp]    // reload memory_start and memory_end
+                ; add x22, x22, x20 // ptr at the same offset into the new tape
+                ; ldr x30, [sp, #16]
+                ; add sp, sp, 32
+                ; ret
+                ; failed:
+                ; add sp, sp, 32
+                ; b >exit
+            );
+        }
+        a64!(ops
+            ; =>io_error
+            ; exit:
+        );
+        if let Some(steps) = opts.steps {
+            load_imm(ops, 9, steps as u64);
+            a64!(ops
+                ; str x23, [x9]
+            );
+        }
+        a64!(ops
+            ; ldp x23, x24, [sp, #48]
+            ; ldp x21, x22, [sp, #32]
+            ; ldp x19, x20, [sp, #16]
+            ; ldp x29, x30, [sp], #64
+            ; ret
+        );
+
+        Ok(start)
+    }
+}
+
+#[test]
+fn test_compile_aarch64() {
+    // the assembler is host independent, so this at least checks the encoding on any host
+    let samples = [
+        "+[,.]",
+        "[+++++]",
+        "+[->++>+++<<]>>>+++>++<<.",
+        "+>+>+[<]>[>]",
+        "[-]>[-]>[-]",
+        "+.....",
+        include_str!("../../examples/mendelbrot.bf"),
+    ];
+    for (src, cell_width) in samples.into_iter().flat_map(|src| {
+        [CellWidth::W8, CellWidth::W16, CellWidth::W32].map(|width| (src, width))
+    }) {
+        let mut ir = crate::bfir::compile(src).unwrap();
+        crate::bfir::optimize(&mut ir);
+        let mut steps = 0;
+        let mut loop_counters = vec![0u64; ir.len()];
+        for (tape_mode, start_offset, arith) in [
+            (TapeMode::Fixed, 0, ArithMode::Wrapping),
+            (TapeMode::Growable, 0, ArithMode::Wrapping),
+            (TapeMode::Bidirectional, 0, ArithMode::Wrapping),
+            (TapeMode::Wrapping, 0, ArithMode::Wrapping),
+            (TapeMode::Fixed, 70000, ArithMode::Saturating),
+        ] {
+            let opts = JitOptions {
+                cell_width,
+                arith,
+                loop_counters: Some(loop_counters.as_mut_ptr()),
+                steps: Some(&mut steps),
+                tape_mode,
+                start_offset,
+                ..JitOptions::default()
+            };
+            let (code, start) = BfVM::compile_aarch64(&ir, opts).unwrap();
+
+            // stp x29, x30, [sp, #-64]!
+            assert_eq!(code[start.0..start.0 + 4], [0xfd, 0x7b, 0xbc, 0xa9]);
+            // ret
+            assert_eq!(code[code.len() - 4..], [0xc0, 0x03, 0x5f, 0xd6]);
+            assert_eq!(code.len() % 4, 0);
+        }
+    }
+}
+
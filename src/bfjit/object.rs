@@ -0,0 +1,243 @@
+//! wrap the generated x64 code into an ELF relocatable object
+
+use super::Helper;
+
+/// what an absolute address in the generated code points to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Extern {
+    Counters,
+    LoopCounters,
+    Steps,
+}
+
+impl Extern {
+    /// name of the symbol standing in for it in an object file
+    pub(crate) fn symbol(self) -> &'static str {
+        match self {
+            Extern::Counters => "bf_counters",
+            Extern::LoopCounters => "bf_loop_counters",
+            Extern::Steps => "bf_steps",
+        }
+    }
+}
+
+/// name of the symbol the helper table entry of `helper` refers to
+fn helper_symbol(helper: Helper) -> &'static str {
+    match helper {
+        Helper::GetByte => "bf_getbyte",
+        Helper::PutByte => "bf_putbyte",
+        Helper::PutByteN => "bf_putbyte_n",
+        Helper::OverflowLeftError => "bf_overflow_left_error",
+        Helper::OverflowRightError => "bf_overflow_right_error",
+        Helper::StepLimitError => "bf_step_limit_error",
+        Helper::CancelledError => "bf_cancelled_error",
+        Helper::Grow => "bf_grow",
+        Helper::DumpTape => "bf_dump_tape",
+        Helper::ReadLine => "bf_read_line",
+        Helper::Interrupt => "bf_interrupt",
+    }
+}
+
+/// a 64-bit absolute address at `offset` in the code, `addend` bytes past `target`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Reloc {
+    pub(crate) offset: usize,
+    pub(crate) target: Extern,
+    pub(crate) addend: i64,
+}
+
+// section indices
+const TEXT: u16 = 1;
+const DATA: u16 = 3;
+const SYMTAB: u32 = 5;
+const STRTAB: u32 = 6;
+const SHSTRTAB: u16 = 7;
+
+/// an ELF64 x86-64 relocatable object with `text` as `.text`, exporting `bf_main` at `entry`
+/// and the helper table `bf_helpers` in `.data.rel.ro`, referring to every helper and
+/// every target of `relocs` by an undefined symbol
+pub(crate) fn write_elf(text: &[u8], entry: usize, relocs: &[Reloc]) -> Vec<u8> {
+    let mut externs: Vec<Extern> = vec![];
+    for r in relocs {
+        if !externs.contains(&r.target) {
+            externs.push(r.target);
+        }
+    }
+
+    // the linker fills in the addresses, the ones of this process are meaningless
+    let mut text = text.to_vec();
+    for r in relocs {
+        text[r.offset..r.offset + 8].fill(0);
+    }
+
+    let mut strtab = vec![0_u8];
+    let add_str = |strtab: &mut Vec<u8>, name: &str| {
+        let at = strtab.len() as u32;
+        strtab.extend_from_slice(name.as_bytes());
+        strtab.push(0);
+        at
+    };
+
+    // null, bf_main, bf_helpers, the helpers, then the externs
+    let data = vec![0_u8; 8 * Helper::ALL.len()];
+    let mut symtab = vec![0_u8; 24];
+    let name = add_str(&mut strtab, "bf_main");
+    push_sym(&mut symtab, name, 0x12, TEXT, entry as u64, (text.len() - entry) as u64);
+    let name = add_str(&mut strtab, "bf_helpers");
+    push_sym(&mut symtab, name, 0x11, DATA, 0, data.len() as u64);
+    let symbols = Helper::ALL.map(helper_symbol).into_iter().chain(externs.iter().map(|e| e.symbol()));
+    for symbol in symbols {
+        let name = add_str(&mut strtab, symbol);
+        push_sym(&mut symtab, name, 0x10, 0, 0, 0);
+    }
+
+    // R_X86_64_64 of symbol `sym` at `offset`
+    let push_rela = |rela: &mut Vec<u8>, offset: usize, sym: usize, addend: i64| {
+        rela.extend_from_slice(&(offset as u64).to_le_bytes());
+        rela.extend_from_slice(&(((sym as u64) << 32) | 1).to_le_bytes());
+        rela.extend_from_slice(&addend.to_le_bytes());
+    };
+    let mut rela = vec![];
+    for r in relocs {
+        let sym = 3 + Helper::ALL.len() + externs.iter().position(|&e| e == r.target).unwrap();
+        push_rela(&mut rela, r.offset, sym, r.addend);
+    }
+    let mut rela_data = vec![];
+    for (i, helper) in Helper::ALL.into_iter().enumerate() {
+        push_rela(&mut rela_data, helper.offset() as usize, 3 + i, 0);
+    }
+
+    let mut shstrtab = vec![0_u8];
+    let names: Vec<u32> = [
+        ".text",
+        ".rela.text",
+        ".data.rel.ro",
+        ".rela.data.rel.ro",
+        ".symtab",
+        ".strtab",
+        ".shstrtab",
+        ".note.GNU-stack",
+    ]
+        .iter()
+        .map(|name| add_str(&mut shstrtab, name))
+        .collect();
+
+    let mut out = vec![0_u8; 64];
+    let place = |out: &mut Vec<u8>, data: &[u8], align: usize| {
+        out.resize(out.len().next_multiple_of(align), 0);
+        let at = out.len() as u64;
+        out.extend_from_slice(data);
+        at
+    };
+    let text_at = place(&mut out, &text, 16);
+    let rela_at = place(&mut out, &rela, 8);
+    let data_at = place(&mut out, &data, 8);
+    let rela_data_at = place(&mut out, &rela_data, 8);
+    let symtab_at = place(&mut out, &symtab, 8);
+    let strtab_at = place(&mut out, &strtab, 1);
+    let shstrtab_at = place(&mut out, &shstrtab, 1);
+    let shoff = place(&mut out, &[], 8);
+
+    let sections = [
+        Section::default(),
+        Section { name: names[0], kind: 1, flags: 0x6, offset: text_at, size: text.len(), align: 16, ..Section::default() },
+        Section {
+            name: names[1],
+            kind: 4,
+            flags: 0x40,
+            offset: rela_at,
+            size: rela.len(),
+            link: SYMTAB,
+            info: TEXT as u32,
+            align: 8,
+            entsize: 24
+        },
+        Section { name: names[2], kind: 1, flags: 0x3, offset: data_at, size: data.len(), align: 8, ..Section::default() },
+        Section {
+            name: names[3],
+            kind: 4,
+            flags: 0x40,
+            offset: rela_data_at,
+            size: rela_data.len(),
+            link: SYMTAB,
+            info: DATA as u32,
+            align: 8,
+            entsize: 24
+        },
+        Section {
+            name: names[4],
+            kind: 2,
+            offset: symtab_at,
+            size: symtab.len(),
+            link: STRTAB,
+            info: 1,
+            align: 8,
+            entsize: 24,
+            ..Section::default()
+        },
+        Section { name: names[5], kind: 3, offset: strtab_at, size: strtab.len(), align: 1, ..Section::default() },
+        Section { name: names[6], kind: 3, offset: shstrtab_at, size: shstrtab.len(), align: 1, ..Section::default() },
+        Section { name: names[7], kind: 1, offset: shoff, align: 1, ..Section::default() },
+    ];
+    for section in &sections {
+        section.write(&mut out);
+    }
+
+    let mut header = vec![];
+    // 64-bit, little endian, version 1, System V
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    header.extend_from_slice(&1_u16.to_le_bytes());     // relocatable
+    header.extend_from_slice(&62_u16.to_le_bytes());    // x86-64
+    header.extend_from_slice(&1_u32.to_le_bytes());
+    header.extend_from_slice(&0_u64.to_le_bytes());     // entry
+    header.extend_from_slice(&0_u64.to_le_bytes());     // program headers
+    header.extend_from_slice(&shoff.to_le_bytes());
+    header.extend_from_slice(&0_u32.to_le_bytes());     // flags
+    header.extend_from_slice(&64_u16.to_le_bytes());    // header size
+    header.extend_from_slice(&0_u16.to_le_bytes());
+    header.extend_from_slice(&0_u16.to_le_bytes());
+    header.extend_from_slice(&64_u16.to_le_bytes());    // section header size
+    header.extend_from_slice(&(sections.len() as u16).to_le_bytes());
+    header.extend_from_slice(&SHSTRTAB.to_le_bytes());
+    out[..64].copy_from_slice(&header);
+    out
+}
+
+/// an `Elf64_Shdr`
+#[derive(Default)]
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: usize,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+impl Section {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.name.to_le_bytes());
+        out.extend_from_slice(&self.kind.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&0_u64.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        out.extend_from_slice(&(self.size as u64).to_le_bytes());
+        out.extend_from_slice(&self.link.to_le_bytes());
+        out.extend_from_slice(&self.info.to_le_bytes());
+        out.extend_from_slice(&self.align.to_le_bytes());
+        out.extend_from_slice(&self.entsize.to_le_bytes());
+    }
+}
+
+/// append an `Elf64_Sym`
+fn push_sym(symtab: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64, size: u64) {
+    symtab.extend_from_slice(&name.to_le_bytes());
+    symtab.push(info);
+    symtab.push(0);
+    symtab.extend_from_slice(&shndx.to_le_bytes());
+    symtab.extend_from_slice(&value.to_le_bytes());
+    symtab.extend_from_slice(&size.to_le_bytes());
+}
@@ -0,0 +1,82 @@
+//! the cells of a vm, on the heap or in lazily zeroed anonymous memory
+
+use std::ops::{Deref, DerefMut};
+
+pub(crate) enum Tape {
+    Heap(Box<[u8]>),
+    /// pages only get committed once the program touches them
+    Mapped(memmap2::MmapMut),
+}
+
+impl Default for Tape {
+    fn default() -> Self {
+        Tape::Heap(Box::default())
+    }
+}
+
+impl Tape {
+    /// `len` zeroed bytes allocated and zeroed right away
+    pub(crate) fn heap(len: usize) -> Self {
+        Tape::Heap(vec![0; len].into_boxed_slice())
+    }
+
+    /// `len` zeroed bytes of anonymous memory, on the heap if that can't be
+    /// mapped on this platform
+    pub(crate) fn lazy(len: usize) -> Self {
+        memmap2::MmapMut::map_anon(len).map_or_else(|_| Self::heap(len), Tape::Mapped)
+    }
+
+    /// grow to `len` bytes, the new ones zeroed
+    pub(crate) fn grow(&mut self, len: usize) {
+        match self {
+            Tape::Heap(memory) => {
+                let mut grown = std::mem::take(memory).into_vec();
+                grown.resize(len, 0);
+                *memory = grown.into_boxed_slice();
+            }
+            Tape::Mapped(memory) => {
+                let mut grown = Self::lazy(len);
+                grown[..memory.len()].copy_from_slice(memory);
+                *self = grown;
+            }
+        }
+    }
+
+    /// zero all cells, mapped memory gets fresh pages instead of touching
+    /// every one of them
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Tape::Heap(memory) => memory.fill(0),
+            Tape::Mapped(memory) => *self = Self::lazy(memory.len()),
+        }
+    }
+
+    /// the cells on the heap, e.g. for the interpreter
+    #[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
+    pub(crate) fn into_boxed(self) -> Box<[u8]> {
+        match self {
+            Tape::Heap(memory) => memory,
+            Tape::Mapped(memory) => memory.to_vec().into_boxed_slice(),
+        }
+    }
+}
+
+impl Deref for Tape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Tape::Heap(memory) => memory,
+            Tape::Mapped(memory) => memory,
+        }
+    }
+}
+
+impl DerefMut for Tape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Tape::Heap(memory) => memory,
+            Tape::Mapped(memory) => memory,
+        }
+    }
+}
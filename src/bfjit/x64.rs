@@ -0,0 +1,602 @@
+use super::object::{Extern, Reloc};
+use super::{BfVM, Helper, JitOptions, TapeMode, MAX_MEM_SIZE};
+use crate::bfir::{self, ArithMode, BfIR, CellWidth};
+use crate::error::{Direction, Result, VMError};
+
+use dynasm::dynasm;
+use dynasmrt::x64::Assembler;
+use dynasmrt::{DynasmApi, DynasmLabelApi};
+
+/// `mov Rq(reg), addr` for `addr` being `addend` bytes past `target`, recorded in `relocs`
+fn load_addr(ops: &mut Assembler, relocs: &mut Vec<Reloc>, reg: u8, target: Extern, addend: usize, addr: i64) {
+    dynasm!(ops
+        ; mov Rq(reg), QWORD addr
+    );
+    relocs.push(Reloc { offset: ops.offset().0 - 8, target, addend: addend as i64 });
+}
+
+/// emit `$op` on a cell sized memory operand and an immediate, which is
+/// `$imm` truncated to the cell width; adding or subtracting it is the same
+/// modulo the cell size, e.g. `AddVal(200)` on 8-bit cells is
+/// `add BYTE [r15], -56` and still adds 200 mod 256
+macro_rules! cell_imm {
+    ($ops:ident, $width:expr; $op:ident [$($mem:tt)*], $imm:expr) => {
+        match $width {
+            CellWidth::W8 => dynasm!($ops ; $op BYTE [$($mem)*], $imm as i8),
+            CellWidth::W16 => dynasm!($ops ; $op WORD [$($mem)*], $imm as i16),
+            CellWidth::W32 => dynasm!($ops ; $op DWORD [$($mem)*], $imm as i32),
+        }
+    };
+}
+
+/// byte displacement of `cells` cells, `None` if it doesn't fit an operand,
+/// in which case it is beyond any tape anyway
+fn disp(cells: i64, width: CellWidth) -> Option<i32> {
+    const _: () = assert!(MAX_MEM_SIZE <= i32::MAX as usize);
+    i32::try_from(cells * width.bytes() as i64).ok()
+}
+
+impl BfVM {
+    pub(super) fn compile_x64(code: &[BfIR], opts: JitOptions) -> Result<(dynasmrt::ExecutableBuffer, dynasmrt::AssemblyOffset, Vec<Reloc>)> {
+        let mut ops = Assembler::new()?;
+        let mut relocs = vec![];
+        let start = BfVM::emit_x64(&mut ops, code, opts, &mut relocs)?;
+        ops.commit().map_err(|e| VMError::Assemble(e.to_string()))?;
+        // only fails while the code is borrowed for running, which it never is here
+        let code = ops.finalize().map_err(|_| VMError::Assemble("code buffer in use".into()))?;
+        Ok((code, start, relocs))
+    }
+
+    /// append the code for `code` to `ops`, with labels of its own so several
+    /// programs can share one buffer, returns where it is entered
+    pub(super) fn emit_x64(
+        ops: &mut Assembler,
+        code: &[BfIR],
+        opts: JitOptions,
+        relocs: &mut Vec<Reloc>
+    ) -> Result<dynasmrt::AssemblyOffset> {
+        let start = ops.offset();
+        let width = opts.cell_width;
+        // the shared exits of this program
+        let cancelled = ops.new_dynamic_label();
+        let budget_exhausted = ops.new_dynamic_label();
+        let overflow_left = ops.new_dynamic_label();
+        let overflow_right = ops.new_dynamic_label();
+        let grow = ops.new_dynamic_label();
+        let io_error = ops.new_dynamic_label();
+
+        // labels of the open loops and their index in the order of their `Jz`
+        let mut loop_stack: Vec<(dynasmrt::DynamicLabel, dynasmrt::DynamicLabel, usize)> = vec![];
+        let mut loops = 0;
+        // out of line stubs passing the ir index of a failed bounds check to
+        // =>overflow_left or =>overflow_right, after taking back `undo` bytes
+        // the instruction already moved ptr by
+        let mut overflow_stubs: Vec<(dynasmrt::DynamicLabel, usize, Direction, i32)> = vec![];
+        let mut stub = |ops: &mut Assembler, i, direction, undo| {
+            let label = ops.new_dynamic_label();
+            overflow_stubs.push((label, i, direction, undo));
+            label
+        };
+        // with a growable tape the stubs of the right end grow it instead and
+        // retry the instruction from its label here
+        let growable = opts.tape_mode == TapeMode::Growable;
+        let mut retry_labels = vec![];
+        // same for exhausting the step budget at a `Jnz`
+        let mut step_stubs: Vec<(dynasmrt::DynamicLabel, usize)> = vec![];
+
+        // this:         rdi r12
+        // memory_start: rsi r13
+        // memory_end:   rdx r14
+        // helpers:      rcx rbp
+        // ptr:              r15
+        // steps left:       rbx
+
+        dynasm!(ops
+            ; push rbx
+            ; push rbp
+            ; push r12
+            ; push r13
+            ; push r14
+            ; push r15
+            ; sub rsp, 8     // keep the stack 16 byte aligned for calls
+            ; mov r12, rdi   // save this
+            ; mov r13, rsi   // save memory_start
+            ; mov r14, rdx   // save memory_end
+            ; mov rbp, rcx   // save helpers
+            ; mov r15, rsi   // ptr = memory_start
+        );
+        if opts.tape_mode == TapeMode::Bidirectional {
+            let shift = width.bytes().trailing_zeros() as i8;
+            dynasm!(ops
+                ; mov rax, rdx
+                ; sub rax, rsi
+                ; shr rax, shift + 1
+                ; shl rax, shift
+                ; add r15, rax   // ptr = the middle cell
+            );
+        }
+        if opts.start_offset > 0 {
+            // the tape takes at most `MAX_MEM_SIZE` bytes, so this fits
+            dynasm!(ops
+                ; add r15, (opts.start_offset * width.bytes()) as i32
+            );
+        }
+        if let Some(steps) = opts.steps {
+            load_addr(ops, relocs, 0, Extern::Steps, 0, steps as i64);
+            dynasm!(ops
+                ; mov rbx, [rax]
+            );
+        }
+
+        use BfIR::*;
+        for (i, &ir) in code.iter().enumerate() {
+            if let Some(counters) = opts.counters {
+                let index = bfir::counter_index(opts.regions, i, ir);
+                load_addr(ops, relocs, 0, Extern::Counters, index * 8, counters.wrapping_add(index) as i64);
+                dynasm!(ops
+                    ; add QWORD [rax], 1
+                );
+            }
+            if growable {
+                let retry = ops.new_dynamic_label();
+                retry_labels.push(retry);
+                dynasm!(ops
+                    ; =>retry
+                );
+            }
+
+            // a fused move and add runs as the move here and the add below
+            let (ir, then_add) = ir.split_move();
+            match ir {
+                AddPtr(x) if opts.tape_mode == TapeMode::Wrapping => {
+                    dynasm!(ops
+                        ; mov rax, r15
+                        ; sub rax, r13      // offset of ptr
+                        ; mov rcx, QWORD x as i64 * width.bytes() as i64
+                        ; add rax, rcx
+                        ; mov rcx, r14
+                        ; sub rcx, r13      // size of the tape
+                        ; cmp rax, rcx
+                        ; jb >wrapped
+                        ; xor edx, edx
+                        ; div rcx
+                        ; mov rax, rdx      // offset % size
+                        ; wrapped:
+                        ; lea r15, [r13 + rax]
+                    )
+                }
+                SubPtr(x) if opts.tape_mode == TapeMode::Wrapping => {
+                    dynasm!(ops
+                        ; mov rcx, r14
+                        ; sub rcx, r13      // size of the tape
+                        ; mov rax, QWORD x as i64 * width.bytes() as i64
+                        ; cmp rax, rcx
+                        ; jb >reduced
+                        ; xor edx, edx
+                        ; div rcx
+                        ; mov rax, rdx      // x % size
+                        ; reduced:
+                        ; mov rdx, r15
+                        ; sub rdx, r13      // offset of ptr
+                        ; sub rdx, rax
+                        ; jnc >wrapped
+                        ; add rdx, rcx      // came in at the right end
+                        ; wrapped:
+                        ; lea r15, [r13 + rdx]
+                    )
+                }
+                AddPtr(x) => {
+                    match disp(x as i64, width) {
+                        Some(d) if !opts.checked(i) => dynasm!(ops
+                            ; add r15, d            // ptr += x, proven to stay on the tape
+                        ),
+                        Some(d) => {
+                            let overflow = stub(ops, i, Direction::Right, d);
+                            dynasm!(ops
+                                ; add r15, d        // ptr += x
+                                ; jc =>overflow
+                                ; cmp r15, r14      // ptr - memory_end
+                                ; jnb =>overflow
+                            )
+                        }
+                        None => {
+                            let overflow = stub(ops, i, Direction::Right, 0);
+                            dynasm!(ops
+                                ; jmp =>overflow
+                            )
+                        }
+                    }
+                }
+                SubPtr(x) => {
+                    match disp(x as i64, width) {
+                        Some(d) if !opts.checked(i) => dynasm!(ops
+                            ; sub r15, d            // ptr -= x, proven to stay on the tape
+                        ),
+                        Some(d) => {
+                            let overflow = stub(ops, i, Direction::Left, -d);
+                            dynasm!(ops
+                                ; sub r15, d        // ptr -= x
+                                ; jc =>overflow
+                                ; cmp r15, r13      // ptr - memory_start
+                                ; jb =>overflow
+                            )
+                        }
+                        None => {
+                            let overflow = stub(ops, i, Direction::Left, 0);
+                            dynasm!(ops
+                                ; jmp =>overflow
+                            )
+                        }
+                    }
+                }
+                AddVal(x) if opts.arith == ArithMode::Saturating => {
+                    cell_imm!(ops, width; add [r15], x.min(width.max()));
+                    dynasm!(ops
+                        ; jnc >done         // no carry out of the cell
+                    );
+                    cell_imm!(ops, width; mov [r15], width.max());
+                    dynasm!(ops
+                        ; done:
+                    )
+                }
+                SubVal(x) if opts.arith == ArithMode::Saturating => {
+                    cell_imm!(ops, width; sub [r15], x.min(width.max()));
+                    dynasm!(ops
+                        ; jnc >done         // no borrow
+                    );
+                    cell_imm!(ops, width; mov [r15], 0);
+                    dynasm!(ops
+                        ; done:
+                    )
+                }
+                AddVal(x) => cell_imm!(ops, width; add [r15], x),  // *ptr += x
+                SubVal(x) => cell_imm!(ops, width; sub [r15], x),  // *ptr -= x
+                SetVal(x) => cell_imm!(ops, width; mov [r15], x),  // *ptr = x
+                MulVal { offset, factor } => {
+                    match width {
+                        CellWidth::W8 => dynasm!(ops ; movzx eax, BYTE [r15]),
+                        CellWidth::W16 => dynasm!(ops ; movzx eax, WORD [r15]),
+                        CellWidth::W32 => dynasm!(ops ; mov eax, DWORD [r15]),
+                    }
+                    dynasm!(ops
+                        ; test eax, eax
+                        ; jz >skip                  // the loop never runs if *ptr == 0
+                    );
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(code, i).filter(|_| opts.checked(i)) {
+                        match (disp(lo as i64, width), disp(hi as i64, width)) {
+                            (Some(lo), Some(hi)) => {
+                                let left = stub(ops, i, Direction::Left, 0);
+                                let right = stub(ops, i, Direction::Right, 0);
+                                dynasm!(ops
+                                    ; lea rcx, [r15 + lo]
+                                    ; cmp rcx, r13      // ptr + lo - memory_start
+                                    ; jb =>left
+                                    ; lea rcx, [r15 + hi]
+                                    ; cmp rcx, r14      // ptr + hi - memory_end
+                                    ; jnb =>right
+                                )
+                            }
+                            (lo, _) => {
+                                let direction = if lo.is_none() { Direction::Left } else { Direction::Right };
+                                let overflow = stub(ops, i, direction, 0);
+                                dynasm!(ops
+                                    ; jmp =>overflow
+                                )
+                            }
+                        }
+                    }
+                    // out of reach offsets never get past the check above
+                    if let Some(d) = disp(offset as i64, width) {
+                        dynasm!(ops
+                            ; imul eax, eax, factor as i32
+                        );
+                        // ptr[offset] += *ptr * factor
+                        match width {
+                            CellWidth::W8 => dynasm!(ops ; add BYTE [r15 + d], al),
+                            CellWidth::W16 => dynasm!(ops ; add WORD [r15 + d], ax),
+                            CellWidth::W32 => dynasm!(ops ; add DWORD [r15 + d], eax),
+                        }
+                    }
+                    dynasm!(ops
+                        ; skip:
+                    )
+                }
+                AddValAt { offset, val } => {
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(code, i).filter(|_| opts.checked(i)) {
+                        match (disp(lo as i64, width), disp(hi as i64, width)) {
+                            (Some(lo), Some(hi)) => {
+                                let left = stub(ops, i, Direction::Left, 0);
+                                let right = stub(ops, i, Direction::Right, 0);
+                                dynasm!(ops
+                                    ; lea rcx, [r15 + lo]
+                                    ; cmp rcx, r13      // ptr + lo - memory_start
+                                    ; jb =>left
+                                    ; lea rcx, [r15 + hi]
+                                    ; cmp rcx, r14      // ptr + hi - memory_end
+                                    ; jnb =>right
+                                )
+                            }
+                            (lo, _) => {
+                                let direction = if lo.is_none() { Direction::Left } else { Direction::Right };
+                                let overflow = stub(ops, i, direction, 0);
+                                dynasm!(ops
+                                    ; jmp =>overflow
+                                )
+                            }
+                        }
+                    }
+                    // out of reach offsets never get past the check above
+                    if let Some(d) = disp(offset as i64, width) {
+                        cell_imm!(ops, width; add [r15 + d], val)   // ptr[offset] += val
+                    }
+                }
+                ClearRange { len } => {
+                    let (Some(d), Some(n)) = (disp(len as i64 - 1, width), disp(len as i64, width)) else {
+                        let overflow = stub(ops, i, Direction::Right, 0);
+                        dynasm!(ops
+                            ; jmp =>overflow
+                        );
+                        continue;
+                    };
+                    if opts.checked(i) {
+                        let overflow = stub(ops, i, Direction::Right, 0);
+                        dynasm!(ops
+                            ; lea rdi, [r15 + d]
+                            ; cmp rdi, r14  // last - memory_end
+                            ; jnb =>overflow
+                        );
+                    }
+                    dynasm!(ops
+                        ; mov rdi, r15
+                        ; mov ecx, n
+                        ; xor eax, eax
+                        ; rep stosb         // zero the cells
+                        ; lea r15, [rdi - width.bytes() as i32]
+                    )
+                }
+                ScanRight | ScanLeft => {
+                    let direction = if let ScanRight = ir { Direction::Right } else { Direction::Left };
+                    let overflow = stub(ops, i, direction, 0);
+                    let shift = width.bytes().trailing_zeros() as i8;
+                    // rcx = cells from ptr up to the end, or down to the start, of the tape
+                    if let ScanRight = ir {
+                        dynasm!(ops
+                            ; mov rcx, r14
+                            ; sub rcx, r15
+                            ; shr rcx, shift
+                        );
+                    }
+                    else {
+                        dynasm!(ops
+                            ; mov rcx, r15
+                            ; sub rcx, r13
+                            ; shr rcx, shift
+                            ; add rcx, 1
+                            ; std
+                        );
+                    }
+                    dynasm!(ops
+                        ; mov rdi, r15
+                        ; xor eax, eax
+                    );
+                    // look for a zero cell, rdi ends up one cell past it
+                    match width {
+                        CellWidth::W8 => dynasm!(ops ; repne scasb),
+                        CellWidth::W16 => dynasm!(ops ; repne scasw),
+                        CellWidth::W32 => dynasm!(ops ; repne scasd),
+                    }
+                    let d = width.bytes() as i32;
+                    if let ScanRight = ir {
+                        dynasm!(ops
+                            ; jne =>overflow    // no zero before memory_end
+                            ; lea r15, [rdi - d]
+                        );
+                    }
+                    else {
+                        dynasm!(ops
+                            ; cld
+                            ; jne =>overflow    // no zero down to memory_start
+                            ; lea r15, [rdi + d]
+                        );
+                    }
+                }
+                GetByte => {
+                    dynasm!(ops
+                        ; mov rdi, r12      // load this
+                        ; mov rsi, r15      // load ptr
+                    );
+                    dynasm!(ops
+                        ; call QWORD [rbp + Helper::GetByte.offset()]   // (this, ptr)
+                        ; test rax, rax
+                        ; jnz =>io_error
+                    )
+                }
+                PutByte => {
+                    dynasm!(ops
+                        ; mov rdi, r12      // load this
+                        ; mov rsi, r15      // load ptr
+                    );
+                    dynasm!(ops
+                        ; call QWORD [rbp + Helper::PutByte.offset()]   // (this, ptr)
+                        ; test rax, rax
+                        ; jnz =>io_error
+                    )
+                }
+                PutByteN { count } => {
+                    dynasm!(ops
+                        ; mov rdi, r12      // load this
+                        ; mov rsi, r15      // load ptr
+                        ; mov edx, count as i32
+                        ; call QWORD [rbp + Helper::PutByteN.offset()]  // (this, ptr, count)
+                        ; test rax, rax
+                        ; jnz =>io_error
+                    )
+                }
+                Nop => {}
+                DumpTape => {
+                    dynasm!(ops
+                        ; mov rdi, r12      // load this
+                        ; mov rsi, r15      // load ptr
+                        ; call QWORD [rbp + Helper::DumpTape.offset()]  // (this, ptr)
+                        ; test rax, rax
+                        ; jnz =>io_error
+                    )
+                }
+                ReadLine => {
+                    dynasm!(ops
+                        ; mov rdi, r12      // load this
+                        ; mov rsi, r15      // load ptr
+                        ; call QWORD [rbp + Helper::ReadLine.offset()]  // (this, ptr)
+                        ; test rax, rax
+                        ; jnz =>io_error
+                    )
+                }
+                Jz => {
+                    let left = ops.new_dynamic_label();
+                    let right = ops.new_dynamic_label();
+                    loop_stack.push((left, right, loops));
+                    loops += 1;
+
+                    cell_imm!(ops, width; cmp [r15], 0);
+                    dynasm!(ops
+                        ; jz => right       // jmp if *ptr == 0
+                        ; => left
+                    )
+                }
+                Jnz => {
+                    let (left, right, id) = loop_stack.pop().ok_or(VMError::UnbalancedIr { index: i })?;
+                    if let Some(loop_counters) = opts.loop_counters {
+                        let counter = loop_counters.wrapping_add(id);
+                        load_addr(ops, relocs, 0, Extern::LoopCounters, id * 8, counter as i64);
+                        dynasm!(ops
+                            ; add QWORD [rax], 1
+                        );
+                    }
+                    dynasm!(ops
+                        ; mov rax, QWORD [rbp + Helper::Interrupt.offset()]
+                        ; cmp BYTE [rax], 0
+                        ; jnz =>cancelled
+                    );
+                    if opts.steps.is_some() {
+                        let exhausted = ops.new_dynamic_label();
+                        step_stubs.push((exhausted, i));
+                        dynasm!(ops
+                            ; sub rbx, 1
+                            ; jc =>exhausted
+                        );
+                    }
+
+                    cell_imm!(ops, width; cmp [r15], 0);
+                    dynasm!(ops
+                        ; jnz => left       // jmp if *ptr != 0
+                        ; => right
+                    )
+                }
+                AddPtrThenAddVal { .. } => unreachable!()
+            }
+            if let Some(val) = then_add {
+                cell_imm!(ops, width; add [r15], val)   // *ptr += val
+            }
+        }
+
+        dynasm!(ops
+            ; xor rax, rax
+            ; jmp >exit
+        );
+        for (label, i, direction, undo) in overflow_stubs {
+            dynasm!(ops
+                ; =>label
+            );
+            if undo != 0 {
+                dynasm!(ops
+                    ; sub r15, undo     // ptr as it was before the instruction
+                );
+            }
+            dynasm!(ops
+                ; mov rsi, QWORD i as i64
+            );
+            match direction {
+                Direction::Left => dynasm!(ops ; jmp =>overflow_left),
+                Direction::Right if growable => dynasm!(ops
+                    ; call =>grow
+                    ; jmp =>retry_labels[i]
+                ),
+                Direction::Right => dynasm!(ops ; jmp =>overflow_right),
+            }
+        }
+        for (label, i) in step_stubs {
+            dynasm!(ops
+                ; =>label
+                ; mov rsi, QWORD i as i64
+                ; jmp =>budget_exhausted
+            );
+        }
+        dynasm!(ops
+            ; =>cancelled
+            ; call QWORD [rbp + Helper::CancelledError.offset()]
+            ; jmp >exit
+            ; =>budget_exhausted
+            ; mov rdi, r12      // load this
+            ; call QWORD [rbp + Helper::StepLimitError.offset()]    // (this, index)
+            ; jmp >exit
+            ; =>overflow_left
+            ; mov rdi, r12      // load this
+            ; mov rdx, r15
+            ; sub rdx, r13      // ptr - memory_start
+            ; call QWORD [rbp + Helper::OverflowLeftError.offset()]     // (this, index, offset)
+            ; jmp >exit
+            ; =>overflow_right
+            ; mov rdi, r12      // load this
+            ; mov rdx, r15
+            ; sub rdx, r13      // ptr - memory_start
+            ; call QWORD [rbp + Helper::OverflowRightError.offset()]    // (this, index, offset)
+            ; jmp >exit
+        );
+        if growable {
+            dynasm!(ops
+                ; =>grow
+                ; sub rsp, 24       // the new bounds, keeping the stack aligned past the return address
+                ; mov rdi, r12      // load this
+                ; mov rdx, r15
+                ; sub rdx, r13      // ptr - memory_start
+                ; mov rcx, rsp
+                ; call QWORD [rbp + Helper::Grow.offset()]  // (this, index, offset, bounds)
+                ; test rax, rax
+                ; jnz >failed
+                ; sub r15, r13
+                ; mov r13, [rsp]    // reload memory_start
+                ; mov r14, [rsp + 8]    // reload memory_end
+                ; add r15, r13      // ptr at the same offset into the new tape
+                ; add rsp, 24
+                ; ret
+                ; failed:
+                ; add rsp, 32       // the bounds and the return address
+                ; jmp >exit
+            );
+        }
+        dynasm!(ops
+            ; =>io_error
+            ; exit:
+        );
+        if let Some(steps) = opts.steps {
+            load_addr(ops, relocs, 1, Extern::Steps, 0, steps as i64);
+            dynasm!(ops
+                ; mov [rcx], rbx
+            );
+        }
+        dynasm!(ops
+            ; add rsp, 8
+            ; pop r15
+            ; pop r14
+            ; pop r13
+            ; pop r12
+            ; pop rbp
+            ; pop rbx
+            ; ret
+        );
+
+        Ok(start)
+    }
+}
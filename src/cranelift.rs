@@ -0,0 +1,539 @@
+//! compile ir into native code with Cranelift, for the hosts it supports
+//! beyond the x64 and aarch64 ones `bfjit` generates code for itself
+//!
+//! the generated code reaches the host through functions it imports by name,
+//! each taking the host first and returning whether to stop the run:
+//!
+//! - `bf_getbyte(host, ptr)` and `bf_putbyte(host, ptr)`, a `,` or `.` on
+//!   the cell at `ptr`
+//! - `bf_putbyte_n(host, ptr, count: u32)`, `count` times `.`
+//! - `bf_overflow_error(host, offset, right: bool)`, an instruction ran off
+//!   the right end of the tape if `right`, else off the left one, with the
+//!   pointer starting `offset` bytes into it, the run always stops
+//! - `bf_dump_tape(host, ptr)` and `bf_read_line(host, ptr)`, a `#` or `;`
+//!   with the pointer on the cell at `ptr`
+
+use crate::bfir::{self, BfIR, CellWidth};
+use crate::bfjit::{EofPolicy, MAX_MEM_SIZE};
+use crate::error::{Direction, Result, RuntimeError, VMError};
+use crate::interp::{dump_line, read_line, DefaultIo, Io};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, FuncRef, InstBuilder, MemFlagsData, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{Linkage, Module, ModuleError};
+
+/// the generated code, run on the tape starting at `memory`
+type RunFn<IO> = unsafe extern "C" fn(host: *mut Host<IO>, memory: *mut u8);
+
+/// a program compiled by Cranelift, along with its tape and io; it behaves
+/// like the JIT on a fixed tape of wrapping cells, steps, cancelling and
+/// profiling are left to `bfjit`
+pub struct CraneliftVm<IO = DefaultIo> {
+    /// owns the code, freed along with the vm
+    module: Option<JITModule>,
+    run: RunFn<IO>,
+    host: Host<IO>
+}
+
+/// what the imported functions work on
+struct Host<IO> {
+    memory: Box<[u8]>,
+    io: IO,
+    cell_width: CellWidth,
+    eof_policy: EofPolicy,
+    /// what stopped the run, taken by `CraneliftVm::run`
+    error: Option<RuntimeError>
+}
+
+impl<IO: Io> Host<IO> {
+    /// byte offset of `ptr` into the tape
+    fn offset(&self, ptr: *const u8) -> usize {
+        ptr as usize - self.memory.as_ptr() as usize
+    }
+
+    /// keep `e` for the caller and stop the run
+    fn fail(&mut self, e: RuntimeError) -> bool {
+        self.error = Some(e);
+        true
+    }
+
+    unsafe extern "C" fn getbyte(host: *mut Self, ptr: *mut u8) -> bool {
+        let host = &mut *host;
+        let (width, at) = (host.cell_width, host.offset(ptr));
+        let val = match host.io.read_byte() {
+            Ok(Some(byte)) => byte as u32,
+            Ok(None) => {
+                let mut val = width.load(&host.memory[at..]);
+                host.eof_policy.apply(&mut val);
+                val
+            }
+            Err(e) => return host.fail(e)
+        };
+        width.store(&mut host.memory[at..], val);
+        false
+    }
+
+    unsafe extern "C" fn putbyte(host: *mut Self, ptr: *mut u8) -> bool {
+        Self::putbyte_n(host, ptr, 1)
+    }
+
+    unsafe extern "C" fn putbyte_n(host: *mut Self, ptr: *mut u8, count: u32) -> bool {
+        let host = &mut *host;
+        // only the low byte of a wider cell is written
+        let byte = host.cell_width.load(&host.memory[host.offset(ptr)..]) as u8;
+        match host.io.write_byte(byte, count) {
+            Ok(()) => false,
+            Err(e) => host.fail(e)
+        }
+    }
+
+    unsafe extern "C" fn overflow_error(host: *mut Self, offset: usize, right: bool) -> bool {
+        let host = &mut *host;
+        let direction = if right { Direction::Right } else { Direction::Left };
+        host.fail(RuntimeError::PointerOverflow { at: offset / host.cell_width.bytes(), direction })
+    }
+
+    unsafe extern "C" fn dump_tape(host: *mut Self, ptr: *mut u8) -> bool {
+        let host = &mut *host;
+        let line = dump_line(&host.memory, host.cell_width, host.offset(ptr) / host.cell_width.bytes());
+        match host.io.dump(&line) {
+            Ok(()) => false,
+            Err(e) => host.fail(e)
+        }
+    }
+
+    unsafe extern "C" fn read_line(host: *mut Self, ptr: *mut u8) -> bool {
+        let host = &mut *host;
+        let cell = host.offset(ptr) / host.cell_width.bytes();
+        let io = &mut host.io;
+        match read_line(&mut host.memory, host.cell_width, cell, || io.read_byte()) {
+            Ok(()) => false,
+            Err(e) => host.fail(e)
+        }
+    }
+}
+
+fn module_error(e: ModuleError) -> VMError {
+    VMError::Assemble(e.to_string())
+}
+
+impl<IO: Io> CraneliftVm<IO> {
+    /// compile `ir` for a tape of `mem_size` cells starting at the first one
+    pub fn new(ir: &[BfIR], mem_size: usize, cell_width: CellWidth, eof_policy: EofPolicy, io: IO) -> Result<Self> {
+        if mem_size == 0 || mem_size > MAX_MEM_SIZE / cell_width.bytes() {
+            return Err(VMError::InvalidMemSize(mem_size));
+        }
+        if let Some(index) = bfir::unbalanced_bracket(ir) {
+            return Err(VMError::UnbalancedIr { index });
+        }
+
+        let helpers: [(&str, *const u8); 6] = [
+            ("bf_getbyte", Host::<IO>::getbyte as *const u8),
+            ("bf_putbyte", Host::<IO>::putbyte as *const u8),
+            ("bf_putbyte_n", Host::<IO>::putbyte_n as *const u8),
+            ("bf_overflow_error", Host::<IO>::overflow_error as *const u8),
+            ("bf_dump_tape", Host::<IO>::dump_tape as *const u8),
+            ("bf_read_line", Host::<IO>::read_line as *const u8),
+        ];
+        let mut builder = JITBuilder::new(cranelift_module::default_libcall_names()).map_err(module_error)?;
+        builder.symbols(helpers);
+        let mut module = JITModule::new(builder);
+        let ptr_type = module.target_config().pointer_type();
+
+        // (host, ptr) -> bool, but `bf_putbyte_n` takes a count and
+        // `bf_overflow_error` an offset and a direction instead of ptr
+        let mut funcs = vec![];
+        for (name, _) in helpers {
+            let mut sig = module.make_signature();
+            sig.params.push(AbiParam::new(ptr_type));
+            match name {
+                "bf_putbyte_n" => sig.params.extend([AbiParam::new(ptr_type), AbiParam::new(types::I32)]),
+                "bf_overflow_error" => sig.params.extend([AbiParam::new(ptr_type), AbiParam::new(types::I8)]),
+                _ => sig.params.push(AbiParam::new(ptr_type))
+            }
+            sig.returns.push(AbiParam::new(types::I8));
+            funcs.push(module.declare_function(name, Linkage::Import, &sig).map_err(module_error)?);
+        }
+
+        let mut sig = module.make_signature();
+        sig.params.extend([AbiParam::new(ptr_type), AbiParam::new(ptr_type)]);
+        let run = module.declare_function("bf_run", Linkage::Local, &sig).map_err(module_error)?;
+        let mut ctx = module.make_context();
+        ctx.func.signature = sig;
+        let refs: Vec<FuncRef> = funcs.into_iter().map(|f| module.declare_func_in_func(f, &mut ctx.func)).collect();
+
+        let mut func_ctx = FunctionBuilderContext::new();
+        let mut b = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let [host, memory] = [b.block_params(entry)[0], b.block_params(entry)[1]];
+        let ptr = b.declare_var(ptr_type);
+        let zero = b.ins().iconst(ptr_type, 0);
+        b.def_var(ptr, zero);
+        let cur = b.declare_var(ptr_type);
+        let exit = b.create_block();
+        let mut body = Body {
+            b,
+            config: module.target_config(),
+            host,
+            memory,
+            ptr,
+            cur,
+            exit,
+            helpers: [refs[0], refs[1], refs[2], refs[3], refs[4], refs[5]],
+            width: cell_width,
+            mem_bytes: (mem_size * cell_width.bytes()) as i64
+        };
+        body.emit(ir);
+        let Body { mut b, config, .. } = body;
+        b.ins().jump(exit, &[]);
+        b.switch_to_block(exit);
+        b.ins().return_(&[]);
+        b.seal_all_blocks();
+        b.finalize(config);
+
+        module.define_function(run, &mut ctx).map_err(module_error)?;
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().map_err(module_error)?;
+        let code = module.get_finalized_function(run);
+        Ok(Self {
+            module: Some(module),
+            run: unsafe { std::mem::transmute::<*const u8, RunFn<IO>>(code) },
+            host: Host {
+                memory: vec![0; mem_size * cell_width.bytes()].into_boxed_slice(),
+                io,
+                cell_width,
+                eof_policy,
+                error: None
+            }
+        })
+    }
+
+    /// run from the first cell on, with the tape as the last run left it
+    pub fn run(&mut self) -> std::result::Result<(), RuntimeError> {
+        let memory = self.host.memory.as_mut_ptr();
+        unsafe { (self.run)(&mut self.host, memory) };
+        self.host.error.take().map_or(Ok(()), Err)
+    }
+
+    /// the cells in native byte order
+    pub fn memory(&self) -> &[u8] {
+        &self.host.memory
+    }
+}
+
+impl<IO> Drop for CraneliftVm<IO> {
+    fn drop(&mut self) {
+        // the code goes with the vm, nothing else points into it
+        if let Some(module) = self.module.take() {
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+// indices of `Body::helpers`
+const GETBYTE: usize = 0;
+const PUTBYTE: usize = 1;
+const PUTBYTE_N: usize = 2;
+const OVERFLOW_ERROR: usize = 3;
+const DUMP_TAPE: usize = 4;
+const READ_LINE: usize = 5;
+
+/// the body of the generated function
+struct Body<'a> {
+    b: FunctionBuilder<'a>,
+    config: cranelift_codegen::isa::TargetFrontendConfig,
+    host: Value,
+    memory: Value,
+    /// byte offset of the pointer into the tape
+    ptr: Variable,
+    /// where a scan is at
+    cur: Variable,
+    /// returns from the function
+    exit: Block,
+    helpers: [FuncRef; 6],
+    width: CellWidth,
+    /// size of the tape in bytes
+    mem_bytes: i64
+}
+
+impl Body<'_> {
+    fn emit(&mut self, ir: &[BfIR]) {
+        use BfIR::*;
+        let bytes = self.width.bytes() as i64;
+        let in_bounds = bfir::in_bounds(ir, 0, (self.mem_bytes / bytes) as usize);
+        let mut loops = vec![];
+        for (i, &op) in ir.iter().enumerate() {
+            let checked = !in_bounds[i];
+            match op {
+                AddPtr(x) | SubPtr(x) => {
+                    let d = if let AddPtr(_) = op { x as i64 * bytes } else { -(x as i64 * bytes) };
+                    if checked {
+                        self.check(d);
+                    }
+                    self.add_ptr(d);
+                }
+                AddPtrThenAddVal { ptr_delta, val } => {
+                    let d = ptr_delta as i64 * bytes;
+                    if checked {
+                        self.check(d);
+                    }
+                    self.add_ptr(d);
+                    self.add_at(0, val as i64);
+                }
+                AddVal(x) => self.add_at(0, x as i64),
+                SubVal(x) => self.add_at(0, -(x as i64)),
+                SetVal(x) => {
+                    let val = self.b.ins().iconst(types::I32, x as i32 as i64);
+                    self.store(0, val);
+                }
+                GetByte => self.call(GETBYTE, &[]),
+                PutByte => self.call(PUTBYTE, &[]),
+                PutByteN { count } => {
+                    let count = self.b.ins().iconst(types::I32, count as i32 as i64);
+                    self.call(PUTBYTE_N, &[count]);
+                }
+                DumpTape => self.call(DUMP_TAPE, &[]),
+                ReadLine => self.call(READ_LINE, &[]),
+                Nop => {}
+                Jz => {
+                    let body = self.b.create_block();
+                    let after = self.b.create_block();
+                    let val = self.load(0);
+                    self.b.ins().brif(val, body, &[], after, &[]);
+                    self.b.switch_to_block(body);
+                    loops.push((body, after));
+                }
+                Jnz => {
+                    // balanced, checked up front
+                    let (body, after) = loops.pop().unwrap();
+                    let val = self.load(0);
+                    self.b.ins().brif(val, body, &[], after, &[]);
+                    self.b.switch_to_block(after);
+                }
+                MulVal { offset, factor } => {
+                    // the loop never runs if *ptr == 0
+                    let val = self.load(0);
+                    let then = self.b.create_block();
+                    let after = self.b.create_block();
+                    self.b.ins().brif(val, then, &[], after, &[]);
+                    self.b.switch_to_block(then);
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(ir, i).filter(|_| checked) {
+                        self.check(lo as i64 * bytes);
+                        self.check(hi as i64 * bytes);
+                    }
+                    let d = offset as i64 * bytes;
+                    let product = self.b.ins().imul_imm_s(val, factor as i32 as i64);
+                    let cell = self.load(d);
+                    let sum = self.b.ins().iadd(cell, product);
+                    self.store(d, sum);     // ptr[offset] += *ptr * factor
+                    self.b.ins().jump(after, &[]);
+                    self.b.switch_to_block(after);
+                }
+                AddValAt { offset, val } => {
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(ir, i).filter(|_| checked) {
+                        self.check(lo as i64 * bytes);
+                        self.check(hi as i64 * bytes);
+                    }
+                    self.add_at(offset as i64 * bytes, val as i64);
+                }
+                ClearRange { len } => {
+                    let d = (len as i64 - 1) * bytes;
+                    if checked {
+                        self.check(d);
+                    }
+                    let addr = self.addr(0);
+                    let zero = self.b.ins().iconst(types::I8, 0);
+                    let size = self.b.ins().iconst(self.config.pointer_type(), len as i64 * bytes);
+                    self.b.call_memset(self.config, addr, zero, size);
+                    self.add_ptr(d);
+                }
+                // scan with cur, so ptr is still where it started on overflow
+                ScanRight | ScanLeft => {
+                    let head = self.b.create_block();
+                    let edge = self.b.create_block();
+                    let overflow = self.b.create_block();
+                    let step = self.b.create_block();
+                    let after = self.b.create_block();
+                    let ptr = self.b.use_var(self.ptr);
+                    self.b.def_var(self.cur, ptr);
+                    self.b.ins().jump(head, &[]);
+
+                    self.b.switch_to_block(head);
+                    let cur = self.b.use_var(self.cur);
+                    let addr = self.b.ins().iadd(self.memory, cur);
+                    let val = self.load_from(addr);
+                    self.b.ins().brif(val, edge, &[], after, &[]);
+
+                    self.b.switch_to_block(edge);
+                    let at_end = if let ScanRight = op {
+                        // cur is on the last cell
+                        self.b.ins().icmp_imm_s(IntCC::UnsignedGreaterThanOrEqual, cur, self.mem_bytes - bytes)
+                    }
+                    else {
+                        // cur is on the first cell
+                        self.b.ins().icmp_imm_s(IntCC::Equal, cur, 0)
+                    };
+                    self.b.ins().brif(at_end, overflow, &[], step, &[]);
+
+                    self.b.switch_to_block(overflow);
+                    self.overflow(op == ScanRight);
+
+                    self.b.switch_to_block(step);
+                    let next = self.b.ins().iadd_imm_s(cur, if let ScanRight = op { bytes } else { -bytes });
+                    self.b.def_var(self.cur, next);
+                    self.b.ins().jump(head, &[]);
+
+                    self.b.switch_to_block(after);
+                    let cur = self.b.use_var(self.cur);
+                    self.b.def_var(self.ptr, cur);
+                }
+            }
+        }
+    }
+
+    /// stop through the overflow helper if `ptr + d` is off the tape
+    fn check(&mut self, d: i64) {
+        if d == 0 {
+            return;
+        }
+        let (limit, cc) = if d > 0 {
+            (self.mem_bytes - d, IntCC::UnsignedGreaterThanOrEqual)
+        }
+        else {
+            (-d, IntCC::UnsignedLessThan)
+        };
+        let overflow = self.b.create_block();
+        let after = self.b.create_block();
+        if limit <= 0 {
+            // off the tape from any cell
+            self.b.ins().jump(overflow, &[]);
+        }
+        else {
+            let ptr = self.b.use_var(self.ptr);
+            let off = self.b.ins().icmp_imm_s(cc, ptr, limit);
+            self.b.ins().brif(off, overflow, &[], after, &[]);
+        }
+        self.b.switch_to_block(overflow);
+        self.overflow(d > 0);
+        self.b.switch_to_block(after);
+    }
+
+    /// report an overflow with the cell ptr is on, then return
+    fn overflow(&mut self, right: bool) {
+        let ptr = self.b.use_var(self.ptr);
+        let right = self.b.ins().iconst(types::I8, right as i64);
+        self.b.ins().call(self.helpers[OVERFLOW_ERROR], &[self.host, ptr, right]);
+        self.b.ins().jump(self.exit, &[]);
+    }
+
+    /// call the helper with the host, the address of the current cell and
+    /// `args`, then return if it says so
+    fn call(&mut self, helper: usize, args: &[Value]) {
+        let addr = self.addr(0);
+        let call = self.b.ins().call(self.helpers[helper], &[&[self.host, addr], args].concat());
+        let stop = self.b.inst_results(call)[0];
+        let after = self.b.create_block();
+        self.b.ins().brif(stop, self.exit, &[], after, &[]);
+        self.b.switch_to_block(after);
+    }
+
+    fn add_ptr(&mut self, d: i64) {
+        let ptr = self.b.use_var(self.ptr);
+        let ptr = self.b.ins().iadd_imm_s(ptr, d);
+        self.b.def_var(self.ptr, ptr);
+    }
+
+    /// ptr[d] += x, wrapping at the cell width
+    fn add_at(&mut self, d: i64, x: i64) {
+        let cell = self.load(d);
+        let sum = self.b.ins().iadd_imm_s(cell, x);
+        self.store(d, sum);
+    }
+
+    /// the address `memory + ptr + d`
+    fn addr(&mut self, d: i64) -> Value {
+        let ptr = self.b.use_var(self.ptr);
+        let addr = self.b.ins().iadd(self.memory, ptr);
+        if d == 0 {
+            addr
+        }
+        else {
+            self.b.ins().iadd_imm_s(addr, d)
+        }
+    }
+
+    /// the cell `d` bytes from ptr as an i32
+    fn load(&mut self, d: i64) -> Value {
+        let addr = self.addr(d);
+        self.load_from(addr)
+    }
+
+    fn load_from(&mut self, addr: Value) -> Value {
+        let flags = MemFlagsData::new();
+        match self.width {
+            CellWidth::W8 => self.b.ins().uload8(types::I32, flags, addr, 0),
+            CellWidth::W16 => self.b.ins().uload16(types::I32, flags, addr, 0),
+            CellWidth::W32 => self.b.ins().load(types::I32, flags, addr, 0),
+        }
+    }
+
+    /// store the i32 `val`, truncated to the cell width, `d` bytes from ptr
+    fn store(&mut self, d: i64, val: Value) {
+        let addr = self.addr(d);
+        let flags = MemFlagsData::new();
+        match self.width {
+            CellWidth::W8 => self.b.ins().istore8(flags, val, addr, 0),
+            CellWidth::W16 => self.b.ins().istore16(flags, val, addr, 0),
+            CellWidth::W32 => self.b.ins().store(flags, val, addr, 0),
+        };
+    }
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_dynasm_parity() {
+    use crate::bfjit::{backend_samples, BfVM, SharedBuf};
+    use crate::interp::StdIo;
+
+    let io = |input: &'static [u8], output: &SharedBuf, dump: &SharedBuf| StdIo {
+        input: Box::new(input),
+        output: Box::new(output.clone()),
+        dump: Box::new(dump.clone()),
+        echo_input: false,
+        utf8: None
+    };
+    for sample in backend_samples() {
+        let (output, dump) = (SharedBuf::default(), SharedBuf::default());
+        let mut vm = CraneliftVm::new(&sample.ir, sample.mem_size, sample.width, EofPolicy::Zero, io(sample.input, &output, &dump)).unwrap();
+        let ret = vm.run();
+        assert_eq!(ret.err(), sample.error.map(|(e, _)| e), "{} {:?}", sample.src, sample.width);
+        assert_eq!(output.take(), sample.output, "{} {:?}", sample.src, sample.width);
+        assert_eq!(vm.memory(), sample.memory, "{} {:?}", sample.src, sample.width);
+    }
+
+    // `#` and `;`, which only come from the ir
+    let ir = [BfIR::AddPtr(1), BfIR::ReadLine, BfIR::AddVal(1), BfIR::DumpTape, BfIR::ReadLine, BfIR::AddPtr(2), BfIR::DumpTape];
+    let (output, dump) = (SharedBuf::default(), SharedBuf::default());
+    let mut vm = CraneliftVm::new(&ir, 8, CellWidth::W8, EofPolicy::Zero, io(b"ab\ncd", &output, &dump)).unwrap();
+    vm.run().unwrap();
+    let expected_dump = SharedBuf::default();
+    let mut jit = BfVM::builder()
+        .source_ir(ir.to_vec())
+        .mem_size(8)
+        .input_bytes(b"ab\ncd")
+        .dump_output(Box::new(expected_dump.clone()))
+        .build()
+        .unwrap();
+    jit.run().unwrap();
+    assert_eq!(vm.memory(), jit.memory());
+    assert_eq!(dump.take(), expected_dump.take());
+
+    assert!(matches!(CraneliftVm::new(&[BfIR::Jnz], 8, CellWidth::W8, EofPolicy::Zero, io(b"", &output, &dump)), Err(VMError::UnbalancedIr { index: 0 })));
+    assert!(matches!(CraneliftVm::new(&[], 0, CellWidth::W8, EofPolicy::Zero, io(b"", &output, &dump)), Err(VMError::InvalidMemSize(0))));
+}
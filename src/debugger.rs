@@ -0,0 +1,247 @@
+use crate::bfir::{self, BfIR, CompileError, SourcePos};
+use crate::bfjit::{EofPolicy, SharedBuf};
+use crate::error::RuntimeError;
+#[cfg(test)]
+use crate::error::Direction;
+use crate::interp::Interpreter;
+
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// what a single `Debugger::step` did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    /// one instruction ran
+    Stepped,
+    /// the program already finished, nothing ran
+    Halted,
+    /// the next instruction is a `,` and no input is queued, nothing ran
+    BlockedOnInput
+}
+
+/// why `Debugger::run_until_break` stopped
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakReason {
+    /// the instruction at this index has a breakpoint and did not run yet
+    Breakpoint(usize),
+    /// the program finished
+    Halted,
+    /// the next instruction is a `,` and no input is queued
+    BlockedOnInput
+}
+
+/// input queued through `Debugger::feed_input`
+#[derive(Clone, Default)]
+struct InputQueue(Arc<Mutex<VecDeque<u8>>>);
+
+impl InputQueue {
+    fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+}
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+/// runs a program one instruction at a time on the interpreter,
+/// with the same eof and bounds semantics as the JIT
+pub struct Debugger {
+    interp: Interpreter,
+    input: InputQueue,
+    /// once set an empty queue reads as eof instead of blocking
+    input_closed: bool,
+    output: SharedBuf,
+    /// source position of every instruction, `(0, 0)` without a source
+    positions: Vec<SourcePos>,
+    breakpoints: HashSet<usize>,
+    /// stopped at a breakpoint, which must not stop the next run right away
+    at_break: bool
+}
+
+impl Debugger {
+    /// `ir` must have balanced brackets, `mem_size` counts cells
+    pub fn new(ir: Vec<BfIR>, mem_size: usize) -> Self {
+        let positions = vec![(0, 0); ir.len()];
+        let input = InputQueue::default();
+        let output = SharedBuf::default();
+        let interp = Interpreter::new(
+            ir,
+            vec![0; mem_size].into_boxed_slice(),
+            Box::new(input.clone()),
+            Box::new(output.clone())
+        );
+        Self {
+            interp,
+            input,
+            input_closed: false,
+            output,
+            positions,
+            breakpoints: HashSet::new(),
+            at_break: false
+        }
+    }
+
+    /// compile `src` without optimizing, so every instruction maps to one
+    /// source character and breakpoints can be set by position
+    pub fn from_source(src: &str, mem_size: usize) -> Result<Self, CompileError> {
+        let (ir, positions) = bfir::compile_with_positions(src)?;
+        Ok(Self { positions, ..Self::new(ir, mem_size) })
+    }
+
+    pub fn with_eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.interp = self.interp.with_eof_policy(eof_policy);
+        self
+    }
+
+    /// queue bytes for the program to read
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.input.0.lock().unwrap().extend(bytes);
+    }
+
+    /// no more input will come, reads past the queued bytes hit eof
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// takes the bytes written so far
+    pub fn take_output(&mut self) -> Vec<u8> {
+        self.output.take()
+    }
+
+    /// break before the instruction at `line`:`col`, false if no
+    /// instruction stems from there
+    pub fn add_breakpoint(&mut self, line: u32, col: u32) -> bool {
+        match self.positions.iter().position(|&pos| pos == (line, col)) {
+            Some(index) => {
+                self.breakpoints.insert(index);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// run until the next breakpoint, the end of the program or a `,`
+    /// without input; a breakpoint at the current instruction only stops
+    /// the run if it did not stop the previous one
+    pub fn run_until_break(&mut self) -> Result<BreakReason, RuntimeError> {
+        let mut resume = self.at_break;
+        loop {
+            let pc = self.pc();
+            if !resume && self.breakpoints.contains(&pc) {
+                self.at_break = true;
+                return Ok(BreakReason::Breakpoint(pc));
+            }
+            resume = false;
+            match self.step()? {
+                StepOutcome::Stepped => {}
+                StepOutcome::Halted => return Ok(BreakReason::Halted),
+                StepOutcome::BlockedOnInput => return Ok(BreakReason::BlockedOnInput)
+            }
+        }
+    }
+
+    /// execute the next instruction
+    pub fn step(&mut self) -> Result<StepOutcome, RuntimeError> {
+        self.at_break = false;
+        match self.interp.next_op() {
+            None => Ok(StepOutcome::Halted),
+            Some(BfIR::GetByte | BfIR::ReadLine) if !self.input_closed && self.input.is_empty() => Ok(StepOutcome::BlockedOnInput),
+            Some(_) => self.interp.step().map(|()| StepOutcome::Stepped)
+        }
+    }
+
+    /// index of the instruction about to run, or the one that failed
+    pub fn pc(&self) -> usize {
+        self.interp.pc()
+    }
+
+    /// index of the current cell
+    pub fn ptr(&self) -> usize {
+        self.interp.ptr()
+    }
+
+    /// value of cell `index`, `None` past the end of the tape
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        self.interp.cell(index)
+    }
+
+    /// the whole tape, one byte per cell
+    pub fn memory(&self) -> &[u8] {
+        &self.interp.memory
+    }
+
+    /// `(line, col)` of the instruction about to run, `None` once halted
+    /// or without a source
+    pub fn position(&self) -> Option<SourcePos> {
+        self.positions.get(self.pc()).copied().filter(|&pos| pos != (0, 0))
+    }
+}
+
+#[test]
+fn test_step() {
+    let mut dbg = Debugger::new(crate::bfir::compile("+++").unwrap(), 1);
+    for i in 0..3 {
+        assert_eq!(dbg.pc(), i);
+        assert_eq!(dbg.cell(0), Some(i as u32));
+        assert_eq!(dbg.step().unwrap(), StepOutcome::Stepped);
+    }
+    assert_eq!(dbg.pc(), 3);
+    assert_eq!(dbg.cell(0), Some(3));
+    assert_eq!(dbg.step().unwrap(), StepOutcome::Halted);
+    assert_eq!(dbg.cell(1), None);
+}
+
+#[test]
+fn test_step_skip_loop() {
+    let mut dbg = Debugger::new(crate::bfir::compile("[>+<-]+").unwrap(), 2);
+    assert_eq!(dbg.step().unwrap(), StepOutcome::Stepped);
+    assert_eq!(dbg.pc(), 6);
+    assert_eq!(dbg.step().unwrap(), StepOutcome::Stepped);
+    assert_eq!((dbg.ptr(), dbg.cell(0), dbg.cell(1)), (0, Some(1), Some(0)));
+}
+
+#[test]
+fn test_step_input() {
+    let mut dbg = Debugger::new(crate::bfir::compile(",.,.").unwrap(), 1)
+        .with_eof_policy(EofPolicy::NegativeOne);
+    assert_eq!(dbg.step().unwrap(), StepOutcome::BlockedOnInput);
+    assert_eq!(dbg.pc(), 0);
+    dbg.feed_input(b"a");
+    while dbg.step().unwrap() == StepOutcome::Stepped {}
+    assert_eq!(dbg.pc(), 2);
+    dbg.close_input();
+    while dbg.step().unwrap() == StepOutcome::Stepped {}
+    assert_eq!(dbg.take_output(), b"a\xff");
+
+    let mut dbg = Debugger::new(crate::bfir::compile("<").unwrap(), 1);
+    assert!(matches!(dbg.step(), Err(RuntimeError::PointerOverflow { at: 0, direction: Direction::Left })));
+    assert_eq!(dbg.pc(), 0);
+}
+
+#[test]
+fn test_breakpoint() {
+    let mut dbg = Debugger::from_source("++.", 2).unwrap();
+    assert!(dbg.add_breakpoint(1, 3));
+    assert!(!dbg.add_breakpoint(1, 4));
+    assert_eq!(dbg.run_until_break().unwrap(), BreakReason::Breakpoint(2));
+    assert_eq!((dbg.ptr(), dbg.cell(0)), (0, Some(2)));
+    assert_eq!(dbg.memory(), [2, 0]);
+    assert_eq!(dbg.position(), Some((1, 3)));
+    assert!(dbg.take_output().is_empty());
+
+    assert_eq!(dbg.run_until_break().unwrap(), BreakReason::Halted);
+    assert_eq!(dbg.take_output(), [2]);
+
+    // a breakpoint inside a loop stops once per iteration
+    let mut dbg = Debugger::from_source("+++[\n-]", 1).unwrap();
+    assert!(dbg.add_breakpoint(2, 1));
+    for left in [3, 2, 1] {
+        assert_eq!(dbg.run_until_break().unwrap(), BreakReason::Breakpoint(4));
+        assert_eq!(dbg.cell(0), Some(left));
+    }
+    assert_eq!(dbg.run_until_break().unwrap(), BreakReason::Halted);
+}
@@ -1,24 +1,206 @@
-use thiserror;
+/// which end of the tape the pointer ran off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// below the first cell
+    Left,
+    /// past the last cell
+    Right
+}
+
+impl core::fmt::Display for Direction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Direction::Left => write!(f, "left"),
+            Direction::Right => write!(f, "right")
+        }
+    }
+}
+
+/// an `io::Error` cut down to its kind and message, which unlike the error
+/// itself can be cloned and compared
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{message}{}", partial_suffix(.partial))]
+pub struct IoError {
+    kind: std::io::ErrorKind,
+    message: String,
+    partial: Option<(usize, usize)>
+}
+
+#[cfg(feature = "std")]
+fn partial_suffix(partial: &Option<(usize, usize)>) -> String {
+    match partial {
+        Some((written, total)) => format!(" after writing {written} of {total} bytes"),
+        None => String::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl IoError {
+    /// `e` failing a write of `total` bytes after `written` of them went out
+    pub(crate) fn after_writing(e: std::io::Error, written: usize, total: usize) -> Self {
+        Self { partial: (written > 0).then_some((written, total)), ..e.into() }
+    }
+
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.kind
+    }
+
+    /// what the original error displayed as
+    pub fn message(&self) -> &str {
+        &self.message
+    }
 
-#[derive(Debug, thiserror::Error)]
+    /// `(written, total)` if the failing write got `written` of its `total`
+    /// bytes out before the error, so the output is truncated rather than
+    /// missing; `None` if nothing was written or the writer can't tell
+    pub fn partial_write(&self) -> Option<(usize, usize)> {
+        self.partial
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for IoError {
+    fn from(e: std::io::Error) -> Self {
+        Self { kind: e.kind(), message: e.to_string(), partial: None }
+    }
+}
+
+/// an error of the same kind and message as the original one
+#[cfg(feature = "std")]
+impl From<IoError> for std::io::Error {
+    fn from(e: IoError) -> Self {
+        std::io::Error::new(e.kind, e.message)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
 pub enum RuntimeError {
+    #[cfg(feature = "std")]
     #[error("IO: {0}")]
-    IO(#[from] std::io::Error),
+    IO(#[from] IoError),
+
+    /// an instruction would have moved or reached past an end of the tape,
+    /// `at` is the index of the cell the pointer was on when it started
+    #[error("Pointer overflow to the {direction} of cell {at}")]
+    PointerOverflow {
+        at: usize,
+        direction: Direction
+    },
+
+    /// the run used up its budget of `steps`, see `BfVmBuilder::max_steps`
+    #[error("Step limit exceeded after {steps} steps")]
+    StepLimitExceeded { steps: u64 },
+
+    #[error("Cancelled")]
+    Cancelled,
 
-    #[error("Pointer overflow")]
-    PointerOverflow
+    /// the run took longer than allowed, see `BfVM::run_with_timeout`
+    #[error("Timed out")]
+    Timeout,
+
+    /// the output took no byte for longer than allowed, see `BfVM::spawn_bounded`
+    #[error("Output timed out")]
+    OutputTimeout,
+
+    /// the output stops being UTF-8 at its byte `at`, see `Utf8Check`
+    #[error("Invalid UTF-8 in the output at byte {at}")]
+    InvalidUtf8 { at: usize }
 }
 
-#[derive(Debug, thiserror::Error)]
+#[cfg(feature = "std")]
+impl RuntimeError {
+    /// the error for a failed write to the output, which timing out makes
+    /// an `OutputTimeout`
+    pub(crate) fn from_output(e: IoError) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::TimedOut => RuntimeError::OutputTimeout,
+            _ => RuntimeError::IO(e)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for RuntimeError {
+    fn from(e: std::io::Error) -> Self {
+        RuntimeError::IO(e.into())
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
 pub enum VMError {
     #[error("IO: {0}")]
-    IO(#[from] std::io::Error),
+    IO(#[from] IoError),
 
     #[error("Compile: {0}")]
     Compile(#[from] crate::bfir::CompileError),
 
     #[error("Runtime: {0}")]
-    Runtime(#[from] RuntimeError)
+    Runtime(#[from] RuntimeError),
+
+    /// a runtime error raised by the instruction at `index` of the ir,
+    /// which stems from `line`:`col` of the source
+    #[error("Runtime: {error} at line {line}:{col}")]
+    RuntimeAt {
+        #[source]
+        error: RuntimeError,
+        index: usize,
+        line: u32,
+        col: u32
+    },
+
+    #[error("Invalid memory size {0}, expected 1 to {max} bytes", max = crate::bfjit::MAX_MEM_SIZE)]
+    InvalidMemSize(usize),
+
+    /// the pointer would start past the last cell, see `BfVmBuilder::start_offset`
+    #[error("Start offset {0} is off the tape")]
+    InvalidStartOffset(usize),
+
+    #[error("No source given, set one with `source_path` or `source_str`")]
+    MissingSource,
+
+    /// the ir given to run has a bracket without a match at `index`,
+    /// see `bfir::unbalanced_bracket`
+    #[error("Unbalanced bracket at instruction {index}")]
+    UnbalancedIr { index: usize },
+
+    /// the ir given to run is malformed otherwise, see `bfir::verify`
+    #[error("Invalid ir: {0}")]
+    InvalidIr(crate::bfir::VerifyError),
+
+    /// the generated code could not be put together, e.g. it jumps to a
+    /// label never defined
+    #[error("Failed to assemble the generated code: {0}")]
+    Assemble(String)
 }
 
-pub type Result<T> = std::result::Result<T, VMError>;
\ No newline at end of file
+#[cfg(feature = "std")]
+impl From<std::io::Error> for VMError {
+    fn from(e: std::io::Error) -> Self {
+        VMError::IO(e.into())
+    }
+}
+
+#[cfg(feature = "std")]
+pub type Result<T> = std::result::Result<T, VMError>;
+
+#[test]
+#[cfg(feature = "std")]
+fn test_error_clone() {
+    let err = RuntimeError::PointerOverflow { at: 3, direction: Direction::Left };
+    assert_eq!(err.clone(), err);
+    assert_ne!(err, RuntimeError::PointerOverflow { at: 3, direction: Direction::Right });
+
+    let err = VMError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "no such file"));
+    let VMError::IO(io) = err.clone() else { unreachable!() };
+    assert_eq!((io.kind(), io.message()), (std::io::ErrorKind::NotFound, "no such file"));
+    assert_eq!(err.to_string(), "IO: no such file");
+    assert_eq!(err.clone(), err);
+    assert_ne!(err, VMError::from(std::io::Error::other("no such file")));
+    // the kind and message survive the way back
+    let back = std::io::Error::from(io);
+    assert_eq!((back.kind(), back.to_string()), (std::io::ErrorKind::NotFound, "no such file".into()));
+    let err = VMError::RuntimeAt { error: RuntimeError::Cancelled, index: 1, line: 1, col: 2 };
+    assert_eq!(err.clone(), err);
+}
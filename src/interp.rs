@@ -0,0 +1,750 @@
+use crate::bfir::{self, ArithMode, BfIR, CellWidth};
+use crate::error::{Direction, RuntimeError};
+
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+/// largest tape that may be requested, in bytes
+pub const MAX_MEM_SIZE: usize = 1024 * 1024 * 1024;
+
+/// what `,` stores into the cell once the input is exhausted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EofPolicy {
+    /// leave the cell as it is
+    #[default]
+    Unchanged,
+    /// store 0
+    Zero,
+    /// store -1, i.e. all bits of the cell set
+    NegativeOne,
+}
+
+/// where the pointer starts and what happens when it runs off the tape
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum TapeMode {
+    /// start at the first cell, running off either end fails with
+    /// `RuntimeError::PointerOverflow`
+    #[default]
+    Fixed,
+    /// like `Fixed`, but running off the right end doubles the tape, up to
+    /// `MAX_MEM_SIZE` bytes, and goes on, the tape keeps its size for later runs
+    Growable,
+    /// like `Fixed`, but start at the middle cell, so the pointer may move
+    /// left of where it started, cell indexes still count from the first cell
+    Bidirectional,
+    /// start at the first cell, moving off either end comes back in at the
+    /// other one as on a ring; the nodes the optimizer makes for offsets and
+    /// scans don't wrap, so it leaves them out, in ir given to the vm they
+    /// still fail with `RuntimeError::PointerOverflow`
+    Wrapping,
+}
+
+impl TapeMode {
+    /// index of the cell the pointer starts at on a tape of `cells` cells
+    pub(crate) fn start_cell(self, cells: usize) -> usize {
+        match self {
+            TapeMode::Fixed | TapeMode::Growable | TapeMode::Wrapping => 0,
+            TapeMode::Bidirectional => cells / 2,
+        }
+    }
+}
+
+/// the tape size to grow `len` bytes to, `None` once it is at `MAX_MEM_SIZE`
+pub(crate) fn grown_len(len: usize) -> Option<usize> {
+    (len < MAX_MEM_SIZE).then(|| (len * 2).min(MAX_MEM_SIZE))
+}
+
+/// how many cells a `#` shows
+const DUMP_CELLS: usize = 8;
+
+/// the cells around `ptr` as `#` shows them, `index:value` each with the
+/// current one marked by a `*`, e.g. `0:3 *1:0 2:0`
+pub(crate) fn dump_line(memory: &[u8], width: CellWidth, ptr: usize) -> String {
+    let cells = memory.len() / width.bytes();
+    let start = ptr.saturating_sub(DUMP_CELLS / 2 - 1).min(cells.saturating_sub(DUMP_CELLS));
+    let mut line = String::new();
+    for i in start..cells.min(start + DUMP_CELLS) {
+        let mark = if i == ptr { "*" } else { "" };
+        let sep = if line.is_empty() { "" } else { " " };
+        line += &format!("{sep}{mark}{i}:{}", width.load(&memory[i * width.bytes()..]));
+    }
+    line
+}
+
+/// what `;` does: read bytes into the cells from `ptr` on until a newline,
+/// which is dropped, the end of the input or the last cell but one, then
+/// store how many were read in the cell after them; at most as many as a
+/// cell holds are read and the pointer stays where it is
+pub(crate) fn read_line(
+    memory: &mut [u8],
+    width: CellWidth,
+    ptr: usize,
+    mut read_byte: impl FnMut() -> Result<Option<u8>, RuntimeError>
+) -> Result<(), RuntimeError> {
+    let cells = memory.len() / width.bytes();
+    let room = (cells - ptr - 1).min(width.max() as usize);
+    let mut count = 0;
+    while count < room {
+        match read_byte()? {
+            None | Some(b'\n') => break,
+            Some(byte) => width.store(&mut memory[(ptr + count) * width.bytes()..], byte as u32)
+        }
+        count += 1;
+    }
+    width.store(&mut memory[(ptr + count) * width.bytes()..], count as u32);
+    Ok(())
+}
+
+/// checks that the bytes written as output make UTF-8, see
+/// `OutputEncoding::Utf8Validated`
+#[derive(Clone, Debug, Default)]
+pub struct Utf8Check {
+    /// the start of a character still missing bytes
+    pending: [u8; 4],
+    len: usize,
+    /// bytes of the characters completed so far
+    written: usize
+}
+
+impl Utf8Check {
+    /// take `count` more `byte`s, failing before the first that can't go on
+    /// valid UTF-8 with `RuntimeError::InvalidUtf8`
+    pub fn write(&mut self, byte: u8, count: u32) -> Result<(), RuntimeError> {
+        // no character is longer than that, after it `byte` must be ascii
+        for _ in 0..count.min(4) {
+            self.pending[self.len] = byte;
+            self.len += 1;
+            match core::str::from_utf8(&self.pending[..self.len]) {
+                Ok(_) => {
+                    self.written += self.len;
+                    self.len = 0;
+                }
+                Err(e) if e.error_len().is_none() => {}
+                Err(_) => {
+                    self.len -= 1;
+                    return Err(RuntimeError::InvalidUtf8 { at: self.written });
+                }
+            }
+        }
+        self.written += count.saturating_sub(4) as usize;
+        Ok(())
+    }
+
+    /// the output is complete, failing if it ends in the middle of a
+    /// character; the next output is checked from its start
+    pub fn finish(&mut self) -> Result<(), RuntimeError> {
+        let at = self.written;
+        let incomplete = self.len > 0;
+        *self = Self::default();
+        if incomplete { Err(RuntimeError::InvalidUtf8 { at }) } else { Ok(()) }
+    }
+}
+
+impl EofPolicy {
+    /// `cell` is truncated to the cell width afterwards
+    pub(crate) fn apply(self, cell: &mut u32) {
+        match self {
+            EofPolicy::Unchanged => {}
+            EofPolicy::Zero => *cell = 0,
+            EofPolicy::NegativeOne => *cell = u32::MAX,
+        }
+    }
+}
+
+/// where an `Interpreter` reads `,` from and writes `.` to
+pub trait Io {
+    /// the next input byte, `None` once the input is exhausted
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError>;
+
+    /// write `byte` `count` times
+    fn write_byte(&mut self, byte: u8, count: u32) -> Result<(), RuntimeError>;
+
+    /// show the line a `#` dumps the tape as, ignored unless implemented
+    fn dump(&mut self, _line: &str) -> Result<(), RuntimeError> {
+        Ok(())
+    }
+}
+
+/// io through `std::io`, as the JIT does it
+#[cfg(feature = "std")]
+pub struct StdIo {
+    pub input: crate::bfjit::Input,
+    pub output: crate::bfjit::Output,
+    /// where `#` dumps the tape to
+    pub dump: crate::bfjit::Output,
+    /// write every byte read to `output` too
+    pub echo_input: bool,
+    /// check the bytes `.` writes, the caller finishes the check
+    pub utf8: Option<Utf8Check>
+}
+
+#[cfg(feature = "std")]
+impl Io for StdIo {
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        let mut buf = [0_u8];
+        match self.input.read(&mut buf)? {
+            0 => Ok(None),
+            _ => {
+                if self.echo_input {
+                    self.output.write_all(&buf).map_err(|e| RuntimeError::from_output(e.into()))?;
+                }
+                Ok(Some(buf[0]))
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8, count: u32) -> Result<(), RuntimeError> {
+        if let Some(check) = &mut self.utf8 {
+            check.write(byte, count)?;
+        }
+        match count {
+            1 => self.output.write_all(&[byte]).map_err(crate::error::IoError::from),
+            _ => crate::bfjit::write_repeated(&mut self.output, byte, count)
+        }.map_err(RuntimeError::from_output)
+    }
+
+    fn dump(&mut self, line: &str) -> Result<(), RuntimeError> {
+        writeln!(self.dump, "{line}")?;
+        Ok(())
+    }
+}
+
+/// io through closures, for targets without `std`: `read` returns the next
+/// input byte or `None` at eof, `write` takes every output byte
+pub struct FnIo<R, W> {
+    pub read: R,
+    pub write: W
+}
+
+impl<R: FnMut() -> Option<u8>, W: FnMut(u8)> Io for FnIo<R, W> {
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        Ok((self.read)())
+    }
+
+    fn write_byte(&mut self, byte: u8, count: u32) -> Result<(), RuntimeError> {
+        for _ in 0..count {
+            (self.write)(byte);
+        }
+        Ok(())
+    }
+}
+
+/// the io of an `Interpreter` unless given otherwise
+#[cfg(feature = "std")]
+pub type DefaultIo = StdIo;
+/// the io of an `Interpreter` unless given otherwise
+#[cfg(not(feature = "std"))]
+pub type DefaultIo = FnIo<fn() -> Option<u8>, fn(u8)>;
+
+/// a portable interpreter with the same observable behavior as the JIT
+pub struct Interpreter<IO = DefaultIo> {
+    ir: Vec<BfIR>,
+    /// index of the matching bracket for every `Jz`/`Jnz`
+    jumps: Vec<usize>,
+    pub(crate) memory: Box<[u8]>,
+    pub(crate) io: IO,
+    eof_policy: EofPolicy,
+    cell_width: CellWidth,
+    arith: ArithMode,
+    tape_mode: TapeMode,
+    start_offset: usize,
+    /// per-kind execution counters when profiling
+    pub(crate) counters: Option<Box<[u64]>>,
+    /// the block of `counters` of every instruction, see `bfir::counter_index`
+    pub(crate) regions: Vec<usize>,
+    /// per-loop iteration counters when profiling loops, and the loop of
+    /// every `Jnz` indexed by its pc
+    pub(crate) loop_counters: Option<Box<[u64]>>,
+    loop_ids: Vec<usize>,
+    max_steps: Option<u64>,
+    /// what is left of `max_steps` in the current run
+    steps: u64,
+    cancel: Option<Arc<AtomicBool>>,
+    pc: usize,
+    ptr: usize
+}
+
+#[cfg(feature = "std")]
+impl Interpreter {
+    /// `ir` must have balanced brackets, as produced by `bfir::compile`,
+    /// `memory` holds the cells in native byte order, `#` dumps to stderr
+    pub fn new(
+        ir: Vec<BfIR>,
+        memory: Box<[u8]>,
+        input: crate::bfjit::Input,
+        output: crate::bfjit::Output
+    ) -> Self {
+        Self::with_io(ir, memory, StdIo { input, output, dump: Box::new(std::io::stderr()), echo_input: false, utf8: None })
+    }
+}
+
+impl<IO: Io> Interpreter<IO> {
+    /// like `new`, with `io` instead of a reader and a writer
+    pub fn with_io(ir: Vec<BfIR>, memory: Box<[u8]>, io: IO) -> Self {
+        let mut jumps = vec![0; ir.len()];
+        let mut stk = vec![];
+        for (i, &op) in ir.iter().enumerate() {
+            match op {
+                BfIR::Jz => stk.push(i),
+                BfIR::Jnz => {
+                    let left = stk.pop().unwrap();
+                    jumps[left] = i;
+                    jumps[i] = left;
+                }
+                _ => {}
+            }
+        }
+
+        Self {
+            ir,
+            jumps,
+            memory,
+            io,
+            eof_policy: EofPolicy::default(),
+            cell_width: CellWidth::default(),
+            arith: ArithMode::default(),
+            tape_mode: TapeMode::default(),
+            start_offset: 0,
+            counters: None,
+            regions: vec![],
+            loop_counters: None,
+            loop_ids: vec![],
+            max_steps: None,
+            steps: 0,
+            cancel: None,
+            pc: 0,
+            ptr: 0
+        }
+    }
+
+    pub fn with_eof_policy(mut self, eof_policy: EofPolicy) -> Self {
+        self.eof_policy = eof_policy;
+        self
+    }
+
+    /// the length of `memory` must be a multiple of the cell size
+    pub fn with_cell_width(mut self, cell_width: CellWidth) -> Self {
+        self.cell_width = cell_width;
+        self
+    }
+
+    pub fn with_arith_mode(mut self, arith: ArithMode) -> Self {
+        self.arith = arith;
+        self
+    }
+
+    pub fn with_tape_mode(mut self, tape_mode: TapeMode) -> Self {
+        self.tape_mode = tape_mode;
+        self
+    }
+
+    /// start the pointer `offset` cells right of where the tape mode starts it,
+    /// `run` fails with `RuntimeError::PointerOverflow` if that is off the tape
+    pub fn with_start_offset(mut self, offset: usize) -> Self {
+        self.start_offset = offset;
+        self
+    }
+
+    /// fail with `RuntimeError::StepLimitExceeded` once `Jnz` ran more than `max_steps` times
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    /// fail with `RuntimeError::Cancelled` at the next `Jnz` once `cancel` is set
+    pub fn with_cancel_flag(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// count executed instructions per kind, see `executed_ops`
+    pub fn with_profiling(mut self) -> Self {
+        self.counters = Some(vec![0; bfir::KIND_NAMES.len()].into_boxed_slice());
+        self
+    }
+
+    /// count the iterations of every loop, see `loop_iterations`
+    pub fn with_loop_profiling(mut self) -> Self {
+        let mut loop_ids = vec![0; self.ir.len()];
+        let mut loops = 0;
+        for (i, &op) in self.ir.iter().enumerate() {
+            if op == BfIR::Jz {
+                loop_ids[self.jumps[i]] = loops;
+                loops += 1;
+            }
+        }
+        self.loop_ids = loop_ids;
+        self.loop_counters = Some(vec![0; loops].into_boxed_slice());
+        self
+    }
+
+    /// how often the body of every loop ran so far, in the order of their
+    /// `Jz`, `None` unless profiling loops
+    pub fn loop_iterations(&self) -> Option<&[u64]> {
+        self.loop_counters.as_deref()
+    }
+
+    /// how many instructions of each kind ran so far, `None` unless profiling
+    #[cfg(feature = "std")]
+    pub fn executed_ops(&self) -> Option<HashMap<&'static str, u64>> {
+        self.counters.as_deref().map(bfir::named_counts)
+    }
+
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        self.pc = 0;
+        self.ptr = self.tape_mode.start_cell(self.cells());
+        self.ptr = self.ptr.checked_add(self.start_offset)
+            .filter(|&p| p < self.cells())
+            .ok_or_else(|| self.overflow(Direction::Right))?;
+        self.steps = self.max_steps.unwrap_or(0);
+        while self.pc < self.ir.len() {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// index of the instruction about to run, or the one that failed
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// index of the current cell
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// value of cell `index`, `None` past the end of the tape
+    pub fn cell(&self, index: usize) -> Option<u32> {
+        (index < self.cells()).then(|| self.load(index))
+    }
+
+    /// the instruction at `pc`, `None` once the program finished
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn next_op(&self) -> Option<BfIR> {
+        self.ir.get(self.pc).copied()
+    }
+
+    fn cells(&self) -> usize {
+        self.memory.len() / self.cell_width.bytes()
+    }
+
+    /// index of the cell at `ptr + offset`, bounds checked like the JIT
+    fn cell_at(&self, offset: i32) -> Result<usize, RuntimeError> {
+        let pos = (self.ptr as i64).checked_add(offset as i64);
+        match pos {
+            Some(pos) if pos >= 0 && (pos as usize) < self.cells() => Ok(pos as usize),
+            Some(pos) if pos < 0 => Err(self.overflow(Direction::Left)),
+            _ => Err(self.overflow(Direction::Right))
+        }
+    }
+
+    /// the error for the current instruction running off the tape
+    fn overflow(&self, direction: Direction) -> RuntimeError {
+        RuntimeError::PointerOverflow { at: self.ptr, direction }
+    }
+
+    fn load(&self, cell: usize) -> u32 {
+        self.cell_width.load(&self.memory[cell * self.cell_width.bytes()..])
+    }
+
+    fn store(&mut self, cell: usize, val: u32) {
+        self.cell_width.store(&mut self.memory[cell * self.cell_width.bytes()..], val)
+    }
+
+    /// execute the instruction at `pc`, which must not be past the end
+    pub(crate) fn step(&mut self) -> Result<(), RuntimeError> {
+        let op = self.ir[self.pc];
+        if let Some(counters) = &mut self.counters {
+            counters[bfir::counter_index(&self.regions, self.pc, op)] += 1;
+        }
+        loop {
+            match self.exec(op) {
+                Err(RuntimeError::PointerOverflow { direction: Direction::Right, .. }) if self.grow() => {}
+                ret => return ret
+            }
+        }
+    }
+
+    /// double the tape if it may grow, false if it may not
+    fn grow(&mut self) -> bool {
+        let len = match (self.tape_mode, grown_len(self.memory.len())) {
+            (TapeMode::Growable, Some(len)) => len,
+            _ => return false
+        };
+        let mut memory = core::mem::take(&mut self.memory).into_vec();
+        memory.resize(len, 0);
+        self.memory = memory.into_boxed_slice();
+        true
+    }
+
+    /// `step` without counting, leaves everything as it was on overflow
+    fn exec(&mut self, op: BfIR) -> Result<(), RuntimeError> {
+        use BfIR::*;
+        match op {
+            AddPtrThenAddVal { val, .. } => {
+                // the move already advances `pc`
+                self.exec(op.split_move().0)?;
+                self.store(self.ptr, self.load(self.ptr).wrapping_add(val));
+                return Ok(());
+            }
+            AddPtr(x) if self.tape_mode == TapeMode::Wrapping => {
+                self.ptr = ((self.ptr as u64 + x as u64) % self.cells() as u64) as usize;
+            }
+            SubPtr(x) if self.tape_mode == TapeMode::Wrapping => {
+                let back = (x as u64 % self.cells() as u64) as usize;
+                self.ptr = (self.ptr + self.cells() - back) % self.cells();
+            }
+            AddPtr(x) => {
+                self.ptr = self.ptr.checked_add(x as usize)
+                    .filter(|&p| p < self.cells())
+                    .ok_or_else(|| self.overflow(Direction::Right))?;
+            }
+            SubPtr(x) => {
+                self.ptr = self.ptr.checked_sub(x as usize)
+                    .ok_or_else(|| self.overflow(Direction::Left))?;
+            }
+            AddVal(x) if self.arith == ArithMode::Saturating => {
+                let max = self.cell_width.max();
+                self.store(self.ptr, self.load(self.ptr).saturating_add(x.min(max)).min(max));
+            }
+            SubVal(x) if self.arith == ArithMode::Saturating => self.store(self.ptr, self.load(self.ptr).saturating_sub(x)),
+            AddVal(x) => self.store(self.ptr, self.load(self.ptr).wrapping_add(x)),
+            SubVal(x) => self.store(self.ptr, self.load(self.ptr).wrapping_sub(x)),
+            SetVal(x) => self.store(self.ptr, x),
+            MulVal { offset, factor } => {
+                let val = self.load(self.ptr);
+                if val != 0 {
+                    if let Some((lo, hi)) = bfir::window_extent(&self.ir, self.pc) {
+                        self.cell_at(lo)?;
+                        self.cell_at(hi)?;
+                    }
+                    let cell = self.cell_at(offset)?;
+                    self.store(cell, self.load(cell).wrapping_add(val.wrapping_mul(factor)));
+                }
+            }
+            AddValAt { offset, val } => {
+                if let Some((lo, hi)) = bfir::window_extent(&self.ir, self.pc) {
+                    self.cell_at(lo)?;
+                    self.cell_at(hi)?;
+                }
+                let cell = self.cell_at(offset)?;
+                self.store(cell, self.load(cell).wrapping_add(val));
+            }
+            ClearRange { len } => {
+                let last = self.cell_at((len - 1) as i32)?;
+                let n = self.cell_width.bytes();
+                self.memory[self.ptr * n..(last + 1) * n].fill(0);
+                self.ptr = last;
+            }
+            ScanRight => {
+                let mut cell = self.ptr;
+                while self.load(cell) != 0 {
+                    cell += 1;
+                    if cell == self.cells() {
+                        return Err(self.overflow(Direction::Right));
+                    }
+                }
+                self.ptr = cell;
+            }
+            ScanLeft => {
+                let mut cell = self.ptr;
+                while self.load(cell) != 0 {
+                    if cell == 0 {
+                        return Err(self.overflow(Direction::Left));
+                    }
+                    cell -= 1;
+                }
+                self.ptr = cell;
+            }
+            GetByte => match self.io.read_byte()? {
+                None => {
+                    let mut val = self.load(self.ptr);
+                    self.eof_policy.apply(&mut val);
+                    self.store(self.ptr, val);
+                }
+                Some(byte) => self.store(self.ptr, byte as u32)
+            },
+            // only the low byte of a wider cell is written
+            PutByte => self.io.write_byte(self.load(self.ptr) as u8, 1)?,
+            PutByteN { count } => self.io.write_byte(self.load(self.ptr) as u8, count)?,
+            Nop => {}
+            DumpTape => self.io.dump(&dump_line(&self.memory, self.cell_width, self.ptr))?,
+            ReadLine => {
+                let io = &mut self.io;
+                read_line(&mut self.memory, self.cell_width, self.ptr, || io.read_byte())?;
+            }
+            Jz => {
+                if self.load(self.ptr) == 0 {
+                    self.pc = self.jumps[self.pc];
+                }
+            }
+            Jnz => {
+                if let Some(loop_counters) = &mut self.loop_counters {
+                    loop_counters[self.loop_ids[self.pc]] += 1;
+                }
+                if self.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+                    return Err(RuntimeError::Cancelled);
+                }
+                if let Some(steps) = self.max_steps {
+                    self.steps = self.steps.checked_sub(1).ok_or(RuntimeError::StepLimitExceeded { steps })?;
+                }
+                if self.load(self.ptr) != 0 {
+                    self.pc = self.jumps[self.pc];
+                }
+            }
+        }
+        self.pc += 1;
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_interpreter() {
+    use crate::bfjit::{BfVM, SharedBuf, DEFAULT_MEM_SIZE};
+
+    for policy in [EofPolicy::Zero, EofPolicy::NegativeOne] {
+        let output = SharedBuf::default();
+        Interpreter::new(
+            bfir::compile("+++,.").unwrap(),
+            vec![0; 1].into_boxed_slice(),
+            Box::new(std::io::empty()),
+            Box::new(output.clone())
+        ).with_eof_policy(policy).run().unwrap();
+        let mut expected = 3;
+        policy.apply(&mut expected);
+        assert_eq!(output.take(), [expected as u8]);
+    }
+
+    let samples: [(&str, &[u8]); 7] = [
+        (",[.[-],]", b"hello"),
+        ("++++[->+>++<<]>>>+++>++<<[-<+++++++++++++++>]<.", b""),
+        ("++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.", b""),
+        // output first, then run off the left end
+        ("+++.>+[-<+>]<.[>+++.<-]<[-]", b""),
+        // scans in both directions, the last one runs off the left end
+        (">+>+>+<<[>]+.<[<]>.[>]<[<]<[<]", b""),
+        ("+>+>+<<[-]>[-]+.>[-]>[-]", b""),
+        ("++++++++[>++++++++<-]>+.....>.....<+..", b""),
+    ];
+
+    for (i, (src, input)) in samples.into_iter().enumerate() {
+        let path = std::env::temp_dir().join(format!("bfjit_test_interp_{}_{}.bf", std::process::id(), i));
+        std::fs::write(&path, src).unwrap();
+
+        let jit_output = SharedBuf::default();
+        let jit_ret = BfVM::new(
+            &path,
+            Box::new(std::io::Cursor::new(input.to_vec())),
+            Box::new(jit_output.clone()),
+            true
+        ).unwrap().run();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut ir = bfir::compile(src).unwrap();
+        bfir::optimize(&mut ir);
+        let interp_output = SharedBuf::default();
+        let mut interp = Interpreter::new(
+            ir,
+            vec![0; DEFAULT_MEM_SIZE].into_boxed_slice(),
+            Box::new(std::io::Cursor::new(input.to_vec())),
+            Box::new(interp_output.clone())
+        );
+        let interp_ret = interp.run();
+
+        assert_eq!(jit_output.take(), interp_output.take());
+        // the jit locates the failing instruction, which is where the interpreter stopped
+        assert_eq!(
+            jit_ret.map_err(|e| match e {
+                crate::error::VMError::RuntimeAt { error, index, .. } => (error.to_string(), index),
+                e => (e.to_string(), 0)
+            }),
+            interp_ret.map_err(|e| (e.to_string(), interp.pc()))
+        );
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_interpreter_profiling() {
+    let mut interp = Interpreter::new(
+        bfir::compile("+++[>++<-]").unwrap(),
+        vec![0; 2].into_boxed_slice(),
+        Box::new(std::io::empty()),
+        Box::new(std::io::sink())
+    ).with_profiling();
+    interp.run().unwrap();
+    assert_eq!(
+        interp.executed_ops().unwrap(),
+        HashMap::from([
+            ("addval", 9),
+            ("jz", 1),
+            ("addptr", 3),
+            ("subptr", 3),
+            ("subval", 3),
+            ("jnz", 3),
+        ])
+    );
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_interpreter_cell_width() {
+    use crate::bfjit::SharedBuf;
+
+    let src = format!("{}.[-]->+++[->>++++<<]>>.", "+".repeat(300));
+    let mut ir = bfir::compile(&src).unwrap();
+    bfir::optimize(&mut ir);
+    let mut ir16 = bfir::compile(&src).unwrap();
+    let mut positions = vec![(0, 0); ir16.len()];
+    bfir::optimize_with_positions(&mut ir16, &mut positions, bfir::OptLevel::Full, CellWidth::W16);
+
+    for (ir, width, cell) in [(ir, CellWidth::W8, [0xff, 0]), (ir16, CellWidth::W16, [0xff, 0xff])] {
+        let output = SharedBuf::default();
+        let mut interp = Interpreter::new(
+            ir,
+            vec![0; 4 * width.bytes()].into_boxed_slice(),
+            Box::new(std::io::empty()),
+            Box::new(output.clone())
+        ).with_cell_width(width);
+        interp.run().unwrap();
+        assert_eq!(output.take(), [44, 12]);
+        assert_eq!(interp.memory[..width.bytes()], cell[..width.bytes()]);
+    }
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn test_interpreter_max_steps() {
+    let run = |src, max_steps| {
+        Interpreter::new(
+            bfir::compile(src).unwrap(),
+            vec![0; 1].into_boxed_slice(),
+            Box::new(std::io::empty()),
+            Box::new(std::io::sink())
+        ).with_max_steps(max_steps).run()
+    };
+    assert!(matches!(run("+[]", 100), Err(RuntimeError::StepLimitExceeded { steps: 100 })));
+    assert!(run("+++[-]", 3).is_ok());
+    assert!(matches!(run("+++[-]", 2), Err(RuntimeError::StepLimitExceeded { steps: 2 })));
+}
+
+#[test]
+fn test_fn_io() {
+    let mut input = b"hi".iter().copied();
+    let mut output = vec![];
+    let mut interp = Interpreter::with_io(
+        bfir::compile(",[.,]+.").unwrap(),
+        vec![0; 1].into_boxed_slice(),
+        FnIo { read: || input.next(), write: |byte| output.push(byte) }
+    ).with_eof_policy(EofPolicy::Zero);
+    interp.run().unwrap();
+    drop(interp);
+    assert_eq!(output, b"hi\x01");
+}
@@ -0,0 +1,20 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod bfir;
+#[cfg(feature = "std")]
+pub mod bfjit;
+#[cfg(feature = "cranelift")]
+pub mod cranelift;
+#[cfg(feature = "std")]
+pub mod debugger;
+pub mod error;
+pub mod interp;
+#[cfg(feature = "std")]
+pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod wasm;
+
+#[cfg(feature = "std")]
+pub use bfjit::{run_bytes, run_str};
@@ -1,8 +1,5 @@
-mod bfir;
-mod bfjit;
-mod error;
-
-use crate::bfjit::BfVM;
+use bfjit::bfir::OptLevel;
+use bfjit::bfjit::BfVM;
 
 use std::io::{stdin, stdout};
 use std::path::PathBuf;
@@ -22,16 +19,13 @@ struct Opt {
 fn main() {
     let opt = Opt::parse();
 
-    let stdin = stdin();
-    let stdout = stdout();
-
-    let ret = BfVM::new(
-        &opt.file_path,
-        Box::new(stdin.lock()),
-        Box::new(stdout.lock()),
-        opt.optimize,
-    )
-    .and_then(|mut vm| vm.run());
+    let ret = BfVM::builder()
+        .source_path(&opt.file_path)
+        .input(Box::new(stdin()))
+        .output(Box::new(stdout()))
+        .opt_level(if opt.optimize { OptLevel::Full } else { OptLevel::None })
+        .build()
+        .and_then(|mut vm| vm.run());
 
     if let Err(e) = &ret {
         eprintln!("bfjit: {}", e);
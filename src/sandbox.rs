@@ -0,0 +1,174 @@
+use crate::bfir::CompileError;
+use crate::bfjit::{BfVM, SharedBuf, TapeMode};
+use crate::error::{Result, RuntimeError, VMError};
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// limits for running programs nobody vouched for, e.g. on a judge: every
+/// run gets a fixed tape, a budget of loop iterations, a deadline and a cap
+/// on the output, all of them small by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Sandbox {
+    max_steps: u64,
+    timeout: Duration,
+    max_output: usize,
+    mem_size: usize,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            max_steps: 10_000_000,
+            timeout: Duration::from_secs(1),
+            max_output: 64 * 1024,
+            mem_size: 30_000,
+        }
+    }
+}
+
+/// how a run in a `Sandbox` ended
+#[derive(Debug)]
+pub enum SandboxOutcome {
+    /// the program ran to its end
+    Completed,
+    /// `]` ran more often than `Sandbox::with_max_steps` allows
+    StepLimitExceeded,
+    /// the run took longer than `Sandbox::with_timeout` allows
+    Timeout,
+    /// the program wrote more than `Sandbox::with_max_output` bytes
+    OutputLimitExceeded,
+    /// the pointer left the tape at `line`:`col`
+    PointerOverflow { line: u32, col: u32 },
+    /// the source did not compile, nothing ran
+    Compile(CompileError),
+    /// anything else that stopped the run
+    Failed(VMError),
+}
+
+/// what `Sandbox::run` gives back
+#[derive(Debug)]
+pub struct SandboxResult {
+    pub outcome: SandboxOutcome,
+    /// what the program wrote before it ended, at most `max_output` bytes
+    pub output: Vec<u8>,
+}
+
+/// collects the output, failing a write once `left` bytes were written
+struct CappedOutput {
+    buf: SharedBuf,
+    left: usize,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl Write for CappedOutput {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        if bytes.len() > self.left {
+            self.exceeded.store(true, Ordering::Relaxed);
+            if self.left == 0 {
+                return Err(std::io::Error::other("output limit exceeded"));
+            }
+        }
+        let n = self.buf.write(&bytes[..bytes.len().min(self.left)])?;
+        self.left -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sandbox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// how often `]` may run, 10 million by default
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// how long a run may take, checked at every `]`, not counting the
+    /// compilation, 1 second by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// how many bytes a program may write, 64 KiB by default
+    pub fn with_max_output(mut self, max_output: usize) -> Self {
+        self.max_output = max_output;
+        self
+    }
+
+    /// how many cells the tape has, it never grows, 30000 by default
+    pub fn with_mem_size(mut self, mem_size: usize) -> Self {
+        self.mem_size = mem_size;
+        self
+    }
+
+    /// compile and run `src` on `input`, eof past its end
+    pub fn run(&self, src: &str, input: &[u8]) -> SandboxResult {
+        let buf = SharedBuf::default();
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let output = CappedOutput { buf: buf.clone(), left: self.max_output, exceeded: exceeded.clone() };
+        let outcome = match self.run_vm(src, input, output) {
+            Ok(()) => SandboxOutcome::Completed,
+            // whatever came of the failed write
+            Err(_) if exceeded.load(Ordering::Relaxed) => SandboxOutcome::OutputLimitExceeded,
+            Err(VMError::Compile(e)) => SandboxOutcome::Compile(e),
+            Err(VMError::RuntimeAt { error: RuntimeError::PointerOverflow { .. }, line, col, .. }) => {
+                SandboxOutcome::PointerOverflow { line, col }
+            }
+            Err(
+                VMError::Runtime(RuntimeError::StepLimitExceeded { .. })
+                | VMError::RuntimeAt { error: RuntimeError::StepLimitExceeded { .. }, .. }
+            ) => SandboxOutcome::StepLimitExceeded,
+            Err(VMError::Runtime(RuntimeError::Timeout)) => SandboxOutcome::Timeout,
+            Err(e) => SandboxOutcome::Failed(e)
+        };
+        SandboxResult { outcome, output: buf.take() }
+    }
+
+    fn run_vm(&self, src: &str, input: &[u8], output: CappedOutput) -> Result<()> {
+        let mut vm = BfVM::builder()
+            .source_str(src)
+            .input_bytes(input)
+            .output(Box::new(output))
+            .dump_output(Box::new(std::io::sink()))
+            .mem_size(self.mem_size)
+            .tape_mode(TapeMode::Fixed)
+            .max_steps(self.max_steps)
+            .build()?;
+        vm.run_with_timeout(self.timeout)
+    }
+}
+
+#[test]
+fn test_sandbox() {
+    let result = Sandbox::new().run(",[.,]", b"hi\0");
+    assert!(matches!(result.outcome, SandboxOutcome::Completed));
+    assert_eq!(result.output, b"hi");
+
+    let result = Sandbox::new().run("+.[]", b"");
+    assert!(matches!(result.outcome, SandboxOutcome::StepLimitExceeded));
+    assert_eq!(result.output, [1]);
+
+    let result = Sandbox::new().with_max_steps(u64::MAX).with_timeout(Duration::from_millis(50)).run("+[]", b"");
+    assert!(matches!(result.outcome, SandboxOutcome::Timeout));
+
+    let result = Sandbox::new().with_max_output(10).run("+[.]", b"");
+    assert!(matches!(result.outcome, SandboxOutcome::OutputLimitExceeded));
+    assert_eq!(result.output, [1; 10]);
+
+    let result = Sandbox::new().with_mem_size(4).run("+\n[>+]", b"");
+    assert!(matches!(result.outcome, SandboxOutcome::PointerOverflow { line: 2, .. }));
+
+    let result = Sandbox::new().run("+[", b"");
+    assert!(matches!(result.outcome, SandboxOutcome::Compile(e) if e.kind() == crate::bfir::CompileErrorKind::UnclosedLeftBracket));
+    assert!(result.output.is_empty());
+}
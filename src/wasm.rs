@@ -0,0 +1,567 @@
+//! compile ir into a WebAssembly module, for browsers and wasm runtimes
+//!
+//! the module imports its io from the host as module `bf`:
+//!
+//! - `getbyte() -> i32`, the next input byte or -1 at eof
+//! - `putbyte(byte: i32)`
+//! - `putbyte_n(byte: i32, count: i32)`, `count` copies of `byte`
+//! - `overflow_left(index: i32, at: i32)` and `overflow_right(index: i32, at: i32)`,
+//!   the instruction at `index` ran off that end of the tape with the pointer
+//!   starting on cell `at`, `run` returns right after
+//! - `dump_tape(at: i32)`, a `#` ran with the pointer on cell `at`, the host
+//!   may show the tape as read from `memory`
+//! - `read_line(at: i32)`, a `;` ran with the pointer on cell `at`, the host
+//!   reads a line into `memory` as `Dialect::read_line` describes
+//!
+//! and exports `run: () -> ()` along with the tape as `memory`, cells in
+//! little endian order from address 0
+
+use crate::bfir::{self, BfIR, CellWidth};
+use crate::bfjit::{EofPolicy, MAX_MEM_SIZE};
+use crate::error::{Result, VMError};
+
+// indices of the imported functions, `run` comes right after them
+const GETBYTE: u32 = 0;
+const PUTBYTE: u32 = 1;
+const PUTBYTE_N: u32 = 2;
+const OVERFLOW_LEFT: u32 = 3;
+const OVERFLOW_RIGHT: u32 = 4;
+const DUMP_TAPE: u32 = 5;
+const READ_LINE: u32 = 6;
+const RUN: u32 = 7;
+
+// locals of `run`
+const PTR: u32 = 0;
+const VAL: u32 = 1;
+const CUR: u32 = 2;
+
+// value types
+const I32: u8 = 0x7f;
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+/// a module running `ir` on a tape of `mem_size` cells starting at the first
+/// one; steps, cancelling and profiling are left to the host
+pub fn emit_module(ir: &[BfIR], mem_size: usize, cell_width: CellWidth, eof_policy: EofPolicy) -> Result<Vec<u8>> {
+    if mem_size == 0 || mem_size > MAX_MEM_SIZE / cell_width.bytes() {
+        return Err(VMError::InvalidMemSize(mem_size));
+    }
+    if let Some(index) = bfir::unbalanced_bracket(ir) {
+        return Err(VMError::UnbalancedIr { index });
+    }
+    let mem_bytes = mem_size * cell_width.bytes();
+    let mut body = Body {
+        code: vec![],
+        width: cell_width,
+        mem_bytes: mem_bytes as i64
+    };
+    body.emit(ir, eof_policy);
+
+    let mut module = b"\0asm".to_vec();
+    module.extend_from_slice(&1_u32.to_le_bytes());
+
+    // () -> i32, (i32) -> (), (i32, i32) -> (), () -> ()
+    let mut types = vec![];
+    vec_len(&mut types, 4);
+    types.extend_from_slice(&[0x60, 0, 1, I32]);
+    types.extend_from_slice(&[0x60, 1, I32, 0]);
+    types.extend_from_slice(&[0x60, 2, I32, I32, 0]);
+    types.extend_from_slice(&[0x60, 0, 0]);
+    section(&mut module, 1, &types);
+
+    let imports = [
+        ("getbyte", 0_u64),
+        ("putbyte", 1),
+        ("putbyte_n", 2),
+        ("overflow_left", 2),
+        ("overflow_right", 2),
+        ("dump_tape", 1),
+        ("read_line", 1),
+    ];
+    let mut section_imports = vec![];
+    vec_len(&mut section_imports, imports.len());
+    for (name, ty) in imports {
+        push_name(&mut section_imports, "bf");
+        push_name(&mut section_imports, name);
+        section_imports.push(0);
+        uleb(&mut section_imports, ty);
+    }
+    section(&mut module, 2, &section_imports);
+
+    section(&mut module, 3, &[1, 3]);
+
+    let pages = mem_bytes.div_ceil(PAGE_SIZE) as u64;
+    let mut memory = vec![1, 1];
+    uleb(&mut memory, pages);
+    uleb(&mut memory, pages);
+    section(&mut module, 5, &memory);
+
+    let mut exports = vec![];
+    vec_len(&mut exports, 2);
+    push_name(&mut exports, "run");
+    exports.push(0);
+    uleb(&mut exports, RUN as u64);
+    push_name(&mut exports, "memory");
+    exports.extend_from_slice(&[2, 0]);
+    section(&mut module, 7, &exports);
+
+    // ptr, val and cur, all i32
+    let mut func = vec![1, 3, I32];
+    func.extend_from_slice(&body.code);
+    func.push(op::END);
+    let mut code = vec![];
+    vec_len(&mut code, 1);
+    vec_len(&mut code, func.len());
+    code.extend_from_slice(&func);
+    section(&mut module, 10, &code);
+
+    Ok(module)
+}
+
+/// the opcodes in use
+mod op {
+    pub(super) const BLOCK: u8 = 0x02;
+    pub(super) const LOOP: u8 = 0x03;
+    pub(super) const IF: u8 = 0x04;
+    pub(super) const ELSE: u8 = 0x05;
+    pub(super) const END: u8 = 0x0b;
+    pub(super) const BR: u8 = 0x0c;
+    pub(super) const BR_IF: u8 = 0x0d;
+    pub(super) const RETURN: u8 = 0x0f;
+    pub(super) const CALL: u8 = 0x10;
+    pub(super) const LOCAL_GET: u8 = 0x20;
+    pub(super) const LOCAL_SET: u8 = 0x21;
+    pub(super) const LOCAL_TEE: u8 = 0x22;
+    pub(super) const I32_CONST: u8 = 0x41;
+    pub(super) const I32_EQZ: u8 = 0x45;
+    pub(super) const I32_GE_S: u8 = 0x4e;
+    pub(super) const I32_LT_U: u8 = 0x49;
+    pub(super) const I32_GE_U: u8 = 0x4f;
+    pub(super) const I32_ADD: u8 = 0x6a;
+    pub(super) const I32_SUB: u8 = 0x6b;
+    pub(super) const I32_MUL: u8 = 0x6c;
+    pub(super) const I32_AND: u8 = 0x71;
+    pub(super) const I32_SHR_U: u8 = 0x76;
+    /// followed by `MEMORY_FILL` and the memory index
+    pub(super) const PREFIX_FC: u8 = 0xfc;
+    pub(super) const MEMORY_FILL: u8 = 0x0b;
+}
+
+/// the body of `run`
+struct Body {
+    code: Vec<u8>,
+    width: CellWidth,
+    /// size of the tape in bytes
+    mem_bytes: i64
+}
+
+impl Body {
+    fn emit(&mut self, ir: &[BfIR], eof_policy: EofPolicy) {
+        use BfIR::*;
+        let bytes = self.width.bytes() as i64;
+        let in_bounds = bfir::in_bounds(ir, 0, (self.mem_bytes / bytes) as usize);
+        for (i, &op) in ir.iter().enumerate() {
+            let checked = !in_bounds[i];
+            match op {
+                AddPtr(x) | SubPtr(x) => {
+                    let d = if let AddPtr(_) = op { x as i64 * bytes } else { -(x as i64 * bytes) };
+                    if checked {
+                        self.check(i, d);
+                    }
+                    self.add_ptr(d);
+                }
+                AddPtrThenAddVal { ptr_delta, val } => {
+                    let d = ptr_delta as i64 * bytes;
+                    if checked {
+                        self.check(i, d);
+                    }
+                    self.add_ptr(d);
+                    self.local(op::LOCAL_GET, PTR);
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.i32_const(val as i64);
+                    self.code.push(op::I32_ADD);
+                    self.store();   // *ptr += val
+                }
+                AddVal(x) | SubVal(x) => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.i32_const(x as i64);
+                    self.code.push(if let AddVal(_) = op { op::I32_ADD } else { op::I32_SUB });
+                    self.store();   // *ptr += x
+                }
+                SetVal(x) => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.i32_const(x as i64);
+                    self.store();   // *ptr = x
+                }
+                GetByte => {
+                    self.call(GETBYTE);
+                    self.local(op::LOCAL_TEE, VAL);
+                    self.i32_const(0);
+                    self.code.extend_from_slice(&[op::I32_GE_S, op::IF, 0x40]);
+                    self.local(op::LOCAL_GET, PTR);
+                    self.local(op::LOCAL_GET, VAL);
+                    self.store();
+                    let mut eof = 0_u32;
+                    eof_policy.apply(&mut eof);
+                    if eof_policy != EofPolicy::Unchanged {
+                        self.code.push(op::ELSE);
+                        self.local(op::LOCAL_GET, PTR);
+                        self.i32_const(eof as i32 as i64);
+                        self.store();
+                    }
+                    self.code.push(op::END);
+                }
+                PutByte | PutByteN { .. } => {
+                    // only the low byte of a wider cell is written
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.i32_const(0xff);
+                    self.code.push(op::I32_AND);
+                    match op {
+                        PutByteN { count } => {
+                            self.i32_const(count as i32 as i64);
+                            self.call(PUTBYTE_N);
+                        }
+                        _ => self.call(PUTBYTE)
+                    }
+                }
+                Nop => {}
+                DumpTape | ReadLine => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.i32_const(self.width.bytes().trailing_zeros() as i64);
+                    self.code.push(op::I32_SHR_U);
+                    self.call(if op == DumpTape { DUMP_TAPE } else { READ_LINE });
+                }
+                Jz => {
+                    self.code.extend_from_slice(&[op::BLOCK, 0x40]);
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.code.extend_from_slice(&[op::I32_EQZ, op::BR_IF, 0, op::LOOP, 0x40]);
+                }
+                Jnz => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.code.extend_from_slice(&[op::BR_IF, 0, op::END, op::END]);
+                }
+                MulVal { offset, factor } => {
+                    let d = offset as i64 * bytes;
+                    // the loop never runs if *ptr == 0
+                    self.local(op::LOCAL_GET, PTR);
+                    self.load();
+                    self.local(op::LOCAL_TEE, VAL);
+                    self.code.extend_from_slice(&[op::IF, 0x40]);
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(ir, i).filter(|_| checked) {
+                        self.check(i, lo as i64 * bytes);
+                        self.check(i, hi as i64 * bytes);
+                    }
+                    self.cell_at(d);
+                    self.local(op::LOCAL_GET, CUR);
+                    self.load();
+                    self.local(op::LOCAL_GET, VAL);
+                    self.i32_const(factor as i32 as i64);
+                    self.code.extend_from_slice(&[op::I32_MUL, op::I32_ADD]);
+                    self.store();   // ptr[offset] += *ptr * factor
+                    self.code.push(op::END);
+                }
+                AddValAt { offset, val } => {
+                    // check the furthest offsets of the window once, at its first node
+                    if let Some((lo, hi)) = bfir::window_extent(ir, i).filter(|_| checked) {
+                        self.check(i, lo as i64 * bytes);
+                        self.check(i, hi as i64 * bytes);
+                    }
+                    self.cell_at(offset as i64 * bytes);
+                    self.local(op::LOCAL_GET, CUR);
+                    self.load();
+                    self.i32_const(val as i32 as i64);
+                    self.code.push(op::I32_ADD);
+                    self.store();   // ptr[offset] += val
+                }
+                ClearRange { len } => {
+                    let d = (len as i64 - 1) * bytes;
+                    if checked {
+                        self.check(i, d);
+                    }
+                    self.local(op::LOCAL_GET, PTR);
+                    self.i32_const(0);
+                    self.i32_const(len as i64 * bytes);
+                    self.code.extend_from_slice(&[op::PREFIX_FC, op::MEMORY_FILL, 0]);
+                    self.add_ptr(d);
+                }
+                // scan with cur, so ptr is still where it started on overflow
+                ScanRight | ScanLeft => {
+                    self.local(op::LOCAL_GET, PTR);
+                    self.local(op::LOCAL_SET, CUR);
+                    self.code.extend_from_slice(&[op::BLOCK, 0x40, op::LOOP, 0x40]);
+                    self.local(op::LOCAL_GET, CUR);
+                    self.load();
+                    self.code.extend_from_slice(&[op::I32_EQZ, op::BR_IF, 1]);
+                    self.local(op::LOCAL_GET, CUR);
+                    if let ScanRight = op {
+                        // cur is on the last cell
+                        self.i32_const(self.mem_bytes - bytes);
+                        self.code.extend_from_slice(&[op::I32_GE_U, op::IF, 0x40]);
+                        self.overflow(i, OVERFLOW_RIGHT);
+                    }
+                    else {
+                        // cur is on the first cell
+                        self.code.extend_from_slice(&[op::I32_EQZ, op::IF, 0x40]);
+                        self.overflow(i, OVERFLOW_LEFT);
+                    }
+                    self.code.push(op::END);
+                    self.local(op::LOCAL_GET, CUR);
+                    self.i32_const(if let ScanRight = op { bytes } else { -bytes });
+                    self.code.push(op::I32_ADD);
+                    self.local(op::LOCAL_SET, CUR);
+                    self.code.extend_from_slice(&[op::BR, 0, op::END, op::END]);
+                    self.local(op::LOCAL_GET, CUR);
+                    self.local(op::LOCAL_SET, PTR);
+                }
+            }
+        }
+    }
+
+    /// return through the overflow helper if `ptr + d` is off the tape
+    fn check(&mut self, i: usize, d: i64) {
+        if d == 0 {
+            return;
+        }
+        let (limit, cmp, helper) = if d > 0 {
+            (self.mem_bytes - d, op::I32_GE_U, OVERFLOW_RIGHT)
+        }
+        else {
+            (-d, op::I32_LT_U, OVERFLOW_LEFT)
+        };
+        if limit <= 0 {
+            // off the tape from any cell
+            self.overflow(i, helper);
+            return;
+        }
+        self.local(op::LOCAL_GET, PTR);
+        self.i32_const(limit);
+        self.code.extend_from_slice(&[cmp, op::IF, 0x40]);
+        self.overflow(i, helper);
+        self.code.push(op::END);
+    }
+
+    /// report the instruction at `i` with the cell ptr is on, then return
+    fn overflow(&mut self, i: usize, helper: u32) {
+        self.i32_const(i as i64);
+        self.local(op::LOCAL_GET, PTR);
+        self.i32_const(self.width.bytes().trailing_zeros() as i64);
+        self.code.push(op::I32_SHR_U);
+        self.call(helper);
+        self.code.push(op::RETURN);
+    }
+
+    fn add_ptr(&mut self, d: i64) {
+        self.local(op::LOCAL_GET, PTR);
+        self.i32_const(d);
+        self.code.push(op::I32_ADD);
+        self.local(op::LOCAL_SET, PTR);
+    }
+
+    /// leave the address `ptr + d` on the stack and in cur
+    fn cell_at(&mut self, d: i64) {
+        self.local(op::LOCAL_GET, PTR);
+        self.i32_const(d);
+        self.code.push(op::I32_ADD);
+        self.local(op::LOCAL_TEE, CUR);
+    }
+
+    fn load(&mut self) {
+        let opcode = match self.width {
+            CellWidth::W8 => 0x2d,  // i32.load8_u
+            CellWidth::W16 => 0x2f, // i32.load16_u
+            CellWidth::W32 => 0x28, // i32.load
+        };
+        self.memarg(opcode);
+    }
+
+    fn store(&mut self) {
+        let opcode = match self.width {
+            CellWidth::W8 => 0x3a,  // i32.store8
+            CellWidth::W16 => 0x3b, // i32.store16
+            CellWidth::W32 => 0x36, // i32.store
+        };
+        self.memarg(opcode);
+    }
+
+    /// a memory access aligned to the cell width, without offset
+    fn memarg(&mut self, opcode: u8) {
+        self.code.extend_from_slice(&[opcode, self.width.bytes().trailing_zeros() as u8, 0]);
+    }
+
+    fn local(&mut self, opcode: u8, index: u32) {
+        self.code.extend_from_slice(&[opcode, index as u8]);
+    }
+
+    fn call(&mut self, func: u32) {
+        self.code.extend_from_slice(&[op::CALL, func as u8]);
+    }
+
+    /// `x` wraps to 32 bits
+    fn i32_const(&mut self, x: i64) {
+        self.code.push(op::I32_CONST);
+        sleb(&mut self.code, x as i32 as i64);
+    }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    vec_len(module, contents.len());
+    module.extend_from_slice(contents);
+}
+
+fn vec_len(out: &mut Vec<u8>, len: usize) {
+    uleb(out, len as u64);
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) {
+    vec_len(out, name.len());
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut x: u64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut x: i64) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if (x == 0 && byte & 0x40 == 0) || (x == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[test]
+fn test_emit_module() {
+    use crate::bfjit::{backend_samples, BfVM, SharedBuf, MANDELBROT};
+    use crate::error::{Direction, RuntimeError};
+    use crate::interp::{dump_line, read_line};
+
+    let ir = bfir::compile("+[-]").unwrap();
+    let module = emit_module(&ir, 16, CellWidth::W8, EofPolicy::Zero).unwrap();
+    assert_eq!(module[..8], *b"\0asm\x01\0\0\0");
+    assert!(matches!(emit_module(&ir, 0, CellWidth::W8, EofPolicy::Zero), Err(VMError::InvalidMemSize(0))));
+    for (ir, index) in [(vec![BfIR::Jnz], 0), (vec![BfIR::Jz, BfIR::Jnz, BfIR::Jz], 2)] {
+        assert!(matches!(emit_module(&ir, 16, CellWidth::W8, EofPolicy::Zero), Err(VMError::UnbalancedIr { index: i }) if i == index));
+    }
+
+    // run the modules in process, giving their output, `#` dumps and tape,
+    // overflows print as `<index@at` or `>index@at`
+    struct Host {
+        input: Vec<u8>,
+        read: usize,
+        out: Vec<u8>,
+        dump: Vec<u8>,
+        width: CellWidth,
+        mem_bytes: usize
+    }
+    type Caller<'a> = wasmi::Caller<'a, Host>;
+    // the cells and the host, for `#` and `;`
+    fn tape<'a>(caller: &'a mut Caller) -> (&'a mut [u8], &'a mut Host) {
+        let memory = caller.get_export("memory").and_then(wasmi::Extern::into_memory).unwrap();
+        let (data, host) = memory.data_and_store_mut(caller);
+        (&mut data[..host.mem_bytes], host)
+    }
+    type Ran = (Vec<u8>, Vec<u8>, Vec<u8>);
+    let run = |module: &[u8], input: &[u8], mem_size: usize, width: CellWidth| -> std::result::Result<Ran, wasmi::Error> {
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, module)?;
+        let host = Host {
+            input: input.to_vec(),
+            read: 0,
+            out: vec![],
+            dump: vec![],
+            width,
+            mem_bytes: mem_size * width.bytes()
+        };
+        let mut store = wasmi::Store::new(&engine, host);
+        let mut linker = wasmi::Linker::new(&engine);
+        linker
+            .func_wrap("bf", "getbyte", |mut caller: Caller| {
+                let host = caller.data_mut();
+                let byte = host.input.get(host.read).map_or(-1, |&b| b as i32);
+                host.read += 1;
+                byte
+            })?
+            .func_wrap("bf", "putbyte", |mut caller: Caller, b: i32| caller.data_mut().out.push(b as u8))?
+            .func_wrap("bf", "putbyte_n", |mut caller: Caller, b: i32, n: i32| {
+                caller.data_mut().out.extend(std::iter::repeat_n(b as u8, n as usize))
+            })?
+            .func_wrap("bf", "overflow_left", |mut caller: Caller, index: i32, at: i32| {
+                caller.data_mut().out.extend(format!("<{}@{}", index, at).bytes())
+            })?
+            .func_wrap("bf", "overflow_right", |mut caller: Caller, index: i32, at: i32| {
+                caller.data_mut().out.extend(format!(">{}@{}", index, at).bytes())
+            })?
+            .func_wrap("bf", "dump_tape", |mut caller: Caller, at: i32| {
+                let (cells, host) = tape(&mut caller);
+                let line = dump_line(cells, host.width, at as usize);
+                host.dump.extend(format!("{}\n", line).bytes());
+            })?
+            .func_wrap("bf", "read_line", |mut caller: Caller, at: i32| {
+                let (cells, host) = tape(&mut caller);
+                let (input, read) = (&host.input, &mut host.read);
+                read_line(cells, host.width, at as usize, || {
+                    *read += 1;
+                    Ok(input.get(*read - 1).copied())
+                }).unwrap();
+            })?;
+        let instance = linker.instantiate_and_start(&mut store, &module)?;
+        instance.get_typed_func::<(), ()>(&store, "run")?.call(&mut store, ())?;
+        let memory = instance.get_memory(&store, "memory").unwrap().data(&store).to_vec();
+        let host = store.into_data();
+        Ok((host.out, host.dump, memory))
+    };
+    // wasmi overflows its stack on the mandelbrot set in a debug build,
+    // and takes seconds over it with optimizations
+    for sample in backend_samples().filter(|sample| sample.src != MANDELBROT) {
+        let module = emit_module(&sample.ir, sample.mem_size, sample.width, EofPolicy::Zero).unwrap();
+        let (out, _, memory) = run(&module, sample.input, sample.mem_size, sample.width)
+            .unwrap_or_else(|e| panic!("{}: {}", sample.src, e));
+        let mut expected = sample.output;
+        if let Some((RuntimeError::PointerOverflow { at, direction }, index)) = sample.error {
+            let end = if direction == Direction::Left { '<' } else { '>' };
+            expected.extend(format!("{}{}@{}", end, index, at).bytes());
+        }
+        assert_eq!(out, expected, "{} {:?}", sample.src, sample.width);
+        // the tape is whole pages, the cells are in native order on little endian hosts
+        assert_eq!(memory[..sample.memory.len()], sample.memory, "{} {:?}", sample.src, sample.width);
+    }
+
+    // `#` and `;`, which only come from the ir
+    let ir = [BfIR::AddPtr(1), BfIR::ReadLine, BfIR::AddVal(1), BfIR::DumpTape, BfIR::ReadLine, BfIR::AddPtr(2), BfIR::DumpTape];
+    for width in [CellWidth::W8, CellWidth::W16, CellWidth::W32] {
+        let module = emit_module(&ir, 8, width, EofPolicy::Zero).unwrap();
+        let (out, dump, memory) = run(&module, b"ab\ncd", 8, width).unwrap();
+        let expected_dump = SharedBuf::default();
+        let mut vm = BfVM::builder()
+            .source_ir(ir.to_vec())
+            .mem_size(8)
+            .cell_width(width)
+            .input_bytes(b"ab\ncd")
+            .dump_output(Box::new(expected_dump.clone()))
+            .build()
+            .unwrap();
+        assert_eq!(out, vm.run_capture().unwrap(), "{:?}", width);
+        assert_eq!(dump, expected_dump.take(), "{:?}", width);
+        assert_eq!(memory[..8 * width.bytes()], *vm.memory(), "{:?}", width);
+    }
+}